@@ -0,0 +1,74 @@
+//! Dumping a full lookback, `cargo bench --bench drain`. The lookback is configured from the
+//! environment like the bot's.
+use criterion::{criterion_group, criterion_main, Criterion};
+use songbird::model::id::UserId;
+
+use rust_discord_record::config::Config;
+use rust_discord_record::receiver::{Tick, AUDIO_PACKET_SIZE};
+use rust_discord_record::Lookback;
+
+/// A 440Hz tone, loud enough that every tick encodes to a full packet.
+fn tone() -> [i16; AUDIO_PACKET_SIZE] {
+    std::array::from_fn(|i| {
+        let t = (i / 2) as f32 / 48_000.0;
+        ((t * 440.0 * std::f32::consts::TAU).sin() * 8000.0) as i16
+    })
+}
+
+/// The lookback filled tick by tick with one user speaking, and the number of frames it holds.
+fn filled(config: &Config) -> (Lookback, usize) {
+    let lookback = Lookback::new(config);
+    let audio = tone();
+    let ticks = config.lookback_duration.as_millis() / 20;
+    for _ in 0..ticks {
+        lookback.tick(
+            &Tick {
+                speaking: vec![(1, Some(&audio[..]))],
+                ..Default::default()
+            },
+            |_| Some(UserId(1)),
+            |_| None,
+        );
+    }
+    let frames = config
+        .encoder
+        .packets_for_duration(config.lookback_duration);
+    (lookback, frames)
+}
+
+/// The copy of the whole buffer taken under its lock before a dump encodes anything.
+fn snapshot(c: &mut Criterion) {
+    let (lookback, frames) = filled(&Config::from_env().unwrap());
+    c.bench_function("snapshot", |b| {
+        b.iter(|| assert_eq!(lookback.snapshot_all(), frames))
+    });
+}
+
+/// Muxing the whole lookback into ogg on every dump against keeping it as ready made pages,
+/// PREMUX_LOOKBACK.
+fn drain_buffer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("drain_buffer");
+    for premux_lookback in [false, true] {
+        let mut config = Config::from_env().unwrap();
+        config.premux_lookback = premux_lookback;
+        let (lookback, frames) = filled(&config);
+        let name = if premux_lookback { "premuxed" } else { "muxed" };
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let dump = lookback
+                    .drain_buffer(&Default::default(), &Default::default())
+                    .unwrap();
+                assert_eq!(dump.packets, frames);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    // a full lookback takes a while to dump, fewer samples keep a run to a few minutes
+    config = Criterion::default().sample_size(10);
+    targets = snapshot, drain_buffer
+}
+criterion_main!(benches);
@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use ogg::{PacketReader, PacketWriteEndInfo, PacketWriter};
+use songbird::model::id::UserId;
+
+use crate::config::{ArchiveMode, Config};
+use crate::encode::{opus_headers, packet_duration, packet_samples, SilencePacker, StreamOptions};
+use crate::receiver::{audio_dir, AUDIO_CHANNELS, AUDIO_FREQUENCY, FrameEncoder};
+
+/// Ends a page, and flushes it to disk, about once a second so a crash loses little.
+const PACKETS_PER_PAGE: u64 = 50;
+
+/// Packets waiting to be written, about two minutes of the mix on its own so even a reconnect's
+/// padding fits. Anything past that is dropped so a slow disk never holds up the tick.
+const QUEUE_PACKETS: usize = 6000;
+
+/// How much of the end of an archive file is read back at first when looking for recent speech,
+/// quadrupled until enough speech is found or the whole file was read.
+const TAIL_BYTES: u64 = 256 * 1024;
+
+/// Appends encoded packets straight to ogg files in `DISCORD_AUDIO_DIR` as they're produced, so
+/// sessions of any length are kept regardless of the in memory lookback. Each file is finished
+/// with an end of stream page when its user leaves or the bot shuts down. A worker thread does the
+/// writing, the tick only queues packets.
+pub struct Archive {
+    mode: ArchiveMode,
+    sender: Option<SyncSender<Command>>,
+    /// each user's latest file, kept after it's finished.
+    user_paths: Arc<DashMap<UserId, PathBuf>>,
+    empty_encoded: bytes::Bytes,
+    /// packets dropped because the disk fell behind.
+    dropped: AtomicU64,
+    /// set once the worker thread is found gone, so that's only warned about once.
+    stopped: AtomicBool,
+}
+
+/// What the worker thread is asked to do, in the order it was asked.
+enum Command {
+    Mix(bytes::Bytes),
+    User(UserId, bytes::Bytes),
+    FinishUser(UserId),
+    /// answered once every file is finished.
+    FinishAll(mpsc::Sender<()>),
+}
+
+impl Archive {
+    pub fn new(config: &Config) -> Self {
+        let empty_encoded = FrameEncoder::new(&config.encoder).empty_encoded().clone();
+        let user_paths: Arc<DashMap<UserId, PathBuf>> = Default::default();
+        let sender = (config.archive != ArchiveMode::Off).then(|| {
+            let (sender, receiver) = mpsc::sync_channel(QUEUE_PACKETS);
+            let worker = Worker {
+                // start of the session, shared by every file name
+                session: chrono::prelude::Local::now()
+                    .format("%Y-%m-%d_%H-%M-%S")
+                    .to_string(),
+                mix: None,
+                users: HashMap::new(),
+                user_paths: user_paths.clone(),
+                empty_encoded: empty_encoded.clone(),
+                compact_silence: config.compact_silence,
+            };
+            std::thread::Builder::new()
+                .name("archive".to_string())
+                .spawn(move || worker.run(receiver))
+                .expect("failed to spawn archive thread");
+            sender
+        });
+        Self {
+            mode: config.archive,
+            sender,
+            user_paths,
+            empty_encoded,
+            dropped: Default::default(),
+            stopped: Default::default(),
+        }
+    }
+
+    pub fn write_mix(&self, packet: &bytes::Bytes) {
+        if matches!(self.mode, ArchiveMode::Mix | ArchiveMode::All) {
+            self.queue(Command::Mix(packet.clone()));
+        }
+    }
+
+    pub fn write_user(&self, user: UserId, packet: &bytes::Bytes) {
+        if matches!(self.mode, ArchiveMode::Users | ArchiveMode::All) {
+            self.queue(Command::User(user, packet.clone()));
+        }
+    }
+
+    fn queue(&self, command: Command) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        match sender.try_send(command) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                // once a second of 20ms frames, not every tick
+                if dropped % 50 == 1 {
+                    tracing::warn!(
+                        "archive disk is too slow, {} packets dropped so far",
+                        dropped
+                    );
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => self.warn_stopped(),
+        }
+    }
+
+    /// Queues a command that can't be dropped, waiting for room if the queue is full. Only for
+    /// the rare ones that aren't sent from the tick.
+    fn send(&self, command: Command) {
+        if let Some(sender) = &self.sender {
+            if sender.send(command).is_err() {
+                self.warn_stopped();
+            }
+        }
+    }
+
+    fn warn_stopped(&self) {
+        if !self.stopped.swap(true, Ordering::Relaxed) {
+            tracing::warn!("archive thread stopped, nothing is archived anymore");
+        }
+    }
+
+    /// The last `duration` of the user's speech in their latest archive file, silence left out.
+    /// `None` if they don't have one, e.g. because `ARCHIVE` doesn't include users. Only the end
+    /// of the file is read, packets not yet written out as a page are left out.
+    pub async fn recent_speech(
+        &self,
+        user: UserId,
+        duration: Duration,
+    ) -> anyhow::Result<Option<Vec<bytes::Bytes>>> {
+        let Some(path) = self.user_paths.get(&user).map(|path| path.clone()) else {
+            return Ok(None);
+        };
+        let empty_encoded = self.empty_encoded.clone();
+        tokio::task::spawn_blocking(move || recent_speech(&path, duration, &empty_encoded))
+            .await?
+            .map(Some)
+    }
+
+    /// Finishes the user's file, e.g. when they leave the channel.
+    pub fn finish_user(&self, user: UserId) {
+        self.send(Command::FinishUser(user));
+    }
+
+    /// Finishes every open file, called on shutdown. Waits for everything queued before it to be
+    /// written.
+    pub fn finish_all(&self) {
+        let (done, finished) = mpsc::channel();
+        self.send(Command::FinishAll(done));
+        // an error means the worker is gone, there's nothing left to wait for
+        let _ = finished.recv();
+    }
+}
+
+/// Owns the open files, only ever touched by the archive thread.
+struct Worker {
+    session: String,
+    mix: Option<OggAppender>,
+    users: HashMap<UserId, OggAppender>,
+    user_paths: Arc<DashMap<UserId, PathBuf>>,
+    empty_encoded: bytes::Bytes,
+    compact_silence: bool,
+}
+
+impl Worker {
+    /// Runs until the [`Archive`] is dropped.
+    fn run(mut self, commands: Receiver<Command>) {
+        for command in commands {
+            match command {
+                Command::Mix(packet) => {
+                    if self.mix.is_none() {
+                        self.mix = self.create(format!("archive_{}_mix.ogg", self.session));
+                    }
+                    if let Some(appender) = &mut self.mix {
+                        appender.append(&packet);
+                    }
+                }
+                Command::User(user, packet) => {
+                    if !self.users.contains_key(&user) {
+                        // each stint in the channel gets its own file
+                        let date = chrono::prelude::Local::now().format("%Y-%m-%d_%H-%M-%S");
+                        let Some(appender) =
+                            self.create(format!("archive_{}_{}_{}.ogg", self.session, user, date))
+                        else {
+                            continue;
+                        };
+                        self.user_paths.insert(user, appender.path.clone());
+                        self.users.insert(user, appender);
+                    }
+                    if let Some(appender) = self.users.get_mut(&user) {
+                        appender.append(&packet);
+                    }
+                }
+                Command::FinishUser(user) => {
+                    if let Some(appender) = self.users.remove(&user) {
+                        appender.finish(&self.empty_encoded);
+                    }
+                }
+                Command::FinishAll(done) => {
+                    if let Some(appender) = self.mix.take() {
+                        appender.finish(&self.empty_encoded);
+                    }
+                    for (_, appender) in self.users.drain() {
+                        appender.finish(&self.empty_encoded);
+                    }
+                    // nobody waiting anymore is fine too
+                    let _ = done.send(());
+                }
+            }
+        }
+    }
+
+    fn create(&self, file_name: String) -> Option<OggAppender> {
+        let packer = self
+            .compact_silence
+            .then(|| SilencePacker::new(self.empty_encoded.clone()));
+        OggAppender::create(audio_dir().join(file_name), packer)
+            .map_err(|e| tracing::error!("failed to start archive file {:?}", e))
+            .ok()
+    }
+}
+
+/// The last `duration` of speech in the archive file at `path`, read from the end of the file
+/// back only as far as it takes to find that much.
+fn recent_speech(
+    path: &Path,
+    duration: Duration,
+    empty_encoded: &bytes::Bytes,
+) -> anyhow::Result<Vec<bytes::Bytes>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut tail = TAIL_BYTES;
+    loop {
+        let start = len.saturating_sub(tail);
+        let mut speech = Vec::new();
+        let mut speech_duration = Duration::ZERO;
+        for packet in packets_from(&mut file, start)?.into_iter().rev() {
+            if speech_duration >= duration {
+                break;
+            }
+            if SilencePacker::is_silence(empty_encoded, &packet) {
+                continue;
+            }
+            speech_duration += packet_duration(&packet);
+            speech.push(packet);
+        }
+        if speech_duration >= duration || start == 0 {
+            speech.reverse();
+            return Ok(speech);
+        }
+        tail *= 4;
+    }
+}
+
+/// The audio packets of the pages at or after `start` in an archive file, which doesn't have to
+/// be at a page boundary. A packet continued from the page before `start` is left out, and so is
+/// a page the worker is still writing at the end of the file.
+fn packets_from(file: &mut File, start: u64) -> anyhow::Result<Vec<bytes::Bytes>> {
+    let mut reader = PacketReader::new(file);
+    reader.seek_bytes(SeekFrom::Start(start))?;
+    let mut packets = Vec::new();
+    loop {
+        match reader.read_packet() {
+            // the OpusHead and OpusTags pages are the only ones at granule 0
+            Ok(Some(packet)) if packet.absgp_page() == 0 => {}
+            Ok(Some(packet)) => packets.push(packet.data.into()),
+            Ok(None) => return Ok(packets),
+            Err(e) if !packets.is_empty() => {
+                tracing::debug!("archive ends in a partly written page {:?}", e);
+                return Ok(packets);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+struct OggAppender {
+    writer: PacketWriter<'static, BufWriter<File>>,
+    path: PathBuf,
+    serial: u32,
+    granule: u64,
+    packets: u64,
+    /// held back one packet so the last one can carry the end of stream flag.
+    pending: Option<bytes::Bytes>,
+    packer: Option<SilencePacker>,
+}
+
+impl OggAppender {
+    fn create(path: PathBuf, packer: Option<SilencePacker>) -> anyhow::Result<Self> {
+        tracing::info!("archiving to {}", path.display());
+        let options = StreamOptions::default();
+        let mut writer = PacketWriter::new(BufWriter::new(File::create(&path)?));
+        let (head, tags) = opus_headers::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(&options);
+        writer.write_packet(
+            head.to_vec(),
+            options.serial,
+            PacketWriteEndInfo::EndPage,
+            0,
+        )?;
+        writer.write_packet(tags, options.serial, PacketWriteEndInfo::EndPage, 0)?;
+        writer.inner_mut().flush()?;
+        Ok(Self {
+            writer,
+            path,
+            serial: options.serial,
+            granule: 0,
+            packets: 0,
+            pending: None,
+            packer,
+        })
+    }
+
+    fn append(&mut self, packet: &bytes::Bytes) {
+        let Some(packer) = &mut self.packer else {
+            self.append_packed(packet.clone());
+            return;
+        };
+        let mut packed = Vec::new();
+        packer.push(packet.clone(), &mut packed);
+        for packet in packed {
+            self.append_packed(packet);
+        }
+    }
+
+    fn append_packed(&mut self, packet: bytes::Bytes) {
+        let Some(previous) = self.pending.replace(packet) else {
+            return;
+        };
+        let end_info = if self.packets % PACKETS_PER_PAGE == PACKETS_PER_PAGE - 1 {
+            PacketWriteEndInfo::EndPage
+        } else {
+            PacketWriteEndInfo::NormalPacket
+        };
+        if let Err(e) = self.write(previous, end_info) {
+            tracing::error!("failed to append to {} {:?}", self.path.display(), e);
+        }
+    }
+
+    fn write(&mut self, packet: bytes::Bytes, end_info: PacketWriteEndInfo) -> anyhow::Result<()> {
+        self.granule += packet_samples(&packet) as u64;
+        self.packets += 1;
+        let end_page = !matches!(end_info, PacketWriteEndInfo::NormalPacket);
+        self.writer
+            .write_packet(packet.to_vec(), self.serial, end_info, self.granule)?;
+        if end_page {
+            self.writer.inner_mut().flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the held back packet, or silence if nothing was ever appended, as end of stream.
+    fn finish(mut self, empty_encoded: &bytes::Bytes) {
+        if let Some(mut packer) = self.packer.take() {
+            let mut packed = Vec::new();
+            packer.finish(&mut packed);
+            for packet in packed {
+                self.append_packed(packet);
+            }
+        }
+        let last = self.pending.take().unwrap_or_else(|| empty_encoded.clone());
+        match self.write(last, PacketWriteEndInfo::EndStream) {
+            Ok(()) => tracing::info!(
+                "finished archive {} ({} packets)",
+                self.path.display(),
+                self.packets
+            ),
+            Err(e) => tracing::error!("failed to finish {} {:?}", self.path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recent_speech_reads_back_from_the_tail() {
+        let encoder = FrameEncoder::new(&Config::for_tests().encoder);
+        let silence = encoder.empty_encoded().clone();
+        let path = std::env::temp_dir().join(format!("archive_tail_{}.ogg", std::process::id()));
+        let mut appender = OggAppender::create(path.clone(), None).unwrap();
+        // 20ms packets of about 1KB each, a megabyte in all so the first tail read falls short
+        let packets: Vec<bytes::Bytes> = (0..1000u32)
+            .map(|i| {
+                let mut packet = vec![silence[0]];
+                packet.extend(std::iter::repeat(i.to_le_bytes()).take(250).flatten());
+                packet.into()
+            })
+            .collect();
+        for (i, packet) in packets.iter().enumerate() {
+            appender.append(packet);
+            if i % 100 == 0 {
+                appender.append(&silence);
+            }
+        }
+        appender.finish(&silence);
+
+        let speech = recent_speech(&path, Duration::from_secs(10), &silence).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(speech, packets[500..]);
+    }
+}
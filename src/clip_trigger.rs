@@ -0,0 +1,133 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serenity::all::Http;
+use serenity::model::id::ChannelId;
+
+use crate::config::{ClipTrigger, normalize_transcript};
+use crate::encode::StreamOptions;
+use crate::lookback::{DrainOptions, DumpResult};
+use crate::receiver::{Receiver, write_ogg_to_disk};
+
+/// Transcribes the last `window` of the lookback every `interval` and dumps the last `duration`
+/// to storage when one of the phrases is in it, posting where it went to the log channel. Checks
+/// overlap so a phrase cut by one check is whole in the next, which would then trigger again, so
+/// after a dump matches are ignored until the phrase has left the transcribed window.
+pub fn spawn(
+    receiver: Arc<Receiver>,
+    http: Arc<Http>,
+    log_channel: ChannelId,
+    trigger: ClipTrigger,
+) {
+    tracing::info!(
+        "listening for {} clip phrases every {}",
+        trigger.phrases.len(),
+        humantime::format_duration(trigger.interval)
+    );
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(trigger.interval);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut last_clip: Option<Instant> = None;
+        loop {
+            ticks.tick().await;
+            if last_clip.is_some_and(|at| at.elapsed() < trigger.window) {
+                continue;
+            }
+            let phrase = match heard_phrase(&receiver, &trigger).await {
+                Ok(Some(phrase)) => phrase,
+                Ok(None) => continue,
+                Err(e) => {
+                    tracing::warn!("clip phrase check failed {:?}", e);
+                    continue;
+                }
+            };
+            last_clip = Some(Instant::now());
+            let message = match clip(&receiver, trigger.duration).await {
+                Ok(message) => format!("heard '{}', {}", phrase, message),
+                Err(e) => {
+                    tracing::error!("clip failed {:?}", e);
+                    format!("heard '{}' but the clip failed: {}", phrase, e)
+                }
+            };
+            if let Err(e) = log_channel.say(&http, message).await {
+                tracing::error!("failed to post clip {:?}", e);
+            }
+        }
+    });
+}
+
+/// The first phrase in a transcript of the recent speech, none without speech to transcribe.
+async fn heard_phrase(
+    receiver: &Arc<Receiver>,
+    trigger: &ClipTrigger,
+) -> anyhow::Result<Option<String>> {
+    let drain = DrainOptions {
+        duration: Some(trigger.window),
+        trim_silence: true,
+        ..Default::default()
+    };
+    let dump = drain_blocking(receiver, drain).await?;
+    if dump.packets == 0 {
+        return Ok(None);
+    }
+    let transcript = normalize_transcript(&receiver.tts.stt(dump.bytes).await?);
+    let padded = format!(" {} ", transcript);
+    Ok(trigger
+        .phrases
+        .iter()
+        .find(|phrase| padded.contains(&format!(" {} ", phrase)))
+        .cloned())
+}
+
+/// Dumps the last `duration` through the export queue and stores it, describing where it went.
+async fn clip(receiver: &Arc<Receiver>, duration: Duration) -> anyhow::Result<String> {
+    let drain = DrainOptions {
+        duration: Some(duration),
+        cues: receiver.cues(),
+        ..Default::default()
+    };
+    let job_receiver = receiver.clone();
+    let dump = receiver
+        .export_queue
+        .enqueue(move || {
+            tracing::info!("running clip");
+            job_receiver
+                .lookback
+                .drain_buffer(&drain, &StreamOptions::default())
+        })
+        .result()
+        .await??;
+    let file_name =
+        write_ogg_to_disk(receiver.sink.as_ref(), &dump.bytes, dump.duration, "ogg").await?;
+    let location = match &receiver.config.media_base_url {
+        Some(base_url) => format!("{}/{}", base_url, file_name),
+        None => format!(
+            "'{}' on {} storage",
+            file_name,
+            receiver.config.storage.name()
+        ),
+    };
+    let mut message = format!(
+        "saved the last {} as {}",
+        humantime::format_duration(Duration::from_secs(dump.duration.as_secs())),
+        location
+    );
+    if let Some(id) = receiver.dump_cache.insert(&dump, "ogg") {
+        message.push_str(&format!(", /redump {} sends it", id));
+    }
+    Ok(message)
+}
+
+async fn drain_blocking(
+    receiver: &Arc<Receiver>,
+    drain: DrainOptions,
+) -> anyhow::Result<DumpResult> {
+    let receiver = receiver.clone();
+    tokio::task::spawn_blocking(move || {
+        receiver
+            .lookback
+            .drain_buffer(&drain, &StreamOptions::default())
+    })
+    .await
+    .map_err(|e| anyhow::anyhow!("transcription drain panicked: {}", e))?
+}
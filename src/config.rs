@@ -0,0 +1,943 @@
+use std::env;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use audiopus::Application;
+use serenity::all::GatewayIntents;
+
+use crate::crypt;
+use crate::resample::SAMPLE_RATES;
+use crate::receiver::{audio_dir, AUDIO_FREQUENCY, VOLUME_FILE};
+use crate::lookback::{PACKET_DURATION, packets_for_duration};
+use crate::storage::StorageBackend;
+
+/// 30 minutes of mixed audio.
+const DEFAULT_LOOKBACK_DURATION: Duration = Duration::from_secs(60 * 30);
+/// 2 minutes of per user audio for voice cloning.
+const DEFAULT_CLONE_DURATION: Duration = Duration::from_secs(60 * 2);
+const DEFAULT_BITRATE: i32 = 24000;
+const DEFAULT_COMPLEXITY: u8 = 10;
+const DEFAULT_MAX_TRACKED_USERS: usize = 100;
+const DEFAULT_UTTERANCE_GAP: Duration = Duration::from_millis(700);
+const DEFAULT_SPEECH_HANGOVER: Duration = Duration::from_millis(300);
+pub(crate) const DEFAULT_VERIFY_THRESHOLD: f32 = 0.8;
+const DEFAULT_MARKER_FREQUENCY: f32 = 1000.0;
+const DEFAULT_MARKER_DURATION: Duration = Duration::from_millis(100);
+const DEFAULT_UNMAPPED_GRACE: Duration = Duration::from_secs(5);
+const DEFAULT_RECONNECT_GRACE: Duration = Duration::from_secs(60);
+const DEFAULT_MIN_REFERENCE_SPEECH: Duration = Duration::from_secs(3);
+const DEFAULT_MAX_REFERENCE_DURATION: Duration = Duration::from_secs(60 * 10);
+const DEFAULT_WAVEFORM_WIDTH: u32 = 1200;
+const DEFAULT_WAVEFORM_HEIGHT: u32 = 200;
+/// per side, the image is held uncompressed while it's rendered, 4096x4096 is already 48MiB.
+const MAX_WAVEFORM_SIZE: u32 = 4096;
+const DEFAULT_PAN_WIDTH: f32 = 0.8;
+const DEFAULT_DUMP_CACHE_MB: usize = 64;
+const DEFAULT_CLIP_WINDOW: Duration = Duration::from_secs(8);
+const DEFAULT_CLIP_INTERVAL: Duration = Duration::from_secs(4);
+const DEFAULT_CLIP_DURATION: Duration = Duration::from_secs(30);
+const DEFAULT_TICK_STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Effective runtime configuration, read once from the environment at startup.
+pub struct Config {
+    pub lookback_duration: Duration,
+    pub clone_duration: Duration,
+    pub encoder: EncoderSettings,
+    /// upper bound on distinct users with a clone buffer.
+    pub max_tracked_users: usize,
+    /// silence longer than this ends an utterance for `lastclip`.
+    pub utterance_gap: Duration,
+    /// silence this short after a user spoke still counts as them talking, so pauses between words
+    /// don't split `lastclip` utterances or go missing from speech stats.
+    pub speech_hangover: Duration,
+    /// `compact` drops per user buffers with less speech than this, fully silent ones always go.
+    pub compact_min_speech_ratio: f32,
+    /// `clone` references need more speech than none and at least this fraction.
+    pub clone_min_speech_ratio: f32,
+    /// `verify` passes a clone reference that matches the user's recent speech at least this well.
+    pub verify_threshold: f32,
+    pub silent_reference: SilentReference,
+    /// `ctts` refuses to clone from a reference with less speech than this.
+    pub min_reference_speech: Duration,
+    /// `clone append` drops the oldest audio of a reference longer than this.
+    pub max_reference_duration: Duration,
+    pub tts_host: Option<String>,
+    /// backend voices `ctts` may request instead of cloning.
+    pub tts_voices: Vec<String>,
+    pub stt_host: Option<String>,
+    /// dumps the lookback when a phrase is heard, off if `None`.
+    pub clip_trigger: Option<ClipTrigger>,
+    /// commands that can't be invoked as `!` prefix commands, only as slash commands.
+    pub slash_only_commands: Vec<String>,
+    /// user ids exempt from caps like `MAX_DUMP_DURATION`.
+    pub trusted_users: Vec<u64>,
+    /// members with any of these role ids are exempt too.
+    pub trusted_roles: Vec<u64>,
+    pub storage: StorageBackend,
+    /// `RECORDING_KEY`, everything written through the storage backend or to disk is encrypted
+    /// with it if set.
+    pub recording_key: Option<crypt::Key>,
+    /// pitch in Hz of the optional alignment beep at the start of a dump.
+    pub marker_frequency: f32,
+    pub marker_duration: Duration,
+    /// mix the bot's own playback (ctts) into the lookback.
+    pub record_playback: bool,
+    /// drop the lookback when the bot is moved to another voice channel.
+    pub clear_lookback_on_move: bool,
+    /// public url the storage backend is served from, enables `dump link`.
+    pub media_base_url: Option<String>,
+    /// what the lookback does while nobody but the bot is in the channel.
+    pub empty_channel: EmptyChannelMode,
+    /// longest audio a single `dump` encodes, unlimited if `None`.
+    pub max_dump_duration: Option<Duration>,
+    /// total size of recent exports kept in memory for `redump`, 0 disables it.
+    pub dump_cache_bytes: usize,
+    /// rate exports are written at unless one asks for another, see
+    /// [`crate::resample::SAMPLE_RATES`].
+    pub export_sample_rate: u32,
+    /// master gain in dB applied to exports and playback, within [`MAX_VOLUME_DB`] either way.
+    pub volume_db: f32,
+    /// how simultaneous speakers are combined in the lookback.
+    pub mix_mode: MixMode,
+    /// how often measured clock drift is logged, disabled if `None`.
+    pub drift_log_interval: Option<Duration>,
+    /// how often the lookback is test drained, disabled if `None`.
+    pub self_check_interval: Option<Duration>,
+    /// post failed self checks to the log channel as well.
+    pub self_check_alert: bool,
+    /// reconnect when no voice tick reached the lookback for this long while connected, off if
+    /// `None`.
+    pub tick_stall_timeout: Option<Duration>,
+    /// how much audio from an ssrc without a known user is held for when its mapping arrives.
+    pub unmapped_grace: Duration,
+    /// voice outages up to this long are bridged with silence, longer ones clear the lookback.
+    pub reconnect_grace: Duration,
+    /// which streams are also appended to ever growing files on disk.
+    pub archive: ArchiveMode,
+    /// where the mix is streamed live as it's encoded, off if `None`.
+    pub live_stream: Option<LiveTarget>,
+    /// size in pixels of the `waveform` preview.
+    pub waveform_width: u32,
+    pub waveform_height: u32,
+    /// store runs of silence in dumps and archives as packed zero length frames.
+    pub compact_silence: bool,
+    /// keep the lookback as ready made ogg pages too, so plain dumps only copy them.
+    pub premux_lookback: bool,
+    /// silent stretches inside a trimmed export are shortened to this, only the ends if `None`.
+    pub trim_max_gap: Option<Duration>,
+    /// what happens to decoded audio that isn't exactly one tick long.
+    pub oversized_audio: OversizedAudio,
+    /// what goes in a user's buffer for a tick they spoke in that the driver didn't decode.
+    pub missing_audio: MissingAudio,
+    /// how speakers are placed in the stereo field of the lookback mix.
+    pub pan: PanMode,
+    /// fraction of the stereo field pan positions use, 1 allows hard left and right.
+    pub pan_width: f32,
+    /// longest `ctts` output played, unlimited if `None`.
+    pub max_tts_duration: Option<Duration>,
+    /// `ctts` text longer than this many characters is spoken a few sentences at a time, off if
+    /// `None`.
+    pub tts_chunk_chars: Option<usize>,
+    /// gateway intents the client connects with.
+    pub intents: GatewayIntents,
+    /// high-pass cutoff in Hz applied to each user's audio, off if `None`.
+    pub highpass_cutoff: Option<f32>,
+    /// rms in dBFS below which a user's audio is gated to silence, off if `None`.
+    pub noise_gate_threshold: Option<f32>,
+    /// also run the high-pass and gate over the lookback mix.
+    pub filter_mix: bool,
+    /// played into the voice channel whenever the bot joins one, off if `None`.
+    pub recording_notice: Option<RecordingNotice>,
+    /// text channel whose messages are kept for the chat sidecar of dumps, `TEXT_CHANNEL_ID` with
+    /// `CHAT_LOG`, off if `None`.
+    pub chat_channel: Option<u64>,
+}
+
+impl Config {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let storage = StorageBackend::from_env()?;
+        let recording_key = crypt::recording_key()?;
+        let media_base_url = parse_media_base_url()?;
+        if media_base_url.is_some() && recording_key.is_some() {
+            return Err(anyhow!(
+                "MEDIA_BASE_URL can't be used with RECORDING_KEY, linked files would be encrypted"
+            ));
+        }
+        let archive = parse_archive()?;
+        if archive != ArchiveMode::Off && recording_key.is_some() {
+            return Err(anyhow!(
+                "ARCHIVE can't be used with RECORDING_KEY, archives are written unencrypted"
+            ));
+        }
+        let compact_silence = parse_env("COMPACT_SILENCE")?.unwrap_or(false);
+        let premux_lookback = parse_env("PREMUX_LOOKBACK")?.unwrap_or(false);
+        if compact_silence && premux_lookback {
+            return Err(anyhow!(
+                "PREMUX_LOOKBACK can't be used with COMPACT_SILENCE, premuxed pages aren't packed"
+            ));
+        }
+        let intents = parse_intents()?;
+        Ok(Self {
+            lookback_duration: DEFAULT_LOOKBACK_DURATION,
+            clone_duration: DEFAULT_CLONE_DURATION,
+            encoder: EncoderSettings {
+                bitrate: DEFAULT_BITRATE,
+                application: parse_application()?,
+                frame_duration: parse_frame_duration()?,
+                complexity: parse_complexity()?,
+                vbr: parse_env("OPUS_VBR")?.unwrap_or(true),
+                fec: parse_env("OPUS_FEC")?.unwrap_or(false),
+                dtx: parse_env("OPUS_DTX")?.unwrap_or(false),
+            },
+            max_tracked_users: parse_max_tracked_users()?,
+            utterance_gap: parse_env::<humantime::Duration>("UTTERANCE_GAP")?
+                .map(Into::into)
+                .unwrap_or(DEFAULT_UTTERANCE_GAP),
+            speech_hangover: parse_env::<humantime::Duration>("SPEECH_HANGOVER")?
+                .map(Into::into)
+                .unwrap_or(DEFAULT_SPEECH_HANGOVER),
+            compact_min_speech_ratio: parse_fraction("COMPACT_MIN_SPEECH_RATIO", 0.0)?,
+            clone_min_speech_ratio: parse_fraction("CLONE_MIN_SPEECH_RATIO", 0.0)?,
+            verify_threshold: parse_fraction("VERIFY_THRESHOLD", DEFAULT_VERIFY_THRESHOLD)?,
+            silent_reference: parse_silent_reference()?,
+            min_reference_speech: parse_env::<humantime::Duration>("MIN_REFERENCE_SPEECH")?
+                .map(Into::into)
+                .unwrap_or(DEFAULT_MIN_REFERENCE_SPEECH),
+            max_reference_duration: parse_env::<humantime::Duration>("MAX_REFERENCE_DURATION")?
+                .map(Into::into)
+                .unwrap_or(DEFAULT_MAX_REFERENCE_DURATION),
+            tts_host: env::var("TTS_HOST").ok(),
+            tts_voices: parse_list("TTS_VOICES"),
+            stt_host: env::var("STT_HOST").ok(),
+            clip_trigger: parse_clip_trigger()?,
+            slash_only_commands: parse_list("SLASH_ONLY_COMMANDS"),
+            trusted_users: parse_ids("TRUSTED_USERS")?,
+            trusted_roles: parse_ids("TRUSTED_ROLES")?,
+            storage,
+            recording_key,
+            marker_frequency: parse_marker_frequency()?,
+            marker_duration: parse_env::<humantime::Duration>("MARKER_DURATION")?
+                .map(Into::into)
+                .unwrap_or(DEFAULT_MARKER_DURATION),
+            record_playback: parse_env("RECORD_PLAYBACK")?.unwrap_or(false),
+            clear_lookback_on_move: parse_env("CLEAR_LOOKBACK_ON_MOVE")?.unwrap_or(false),
+            media_base_url,
+            empty_channel: parse_empty_channel()?,
+            max_dump_duration: parse_cap("MAX_DUMP_DURATION")?,
+            dump_cache_bytes: parse_env::<usize>("DUMP_CACHE_MB")?
+                .unwrap_or(DEFAULT_DUMP_CACHE_MB)
+                .saturating_mul(1024 * 1024),
+            export_sample_rate: parse_export_sample_rate()?,
+            volume_db: parse_volume()?,
+            mix_mode: parse_mix_mode()?,
+            drift_log_interval: parse_env::<humantime::Duration>("DRIFT_LOG_INTERVAL")?
+                .map(Into::into),
+            self_check_interval: parse_env::<humantime::Duration>("SELF_CHECK_INTERVAL")?
+                .map(Into::into),
+            self_check_alert: parse_env("SELF_CHECK_ALERT")?.unwrap_or(false),
+            tick_stall_timeout: Some(
+                parse_env::<humantime::Duration>("TICK_STALL_TIMEOUT")?
+                    .map(Into::into)
+                    .unwrap_or(DEFAULT_TICK_STALL_TIMEOUT),
+            )
+            .filter(|timeout| !timeout.is_zero()),
+            unmapped_grace: parse_env::<humantime::Duration>("UNMAPPED_GRACE")?
+                .map(Into::into)
+                .unwrap_or(DEFAULT_UNMAPPED_GRACE),
+            reconnect_grace: parse_env::<humantime::Duration>("RECONNECT_GRACE")?
+                .map(Into::into)
+                .unwrap_or(DEFAULT_RECONNECT_GRACE),
+            archive,
+            live_stream: parse_live_stream()?,
+            waveform_width: parse_waveform_size("WAVEFORM_WIDTH", DEFAULT_WAVEFORM_WIDTH)?,
+            waveform_height: parse_waveform_size("WAVEFORM_HEIGHT", DEFAULT_WAVEFORM_HEIGHT)?,
+            compact_silence,
+            premux_lookback,
+            trim_max_gap: parse_env::<humantime::Duration>("TRIM_MAX_GAP")?.map(Into::into),
+            oversized_audio: parse_oversized_audio()?,
+            missing_audio: parse_missing_audio()?,
+            pan: parse_pan()?,
+            pan_width: parse_fraction("PAN_WIDTH", DEFAULT_PAN_WIDTH)?,
+            max_tts_duration: parse_cap("MAX_TTS_DURATION")?,
+            tts_chunk_chars: parse_env("TTS_CHUNK_CHARS")?.filter(|chars| *chars > 0),
+            intents,
+            highpass_cutoff: parse_highpass_cutoff()?,
+            noise_gate_threshold: parse_noise_gate_threshold()?,
+            filter_mix: parse_env("FILTER_MIX")?.unwrap_or(false),
+            recording_notice: parse_recording_notice()?,
+            chat_channel: parse_chat_channel(intents)?,
+        })
+    }
+
+    /// The defaults [`Self::from_env`] falls back to with nothing set, without reading the
+    /// environment, so tests don't depend on the shell they're run from.
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        Self {
+            lookback_duration: DEFAULT_LOOKBACK_DURATION,
+            clone_duration: DEFAULT_CLONE_DURATION,
+            encoder: EncoderSettings {
+                bitrate: DEFAULT_BITRATE,
+                application: Application::Audio,
+                frame_duration: Duration::from_millis(20),
+                complexity: DEFAULT_COMPLEXITY,
+                vbr: true,
+                fec: false,
+                dtx: false,
+            },
+            max_tracked_users: DEFAULT_MAX_TRACKED_USERS,
+            utterance_gap: DEFAULT_UTTERANCE_GAP,
+            speech_hangover: DEFAULT_SPEECH_HANGOVER,
+            compact_min_speech_ratio: 0.0,
+            clone_min_speech_ratio: 0.0,
+            verify_threshold: DEFAULT_VERIFY_THRESHOLD,
+            silent_reference: SilentReference::Warn,
+            min_reference_speech: DEFAULT_MIN_REFERENCE_SPEECH,
+            max_reference_duration: DEFAULT_MAX_REFERENCE_DURATION,
+            tts_host: None,
+            tts_voices: Vec::new(),
+            stt_host: None,
+            clip_trigger: None,
+            slash_only_commands: Vec::new(),
+            trusted_users: Vec::new(),
+            trusted_roles: Vec::new(),
+            storage: StorageBackend::Fs,
+            recording_key: None,
+            marker_frequency: DEFAULT_MARKER_FREQUENCY,
+            marker_duration: DEFAULT_MARKER_DURATION,
+            record_playback: false,
+            clear_lookback_on_move: false,
+            media_base_url: None,
+            empty_channel: EmptyChannelMode::Record,
+            max_dump_duration: None,
+            dump_cache_bytes: DEFAULT_DUMP_CACHE_MB * 1024 * 1024,
+            export_sample_rate: AUDIO_FREQUENCY,
+            volume_db: 0.0,
+            mix_mode: MixMode::Sum,
+            drift_log_interval: None,
+            self_check_interval: None,
+            self_check_alert: false,
+            tick_stall_timeout: Some(DEFAULT_TICK_STALL_TIMEOUT),
+            unmapped_grace: DEFAULT_UNMAPPED_GRACE,
+            reconnect_grace: DEFAULT_RECONNECT_GRACE,
+            archive: ArchiveMode::Off,
+            live_stream: None,
+            waveform_width: DEFAULT_WAVEFORM_WIDTH,
+            waveform_height: DEFAULT_WAVEFORM_HEIGHT,
+            compact_silence: false,
+            premux_lookback: false,
+            trim_max_gap: None,
+            oversized_audio: OversizedAudio::Split,
+            missing_audio: MissingAudio::Silence,
+            pan: PanMode::Off,
+            pan_width: DEFAULT_PAN_WIDTH,
+            max_tts_duration: None,
+            tts_chunk_chars: None,
+            intents: GatewayIntents::non_privileged() | GatewayIntents::MESSAGE_CONTENT,
+            highpass_cutoff: None,
+            noise_gate_threshold: None,
+            filter_mix: false,
+            recording_notice: None,
+            chat_channel: None,
+        }
+    }
+}
+
+/// Shared by every opus encoder so the mix and per user buffers sound the same.
+#[derive(Clone, Copy)]
+pub struct EncoderSettings {
+    /// bits per second.
+    pub bitrate: i32,
+    pub application: Application,
+    /// length of each encoded opus packet, longer frames trade loss resilience for less
+    /// container overhead.
+    pub frame_duration: Duration,
+    /// 0 to 10, higher spends more cpu for better quality at the same bitrate.
+    pub complexity: u8,
+    pub vbr: bool,
+    /// in-band forward error correction, costs bitrate so a decoder can rebuild a lost packet.
+    pub fec: bool,
+    /// discontinuous transmission, silence is encoded as frames that only carry their length.
+    pub dtx: bool,
+}
+
+/// Bitrates libopus accepts.
+pub const BITRATE_RANGE: std::ops::RangeInclusive<i32> = 500..=512_000;
+pub const MAX_COMPLEXITY: u8 = 10;
+
+/// Furthest the master volume goes either way, in dB.
+pub const MAX_VOLUME_DB: f32 = 30.0;
+
+impl EncoderSettings {
+    /// Checks the fields that can be changed at runtime, see `encoder`.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !BITRATE_RANGE.contains(&self.bitrate) {
+            return Err(anyhow!(
+                "bitrate {} is outside {} to {} bps",
+                self.bitrate,
+                BITRATE_RANGE.start(),
+                BITRATE_RANGE.end()
+            ));
+        }
+        if self.complexity > MAX_COMPLEXITY {
+            return Err(anyhow!(
+                "complexity {} is above {}",
+                self.complexity,
+                MAX_COMPLEXITY
+            ));
+        }
+        Ok(())
+    }
+
+    /// driver ticks buffered into each encoded frame.
+    pub fn ticks_per_frame(&self) -> usize {
+        packets_for_duration(self.frame_duration, PACKET_DURATION)
+    }
+
+    /// how many whole encoded frames fit in `duration`, see [`packets_for_duration`].
+    pub fn packets_for_duration(&self, duration: Duration) -> usize {
+        packets_for_duration(duration, self.frame_duration)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EmptyChannelMode {
+    /// keep recording silence.
+    Record,
+    /// stop buffering, the lookback is kept for when someone joins.
+    Pause,
+    /// stop buffering and drop the lookback.
+    Clear,
+}
+
+fn parse_empty_channel() -> anyhow::Result<EmptyChannelMode> {
+    match env::var("EMPTY_CHANNEL").as_deref() {
+        Err(_) | Ok("record") => Ok(EmptyChannelMode::Record),
+        Ok("pause") => Ok(EmptyChannelMode::Pause),
+        Ok("clear") => Ok(EmptyChannelMode::Clear),
+        Ok(other) => Err(anyhow!(
+            "unknown EMPTY_CHANNEL '{}', expected record, pause or clear",
+            other
+        )),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MixMode {
+    /// plain sum, gets louder and eventually clips as more people talk.
+    Sum,
+    /// sum divided by the square root of the active speaker count, keeps perceived loudness
+    /// roughly level for uncorrelated voices.
+    Normalize,
+}
+
+fn parse_mix_mode() -> anyhow::Result<MixMode> {
+    match env::var("MIX_MODE").as_deref() {
+        Err(_) | Ok("sum") => Ok(MixMode::Sum),
+        Ok("normalize") => Ok(MixMode::Normalize),
+        Ok(other) => Err(anyhow!(
+            "unknown MIX_MODE '{}', expected sum or normalize",
+            other
+        )),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveMode {
+    Off,
+    /// the same mix the lookback records.
+    Mix,
+    /// one file per user per stint in the channel.
+    Users,
+    All,
+}
+
+fn parse_archive() -> anyhow::Result<ArchiveMode> {
+    match env::var("ARCHIVE").as_deref() {
+        Err(_) | Ok("off") => Ok(ArchiveMode::Off),
+        Ok("mix") => Ok(ArchiveMode::Mix),
+        Ok("users") => Ok(ArchiveMode::Users),
+        Ok("all") => Ok(ArchiveMode::All),
+        Ok(other) => Err(anyhow!(
+            "unknown ARCHIVE '{}', expected off, mix, users or all",
+            other
+        )),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum LiveTarget {
+    /// a named pipe created beforehand, e.g. with `mkfifo`.
+    Fifo(PathBuf),
+    /// address the bot listens on for one consumer at a time.
+    Tcp(String),
+}
+
+fn parse_live_stream() -> anyhow::Result<Option<LiveTarget>> {
+    let Ok(target) = env::var("LIVE_STREAM") else {
+        return Ok(None);
+    };
+    if let Some(path) = target.strip_prefix("fifo:") {
+        return Ok(Some(LiveTarget::Fifo(PathBuf::from(path))));
+    }
+    if let Some(address) = target.strip_prefix("tcp:") {
+        return Ok(Some(LiveTarget::Tcp(address.to_string())));
+    }
+    Err(anyhow!(
+        "unknown LIVE_STREAM '{}', expected fifo:<path> or tcp:<address>",
+        target
+    ))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OversizedAudio {
+    /// cut into tick sized packets, the last one padded with silence.
+    Split,
+    Drop,
+}
+
+fn parse_export_sample_rate() -> anyhow::Result<u32> {
+    let Ok(rate) = env::var("EXPORT_SAMPLE_RATE") else {
+        return Ok(AUDIO_FREQUENCY);
+    };
+    rate.parse()
+        .ok()
+        .filter(|rate| SAMPLE_RATES.contains(rate))
+        .ok_or_else(|| {
+            anyhow!(
+                "unsupported EXPORT_SAMPLE_RATE '{}', expected one of {:?}",
+                rate,
+                SAMPLE_RATES
+            )
+        })
+}
+
+/// `VOLUME`, unless the `volume` command saved a change to [`VOLUME_FILE`] since, which wins so the
+/// change outlasts a restart. Out of range is refused rather than clamped, unlike at runtime.
+fn parse_volume() -> anyhow::Result<f32> {
+    let saved = audio_dir().join(VOLUME_FILE);
+    let (source, volume) = match std::fs::read_to_string(&saved) {
+        Ok(volume) => (saved.display().to_string(), volume.trim().to_string()),
+        Err(e) if e.kind() == ErrorKind::NotFound => match env::var("VOLUME") {
+            Ok(volume) => ("VOLUME".to_string(), volume),
+            Err(_) => return Ok(0.0),
+        },
+        Err(e) => return Err(anyhow!("failed to read {}: {}", saved.display(), e)),
+    };
+    match volume.parse::<f32>() {
+        Ok(db) if db.is_finite() && db.abs() <= MAX_VOLUME_DB => Ok(db),
+        _ => Err(anyhow!(
+            "{} '{}' is not a number of dB within {} dB either way",
+            source,
+            volume,
+            MAX_VOLUME_DB
+        )),
+    }
+}
+
+fn parse_oversized_audio() -> anyhow::Result<OversizedAudio> {
+    match env::var("OVERSIZED_AUDIO").as_deref() {
+        Err(_) | Ok("split") => Ok(OversizedAudio::Split),
+        Ok("drop") => Ok(OversizedAudio::Drop),
+        Ok(other) => Err(anyhow!(
+            "unknown OVERSIZED_AUDIO '{}', expected split or drop",
+            other
+        )),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MissingAudio {
+    Silence,
+    /// the user's last decoded packet once, silence if the gap goes on.
+    Repeat,
+    /// nothing, the user's buffer falls a tick behind the others.
+    Skip,
+}
+
+fn parse_missing_audio() -> anyhow::Result<MissingAudio> {
+    match env::var("MISSING_AUDIO").as_deref() {
+        Err(_) | Ok("silence") => Ok(MissingAudio::Silence),
+        Ok("repeat") => Ok(MissingAudio::Repeat),
+        Ok("skip") => Ok(MissingAudio::Skip),
+        Ok(other) => Err(anyhow!(
+            "unknown MISSING_AUDIO '{}', expected silence, repeat or skip",
+            other
+        )),
+    }
+}
+
+/// What `clone` does when the user's buffer has too little speech to be a usable reference.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SilentReference {
+    Allow,
+    /// clone anyway and log a warning.
+    Warn,
+    Error,
+}
+
+/// Has to fit below the nyquist frequency of the recording or the beep aliases into another pitch.
+fn parse_marker_frequency() -> anyhow::Result<f32> {
+    let Ok(frequency) = env::var("MARKER_FREQUENCY") else {
+        return Ok(DEFAULT_MARKER_FREQUENCY);
+    };
+    let nyquist = AUDIO_FREQUENCY as f32 / 2.0;
+    match frequency.parse::<f32>() {
+        Ok(frequency) if frequency > 0.0 && frequency < nyquist => Ok(frequency),
+        _ => Err(anyhow!(
+            "MARKER_FREQUENCY '{}' is not a frequency between 0 and {} Hz",
+            frequency,
+            nyquist
+        )),
+    }
+}
+
+/// Like the marker, a cutoff at or above the nyquist frequency can't be filtered.
+fn parse_highpass_cutoff() -> anyhow::Result<Option<f32>> {
+    let nyquist = AUDIO_FREQUENCY as f32 / 2.0;
+    match parse_env::<f32>("HIGHPASS_CUTOFF")? {
+        None => Ok(None),
+        Some(cutoff) if cutoff > 0.0 && cutoff < nyquist => Ok(Some(cutoff)),
+        Some(cutoff) => Err(anyhow!(
+            "HIGHPASS_CUTOFF {} is not a frequency between 0 and {} Hz",
+            cutoff,
+            nyquist
+        )),
+    }
+}
+
+fn parse_noise_gate_threshold() -> anyhow::Result<Option<f32>> {
+    match parse_env::<f32>("NOISE_GATE_THRESHOLD")? {
+        None => Ok(None),
+        Some(threshold) if threshold.is_finite() && threshold <= 0.0 => Ok(Some(threshold)),
+        Some(threshold) => Err(anyhow!(
+            "NOISE_GATE_THRESHOLD {} is not a level in dBFS at or below 0",
+            threshold
+        )),
+    }
+}
+
+/// An optional cap like `MAX_DUMP_DURATION`, `None` if unset. A bare number gets a hint about
+/// the missing unit, and zero is refused rather than capping everything to nothing.
+fn parse_cap(key: &str) -> anyhow::Result<Option<Duration>> {
+    let Ok(value) = env::var(key) else {
+        return Ok(None);
+    };
+    match humantime::parse_duration(&value) {
+        Ok(cap) if !cap.is_zero() => Ok(Some(cap)),
+        Ok(_) => Err(anyhow!("{} must be above zero, unset it for no cap", key)),
+        Err(e) => Err(anyhow!(
+            "{} '{}' is not a duration with a unit like 10m: {}",
+            key,
+            value,
+            e
+        )),
+    }
+}
+
+fn parse_waveform_size(key: &str, default: u32) -> anyhow::Result<u32> {
+    match parse_env(key)? {
+        None => Ok(default),
+        Some(size) if (1..=MAX_WAVEFORM_SIZE).contains(&size) => Ok(size),
+        Some(size) => Err(anyhow!(
+            "{} {} must be between 1 and {} pixels",
+            key,
+            size,
+            MAX_WAVEFORM_SIZE
+        )),
+    }
+}
+
+fn parse_fraction(key: &str, default: f32) -> anyhow::Result<f32> {
+    match parse_env::<f32>(key)? {
+        None => Ok(default),
+        Some(fraction) if (0.0..=1.0).contains(&fraction) => Ok(fraction),
+        Some(fraction) => Err(anyhow!("{} {} must be between 0 and 1", key, fraction)),
+    }
+}
+
+fn parse_max_tracked_users() -> anyhow::Result<usize> {
+    match parse_env("MAX_TRACKED_USERS")? {
+        None => Ok(DEFAULT_MAX_TRACKED_USERS),
+        Some(0) => Err(anyhow!("MAX_TRACKED_USERS must be at least 1")),
+        Some(max) => Ok(max),
+    }
+}
+
+fn parse_silent_reference() -> anyhow::Result<SilentReference> {
+    match env::var("SILENT_REFERENCE").as_deref() {
+        Ok("allow") => Ok(SilentReference::Allow),
+        Err(_) | Ok("warn") => Ok(SilentReference::Warn),
+        Ok("error") => Ok(SilentReference::Error),
+        Ok(other) => Err(anyhow!(
+            "unknown SILENT_REFERENCE '{}', expected allow, warn or error",
+            other
+        )),
+    }
+}
+
+/// Tells the channel it's being recorded, for places that require consent.
+#[derive(Clone, Debug)]
+pub enum RecordingNotice {
+    /// any audio file symphonia can decode.
+    File(PathBuf),
+    /// spoken by the tts backend with one of its voices.
+    Text { text: String, voice: String },
+}
+
+fn parse_recording_notice() -> anyhow::Result<Option<RecordingNotice>> {
+    match (
+        env::var("RECORDING_NOTICE_FILE"),
+        env::var("RECORDING_NOTICE_TEXT"),
+    ) {
+        (Err(_), Err(_)) => Ok(None),
+        (Ok(_), Ok(_)) => Err(anyhow!(
+            "RECORDING_NOTICE_FILE and RECORDING_NOTICE_TEXT can't both be set"
+        )),
+        (Ok(path), Err(_)) => {
+            let path = PathBuf::from(path);
+            if !path.is_file() {
+                return Err(anyhow!(
+                    "RECORDING_NOTICE_FILE '{}' is not a file",
+                    path.display()
+                ));
+            }
+            Ok(Some(RecordingNotice::File(path)))
+        }
+        (Err(_), Ok(text)) => {
+            let voice = parse_list("TTS_VOICES").into_iter().next();
+            match (env::var("TTS_HOST"), voice) {
+                (Ok(_), Some(voice)) => Ok(Some(RecordingNotice::Text { text, voice })),
+                _ => Err(anyhow!(
+                    "RECORDING_NOTICE_TEXT needs TTS_HOST and TTS_VOICES, it's spoken with the first voice"
+                )),
+            }
+        }
+    }
+}
+
+/// Transcribes the lookback as it records and dumps it when someone says one of the phrases.
+#[derive(Clone, Debug)]
+pub struct ClipTrigger {
+    /// normalized with [`normalize_transcript`].
+    pub phrases: Vec<String>,
+    /// how much recent audio each check transcribes.
+    pub window: Duration,
+    /// time between checks, at most `window` so no audio goes unchecked.
+    pub interval: Duration,
+    /// how much of the lookback a triggered dump holds.
+    pub duration: Duration,
+}
+
+fn parse_clip_trigger() -> anyhow::Result<Option<ClipTrigger>> {
+    let phrases: Vec<_> = parse_list("CLIP_PHRASES")
+        .iter()
+        .map(|phrase| normalize_transcript(phrase))
+        .filter(|phrase| !phrase.is_empty())
+        .collect();
+    if phrases.is_empty() {
+        return Ok(None);
+    }
+    if env::var("STT_HOST").is_err() {
+        return Err(anyhow!(
+            "CLIP_PHRASES needs STT_HOST to transcribe the lookback"
+        ));
+    }
+    let window = parse_env::<humantime::Duration>("CLIP_WINDOW")?
+        .map(Into::into)
+        .unwrap_or(DEFAULT_CLIP_WINDOW);
+    let interval = parse_env::<humantime::Duration>("CLIP_INTERVAL")?
+        .map(Into::into)
+        .unwrap_or(DEFAULT_CLIP_INTERVAL);
+    if interval.is_zero() || interval > window {
+        return Err(anyhow!(
+            "CLIP_INTERVAL must be above zero and at most CLIP_WINDOW, or audio between checks is never transcribed"
+        ));
+    }
+    Ok(Some(ClipTrigger {
+        phrases,
+        window,
+        interval,
+        duration: parse_env::<humantime::Duration>("CLIP_DURATION")?
+            .map(Into::into)
+            .unwrap_or(DEFAULT_CLIP_DURATION),
+    }))
+}
+
+/// Lowercase words separated by single spaces, so phrases match transcripts regardless of case
+/// and punctuation. Apostrophes are dropped, transcribers differ in which one they write.
+pub fn normalize_transcript(text: &str) -> String {
+    text.to_lowercase()
+        .replace(['\'', '\u{2019}'], "")
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PanMode {
+    Off,
+    /// spread out in the order users are first heard, positions depend on who showed up first.
+    Spread,
+    /// derived from the user id, the same person is always in the same place.
+    User,
+}
+
+fn parse_pan() -> anyhow::Result<PanMode> {
+    match env::var("PAN_MODE").as_deref() {
+        Err(_) | Ok("off") => Ok(PanMode::Off),
+        Ok("spread") => Ok(PanMode::Spread),
+        Ok("user") => Ok(PanMode::User),
+        Ok(other) => Err(anyhow!(
+            "unknown PAN_MODE '{}', expected off, spread or user",
+            other
+        )),
+    }
+}
+
+/// The `OPUS_APPLICATION` value for an application.
+pub fn application_name(application: Application) -> &'static str {
+    match application {
+        Application::Voip => "voip",
+        Application::Audio => "audio",
+        Application::LowDelay => "lowdelay",
+    }
+}
+
+fn parse_application() -> anyhow::Result<Application> {
+    match env::var("OPUS_APPLICATION").as_deref() {
+        Err(_) | Ok("audio") => Ok(Application::Audio),
+        Ok("voip") => Ok(Application::Voip),
+        Ok("lowdelay") => Ok(Application::LowDelay),
+        Ok(other) => Err(anyhow!(
+            "unknown OPUS_APPLICATION '{}', expected voip, audio or lowdelay",
+            other
+        )),
+    }
+}
+
+fn parse_intents() -> anyhow::Result<GatewayIntents> {
+    let Ok(names) = env::var("GATEWAY_INTENTS") else {
+        return Ok(GatewayIntents::non_privileged() | GatewayIntents::MESSAGE_CONTENT);
+    };
+    let mut intents = GatewayIntents::empty();
+    for name in names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        intents |= match name {
+            "NON_PRIVILEGED" => GatewayIntents::non_privileged(),
+            name => GatewayIntents::from_name(name)
+                .ok_or_else(|| anyhow!("unknown gateway intent '{}' in GATEWAY_INTENTS", name))?,
+        };
+    }
+    // joining the voice channel and tracking who is in it need these whatever else is enabled
+    let required = GatewayIntents::GUILDS | GatewayIntents::GUILD_VOICE_STATES;
+    if !intents.contains(required) {
+        return Err(anyhow!(
+            "GATEWAY_INTENTS is missing {:?}, the bot can't join voice without it",
+            required - intents
+        ));
+    }
+    Ok(intents)
+}
+
+fn parse_chat_channel(intents: GatewayIntents) -> anyhow::Result<Option<u64>> {
+    if !parse_env("CHAT_LOG")?.unwrap_or(false) {
+        return Ok(None);
+    }
+    // without message content the bot still sees messages, but with empty text
+    let needed = GatewayIntents::GUILD_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
+    if !intents.contains(needed) {
+        return Err(anyhow!(
+            "CHAT_LOG needs {:?} in GATEWAY_INTENTS to read messages",
+            needed - intents
+        ));
+    }
+    let channel = env::var("TEXT_CHANNEL_ID")
+        .map_err(|_| anyhow!("CHAT_LOG needs TEXT_CHANNEL_ID, its messages are logged"))?;
+    Ok(Some(channel.parse()?))
+}
+
+fn parse_media_base_url() -> anyhow::Result<Option<String>> {
+    let Ok(base_url) = env::var("MEDIA_BASE_URL") else {
+        return Ok(None);
+    };
+    let url = reqwest::Url::parse(&base_url)
+        .map_err(|e| anyhow!("invalid MEDIA_BASE_URL '{}': {}", base_url, e))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(anyhow!(
+            "invalid MEDIA_BASE_URL '{}', expected an http or https url",
+            base_url
+        ));
+    }
+    Ok(Some(base_url.trim_end_matches('/').to_string()))
+}
+
+fn parse_frame_duration() -> anyhow::Result<Duration> {
+    match env::var("FRAME_DURATION").as_deref() {
+        Err(_) | Ok("20ms") => Ok(Duration::from_millis(20)),
+        Ok("40ms") => Ok(Duration::from_millis(40)),
+        Ok("60ms") => Ok(Duration::from_millis(60)),
+        Ok(other) => Err(anyhow!(
+            "unknown FRAME_DURATION '{}', expected 20ms, 40ms or 60ms",
+            other
+        )),
+    }
+}
+
+fn parse_complexity() -> anyhow::Result<u8> {
+    match parse_env("OPUS_COMPLEXITY")? {
+        None => Ok(DEFAULT_COMPLEXITY),
+        Some(complexity) if complexity <= MAX_COMPLEXITY => Ok(complexity),
+        Some(complexity) => Err(anyhow!(
+            "OPUS_COMPLEXITY {} must be between 0 and {}",
+            complexity,
+            MAX_COMPLEXITY
+        )),
+    }
+}
+
+/// `key` parsed, `None` if it isn't set. A set value that doesn't parse is an error rather than
+/// the default, so a typo can't quietly change behavior.
+fn parse_env<T>(key: &str) -> anyhow::Result<Option<T>>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let Ok(value) = env::var(key) else {
+        return Ok(None);
+    };
+    value
+        .parse()
+        .map(Some)
+        .map_err(|e| anyhow!("{} '{}' is invalid: {}", key, value, e))
+}
+
+/// Comma separated values with blanks dropped, empty if unset.
+fn parse_list(key: &str) -> Vec<String> {
+    env::var(key)
+        .map(|values| {
+            values
+                .split(',')
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Comma separated discord ids, empty if unset.
+fn parse_ids(key: &str) -> anyhow::Result<Vec<u64>> {
+    parse_list(key)
+        .iter()
+        .map(|id| {
+            id.parse()
+                .map_err(|_| anyhow!("{} contains '{}', which is not a discord id", key, id))
+        })
+        .collect()
+}
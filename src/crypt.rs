@@ -0,0 +1,102 @@
+use std::env;
+
+use anyhow::{anyhow, Context};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+pub use chacha20poly1305::Key;
+
+/// Marks an encrypted recording, followed by the 24 byte nonce and the ciphertext. Files without
+/// it are plain ogg and are read as is.
+const MAGIC: &[u8] = b"RDRENC1\0";
+const NONCE_SIZE: usize = 24;
+
+/// The optional `RECORDING_KEY`, 32 bytes as 64 hex characters. Read once at startup into
+/// [`crate::config::Config::recording_key`].
+pub fn recording_key() -> anyhow::Result<Option<Key>> {
+    let Ok(key) = env::var("RECORDING_KEY") else {
+        return Ok(None);
+    };
+    let key = hex::decode(key.trim()).context("RECORDING_KEY must be hex")?;
+    if key.len() != 32 {
+        return Err(anyhow!(
+            "RECORDING_KEY must be 32 bytes (64 hex characters), got {} bytes",
+            key.len()
+        ));
+    }
+    Ok(Some(*Key::from_slice(&key)))
+}
+
+/// Encrypts with `key` if one is configured, otherwise returns the data untouched.
+pub fn seal(key: Option<&Key>, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let Some(key) = key else {
+        return Ok(data.to_vec());
+    };
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = XChaCha20Poly1305::new(key)
+        .encrypt(&nonce, data)
+        .map_err(|_| anyhow!("failed to encrypt recording"))?;
+    let mut sealed = Vec::with_capacity(MAGIC.len() + NONCE_SIZE + ciphertext.len());
+    sealed.extend_from_slice(MAGIC);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// Reverses [`seal`], plain files pass through unchanged.
+pub fn open(key: Option<&Key>, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let Some(sealed) = data.strip_prefix(MAGIC) else {
+        return Ok(data);
+    };
+    let key = key.ok_or_else(|| anyhow!("recording is encrypted but RECORDING_KEY is not set"))?;
+    if sealed.len() < NONCE_SIZE {
+        return Err(anyhow!("encrypted recording is truncated"));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+    XChaCha20Poly1305::new(key)
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| {
+            anyhow!("failed to decrypt recording, RECORDING_KEY doesn't match the one it was written with")
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8] = b"OggS not really an ogg file";
+
+    #[test]
+    fn round_trips_with_the_same_key() {
+        let key = Key::from([7; 32]);
+        let sealed = seal(Some(&key), DATA).unwrap();
+        assert!(sealed.starts_with(MAGIC));
+        assert!(!sealed.windows(DATA.len()).any(|window| window == DATA));
+        assert_eq!(open(Some(&key), sealed).unwrap(), DATA);
+    }
+
+    #[test]
+    fn nonce_differs_per_file() {
+        let key = Key::from([7; 32]);
+        assert_ne!(
+            seal(Some(&key), DATA).unwrap(),
+            seal(Some(&key), DATA).unwrap()
+        );
+    }
+
+    #[test]
+    fn wrong_or_missing_key_is_a_clear_error() {
+        let sealed = seal(Some(&Key::from([7; 32])), DATA).unwrap();
+        let wrong = open(Some(&Key::from([8; 32])), sealed.clone()).unwrap_err();
+        assert!(wrong.to_string().contains("doesn't match"), "{}", wrong);
+        let missing = open(None, sealed).unwrap_err();
+        assert!(missing.to_string().contains("not set"), "{}", missing);
+    }
+
+    #[test]
+    fn without_a_key_files_stay_plain() {
+        let sealed = seal(None, DATA).unwrap();
+        assert_eq!(sealed, DATA);
+        assert_eq!(open(None, sealed).unwrap(), DATA);
+    }
+}
@@ -0,0 +1,207 @@
+use std::io::{Cursor, ErrorKind};
+
+use anyhow::anyhow;
+use audiopus::{Channels, MutSignals, SampleRate};
+use audiopus::coder::Decoder;
+use audiopus::packet::Packet;
+use ogg::PacketReader;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use crate::encode;
+use crate::resample::resample;
+use crate::receiver::{
+    AUDIO_CHANNELS, AUDIO_FREQUENCY, AUDIO_PACKET_SIZE, empty_raw_audio, RawAudioPacket,
+};
+
+/// Splits an ogg opus stream back into its opus packets, dropping the OpusHead and OpusTags
+/// header packets.
+pub fn demux(ogg_data: &[u8]) -> anyhow::Result<Vec<bytes::Bytes>> {
+    let mut packet_reader = PacketReader::new(Cursor::new(ogg_data));
+    let head = packet_reader
+        .read_packet()?
+        .ok_or_else(|| anyhow!("empty ogg stream"))?;
+    if !head.data.starts_with(b"OpusHead") {
+        return Err(anyhow!("not an ogg opus stream"));
+    }
+    let tags = packet_reader
+        .read_packet()?
+        .ok_or_else(|| anyhow!("ogg opus stream is missing OpusTags"))?;
+    if !tags.data.starts_with(b"OpusTags") {
+        return Err(anyhow!("ogg opus stream is missing OpusTags"));
+    }
+
+    let mut packets = Vec::new();
+    while let Some(packet) = packet_reader.read_packet()? {
+        packets.push(packet.data.into());
+    }
+    Ok(packets)
+}
+
+// 120ms, the longest a single opus packet can be
+const MAX_PACKET_SAMPLES: usize = 5760;
+
+/// A 48kHz stereo opus decoder with room for the longest packet.
+struct PacketDecoder {
+    decoder: Decoder,
+    output: Vec<i16>,
+}
+
+impl PacketDecoder {
+    fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            decoder: Decoder::new(SampleRate::Hz48000, Channels::Stereo)?,
+            output: vec![0i16; MAX_PACKET_SAMPLES * AUDIO_CHANNELS as usize],
+        })
+    }
+
+    /// The packet's interleaved pcm, `None` if the decoder rejects it. Empty packets are
+    /// concealed like lost ones.
+    fn decode(&mut self, packet: &[u8]) -> anyhow::Result<Option<&[i16]>> {
+        match self.decoder.decode(
+            Packet::try_from(packet).ok(),
+            MutSignals::try_from(&mut self.output[..])?,
+            false,
+        ) {
+            Ok(samples) => Ok(Some(&self.output[..samples * AUDIO_CHANNELS as usize])),
+            Err(e) => {
+                tracing::debug!("failed to decode opus packet {:?}", e);
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Decodes opus packets to interleaved 48kHz stereo pcm. Empty packets are concealed like lost
+/// ones, packets the decoder rejects become silence as long as their TOC says they are, so one
+/// corrupt packet costs a gap instead of the whole export and everything after it stays in time.
+pub fn decode_opus(packets: &[bytes::Bytes]) -> anyhow::Result<Vec<i16>> {
+    let mut decoder = PacketDecoder::new()?;
+    let mut pcm = Vec::new();
+    let mut failed = 0;
+    for packet in packets {
+        match decoder.decode(packet)? {
+            Some(decoded) => pcm.extend_from_slice(decoded),
+            None => {
+                failed += 1;
+                let samples = encode::packet_samples(packet).min(MAX_PACKET_SAMPLES);
+                pcm.resize(pcm.len() + samples * AUDIO_CHANNELS as usize, 0);
+            }
+        }
+    }
+    if failed > 0 {
+        tracing::warn!(
+            "replaced {} of {} undecodable opus packets with silence",
+            failed,
+            packets.len()
+        );
+    }
+    Ok(pcm)
+}
+
+/// How many of `packets` the decoder rejects, without keeping the decoded audio.
+pub fn count_undecodable(packets: &[bytes::Bytes]) -> anyhow::Result<usize> {
+    let mut decoder = PacketDecoder::new()?;
+    let mut failed = 0;
+    for packet in packets {
+        if decoder.decode(packet)?.is_none() {
+            failed += 1;
+        }
+    }
+    Ok(failed)
+}
+
+/// Decodes any symphonia supported audio into 48kHz stereo packets, the same shape as received
+/// voice. The last packet is padded with silence.
+pub fn decode_to_raw_audio(data: bytes::Bytes, hint: &Hint) -> anyhow::Result<Vec<RawAudioPacket>> {
+    let media_source = MediaSourceStream::new(Box::new(Cursor::new(data)), Default::default());
+    let mut format = symphonia::default::get_probe()
+        .format(
+            hint,
+            media_source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?
+        .format;
+    let track = format
+        .default_track()
+        .ok_or_else(|| anyhow!("no audio track"))?;
+    let track_id = track.id;
+    let sample_rate = track
+        .codec_params
+        .sample_rate
+        .ok_or_else(|| anyhow!("unknown sample rate"))?;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    // interleaved stereo at the source sample rate
+    let mut stereo = Vec::new();
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = decoder.decode(&packet)?;
+        let spec = *decoded.spec();
+        let mut sample_buffer = SampleBuffer::<i16>::new(decoded.capacity() as u64, spec);
+        sample_buffer.copy_interleaved_ref(decoded);
+        for frame in sample_buffer.samples().chunks(spec.channels.count()) {
+            let left = frame[0];
+            stereo.push(left);
+            stereo.push(frame.get(1).copied().unwrap_or(left));
+        }
+    }
+
+    let resampled = resample(
+        &stereo,
+        AUDIO_CHANNELS as usize,
+        sample_rate,
+        AUDIO_FREQUENCY,
+    );
+    Ok(resampled
+        .chunks(AUDIO_PACKET_SIZE)
+        .map(|chunk| {
+            let mut packet = empty_raw_audio();
+            packet[..chunk.len()].copy_from_slice(chunk);
+            packet
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::receiver::tests::tone;
+    use crate::receiver::FrameEncoder;
+
+    /// Two 10ms CELT frames with an odd payload, which can't be split in two equal halves. The
+    /// TOC is fine, so it still says how long it was meant to be.
+    const UNDECODABLE: [u8; 4] = [30 << 3 | 1 << 2 | 1, 0, 0, 0];
+
+    #[test]
+    fn undecodable_packet_becomes_a_silence_gap() {
+        let mut encoder = FrameEncoder::new(&Config::for_tests().encoder);
+        let audio = tone();
+        let mut packets: Vec<_> = (0..10).filter_map(|_| encoder.push(Some(&audio))).collect();
+        packets[5] = bytes::Bytes::from_static(&UNDECODABLE);
+        assert_eq!(count_undecodable(&packets).unwrap(), 1);
+
+        let pcm = decode_opus(&packets).unwrap();
+        assert_eq!(pcm.len(), 10 * AUDIO_PACKET_SIZE);
+        let ticks: Vec<_> = pcm.chunks(AUDIO_PACKET_SIZE).collect();
+        assert!(ticks[5].iter().all(|sample| *sample == 0));
+        for tick in [ticks[4], ticks[7]] {
+            assert!(tick.iter().any(|sample| sample.unsigned_abs() > 1000));
+        }
+    }
+}
@@ -1,31 +1,59 @@
+use std::collections::HashMap;
 use std::io;
-use std::sync::Arc;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
 use anyhow::{anyhow, Error};
 use async_trait::async_trait;
-use poise::CreateReply;
+use audiopus::Application;
+use poise::{ChoiceParameter, CreateReply};
 use serenity::{
     client,
     model::{gateway::Ready, id::ChannelId, id::GuildId},
     prelude::Mentionable,
 };
-use serenity::all::CreateAttachment;
-use songbird::{CoreEvent, Event, EventContext, EventHandler as VoiceEventHandler};
+use serenity::all::{CreateAttachment, CreateEmbed, FullEvent, Http};
+use serenity::http::{HttpError, StatusCode};
+use songbird::{
+    CoreEvent, Event, EventContext, EventHandler as VoiceEventHandler, Songbird, TrackEvent,
+};
 use songbird::input::{AudioStream, Input, LiveInput};
 use songbird::input::core::io::MediaSource;
 use songbird::input::core::probe::Hint;
 use songbird::model::id::UserId;
+use songbird::tracks::{PlayMode, ReadyState, TrackHandle};
 
-use crate::receiver::{Receiver, user_to_ogg_file, write_ogg_to_disk, write_ogg_to_disk_named};
+use crate::{clip_trigger, decode, encode, level, self_check, tick_watchdog, tts, waveform};
+use crate::voice_match::VoiceProfile;
+use crate::config::{application_name, OversizedAudio, RecordingNotice};
+use crate::lookback::{
+    DrainOptions, DumpResult, InjectionHandle, PACKET_DURATION, packets_for_duration,
+};
+use crate::resample::{resample, SAMPLE_RATES};
+use crate::receiver::{
+    audio_dir, AUDIO_CHANNELS, AUDIO_FREQUENCY, make_opus_encoder_with_channels, read_ogg_file,
+    RawAudioPacket, Receiver, sanitize_file_name, to_raw_audio_packets, user_label,
+    user_to_ogg_file, write_ogg_to_disk, write_ogg_to_disk_named,
+};
 
 type Context<'a> = poise::Context<'a, Arc<Receiver>, Error>;
 
+/// Takes the `!` prefix form away from a command and its subcommands.
+pub fn make_slash_only(command: &mut poise::Command<Arc<Receiver>, Error>) {
+    command.prefix_action = None;
+    for subcommand in &mut command.subcommands {
+        make_slash_only(subcommand);
+    }
+}
+
 pub async fn on_ready(
     ctx: &client::Context,
     ready: &Ready,
     guild: GuildId,
     voice_channel: ChannelId,
-    text_channel: ChannelId,
+    log_channel: ChannelId,
     receiver: Arc<Receiver>,
 ) -> anyhow::Result<()> {
     tracing::info!(
@@ -34,12 +62,121 @@ pub async fn on_ready(
         env!("CARGO_PKG_NAME"),
         env!("CARGO_PKG_VERSION")
     );
-    join_voice_channel(ctx, voice_channel, guild, text_channel, receiver)
+    if let Err(e) =
+        join_voice_channel(ctx, voice_channel, guild, log_channel, receiver.clone()).await
+    {
+        tracing::error!("failed to join voice channel on startup {:?}", e);
+        let _ = log_channel
+            .say(
+                &ctx.http,
+                format!("Failed to join {}: {}", voice_channel.mention(), e),
+            )
+            .await;
+        return Err(e);
+    }
+    if let Some(trigger) = receiver.config.clip_trigger.clone() {
+        clip_trigger::spawn(receiver.clone(), ctx.http.clone(), log_channel, trigger);
+    }
+    if let Some(timeout) = receiver.config.tick_stall_timeout {
+        let manager = songbird::get(ctx)
+            .await
+            .ok_or_else(|| anyhow!("Songbird Voice client placed in at initialisation."))?;
+        tick_watchdog::spawn(
+            receiver.clone(),
+            ctx.http.clone(),
+            manager,
+            log_channel,
+            timeout,
+        );
+    }
+    if let Some(interval) = receiver.config.self_check_interval {
+        self_check::spawn(receiver, ctx.http.clone(), log_channel, interval);
+    }
+    Ok(())
+}
+
+pub async fn on_event(
+    ctx: &client::Context,
+    event: &FullEvent,
+    receiver: &Receiver,
+) -> anyhow::Result<()> {
+    match event {
+        FullEvent::VoiceStateUpdate { new, .. } if new.guild_id == Some(receiver.guild_id) => {
+            update_occupancy(ctx, receiver);
+            let followed = *receiver.following.lock().expect("following lock panicked");
+            if followed.is_some_and(|user| user.0 == new.user_id.get()) {
+                // leaving voice keeps the bot where it is until they join somewhere again
+                if let Some(channel) = new.channel_id {
+                    tracing::info!("following uid {} to {}", new.user_id, channel);
+                    move_to_channel(ctx, receiver, channel).await?;
+                }
+            }
+        }
+        FullEvent::Message { new_message }
+            if receiver.config.chat_channel == Some(new_message.channel_id.get()) =>
+        {
+            // the bot's own replies to commands would only be noise in the log
+            if new_message.author.bot || new_message.content.is_empty() {
+                return Ok(());
+            }
+            let author = new_message
+                .member
+                .as_ref()
+                .and_then(|member| member.nick.clone())
+                .or_else(|| new_message.author.global_name.clone())
+                .unwrap_or_else(|| new_message.author.name.clone());
+            receiver.log_chat(author, new_message.content.clone());
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Moves the existing call, so the handlers registered in `join_voice_channel` stay attached and
+/// `ChannelMoveHandler` announces the move.
+async fn move_to_channel(
+    ctx: &client::Context,
+    receiver: &Receiver,
+    channel: ChannelId,
+) -> anyhow::Result<()> {
+    if receiver
+        .channel()
+        .is_some_and(|current| current.0.get() == channel.get())
+    {
+        return Ok(());
+    }
+    let manager = songbird::get(ctx)
         .await
-        .map_err(|e| {
-            tracing::error!("failed to join voice channel on startup {:?}", e);
-            e
+        .ok_or_else(|| anyhow!("Songbird Voice client placed in at initialisation."))?;
+    if manager.get(receiver.guild_id).is_none() {
+        return Err(anyhow!("not connected to voice, can't move"));
+    }
+    manager.join(receiver.guild_id, channel).await?;
+    Ok(())
+}
+
+/// The voice channel the user is in according to the cache.
+fn voice_channel_of(
+    ctx: &client::Context,
+    guild: GuildId,
+    user: poise::serenity_prelude::UserId,
+) -> Option<ChannelId> {
+    ctx.cache.guild(guild)?.voice_states.get(&user)?.channel_id
+}
+
+/// Recounts the users in the bot's voice channel from the cache. Assumes occupied if the guild
+/// isn't cached yet so nothing is missed.
+fn update_occupancy(ctx: &client::Context, receiver: &Receiver) {
+    let Some(channel) = receiver.channel() else {
+        return;
+    };
+    let bot = ctx.cache.current_user().id;
+    let occupied = ctx.cache.guild(receiver.guild_id).map_or(true, |guild| {
+        guild.voice_states.values().any(|state| {
+            state.user_id != bot && state.channel_id.map(|id| id.get()) == Some(channel.0.get())
         })
+    });
+    receiver.set_occupied(occupied);
 }
 
 struct ArcEventHandlerInvoker<T: VoiceEventHandler> {
@@ -53,11 +190,47 @@ impl<T: VoiceEventHandler> VoiceEventHandler for ArcEventHandlerInvoker<T> {
     }
 }
 
+/// Tells the text channel when the bot is dragged to another voice channel. Songbird reconnects on
+/// its own, this only keeps the receiver's view of the connection up to date.
+struct ChannelMoveHandler {
+    http: Arc<Http>,
+    log_channel: ChannelId,
+    receiver: Arc<Receiver>,
+    manager: Arc<Songbird>,
+    bot: UserId,
+}
+
+#[async_trait]
+impl VoiceEventHandler for ChannelMoveHandler {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        let (EventContext::DriverConnect(data) | EventContext::DriverReconnect(data)) = ctx else {
+            return None;
+        };
+        if !self.receiver.on_connect(data.channel_id) {
+            return None;
+        }
+        announce_recording(self.manager.clone(), self.receiver.clone(), self.bot);
+        let message = match data.channel_id {
+            Some(channel) => format!("Moved to {}", ChannelId::new(channel.0.get()).mention()),
+            None => "Moved to an unknown voice channel".to_string(),
+        };
+        let message = if self.receiver.config.clear_lookback_on_move {
+            format!("{}, lookback cleared", message)
+        } else {
+            message
+        };
+        if let Err(e) = self.log_channel.say(&self.http, message).await {
+            tracing::error!("failed to announce channel move {:?}", e);
+        }
+        None
+    }
+}
+
 async fn join_voice_channel(
     ctx: &client::Context,
     connect_to: ChannelId,
     guild_id: GuildId,
-    response_channel: ChannelId,
+    log_channel: ChannelId,
     receiver: Arc<Receiver>,
 ) -> anyhow::Result<()> {
     let manager = songbird::get(ctx)
@@ -65,8 +238,23 @@ async fn join_voice_channel(
         .ok_or_else(|| anyhow!("Songbird Voice client placed in at initialisation."))?;
 
     let handler_lock = manager.join(guild_id, connect_to).await?;
+    let bot = UserId(ctx.cache.current_user().id.get());
 
     let mut handler = handler_lock.lock().await;
+    // the initial connect already happened inside join, record it so later ones can be compared
+    receiver.on_connect(Some(connect_to.into()));
+    for event in [CoreEvent::DriverConnect, CoreEvent::DriverReconnect] {
+        handler.add_global_event(
+            event.into(),
+            ChannelMoveHandler {
+                http: ctx.http.clone(),
+                log_channel,
+                receiver: receiver.clone(),
+                manager: manager.clone(),
+                bot,
+            },
+        );
+    }
     handler.add_global_event(
         CoreEvent::VoiceTick.into(),
         ArcEventHandlerInvoker {
@@ -79,93 +267,2512 @@ async fn join_voice_channel(
             delegate: receiver.clone(),
         },
     );
+    handler.add_global_event(
+        CoreEvent::ClientDisconnect.into(),
+        ArcEventHandlerInvoker {
+            delegate: receiver.clone(),
+        },
+    );
+    handler.add_global_event(
+        CoreEvent::DriverDisconnect.into(),
+        ArcEventHandlerInvoker {
+            delegate: receiver.clone(),
+        },
+    );
+
+    update_occupancy(ctx, &receiver);
+    announce_recording(manager, receiver, bot);
 
-    response_channel
+    log_channel
         .say(&ctx.http, &format!("Joined {}", connect_to.mention()))
         .await?;
     Ok(())
 }
 
-#[poise::command(slash_command)]
+/// Plays the `RECORDING_NOTICE_*` notice into the call in the background, so joining never waits
+/// on it or fails because of it.
+fn announce_recording(manager: Arc<Songbird>, receiver: Arc<Receiver>, bot: UserId) {
+    if receiver.config.recording_notice.is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        if let Err(e) = play_recording_notice(&manager, &receiver, bot).await {
+            tracing::error!("failed to play the recording notice {:?}", e);
+        }
+    });
+}
+
+async fn play_recording_notice(
+    manager: &Songbird,
+    receiver: &Receiver,
+    bot: UserId,
+) -> anyhow::Result<()> {
+    let mut hint = Hint::default();
+    let notice = match &receiver.config.recording_notice {
+        Some(RecordingNotice::File(path)) => {
+            if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+                hint.with_extension(extension);
+            }
+            tokio::fs::read(path).await?.into()
+        }
+        Some(RecordingNotice::Text { text, voice }) => {
+            hint.mime_type("audio/wav").with_extension("wav");
+            receiver
+                .tts
+                .tts(bot, text.clone(), Some(voice.clone()))
+                .await?
+        }
+        None => return Ok(()),
+    };
+    let handler_lock = manager
+        .get(receiver.guild_id)
+        .ok_or_else(|| anyhow!("not in a voice channel"))?;
+    tracing::info!("playing recording notice");
+    let _ = handler_lock
+        .lock()
+        .await
+        .play_input(bytes_to_input(notice, hint));
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, poise::ChoiceParameter)]
+pub enum ExportFormat {
+    #[default]
+    Ogg,
+    Wav,
+    Packets,
+    // mono is a format rather than its own export flag, see `ExportRequest`
+    OggMono,
+    WavMono,
+    /// left and right as two mono files, for setups that route different sources to each side.
+    Split,
+}
+
+/// How `ExportFormat::Packets` is laid out, told to whoever exports it.
+const PACKETS_FRAMING: &str = "raw 48kHz stereo opus packets, each prefixed with its length as a \
+    big endian u16";
+
+impl ExportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Ogg | Self::OggMono | Self::Split => "ogg",
+            Self::Wav | Self::WavMono => "wav",
+            Self::Packets => "bin",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Self::Ogg => "the lookback's own opus packets, no re-encoding",
+            Self::Wav => "decoded 16 bit pcm, much larger",
+            Self::Packets => PACKETS_FRAMING,
+            Self::OggMono => {
+                "ogg downmixed to one channel, re-encoded at the current encoder settings"
+            }
+            Self::WavMono => "wav downmixed to one channel, half the size",
+            Self::Split => "the left and right channels as two mono ogg files, re-encoded",
+        }
+    }
+
+    /// Every format this build offers, in the order `export` lists them.
+    fn available() -> impl Iterator<Item = Self> {
+        (0..Self::list().len()).filter_map(Self::from_index)
+    }
+}
+
+/// How durations are written, for the help of every command taking one.
+const DURATION_SYNTAX: &str = "Durations are written like `90s`, `5m`, `1h 30m` or `2m30s`.";
+
+/// `help export`, built from the formats this build has so it never lists one that's missing.
+fn export_help() -> String {
+    let mut help = String::from("format:\n");
+    for format in ExportFormat::available() {
+        let default = if format == ExportFormat::default() {
+            " (default)"
+        } else {
+            ""
+        };
+        help.push_str(&format!(
+            "  {}{}: {}\n",
+            format.name(),
+            default,
+            format.description()
+        ));
+    }
+    help.push_str(
+        "duration: only the most recent part of the lookback, the whole lookback if not given\n\
+         trim: drop leading and trailing silence, and shorten silences longer than the trim gap, \
+         see trimpreview\n\
+         gain: gain in dB on top of the volume, or `normalize` to bring the loudest sample to \
+         -1 dBFS instead\n\
+         to_disk: also store the file\n\
+         link: store the file and reply with a link instead of an attachment\n\
+         marker: start with a short beep to align against\n\
+         timestamps: also attach a csv of when each packet was captured\n\
+         speaker: only the moments that user was talking\n\
+         rate: sample rate to write at, wav is resampled and ogg only carries it in its header\n",
+    );
+    help.push_str(DURATION_SYNTAX);
+    help
+}
+
+/// The formats part of `formats`, listed by the name `export format:` takes.
+fn format_list() -> String {
+    let mut list = String::from("export formats:\n");
+    for format in ExportFormat::available() {
+        let default = if format == ExportFormat::default() {
+            " (default)"
+        } else {
+            ""
+        };
+        list.push_str(&format!(
+            "- {}{}: {}\n",
+            format.name(),
+            default,
+            format.description()
+        ));
+    }
+    list
+}
+
+/// `help dump`, pointing at the `export` options each of its keywords maps to.
+fn dump_help() -> String {
+    format!(
+        "duration, marker, gain, link and timestamps work like in export. write_to_disk is \
+         export's to_disk, packets is `format Packets` and leaves out the ogg container.\n{}",
+        DURATION_SYNTAX
+    )
+}
+
+/// `export`'s gain, dB or `normalize`. One argument rather than a normalize flag next to it, see
+/// `ExportRequest`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportGain {
+    Db(f32),
+    Normalize,
+}
+
+impl std::str::FromStr for ExportGain {
+    type Err = std::num::ParseFloatError;
+
+    fn from_str(gain: &str) -> Result<Self, Self::Err> {
+        if gain.eq_ignore_ascii_case("normalize") {
+            return Ok(Self::Normalize);
+        }
+        gain.parse().map(Self::Db)
+    }
+}
+
+/// Where `clone` takes the reference audio from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, poise::ChoiceParameter)]
+pub enum CloneSource {
+    /// the clone buffer if the user spoke recently, the archive otherwise.
+    #[default]
+    Auto,
+    /// the user's last couple of minutes in the channel.
+    Buffer,
+    /// the user's most recent speech in their `ARCHIVE` file, however long ago.
+    Archive,
+}
+
+/// Everything `export` and the `dump` alias can ask for. Every optional argument `export` takes
+/// doubles the code generated for parsing `!export`, so related options share one argument where
+/// they can, e.g. mono formats and `normalize` as a gain.
+#[derive(Default)]
+struct ExportRequest {
+    format: ExportFormat,
+    duration: Option<String>,
+    trim: bool,
+    normalize: bool,
+    to_disk: bool,
+    link: bool,
+    marker: bool,
+    timestamps: bool,
+    gain: Option<f32>,
+    /// sample rate to write at, `EXPORT_SAMPLE_RATE` if `None`.
+    rate: Option<u32>,
+    /// only audio captured after this, e.g. a mark's start.
+    since: Option<SystemTime>,
+    /// only the frames this user spoke in.
+    speaker: Option<UserId>,
+}
+
+/// Exports the lookback, or its last `duration`, as a file.
+#[poise::command(slash_command, prefix_command, help_text_fn = "export_help")]
+#[allow(clippy::too_many_arguments)]
+pub async fn export(
+    ctx: Context<'_>,
+    format: Option<ExportFormat>,
+    duration: Option<String>,
+    trim: Option<bool>,
+    to_disk: Option<bool>,
+    link: Option<bool>,
+    marker: Option<bool>,
+    timestamps: Option<bool>,
+    gain: Option<ExportGain>,
+    speaker: Option<poise::serenity_prelude::User>,
+    rate: Option<u32>,
+) -> Result<(), Error> {
+    run_export(
+        ctx,
+        ExportRequest {
+            format: format.unwrap_or_default(),
+            duration,
+            trim: trim.unwrap_or(false),
+            normalize: gain == Some(ExportGain::Normalize),
+            to_disk: to_disk.unwrap_or(false),
+            link: link.unwrap_or(false),
+            marker: marker.unwrap_or(false),
+            timestamps: timestamps.unwrap_or(false),
+            gain: match gain {
+                Some(ExportGain::Db(gain)) => Some(gain),
+                _ => None,
+            },
+            rate,
+            speaker: speaker.map(|user| UserId(user.id.get())),
+            ..Default::default()
+        },
+    )
+    .await
+}
+
+/// Deprecated, kept for muscle memory. Same as `export` in ogg.
+#[poise::command(slash_command, prefix_command, help_text_fn = "dump_help")]
+#[allow(clippy::too_many_arguments)]
 pub async fn dump(
     ctx: Context<'_>,
     duration: Option<String>,
     write_to_disk: Option<bool>,
+    marker: Option<bool>,
+    gain: Option<f32>,
+    link: Option<bool>,
+    timestamps: Option<bool>,
+    packets: Option<bool>,
 ) -> Result<(), Error> {
-    let write_to_disk = write_to_disk.unwrap_or(false);
-    tracing::info!("dumping to disk '{}'", write_to_disk);
-    ctx.say("dumping").await?;
-    let drain_duration = match duration {
-        Some(duration) => humantime::parse_duration(&duration).ok(),
-        _ => None,
+    ctx.say("/dump is deprecated, use /export").await?;
+    let format = if packets.unwrap_or(false) {
+        ExportFormat::Packets
+    } else {
+        ExportFormat::Ogg
+    };
+    run_export(
+        ctx,
+        ExportRequest {
+            format,
+            duration,
+            to_disk: write_to_disk.unwrap_or(false),
+            link: link.unwrap_or(false),
+            marker: marker.unwrap_or(false),
+            timestamps: timestamps.unwrap_or(false),
+            gain,
+            ..Default::default()
+        },
+    )
+    .await
+}
+
+/// Export formats this build supports and the settings exports use.
+#[poise::command(slash_command, prefix_command)]
+pub async fn formats(ctx: Context<'_>) -> Result<(), Error> {
+    let config = &ctx.data().config;
+    let mut reply = format_list();
+    let max_length = match config.max_dump_duration {
+        Some(max) => humantime::format_duration(max).to_string(),
+        None => "unlimited".to_string(),
+    };
+    reply.push_str(&format!(
+        "max length: {}\ndefault sample rate: {} Hz\nbitrate: {} bps in {} frames\nsilence packing: {}\nlinks: {}\nstorage: {}{}",
+        max_length,
+        config.export_sample_rate,
+        ctx.data().encoder().bitrate,
+        humantime::format_duration(config.encoder.frame_duration),
+        if config.compact_silence { "on" } else { "off" },
+        if config.media_base_url.is_some() {
+            "available"
+        } else {
+            "MEDIA_BASE_URL not set"
+        },
+        config.storage.name(),
+        if config.recording_key.is_some() {
+            ", encrypted"
+        } else {
+            ""
+        }
+    ));
+    ctx.say(reply).await?;
+    Ok(())
+}
+
+async fn run_export(ctx: Context<'_>, request: ExportRequest) -> Result<(), Error> {
+    if request.format == ExportFormat::Packets && (request.normalize || request.gain.is_some()) {
+        return Err(anyhow!(
+            "raw packets have no header to carry gain, export ogg or wav instead"
+        ));
+    }
+    let rate = request.rate.unwrap_or(ctx.data().config.export_sample_rate);
+    if !SAMPLE_RATES.contains(&rate) {
+        return Err(anyhow!(
+            "unsupported sample rate {}, expected one of {:?}",
+            rate,
+            SAMPLE_RATES
+        ));
+    }
+    if request.format == ExportFormat::Packets && request.rate.is_some() {
+        return Err(anyhow!(
+            "raw packets have no header to carry a sample rate, export ogg or wav instead"
+        ));
+    }
+    // the master volume goes on top of the export's own gain, normalize replaces both
+    let gain = request.gain.unwrap_or(0.0) + ctx.data().volume_db();
+    let options = encode::StreamOptions {
+        output_gain: encode::output_gain_from_db(gain)?,
+        ..Default::default()
+    };
+    // a typo in the duration is refused rather than exporting the whole lookback
+    let drain_duration = match (request.since, request.duration) {
+        (Some(since), _) => since.elapsed().ok(),
+        (None, duration) => duration
+            .map(|duration| humantime::parse_duration(&duration))
+            .transpose()?,
     };
+    tracing::info!(
+        "exporting {:?} to disk '{}'",
+        request.format,
+        request.to_disk
+    );
+    ctx.say("dumping").await?;
 
     let receiver = ctx.data();
-    let ogg_file = receiver.lookback.drain_buffer(drain_duration)?;
-    ctx.say("dumped").await?;
-    if write_to_disk {
-        write_ogg_to_disk(&ogg_file).await?;
+    let drain_duration = match max_dump_duration(ctx).await {
+        Some(max)
+            if drain_duration.unwrap_or_else(|| receiver.lookback.buffered_duration()) > max =>
+        {
+            tracing::info!(
+                "clamping dump by '{}' from {:?} to {:?}",
+                ctx.author().name,
+                drain_duration,
+                max
+            );
+            ctx.say(format!(
+                "dumps are capped at {}, only dumping the most recent {}",
+                humantime::format_duration(max),
+                humantime::format_duration(max)
+            ))
+            .await?;
+            Some(max)
+        }
+        _ => drain_duration,
+    };
+    let drain = DrainOptions {
+        duration: drain_duration,
+        with_marker: request.marker,
+        with_timestamps: request.timestamps,
+        trim_silence: request.trim,
+        since: request.since,
+        speaker: request.speaker,
+        cues: receiver.cues(),
+        chat: receiver.chat(),
+    };
+    let (format, normalize) = (request.format, request.normalize);
+    let author = ctx.author().name.clone();
+    let job_receiver = receiver.clone();
+    let queued = receiver.export_queue.enqueue(move || {
+        tracing::info!("running dump by '{}'", author);
+        encode_export(
+            &job_receiver,
+            &drain,
+            options,
+            format,
+            normalize,
+            gain,
+            rate,
+        )
+    });
+    say_queue_position(ctx, queued.ahead).await?;
+    let dump = queued.result().await??;
+    let extension = request.format.extension();
+    let mut reply = format!(
+        "dumped {} ({} packets)",
+        humantime::format_duration(Duration::from_secs(dump.duration.as_secs())),
+        dump.packets
+    );
+    if let Some(id) = receiver.dump_cache.insert(&dump, extension) {
+        reply.push_str(&format!(", /redump {} sends it again", id));
     }
-    ctx.send(
-        CreateReply::default()
-            .content("some audio file")
-            .attachment(CreateAttachment::bytes(ogg_file, "dump.ogg")),
+    if request.format == ExportFormat::Packets {
+        reply.push_str(&format!("\n{}", PACKETS_FRAMING));
+    }
+    if !dump.chapters.is_empty() {
+        let embedded = if extension == "ogg" {
+            "cues, written into the file as chapters:"
+        } else {
+            "cues, only listed here:"
+        };
+        reply.push_str(&format!("\n{}", embedded));
+        for (offset, label) in &dump.chapters {
+            reply.push_str(&format!("\n{} {}", encode::chapter_time(*offset), label));
+        }
+    }
+    ctx.say(reply).await?;
+    match (request.link, &receiver.config.media_base_url) {
+        (true, Some(base_url)) => {
+            let (file_name, sidecar_names) = store_dump(receiver, &dump, extension).await?;
+            let mut reply = format!("{}/{}", base_url, file_name);
+            for sidecar_name in sidecar_names {
+                reply.push_str(&format!("\n{}/{}", base_url, sidecar_name));
+            }
+            ctx.say(reply).await?;
+            return Ok(());
+        }
+        (true, None) => {
+            ctx.say("MEDIA_BASE_URL is not set, attaching instead")
+                .await?;
+        }
+        _ => {}
+    }
+    let stored = if request.to_disk {
+        Some(store_dump(receiver, &dump, extension).await?.0)
+    } else {
+        None
+    };
+    // kept to store the file instead if discord turns it down
+    let mut reply = CreateReply::default()
+        .content("some audio file")
+        .attachment(CreateAttachment::bytes(
+            dump.bytes.clone(),
+            dump_file_name(extension, dump.right_channel.is_some()),
+        ));
+    if let Some(right) = dump.right_channel.clone() {
+        reply = reply.attachment(CreateAttachment::bytes(right, "dump.right.ogg"));
+    }
+    if let Some(sidecar) = dump.timestamps.clone() {
+        reply = reply.attachment(CreateAttachment::bytes(sidecar, "dump.csv"));
+    }
+    if let Some(chat) = dump.chat.clone() {
+        reply = reply.attachment(CreateAttachment::bytes(chat, "chat.csv"));
+    }
+    match ctx.send(reply).await {
+        Ok(_) => Ok(()),
+        Err(e) if is_upload_too_large(&e) => {
+            tracing::info!(
+                "dump of {} bytes is over the upload limit, storing it instead",
+                dump.bytes.len()
+            );
+            let file_name = match stored {
+                Some(file_name) => file_name,
+                None => store_dump(receiver, &dump, extension).await?.0,
+            };
+            let location = match &receiver.config.media_base_url {
+                Some(base_url) => format!("{}/{}", base_url, file_name),
+                None => format!(
+                    "'{}' on {} storage",
+                    file_name,
+                    receiver.config.storage.name()
+                ),
+            };
+            ctx.say(format!(
+                "the {:.1} MB file is over this server's upload limit, stored it as {} instead. \
+                 a shorter duration or a mono format fits in an attachment",
+                dump.bytes.len() as f64 / (1024.0 * 1024.0),
+                location
+            ))
+            .await?;
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// What an export's file is attached as, a split export's main file is its left channel.
+fn dump_file_name(extension: &str, split: bool) -> String {
+    if split {
+        format!("dump.left.{}", extension)
+    } else {
+        format!("dump.{}", extension)
+    }
+}
+
+/// Drains the lookback and converts it to `format` at `rate`, the part of an export that runs on the
+/// export queue.
+fn encode_export(
+    receiver: &Receiver,
+    drain: &DrainOptions,
+    mut options: encode::StreamOptions,
+    format: ExportFormat,
+    normalize: bool,
+    gain: f32,
+    rate: u32,
+) -> anyhow::Result<DumpResult> {
+    if rate != AUDIO_FREQUENCY {
+        options.input_rate = Some(rate);
+    }
+    let mut dump = match format {
+        ExportFormat::Packets => receiver.lookback.drain_raw_packets(drain)?,
+        _ => receiver.lookback.drain_buffer(drain, &options)?,
+    };
+    tracing::info!("dump produced stream serial {:#010x}", options.serial);
+    // plain ogg only needs decoding to find the loudest sample, raw packets never do
+    if normalize || !matches!(format, ExportFormat::Ogg | ExportFormat::Packets) {
+        let packets = decode::demux(&dump.bytes)?;
+        let pcm = decode::decode_opus(&packets)?;
+        let gain_db = if normalize {
+            normalize_gain_db(&pcm)
+        } else {
+            gain
+        };
+        dump.bytes = match format {
+            // the header gain is applied by the player, the packets stay untouched
+            ExportFormat::Ogg => {
+                options.output_gain = encode::output_gain_from_db(gain_db)?;
+                encode::encode_with_comments::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(
+                    &packets,
+                    &options,
+                    &encode::chapter_comments(&dump.chapters),
+                )?
+            }
+            ExportFormat::OggMono => {
+                options.output_gain = encode::output_gain_from_db(gain_db)?;
+                let encoder =
+                    make_opus_encoder_with_channels(&receiver.encoder(), audiopus::Channels::Mono);
+                let mono =
+                    encode::encode_mono_like(&packets, &encode::downmix_to_mono(&pcm), &encoder)?;
+                dump.packets = mono.len();
+                encode::encode_with_comments::<AUDIO_FREQUENCY, 1>(
+                    &mono,
+                    &options,
+                    &encode::chapter_comments(&dump.chapters),
+                )?
+            }
+            ExportFormat::Split => {
+                options.output_gain = encode::output_gain_from_db(gain_db)?;
+                let (left, right) = encode::split_channels(&pcm);
+                let mut channels = [left, right].into_iter().map(|channel| {
+                    // each channel gets its own encoder so neither predicts from the other
+                    let encoder = make_opus_encoder_with_channels(
+                        &receiver.encoder(),
+                        audiopus::Channels::Mono,
+                    );
+                    let mono = encode::encode_mono_like(&packets, &channel, &encoder)?;
+                    let bytes = encode::encode_with_comments::<AUDIO_FREQUENCY, 1>(
+                        &mono,
+                        &options,
+                        &encode::chapter_comments(&dump.chapters),
+                    )?;
+                    anyhow::Ok((mono.len(), bytes))
+                });
+                let (packets, left) = channels.next().expect("left channel")?;
+                let (_, right) = channels.next().expect("right channel")?;
+                dump.packets = packets;
+                dump.right_channel = Some(right);
+                left
+            }
+            ExportFormat::Wav => encode::encode_wav::<AUDIO_CHANNELS>(
+                &resample(
+                    &apply_gain(pcm, gain_db),
+                    AUDIO_CHANNELS as usize,
+                    AUDIO_FREQUENCY,
+                    rate,
+                ),
+                rate,
+            ),
+            ExportFormat::WavMono => encode::encode_wav::<1>(
+                &resample(
+                    &apply_gain(encode::downmix_to_mono(&pcm), gain_db),
+                    1,
+                    AUDIO_FREQUENCY,
+                    rate,
+                ),
+                rate,
+            ),
+            ExportFormat::Packets => unreachable!("raw packets are never decoded"),
+        };
+    }
+    Ok(dump)
+}
+
+/// Tells the user their export waits for others, silent if it runs right away.
+async fn say_queue_position(ctx: Context<'_>, ahead: usize) -> Result<(), Error> {
+    if ahead > 0 {
+        ctx.say(format!(
+            "queued behind {} other export{}",
+            ahead,
+            if ahead == 1 { "" } else { "s" }
+        ))
+        .await?;
+    }
+    Ok(())
+}
+
+/// Whether discord refused a message for attachments over the server's upload size limit.
+fn is_upload_too_large(error: &serenity::Error) -> bool {
+    // 40005 is discord's "request entity too large", 413 comes from its proxy before the api
+    matches!(
+        error,
+        serenity::Error::Http(HttpError::UnsuccessfulRequest(response))
+            if response.error.code == 40005
+                || response.status_code == StatusCode::PAYLOAD_TOO_LARGE
     )
-    .await?;
+}
+
+/// Sends a recent export again from memory, without re-encoding.
+#[poise::command(slash_command, prefix_command)]
+pub async fn redump(ctx: Context<'_>, id: u64) -> Result<(), Error> {
+    let cache = &ctx.data().dump_cache;
+    let Some(dump) = cache.get(id) else {
+        let ids = cache.ids();
+        return Err(anyhow!(
+            "dump {} is no longer cached, cached dumps: {}",
+            id,
+            if ids.is_empty() {
+                "none".to_string()
+            } else {
+                ids.iter()
+                    .map(u64::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            }
+        ));
+    };
+    tracing::info!("redumping {} for '{}'", id, ctx.author().name);
+    let mut reply = CreateReply::default()
+        .content(format!("dump {}", id))
+        .attachment(CreateAttachment::bytes(
+            dump.bytes.to_vec(),
+            dump_file_name(dump.extension, dump.right_channel.is_some()),
+        ));
+    if let Some(right) = dump.right_channel {
+        reply = reply.attachment(CreateAttachment::bytes(right.to_vec(), "dump.right.ogg"));
+    }
+    if let Some(sidecar) = dump.timestamps {
+        reply = reply.attachment(CreateAttachment::bytes(sidecar.to_vec(), "dump.csv"));
+    }
+    if let Some(chat) = dump.chat {
+        reply = reply.attachment(CreateAttachment::bytes(chat.to_vec(), "chat.csv"));
+    }
+    ctx.send(reply).await?;
     Ok(())
 }
 
-#[poise::command(slash_command)]
-pub async fn clone(ctx: Context<'_>, user: poise::serenity_prelude::User) -> Result<(), Error> {
-    tracing::info!("cloning last 2m of voice for user '{}'", user);
-    ctx.say(format!("cloning last 2m of voice for user '{}'", user))
+/// Decoded audio re-encoded as wav at `volume_db`, held back as far as needed to keep it from
+/// clipping. Left alone at 0 dB.
+fn at_volume(audio: bytes::Bytes, hint: &Hint, volume_db: f32) -> anyhow::Result<bytes::Bytes> {
+    if volume_db == 0.0 {
+        return Ok(audio);
+    }
+    let pcm = decode::decode_to_raw_audio(audio, hint)?.concat();
+    let gain_db = volume_db.min(normalize_gain_db(&pcm));
+    Ok(encode::encode_wav::<AUDIO_CHANNELS>(&apply_gain(pcm, gain_db), AUDIO_FREQUENCY).into())
+}
+
+/// Gain that brings the loudest sample to -1 dBFS, 0 for silence.
+fn normalize_gain_db(pcm: &[i16]) -> f32 {
+    const TARGET_DBFS: f32 = -1.0;
+    let peak = pcm
+        .iter()
+        .map(|sample| sample.unsigned_abs())
+        .max()
+        .unwrap_or(0);
+    if peak == 0 {
+        return 0.0;
+    }
+    TARGET_DBFS - 20.0 * (peak as f32 / i16::MAX as f32).log10()
+}
+
+fn apply_gain(mut pcm: Vec<i16>, gain_db: f32) -> Vec<i16> {
+    if gain_db == 0.0 {
+        return pcm;
+    }
+    let factor = 10f32.powf(gain_db / 20.0);
+    for sample in &mut pcm {
+        *sample = (*sample as f32 * factor).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+    }
+    pcm
+}
+
+/// Stores a dump and whichever of its timestamp and chat sidecars it has, returning the names they
+/// were stored under. The sidecars share the recording's name with a `.csv` and `.chat.csv`
+/// extension.
+async fn store_dump(
+    receiver: &Receiver,
+    dump: &DumpResult,
+    extension: &str,
+) -> anyhow::Result<(String, Vec<String>)> {
+    let file_name = write_ogg_to_disk(
+        receiver.sink.as_ref(),
+        &dump.bytes,
+        dump.duration,
+        extension,
+    )
+    .await?;
+    let stem = file_name.trim_end_matches(&format!(".{}", extension));
+    let mut sidecar_names = Vec::new();
+    for (sidecar, sidecar_extension) in [(&dump.timestamps, "csv"), (&dump.chat, "chat.csv")] {
+        let Some(sidecar) = sidecar else {
+            continue;
+        };
+        let sidecar_name = format!("{}.{}", stem, sidecar_extension);
+        receiver.sink.put(&sidecar_name, sidecar.as_bytes()).await?;
+        sidecar_names.push(sidecar_name);
+    }
+    if let Some(right) = &dump.right_channel {
+        let right_name = format!("{}.right.ogg", stem);
+        receiver.sink.put(&right_name, right).await?;
+        sidecar_names.push(right_name);
+    }
+    Ok((file_name, sidecar_names))
+}
+
+/// Suggests users with buffered audio by display name, the value is their id.
+async fn autocomplete_tracked_user(
+    ctx: Context<'_>,
+    partial: &str,
+) -> Vec<poise::serenity_prelude::AutocompleteChoice> {
+    user_choices(
+        ctx,
+        partial,
+        ctx.data().tts.per_user_sound_buffer.tracked_users(),
+    )
+}
+
+/// Like [`autocomplete_tracked_user`], left to users with a clone reference to speak with.
+async fn autocomplete_cloned_user(
+    ctx: Context<'_>,
+    partial: &str,
+) -> Vec<poise::serenity_prelude::AutocompleteChoice> {
+    let receiver = ctx.data();
+    let mut cloned = Vec::new();
+    for user_id in receiver.tts.per_user_sound_buffer.tracked_users() {
+        if receiver.has_clone_reference(user_id).await {
+            cloned.push(user_id);
+        }
+    }
+    user_choices(ctx, partial, cloned)
+}
+
+/// `users` whose display name contains `partial`, the value is their id.
+fn user_choices(
+    ctx: Context<'_>,
+    partial: &str,
+    users: Vec<UserId>,
+) -> Vec<poise::serenity_prelude::AutocompleteChoice> {
+    // discord shows at most 25 choices
+    const MAX_CHOICES: usize = 25;
+    let partial = partial.to_lowercase();
+    let mut choices = Vec::new();
+    for user_id in users {
+        let name = cached_display_name(ctx, user_id);
+        if name.to_lowercase().contains(&partial) {
+            choices.push(poise::serenity_prelude::AutocompleteChoice::new(
+                name,
+                user_id.0.to_string(),
+            ));
+        }
+        if choices.len() >= MAX_CHOICES {
+            break;
+        }
+    }
+    choices
+}
+
+/// Server nickname, global name or username, whichever the cache knows first. Falls back to the id.
+fn cached_display_name(ctx: Context<'_>, user_id: UserId) -> String {
+    let id = poise::serenity_prelude::UserId::new(user_id.0);
+    let cache = ctx.cache();
+    cache
+        .guild(ctx.data().guild_id)
+        .and_then(|guild| {
+            guild
+                .members
+                .get(&id)
+                .map(|member| member.display_name().to_string())
+        })
+        .or_else(|| {
+            cache.user(id).map(|user| {
+                user.global_name
+                    .clone()
+                    .unwrap_or_else(|| user.name.clone())
+            })
+        })
+        .unwrap_or_else(|| user_id.0.to_string())
+}
+
+/// Accepts the id an autocomplete choice submits, or a pasted mention.
+async fn resolve_user(
+    ctx: Context<'_>,
+    user: &str,
+) -> anyhow::Result<poise::serenity_prelude::User> {
+    let id: u64 = user
+        .trim()
+        .trim_start_matches("<@")
+        .trim_start_matches('!')
+        .trim_end_matches('>')
+        .parse()
+        .ok()
+        .filter(|id| *id != 0)
+        .ok_or_else(|| anyhow!("'{}' is not a user", user))?;
+    Ok(poise::serenity_prelude::UserId::new(id)
+        .to_user(ctx)
+        .await?)
+}
+
+/// Whether the invoking user is in `TRUSTED_USERS` or has one of `TRUSTED_ROLES`, which lifts caps
+/// like `MAX_DUMP_DURATION` for them.
+async fn is_trusted(ctx: Context<'_>) -> bool {
+    let config = &ctx.data().config;
+    if config.trusted_users.contains(&ctx.author().id.get()) {
+        return true;
+    }
+    if config.trusted_roles.is_empty() {
+        return false;
+    }
+    ctx.author_member().await.is_some_and(|member| {
+        member
+            .roles
+            .iter()
+            .any(|role| config.trusted_roles.contains(&role.get()))
+    })
+}
+
+/// `MAX_DUMP_DURATION`, unless the invoking user is trusted.
+async fn max_dump_duration(ctx: Context<'_>) -> Option<Duration> {
+    if is_trusted(ctx).await {
+        return None;
+    }
+    ctx.data().config.max_dump_duration
+}
+
+/// Saves a user's recent speech as the reference their voice is cloned from.
+#[poise::command(slash_command, prefix_command)]
+pub async fn clone(
+    ctx: Context<'_>,
+    #[autocomplete = "autocomplete_tracked_user"] user: String,
+    source: Option<CloneSource>,
+    append: Option<bool>,
+) -> Result<(), Error> {
+    let user = resolve_user(ctx, &user).await?;
+    let source = source.unwrap_or_default();
+    tracing::info!("cloning voice for user '{}' from {:?}", user, source);
+    ctx.say(format!("cloning voice for user '{}'", user))
         .await?;
     let receiver = ctx.data();
 
     let user_id = UserId(user.id.get());
-    let ogg_file = {
-        // closure to limit lock scope
+    let display_name = user.global_name.as_deref().unwrap_or(&user.name);
+    let comments = encode::user_comments(display_name, user_id.0);
+    let clone_duration = humantime::format_duration(receiver.config.clone_duration);
+    let buffer = &receiver.tts.per_user_sound_buffer;
+    let from_archive = match source {
+        CloneSource::Auto => !buffer.has_speech(user_id),
+        CloneSource::Buffer => false,
+        CloneSource::Archive => true,
+    };
+    let archived = if from_archive {
         receiver
-            .tts
-            .per_user_sound_buffer
-            .read()
-            .await
-            .get_ogg_buffer(user_id)?
+            .archive
+            .recent_speech(user_id, receiver.config.clone_duration)
+            .await?
+            .filter(|packets| !packets.is_empty())
+    } else {
+        None
+    };
+    let (ogg_file, used) = match archived {
+        Some(packets) => (
+            encode::encode_with_comments::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(
+                &packets,
+                &encode::StreamOptions::default(),
+                &comments,
+            )?,
+            format!("their last {} of archived speech", clone_duration),
+        ),
+        None if source == CloneSource::Archive => {
+            return Err(anyhow!(
+                "no archived speech for '{}', ARCHIVE has to be users or all",
+                user.name
+            ));
+        }
+        None => (
+            buffer.get_clone_reference(user_id, &comments)?,
+            format!("the last {} clone buffer", clone_duration),
+        ),
+    };
+
+    // appending to a user without a reference yet just starts one
+    let (ogg_file, used) = if append.unwrap_or(false) && receiver.has_clone_reference(user_id).await
+    {
+        let (merged, duration) = tts::append_reference(
+            &read_ogg_file(
+                user_to_ogg_file(user_id),
+                receiver.config.recording_key.as_ref(),
+            )
+            .await?,
+            &ogg_file,
+            receiver.config.max_reference_duration,
+            &comments,
+        )?;
+        let used = format!(
+            "{} appended to their reference, now {} long",
+            used,
+            humantime::format_duration(Duration::from_secs(duration.as_secs()))
+        );
+        (merged, used)
+    } else {
+        (ogg_file, used)
     };
 
-    write_ogg_to_disk_named(&ogg_file, user_to_ogg_file(user_id)).await?;
-    ctx.say("finished cloning").await?;
+    write_ogg_to_disk_named(
+        &ogg_file,
+        user_to_ogg_file(user_id),
+        receiver.config.recording_key.as_ref(),
+    )
+    .await?;
+    ctx.send(
+        CreateReply::default()
+            .content(format!("finished cloning from {}", used))
+            .attachment(CreateAttachment::bytes(
+                ogg_file,
+                format!("{}.ogg", user_label(display_name, user_id)),
+            )),
+    )
+    .await?;
     Ok(())
 }
 
-#[poise::command(slash_command)]
+/// Speaks text in the voice cloned from a user, or in one of the configured voices.
+#[poise::command(slash_command, prefix_command)]
 pub async fn ctts(
     ctx: Context<'_>,
-    user: poise::serenity_prelude::User,
+    #[autocomplete = "autocomplete_cloned_user"] user: String,
     text: String,
+    voice: Option<String>,
+    save: Option<bool>,
 ) -> Result<(), Error> {
-    tracing::info!("tts for user '{}': {}", user, text);
-    ctx.say("working on tts").await?;
-
+    let user = resolve_user(ctx, &user).await?;
+    tracing::info!("tts for user '{}' voice {:?}: {}", user, voice, text);
     let receiver = ctx.data();
     let user_id = UserId(user.id.get());
-    let ogg_output = receiver.tts.tts(user_id, text).await?;
+    if voice.is_none() && !receiver.has_clone_reference(user_id).await {
+        return Err(anyhow!(
+            "'{}' has no clone reference yet, /clone them first",
+            user.name
+        ));
+    }
+    ctx.say("working on tts").await?;
+
+    const MAX_TEXT_LEN: usize = 40;
+    let text_label = sanitize_file_name(&text, MAX_TEXT_LEN);
+    let mut hint = Hint::default();
+    hint.mime_type("audio/wav").with_extension("wav");
+    let speech = receiver.tts.tts_chunked(user_id, text, voice).await?;
+    if let Some((failed, e)) = &speech.failed {
+        ctx.say(format!(
+            "only spoke {} of {} parts, part {} failed: {}",
+            failed,
+            speech.chunks,
+            failed + 1,
+            e
+        ))
+        .await?;
+    }
+    let mut tts_output = at_volume(speech.audio, &hint, receiver.volume_db())?;
+    let max_duration = if is_trusted(ctx).await {
+        None
+    } else {
+        receiver.config.max_tts_duration
+    };
+    let decoded = if receiver.config.record_playback || max_duration.is_some() {
+        match decode::decode_to_raw_audio(tts_output.clone(), &hint) {
+            Ok(packets) => Some(packets),
+            // without decoding there's no telling how long it is
+            Err(e) if max_duration.is_some() => {
+                return Err(anyhow!("couldn't check the tts output's length: {:?}", e));
+            }
+            Err(e) => {
+                tracing::warn!("failed to decode tts output for the lookback {:?}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let decoded = match (decoded, max_duration) {
+        (Some(mut packets), Some(max_duration))
+            if packets.len() > packets_for_duration(max_duration, PACKET_DURATION) =>
+        {
+            tracing::info!(
+                "truncating {} packets of tts output to {:?}",
+                packets.len(),
+                max_duration
+            );
+            packets.truncate(packets_for_duration(max_duration, PACKET_DURATION));
+            tts_output =
+                encode::encode_wav::<AUDIO_CHANNELS>(&packets.concat(), AUDIO_FREQUENCY).into();
+            ctx.say(format!(
+                "tts output was longer than {}, only playing the start",
+                humantime::format_duration(max_duration)
+            ))
+            .await?;
+            Some(packets)
+        }
+        (decoded, _) => decoded,
+    };
+    if save.unwrap_or(false) {
+        let display_name = user.global_name.as_deref().unwrap_or(&user.name);
+        let file_name = format!(
+            "tts_{}_{}_{}.wav",
+            user_label(display_name, user_id),
+            if text_label.is_empty() {
+                "speech"
+            } else {
+                &text_label
+            },
+            chrono::prelude::Local::now().format("%Y-%m-%d_%H-%M-%S")
+        );
+        write_ogg_to_disk_named(
+            &tts_output,
+            file_name.clone().into(),
+            receiver.config.recording_key.as_ref(),
+        )
+        .await?;
+        ctx.say(format!("saved as {}", file_name)).await?;
+    }
 
     let manager = songbird::get(ctx.serenity_context())
         .await
         .ok_or_else(|| anyhow!("Songbird Voice client placed in at initialisation."))?;
 
     if let Some(handler_lock) = manager.get(receiver.guild_id) {
-        let mut handler = handler_lock.lock().await;
-        let mut hint = Hint::default();
-        hint.mime_type("audio/wav").with_extension("wav");
-        let audio_stream: AudioStream<Box<dyn MediaSource>> = AudioStream {
-            input: Box::new(io::Cursor::new(ogg_output)),
-            hint: Some(hint),
-        };
-        let input = Input::Live(LiveInput::Raw(audio_stream), None);
-        let _ = handler.play_input(input);
+        let track = handler_lock
+            .lock()
+            .await
+            .play_input(bytes_to_input(tts_output, hint));
+        if let (true, Some(packets)) = (receiver.config.record_playback, decoded) {
+            record_playback(receiver, &track, packets).await;
+        }
     }
     ctx.say("finished tts").await?;
     Ok(())
 }
+
+/// Rough check that a user's clone reference sounds like their recent speech.
+///
+/// Compares the average spectrum of the two, a heuristic that a new mic or room throws off and not
+/// speaker verification.
+#[poise::command(slash_command, prefix_command)]
+pub async fn verify(ctx: Context<'_>, user: poise::serenity_prelude::User) -> Result<(), Error> {
+    // enough to average over, short enough to stay recent
+    const SAMPLE: Duration = Duration::from_secs(20);
+    const MIN_SPEECH: Duration = Duration::from_secs(3);
+    tracing::info!("verifying clone reference of '{}'", user);
+    let receiver = ctx.data();
+    let user_id = UserId(user.id.get());
+    let display_name = user
+        .global_name
+        .as_deref()
+        .unwrap_or(&user.name)
+        .to_string();
+    if !receiver.has_clone_reference(user_id).await {
+        return Err(anyhow!(
+            "'{}' has no clone reference yet, /clone them first",
+            display_name
+        ));
+    }
+    let sample = receiver
+        .tts
+        .per_user_sound_buffer
+        .recent_speech(user_id, SAMPLE);
+    if sample.is_empty() {
+        return Err(anyhow!(
+            "'{}' hasn't said anything recently to compare against, have them talk and try again",
+            display_name
+        ));
+    }
+    let reference = read_ogg_file(
+        user_to_ogg_file(user_id),
+        receiver.config.recording_key.as_ref(),
+    )
+    .await?;
+    let queued = receiver.export_queue.enqueue(move || {
+        let live = VoiceProfile::of(&decode::decode_opus(&sample)?);
+        let reference = VoiceProfile::of(&decode::decode_opus(&decode::demux(&reference)?)?);
+        anyhow::Ok((live, reference))
+    });
+    say_queue_position(ctx, queued.ahead).await?;
+    let format = |duration: Duration| {
+        humantime::format_duration(Duration::from_secs(duration.as_secs())).to_string()
+    };
+    let (live, reference) = match queued.result().await?? {
+        (Some(live), Some(reference))
+            if live.speech >= MIN_SPEECH && reference.speech >= MIN_SPEECH =>
+        {
+            (live, reference)
+        }
+        (live, reference) => {
+            let speech = |profile: Option<VoiceProfile>| {
+                profile.map_or(Duration::ZERO, |profile| profile.speech)
+            };
+            return Err(anyhow!(
+                "not enough speech to compare, {} recent and {} in the reference, at least {} of each",
+                format(speech(live)),
+                format(speech(reference)),
+                humantime::format_duration(MIN_SPEECH)
+            ));
+        }
+    };
+    let similarity = live.similarity(&reference);
+    let threshold = receiver.config.verify_threshold;
+    ctx.say(format!(
+        "{}: '{}' matches their clone reference {:.0}%, the threshold is {:.0}%. compared {} of \
+         recent speech with {} of the reference by average spectrum only. this is a rough \
+         heuristic, not speaker verification, and a different mic or room can fail the right person",
+        if similarity >= threshold { "pass" } else { "fail" },
+        display_name,
+        similarity * 100.0,
+        threshold * 100.0,
+        format(live.speech),
+        format(reference.speech)
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Gateway latency and voice packet loss, for diagnosing recording glitches.
+#[poise::command(slash_command, prefix_command)]
+pub async fn ping(ctx: Context<'_>) -> Result<(), Error> {
+    let receiver = ctx.data();
+    // zero until the shard has had its first heartbeat acknowledged
+    let gateway = match ctx.ping().await {
+        Duration::ZERO => "not measured yet".to_string(),
+        latency => format!("{}ms", latency.as_millis()),
+    };
+    let mut reply = format!("gateway heartbeat: {}\n", gateway);
+
+    let manager = songbird::get(ctx.serenity_context())
+        .await
+        .ok_or_else(|| anyhow!("Songbird Voice client placed in at initialisation."))?;
+    let connection = match manager.get(receiver.guild_id) {
+        Some(handler_lock) => handler_lock
+            .lock()
+            .await
+            .current_connection()
+            .map(|info| info.endpoint.clone()),
+        None => None,
+    };
+    match connection {
+        Some(endpoint) => reply.push_str(&format!("voice server: {}\n", endpoint)),
+        None => reply.push_str("voice: not connected\n"),
+    }
+
+    // songbird doesn't expose the voice websocket's round trip, loss is the next best signal
+    let mut counts = receiver.packet_counts();
+    counts.sort_by_key(|(_, counts)| std::cmp::Reverse(counts.lost));
+    if counts.is_empty() {
+        reply.push_str("no voice packets since connecting\n");
+    }
+    for (user, counts) in counts {
+        let user = match user {
+            Some(user) => cached_display_name(ctx, user),
+            None => "unknown user".to_string(),
+        };
+        let total = counts.received + counts.lost;
+        reply.push_str(&format!(
+            "{}: {} of {} packets lost ({:.1}%)\n",
+            user,
+            counts.lost,
+            total,
+            counts.lost as f64 * 100.0 / total.max(1) as f64
+        ));
+    }
+    reply.push_str(&format!(
+        "dropped before their user was known: {} packets",
+        receiver.unmapped_dropped()
+    ));
+    ctx.say(reply).await?;
+    Ok(())
+}
+
+/// Round trip from playing audio to capturing it again, in ms.
+///
+/// Plays the marker beep and times how long until it shows up in the lookback. Discord never sends
+/// the bot its own audio, so this only works while someone's mic picks up the bot from their
+/// speakers, and includes their device's latency.
+#[poise::command(slash_command, prefix_command)]
+pub async fn latency(ctx: Context<'_>) -> Result<(), Error> {
+    const LISTEN_FOR: Duration = Duration::from_secs(5);
+    const POLL_INTERVAL: Duration = Duration::from_millis(250);
+    let receiver = ctx.data();
+    let manager = songbird::get(ctx.serenity_context())
+        .await
+        .ok_or_else(|| anyhow!("Songbird Voice client placed in at initialisation."))?;
+    let handler_lock = manager
+        .get(receiver.guild_id)
+        .ok_or_else(|| anyhow!("not in a voice channel"))?;
+    let marker = ogg_bytes_to_input(receiver.lookback.marker_ogg()?);
+
+    let played_at = SystemTime::now();
+    let _ = handler_lock.lock().await.play_input(marker);
+    let mut heard_at = None;
+    let mut listened = Duration::ZERO;
+    while heard_at.is_none() && listened < LISTEN_FOR {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        listened += POLL_INTERVAL;
+        heard_at = receiver
+            .lookback
+            .find_tone(played_at, receiver.config.marker_frequency)?;
+    }
+    let reply = match heard_at {
+        Some(heard_at) => format!(
+            "heard the marker back after {}ms, to within {}ms",
+            heard_at
+                .duration_since(played_at)
+                .unwrap_or_default()
+                .as_millis(),
+            receiver.encoder().frame_duration.as_millis()
+        ),
+        None => format!(
+            "didn't hear the marker back within {}, someone's mic has to pick up the bot for this",
+            humantime::format_duration(LISTEN_FOR)
+        ),
+    };
+    ctx.say(reply).await?;
+    Ok(())
+}
+
+/// The live ssrc to user mapping, to debug audio attributed to the wrong person or lost.
+#[poise::command(
+    slash_command,
+    prefix_command,
+    default_member_permissions = "ADMINISTRATOR",
+    required_permissions = "ADMINISTRATOR"
+)]
+pub async fn ssrc(ctx: Context<'_>) -> Result<(), Error> {
+    let receiver = ctx.data();
+    let ssrc_to_user = receiver.ssrc_to_user();
+    let user_to_ssrc = receiver.user_to_ssrc();
+    let name = |user: UserId| format!("{} ({})", cached_display_name(ctx, user), user);
+    let mut table = String::from("ssrc        user\n");
+    for (ssrc, user) in &ssrc_to_user {
+        table.push_str(&format!("{:<11} {}\n", ssrc, name(*user)));
+    }
+    // the maps are updated one after the other, so they only disagree when something went wrong
+    // or a speaking update is being applied right now
+    let mut notes = Vec::new();
+    for (ssrc, user) in &ssrc_to_user {
+        match user_to_ssrc.iter().find(|(mapped, _)| mapped == user) {
+            Some((_, current)) if current != ssrc => notes.push(format!(
+                "ssrc {} is stale, {} has moved on to ssrc {}",
+                ssrc,
+                name(*user),
+                current
+            )),
+            None => notes.push(format!(
+                "ssrc {} maps to {}, who has no ssrc",
+                ssrc,
+                name(*user)
+            )),
+            _ => {}
+        }
+    }
+    for (user, ssrc) in &user_to_ssrc {
+        match ssrc_to_user.iter().find(|(mapped, _)| mapped == ssrc) {
+            Some((_, mapped)) if mapped != user => notes.push(format!(
+                "{} has ssrc {}, which maps to {}",
+                name(*user),
+                ssrc,
+                name(*mapped)
+            )),
+            None => notes.push(format!(
+                "{} has ssrc {}, which maps to nobody",
+                name(*user),
+                ssrc
+            )),
+            _ => {}
+        }
+    }
+    for (ssrc, ticks) in receiver.unmapped_ssrcs() {
+        notes.push(format!(
+            "ssrc {} has no user yet, holding {} ticks of its audio",
+            ssrc, ticks
+        ));
+    }
+    let mut reply = if ssrc_to_user.is_empty() {
+        "no ssrc mappings since connecting\n".to_string()
+    } else {
+        format!("```\n{}```\n", table)
+    };
+    if notes.is_empty() {
+        reply.push_str("both directions agree");
+    } else {
+        reply.push_str(&notes.join("\n"));
+    }
+    reply.push_str(&format!(
+        "\nconflicting speaking updates resolved since startup: {}",
+        receiver.mapping_conflicts()
+    ));
+    ctx.say(reply).await?;
+    Ok(())
+}
+
+/// Version, uptime and the effective configuration.
+#[poise::command(slash_command, prefix_command)]
+pub async fn info(ctx: Context<'_>) -> Result<(), Error> {
+    let receiver = ctx.data();
+    let config = &receiver.config;
+    let encoder = receiver.encoder();
+    let uptime = Duration::from_secs(receiver.started_at.elapsed().as_secs());
+    let tts = if config.tts_host.is_some() {
+        "configured"
+    } else {
+        "not configured"
+    };
+    let embed = CreateEmbed::new()
+        .title(env!("CARGO_PKG_NAME"))
+        .field("version", env!("CARGO_PKG_VERSION"), true)
+        .field(
+            "uptime",
+            humantime::format_duration(uptime).to_string(),
+            true,
+        )
+        .field(
+            "lookback",
+            humantime::format_duration(config.lookback_duration).to_string(),
+            true,
+        )
+        .field(
+            "clone buffer",
+            humantime::format_duration(config.clone_duration).to_string(),
+            true,
+        )
+        .field("bitrate", format!("{} bps", encoder.bitrate), true)
+        .field(
+            "frame duration",
+            humantime::format_duration(config.encoder.frame_duration).to_string(),
+            true,
+        )
+        .field(
+            "opus application",
+            format!("{:?}", encoder.application),
+            true,
+        )
+        .field(
+            "max tracked users",
+            config.max_tracked_users.to_string(),
+            true,
+        )
+        .field("tts", tts, true)
+        .field(
+            "unmapped packets dropped",
+            receiver.unmapped_dropped().to_string(),
+            true,
+        )
+        .field(
+            "ssrc mapping conflicts",
+            receiver.mapping_conflicts().to_string(),
+            true,
+        )
+        .field("storage", config.storage.name(), true)
+        .field(
+            "encryption at rest",
+            if config.recording_key.is_some() {
+                "on"
+            } else {
+                "off"
+            },
+            true,
+        );
+    ctx.send(CreateReply::default().embed(embed)).await?;
+    Ok(())
+}
+
+/// `OPUS_APPLICATION` as an `encoder` choice.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, poise::ChoiceParameter)]
+pub enum EncoderApplication {
+    Voip,
+    Audio,
+    Lowdelay,
+}
+
+impl From<EncoderApplication> for Application {
+    fn from(application: EncoderApplication) -> Self {
+        match application {
+            EncoderApplication::Voip => Application::Voip,
+            EncoderApplication::Audio => Application::Audio,
+            EncoderApplication::Lowdelay => Application::LowDelay,
+        }
+    }
+}
+
+/// Shows the master volume in dB, or sets it for exports, replays and ctts from now on.
+///
+/// Exports and dumps add their own gain to it, replays too, and normalize ignores it. Kept within
+/// 30 dB either way, playback is held back where it would clip. Saved in the audio dir, so it
+/// outlasts a restart.
+#[poise::command(slash_command, prefix_command)]
+pub async fn volume(ctx: Context<'_>, db: Option<f32>) -> Result<(), Error> {
+    let receiver = ctx.data();
+    let current = receiver.volume_db();
+    match db {
+        Some(db) => {
+            let volume = receiver.set_volume_db(db).await?;
+            tracing::info!(
+                "volume changed by '{}': {:+.1} -> {:+.1} dB",
+                ctx.author().name,
+                current,
+                volume
+            );
+            let mut reply = format!("volume {:+.1} -> {:+.1} dB", current, volume);
+            if volume != db {
+                reply.push_str(&format!(", {:+.1} dB is out of range", db));
+            }
+            ctx.say(reply).await?;
+        }
+        None => {
+            ctx.say(format!("volume is {:+.1} dB", current)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Shows the opus encoder settings, or changes them for everything encoded from now on.
+#[poise::command(slash_command, prefix_command)]
+pub async fn encoder(
+    ctx: Context<'_>,
+    bitrate: Option<i32>,
+    complexity: Option<u8>,
+    application: Option<EncoderApplication>,
+    vbr: Option<bool>,
+    fec: Option<bool>,
+) -> Result<(), Error> {
+    let receiver = ctx.data();
+    let current = receiver.encoder();
+    let mut updated = current;
+    updated.bitrate = bitrate.unwrap_or(current.bitrate);
+    updated.complexity = complexity.unwrap_or(current.complexity);
+    updated.application = application.map_or(current.application, Into::into);
+    updated.vbr = vbr.unwrap_or(current.vbr);
+    updated.fec = fec.unwrap_or(current.fec);
+
+    let on_off = |enabled: bool| if enabled { "on" } else { "off" };
+    let mut changes = Vec::new();
+    if updated.bitrate != current.bitrate {
+        changes.push(format!(
+            "bitrate {} -> {} bps",
+            current.bitrate, updated.bitrate
+        ));
+    }
+    if updated.complexity != current.complexity {
+        changes.push(format!(
+            "complexity {} -> {}",
+            current.complexity, updated.complexity
+        ));
+    }
+    if updated.application != current.application {
+        changes.push(format!(
+            "application {} -> {}",
+            application_name(current.application),
+            application_name(updated.application)
+        ));
+    }
+    if updated.vbr != current.vbr {
+        changes.push(format!(
+            "vbr {} -> {}",
+            on_off(current.vbr),
+            on_off(updated.vbr)
+        ));
+    }
+    if updated.fec != current.fec {
+        changes.push(format!(
+            "fec {} -> {}",
+            on_off(current.fec),
+            on_off(updated.fec)
+        ));
+    }
+    if changes.is_empty() {
+        ctx.say(format!(
+            "bitrate: {} bps\ncomplexity: {}\napplication: {}\nvbr: {}\nfec: {}\ndtx: {}\nframe duration: {}",
+            current.bitrate,
+            current.complexity,
+            application_name(current.application),
+            on_off(current.vbr),
+            on_off(current.fec),
+            on_off(current.dtx),
+            humantime::format_duration(current.frame_duration)
+        ))
+        .await?;
+        return Ok(());
+    }
+    receiver.set_encoder(updated)?;
+    tracing::info!(
+        "encoder changed by '{}': {}",
+        ctx.author().name,
+        changes.join(", ")
+    );
+    ctx.say(format!("changed {}", changes.join(", "))).await?;
+    Ok(())
+}
+
+/// Cuts a recording in the audio dir down to the part between two offsets.
+///
+/// `file` is a file name inside the audio dir, `start` and `end` are offsets from its beginning,
+/// e.g. `trim recording.ogg 1m 2m30s`. Durations are written like `90s`, `5m` or `2m30s`.
+#[poise::command(slash_command, prefix_command)]
+pub async fn trim(ctx: Context<'_>, file: String, start: String, end: String) -> Result<(), Error> {
+    tracing::info!("trimming '{}' from {} to {}", file, start, end);
+    check_recording_name(&file)?;
+    let start = humantime::parse_duration(&start)?;
+    let end = humantime::parse_duration(&end)?;
+    if start >= end {
+        return Err(anyhow!("start must be before end"));
+    }
+    ctx.say("trimming").await?;
+
+    let ogg_file = read_ogg_file(
+        file.clone().into(),
+        ctx.data().config.recording_key.as_ref(),
+    )
+    .await?;
+    let packets = decode::demux(&ogg_file)?;
+    // start offset of each packet, packets aren't necessarily all the same length
+    let offsets: Vec<Duration> = packets
+        .iter()
+        .scan(Duration::ZERO, |elapsed, packet| {
+            let offset = *elapsed;
+            *elapsed += encode::packet_duration(packet);
+            Some(offset)
+        })
+        .collect();
+    let total_duration = offsets.last().copied().unwrap_or_default()
+        + packets
+            .last()
+            .map_or(Duration::ZERO, |packet| encode::packet_duration(packet));
+    if end > total_duration {
+        return Err(anyhow!(
+            "end {} is past the end of the recording ({})",
+            humantime::format_duration(end),
+            humantime::format_duration(total_duration)
+        ));
+    }
+    let start_index = offsets.partition_point(|offset| *offset < start);
+    let end_index = offsets.partition_point(|offset| *offset < end);
+    let options = encode::StreamOptions::default();
+    tracing::info!(
+        "trim of '{}' produced stream serial {:#010x}",
+        file,
+        options.serial
+    );
+    let trimmed = encode::encode_with_options::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(
+        &packets[start_index..end_index],
+        &options,
+    )?;
+
+    let trimmed_name = format!(
+        "{}_trimmed.ogg",
+        Path::new(&file)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or("recording")
+    );
+    ctx.send(
+        CreateReply::default()
+            .content("trimmed")
+            .attachment(CreateAttachment::bytes(trimmed, trimmed_name)),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Only plain file names inside the audio dir, nothing that could point outside of it.
+fn check_recording_name(name: &str) -> anyhow::Result<()> {
+    if Path::new(name).file_name() != Some(name.as_ref()) {
+        return Err(anyhow!("'{}' is not a recording file name", name));
+    }
+    Ok(())
+}
+
+/// Renames a recording in the audio dir, optionally setting the title players show for it.
+///
+/// `file` and `new_name` are file names in the audio dir, `new_name` gets `file`'s extension if it
+/// doesn't have it. An existing file is never overwritten. `title` sets the TITLE tag of an ogg
+/// recording. The timestamp and chat sidecars are renamed along with it.
+#[poise::command(slash_command, prefix_command)]
+pub async fn tag(
+    ctx: Context<'_>,
+    file: String,
+    new_name: String,
+    title: Option<String>,
+) -> Result<(), Error> {
+    tracing::info!("renaming '{}' to '{}' titled {:?}", file, new_name, title);
+    check_recording_name(&file)?;
+    check_recording_name(&new_name)?;
+    let extension = Path::new(&file)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+    let new_name = match Path::new(&new_name).extension() {
+        Some(new_extension) if new_extension == extension => new_name,
+        _ if extension.is_empty() => new_name,
+        _ => format!("{}.{}", new_name, extension),
+    };
+    if new_name == file {
+        return Err(anyhow!("'{}' already has that name", file));
+    }
+    let audio_dir = audio_dir();
+    let new_path = audio_dir.join(&new_name);
+    if tokio::fs::try_exists(&new_path).await? {
+        return Err(anyhow!("'{}' already exists, pick another name", new_name));
+    }
+    let sidecar = |name: &str, sidecar_extension: &str| {
+        audio_dir.join(format!(
+            "{}.{}",
+            Path::new(name)
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or(name),
+            sidecar_extension
+        ))
+    };
+    let mut sidecars = Vec::new();
+    if extension != "csv" {
+        for (sidecar_extension, what) in [
+            ("csv", "timestamps"),
+            ("chat.csv", "chat log"),
+            ("right.ogg", "right channel"),
+        ] {
+            let old_sidecar = sidecar(&file, sidecar_extension);
+            if !tokio::fs::try_exists(&old_sidecar).await? {
+                continue;
+            }
+            let new_sidecar = sidecar(&new_name, sidecar_extension);
+            if tokio::fs::try_exists(&new_sidecar).await? {
+                return Err(anyhow!(
+                    "'{}' already exists, pick another name",
+                    new_sidecar.display()
+                ));
+            }
+            sidecars.push((old_sidecar, new_sidecar, what));
+        }
+    }
+
+    let mut reply = match &title {
+        Some(title) => {
+            if extension != "ogg" {
+                return Err(anyhow!("only ogg recordings have a title tag"));
+            }
+            let retitled = encode::retitle(
+                &read_ogg_file(
+                    file.clone().into(),
+                    ctx.data().config.recording_key.as_ref(),
+                )
+                .await?,
+                title,
+            )?;
+            write_ogg_to_disk_named(
+                &retitled,
+                new_name.clone().into(),
+                ctx.data().config.recording_key.as_ref(),
+            )
+            .await?;
+            tokio::fs::remove_file(audio_dir.join(&file)).await?;
+            format!("renamed '{}' to '{}' titled '{}'", file, new_name, title)
+        }
+        None => {
+            tokio::fs::rename(audio_dir.join(&file), &new_path)
+                .await
+                .map_err(|e| anyhow!("couldn't rename '{}': {}", file, e))?;
+            format!("renamed '{}' to '{}'", file, new_name)
+        }
+    };
+    for (old_sidecar, new_sidecar, what) in sidecars {
+        tokio::fs::rename(&old_sidecar, &new_sidecar).await?;
+        reply.push_str(&format!(", {} moved along", what));
+    }
+    ctx.say(reply).await?;
+    Ok(())
+}
+
+/// The last continuous thing a user said.
+#[poise::command(slash_command, prefix_command)]
+pub async fn lastclip(ctx: Context<'_>, user: poise::serenity_prelude::User) -> Result<(), Error> {
+    tracing::info!("clipping last utterance for user '{}'", user);
+    let receiver = ctx.data();
+    let user_id = UserId(user.id.get());
+    let display_name = user.global_name.as_deref().unwrap_or(&user.name);
+    let ogg_file = receiver
+        .tts
+        .per_user_sound_buffer
+        .get_last_utterance(user_id, &encode::user_comments(display_name, user_id.0))?;
+    ctx.send(
+        CreateReply::default()
+            .content(format!("last thing '{}' said", display_name))
+            .attachment(CreateAttachment::bytes(
+                ogg_file,
+                format!("{}_lastclip.ogg", user_label(display_name, user_id)),
+            )),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Every tracked user's buffer as one zip, so many users don't run into the attachment limit.
+#[poise::command(slash_command, prefix_command)]
+pub async fn export_users(ctx: Context<'_>, aligned: Option<bool>) -> Result<(), Error> {
+    tracing::info!("exporting all per user buffers, aligned {:?}", aligned);
+    ctx.say("exporting").await?;
+    let buffer = &ctx.data().tts.per_user_sound_buffer;
+    let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+    // ogg opus is already compressed
+    let options =
+        zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    let mut exported = 0;
+    let mut skipped = Vec::new();
+    let (speaking, silent): (Vec<_>, Vec<_>) = buffer
+        .tracked_users()
+        .into_iter()
+        .partition(|user_id| buffer.has_speech(*user_id));
+    skipped.extend(
+        silent
+            .into_iter()
+            .map(|user_id| cached_display_name(ctx, user_id)),
+    );
+    // resolved up front, the cache isn't reachable from the export queue
+    let names: HashMap<UserId, String> = speaking
+        .iter()
+        .map(|user_id| (*user_id, cached_display_name(ctx, *user_id)))
+        .collect();
+    let job_names = names.clone();
+    let job_receiver = ctx.data().clone();
+    let queued = ctx.data().export_queue.enqueue(move || {
+        let buffer = &job_receiver.tts.per_user_sound_buffer;
+        let comments = |user_id: UserId| {
+            let name = job_names.get(&user_id).map_or("", String::as_str);
+            encode::user_comments(name, user_id.0)
+        };
+        if aligned.unwrap_or(false) {
+            buffer.get_aligned_ogg_buffers(&speaking, comments)
+        } else {
+            // the user may have been evicted since listing
+            let mut buffers = buffer.get_all_ogg_buffers(comments)?;
+            Ok(speaking
+                .into_iter()
+                .filter_map(|user_id| Some((user_id, buffers.remove(&user_id)?)))
+                .collect())
+        }
+    });
+    say_queue_position(ctx, queued.ahead).await?;
+    let tracks: Vec<_> = queued.result().await??;
+    for (user_id, ogg_file) in tracks {
+        zip.start_file(
+            format!("{}.ogg", user_label(&names[&user_id], user_id)),
+            options,
+        )?;
+        zip.write_all(&ogg_file)?;
+        exported += 1;
+    }
+    if exported == 0 {
+        return Err(anyhow!("no users have buffered speech"));
+    }
+    let zip_file = zip.finish()?.into_inner();
+
+    let mut content = format!("exported {} users", exported);
+    if !skipped.is_empty() {
+        content.push_str(&format!(
+            ", skipped {} without speech: {}",
+            skipped.len(),
+            skipped.join(", ")
+        ));
+    }
+    ctx.send(
+        CreateReply::default()
+            .content(content)
+            .attachment(CreateAttachment::bytes(zip_file, "users.zip")),
+    )
+    .await?;
+    Ok(())
+}
+
+/// The per user buffers mixed with every speaker brought to the same loudness.
+///
+/// Meant for when one person's headset is much louder than another's laptop mic. Only reaches as
+/// far back as the per user buffers, not the whole lookback.
+#[poise::command(slash_command, prefix_command)]
+pub async fn export_leveled(ctx: Context<'_>) -> Result<(), Error> {
+    tracing::info!("exporting leveled mix");
+    ctx.say("exporting").await?;
+    let buffer = &ctx.data().tts.per_user_sound_buffer;
+    let speaking: Vec<_> = buffer
+        .tracked_users()
+        .into_iter()
+        .filter(|user_id| buffer.has_speech(*user_id))
+        .collect();
+    if speaking.is_empty() {
+        return Err(anyhow!("no users have buffered speech"));
+    }
+    let job_receiver = ctx.data().clone();
+    let queued = ctx.data().export_queue.enqueue(move || {
+        let tracks = job_receiver
+            .tts
+            .per_user_sound_buffer
+            .get_aligned_packets(&speaking);
+        let (packets, levels) = level::leveled_mix(&tracks, &job_receiver.encoder(), |user| {
+            job_receiver.panner.position(user)
+        })?;
+        let ogg_file = encode::encode::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(&packets)?;
+        let duration: Duration = packets
+            .iter()
+            .map(|packet| encode::packet_duration(packet))
+            .sum();
+        anyhow::Ok((ogg_file, duration, levels))
+    });
+    say_queue_position(ctx, queued.ahead).await?;
+    let (ogg_file, duration, levels) = queued.result().await??;
+    let mut content = format!(
+        "leveled mix of {} users, {}",
+        levels.len(),
+        humantime::format_duration(Duration::from_secs(duration.as_secs()))
+    );
+    for level in levels {
+        let display_name = cached_display_name(ctx, level.user);
+        match level.loudness {
+            Some(loudness) => content.push_str(&format!(
+                "\n{}: speech at {:.1} dBFS, {:+.1} dB",
+                display_name, loudness, level.gain_db
+            )),
+            None => content.push_str(&format!("\n{}: too quiet to measure", display_name)),
+        }
+    }
+    ctx.send(
+        CreateReply::default()
+            .content(content)
+            .attachment(CreateAttachment::bytes(ogg_file, "leveled.ogg")),
+    )
+    .await?;
+    Ok(())
+}
+
+/// The lookback, or its last `duration`, as a zip of standalone ogg files of `length` each.
+///
+/// For editors that handle many short files better than one long one. `length` defaults to 5m,
+/// the files play back to back without gaps.
+#[poise::command(slash_command, prefix_command)]
+pub async fn export_segments(
+    ctx: Context<'_>,
+    length: Option<String>,
+    duration: Option<String>,
+) -> Result<(), Error> {
+    const DEFAULT_SEGMENT_LENGTH: Duration = Duration::from_secs(5 * 60);
+    let length = match length {
+        Some(length) => humantime::parse_duration(&length)?,
+        None => DEFAULT_SEGMENT_LENGTH,
+    };
+    if length < PACKET_DURATION {
+        return Err(anyhow!("segments have to be at least one packet long"));
+    }
+    let duration = duration
+        .map(|duration| humantime::parse_duration(&duration))
+        .transpose()?;
+    tracing::info!("exporting {:?} in segments of {:?}", duration, length);
+    ctx.say("exporting").await?;
+    let receiver = ctx.data();
+    let duration = match max_dump_duration(ctx).await {
+        Some(max) if duration.unwrap_or_else(|| receiver.lookback.buffered_duration()) > max => {
+            ctx.say(format!(
+                "dumps are capped at {}, only exporting the most recent {}",
+                humantime::format_duration(max),
+                humantime::format_duration(max)
+            ))
+            .await?;
+            Some(max)
+        }
+        _ => duration,
+    };
+    let drain = DrainOptions {
+        duration,
+        ..Default::default()
+    };
+    let job_receiver = receiver.clone();
+    let queued = receiver
+        .export_queue
+        .enqueue(move || job_receiver.lookback.drain_segments(&drain, length));
+    say_queue_position(ctx, queued.ahead).await?;
+    let segments = queued.result().await??;
+
+    let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+    // ogg opus is already compressed
+    let options =
+        zip::write::SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    for (i, segment) in segments.iter().enumerate() {
+        zip.start_file(format!("segment_{:03}.ogg", i + 1), options)?;
+        zip.write_all(&segment.bytes)?;
+    }
+    let zip_file = zip.finish()?.into_inner();
+    let total: Duration = segments.iter().map(|segment| segment.duration).sum();
+    let content = format!(
+        "exported {} in {} segments of up to {}",
+        humantime::format_duration(Duration::from_secs(total.as_secs())),
+        segments.len(),
+        humantime::format_duration(length)
+    );
+    let reply = CreateReply::default()
+        .content(content.clone())
+        .attachment(CreateAttachment::bytes(zip_file.clone(), "segments.zip"));
+    match ctx.send(reply).await {
+        Ok(_) => Ok(()),
+        Err(e) if is_upload_too_large(&e) => {
+            let file_name =
+                write_ogg_to_disk(receiver.sink.as_ref(), &zip_file, total, "zip").await?;
+            let location = match &receiver.config.media_base_url {
+                Some(base_url) => format!("{}/{}", base_url, file_name),
+                None => format!(
+                    "'{}' on {} storage",
+                    file_name,
+                    receiver.config.storage.name()
+                ),
+            };
+            ctx.say(format!(
+                "{}, over this server's upload limit so stored as {}",
+                content, location
+            ))
+            .await?;
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Marks a span of the lookback to export once it's over.
+#[poise::command(
+    slash_command,
+    prefix_command,
+    subcommands("mark_start", "mark_stop"),
+    subcommand_required
+)]
+pub async fn mark(_ctx: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Starts a mark, ignored while one is already running.
+#[poise::command(slash_command, prefix_command, rename = "start")]
+pub async fn mark_start(ctx: Context<'_>) -> Result<(), Error> {
+    let started = {
+        let mut mark = ctx.data().mark.lock().expect("mark lock panicked");
+        match *mark {
+            Some(started) => Err(started),
+            None => {
+                *mark = Some(SystemTime::now());
+                Ok(())
+            }
+        }
+    };
+    match started {
+        Ok(()) => {
+            tracing::info!("mark started by '{}'", ctx.author().name);
+            ctx.say("marking, /mark stop exports everything from now")
+                .await?;
+        }
+        Err(started) => {
+            let elapsed = started.elapsed().unwrap_or_default();
+            ctx.say(format!(
+                "already marking since {} ago, stop that one first",
+                humantime::format_duration(Duration::from_secs(elapsed.as_secs()))
+            ))
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Ends the running mark and exports the marked span.
+#[poise::command(slash_command, prefix_command, rename = "stop")]
+pub async fn mark_stop(ctx: Context<'_>) -> Result<(), Error> {
+    let receiver = ctx.data();
+    let started = receiver
+        .mark
+        .lock()
+        .expect("mark lock panicked")
+        .take()
+        .ok_or_else(|| anyhow!("no mark running, start one with /mark start"))?;
+    tracing::info!("mark stopped by '{}'", ctx.author().name);
+    if started.elapsed().unwrap_or_default() > receiver.lookback.buffered_duration() {
+        ctx.say("the mark started before the oldest buffered audio, its beginning is lost")
+            .await?;
+    }
+    run_export(
+        ctx,
+        ExportRequest {
+            since: Some(started),
+            ..Default::default()
+        },
+    )
+    .await
+}
+
+/// Cues the current moment, dumps covering it get a chapter with the label there.
+#[poise::command(slash_command, prefix_command)]
+pub async fn cue(ctx: Context<'_>, #[rest] label: String) -> Result<(), Error> {
+    let label = label.trim().trim_matches('"').to_string();
+    if label.is_empty() {
+        return Err(anyhow!("a cue needs a label"));
+    }
+    tracing::info!("cue '{}' by '{}'", label, ctx.author().name);
+    let cues = ctx.data().add_cue(label.clone());
+    ctx.say(format!(
+        "cued '{}', {} cue{} in the lookback",
+        label,
+        cues,
+        if cues == 1 { "" } else { "s" }
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Wipes all buffered audio from memory, files on disk are kept.
+// slash only, so it always shows its permission requirement and can't come from a stray message
+#[poise::command(
+    slash_command,
+    default_member_permissions = "ADMINISTRATOR",
+    required_permissions = "ADMINISTRATOR"
+)]
+pub async fn reset(ctx: Context<'_>) -> Result<(), Error> {
+    tracing::info!("reset by '{}'", ctx.author().name);
+    let cleared = ctx.data().clear_all();
+    ctx.say(format!(
+        "cleared {} of lookback, {} user buffers, {} unattributed speakers and {} cached dumps",
+        humantime::format_duration(Duration::from_secs(cleared.lookback.as_secs())),
+        cleared.users,
+        cleared.held_ssrcs,
+        cleared.dumps
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Lists the commands, or explains one of them in detail.
+#[poise::command(slash_command, prefix_command)]
+pub async fn help(
+    ctx: Context<'_>,
+    #[autocomplete = "poise::builtins::autocomplete_command"] command: Option<String>,
+) -> Result<(), Error> {
+    // only registered commands are listed, so clone and ctts are missing without TTS_HOST
+    let config = poise::builtins::HelpConfiguration {
+        extra_text_at_bottom: "help <command> explains a command and its options",
+        show_subcommands: true,
+        ..Default::default()
+    };
+    poise::builtins::help(ctx, command.as_deref(), config).await?;
+    Ok(())
+}
+
+/// Joins the user's voice channel and follows them whenever they move.
+#[poise::command(slash_command, prefix_command)]
+pub async fn follow(ctx: Context<'_>, user: poise::serenity_prelude::User) -> Result<(), Error> {
+    let receiver = ctx.data();
+    tracing::info!("following '{}'", user.name);
+    *receiver.following.lock().expect("following lock panicked") = Some(UserId(user.id.get()));
+    match voice_channel_of(ctx.serenity_context(), receiver.guild_id, user.id) {
+        Some(channel) => {
+            move_to_channel(ctx.serenity_context(), receiver, channel).await?;
+            ctx.say(format!("following {} in {}", user.name, channel.mention()))
+                .await?;
+        }
+        None => {
+            ctx.say(format!(
+                "{} is offline or not in a voice channel, I'll join them when they are",
+                user.name
+            ))
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Stops following, the bot stays in its current voice channel.
+#[poise::command(slash_command, prefix_command)]
+pub async fn unfollow(ctx: Context<'_>) -> Result<(), Error> {
+    let receiver = ctx.data();
+    let followed = receiver
+        .following
+        .lock()
+        .expect("following lock panicked")
+        .take();
+    match followed {
+        Some(user) => {
+            tracing::info!("stopped following uid {}", user);
+            ctx.say(format!(
+                "stopped following {}",
+                cached_display_name(ctx, user)
+            ))
+            .await?;
+        }
+        None => {
+            ctx.say("not following anyone").await?;
+        }
+    }
+    Ok(())
+}
+
+/// How much audio is buffered right now, cheap enough to check before every dump.
+///
+/// `users` also lists each user's clone buffer and how much of it is speech.
+#[poise::command(slash_command, prefix_command)]
+pub async fn duration(ctx: Context<'_>, users: Option<bool>) -> Result<(), Error> {
+    let receiver = ctx.data();
+    let format = |duration: Duration| {
+        humantime::format_duration(Duration::from_secs(duration.as_secs())).to_string()
+    };
+    let buffered = receiver.lookback.buffered_duration();
+    let mut reply = format!(
+        "lookback: {} of {}",
+        format(buffered),
+        format(receiver.config.lookback_duration)
+    );
+    if let Some(max) = max_dump_duration(ctx).await.filter(|max| *max < buffered) {
+        reply.push_str(&format!(", you can dump the most recent {}", format(max)));
+    }
+    if users.unwrap_or(false) {
+        let buffer = &receiver.tts.per_user_sound_buffer;
+        let mut durations: Vec<_> = buffer
+            .tracked_users()
+            .into_iter()
+            .filter_map(|user| Some((user, buffer.buffered_duration(user)?)))
+            .collect();
+        durations.sort_by_key(|(_, (_, speech))| std::cmp::Reverse(*speech));
+        if durations.is_empty() {
+            reply.push_str("\nno user buffers");
+        }
+        for (user, (buffered, speech)) in durations {
+            reply.push_str(&format!(
+                "\n{}: {}, {} of it speech",
+                cached_display_name(ctx, user),
+                format(buffered),
+                format(speech)
+            ));
+        }
+    }
+    ctx.say(reply).await?;
+    Ok(())
+}
+
+/// Waveform image of the lookback, to find the interesting part before dumping.
+///
+/// `duration` limits it to the most recent part, e.g. `5m`. Durations are written like `90s`,
+/// `5m` or `2m30s`.
+#[poise::command(slash_command, prefix_command)]
+pub async fn waveform(ctx: Context<'_>, duration: Option<String>) -> Result<(), Error> {
+    let receiver = ctx.data();
+    let duration = duration
+        .map(|duration| humantime::parse_duration(&duration))
+        .transpose()?;
+    // decoding costs about as much as a wav export, so the same cap applies
+    let duration = match (duration, max_dump_duration(ctx).await) {
+        (Some(duration), Some(max)) => Some(duration.min(max)),
+        (None, max) => max,
+        (duration, None) => duration,
+    };
+    let job_receiver = receiver.clone();
+    let queued = receiver.export_queue.enqueue(move || {
+        let dump = job_receiver.lookback.drain_buffer(
+            &DrainOptions {
+                duration,
+                ..Default::default()
+            },
+            &Default::default(),
+        )?;
+        let pcm = decode::decode_opus(&decode::demux(&dump.bytes)?)?;
+        let image = waveform::render_png(
+            &pcm,
+            job_receiver.config.waveform_width,
+            job_receiver.config.waveform_height,
+        )?;
+        anyhow::Ok((dump, image))
+    });
+    say_queue_position(ctx, queued.ahead).await?;
+    let (dump, image) = queued.result().await??;
+    ctx.send(
+        CreateReply::default()
+            .content(format!(
+                "last {}",
+                humantime::format_duration(Duration::from_secs(dump.duration.as_secs()))
+            ))
+            .attachment(CreateAttachment::bytes(image, "waveform.png")),
+    )
+    .await?;
+    Ok(())
+}
+
+/// How much `export trim` would remove, or sets the longest silence it keeps.
+///
+/// Reports the length of the lookback, or its most recent `duration`, before and after trimming
+/// and how much silence came off the ends and out of the gaps, without encoding anything.
+/// `max_gap` first sets the longest silence a trimmed export keeps inside it, e.g. `2s`, or `off`
+/// to only trim the ends.
+#[poise::command(slash_command, prefix_command)]
+pub async fn trimpreview(
+    ctx: Context<'_>,
+    duration: Option<String>,
+    max_gap: Option<String>,
+) -> Result<(), Error> {
+    let lookback = &ctx.data().lookback;
+    let duration = duration
+        .map(|duration| humantime::parse_duration(&duration))
+        .transpose()?;
+    let mut reply = String::new();
+    if let Some(max_gap) = max_gap {
+        let max_gap = match max_gap.as_str() {
+            "off" => None,
+            max_gap => Some(humantime::parse_duration(max_gap)?),
+        };
+        tracing::info!("trim max gap set to {:?}", max_gap);
+        lookback.set_trim_max_gap(max_gap);
+    }
+    let format = |duration: Duration| {
+        humantime::format_duration(Duration::from_millis(duration.as_millis() as u64)).to_string()
+    };
+    match lookback.trim_max_gap() {
+        Some(max_gap) => reply.push_str(&format!("max gap: {}\n", format(max_gap))),
+        None => reply.push_str("max gap: off, only the ends are trimmed\n"),
+    }
+    let preview = lookback.trim_preview(duration);
+    reply.push_str(&format!(
+        "before: {}\nafter: {}\nremoved: {} from the ends, {} from gaps",
+        format(preview.before),
+        format(preview.after),
+        format(preview.edges),
+        format(preview.gaps)
+    ));
+    ctx.say(reply).await?;
+    Ok(())
+}
+
+/// Frees per user buffers that only contain silence.
+// slash only like reset, it throws buffers away
+#[poise::command(slash_command)]
+pub async fn compact(ctx: Context<'_>) -> Result<(), Error> {
+    let receiver = ctx.data();
+    let reclaimed = receiver
+        .tts
+        .per_user_sound_buffer
+        .compact(receiver.config.compact_min_speech_ratio);
+    tracing::info!("compact reclaimed {} per user buffers", reclaimed);
+    ctx.say(format!("reclaimed {} silent per user buffers", reclaimed))
+        .await?;
+    Ok(())
+}
+
+/// Wraps in memory audio so songbird can probe and play it.
+fn bytes_to_input(data: impl AsRef<[u8]> + Send + Sync + 'static, hint: Hint) -> Input {
+    let audio_stream: AudioStream<Box<dyn MediaSource>> = AudioStream {
+        input: Box::new(io::Cursor::new(data)),
+        hint: Some(hint),
+    };
+    Input::Live(LiveInput::Raw(audio_stream), None)
+}
+
+fn ogg_bytes_to_input(ogg_data: Vec<u8>) -> Input {
+    let mut hint = Hint::default();
+    hint.mime_type("audio/ogg").with_extension("ogg");
+    bytes_to_input(ogg_data, hint)
+}
+
+enum Playback {
+    Pending(Vec<RawAudioPacket>),
+    Injected(InjectionHandle),
+    Done,
+}
+
+/// Mixes the audio of a track into the lookback once the track has actually started, and drops
+/// what's left of it if the track is stopped or fails before the end.
+struct PlaybackRecorder {
+    receiver: Arc<Receiver>,
+    playback: Mutex<Playback>,
+}
+
+impl PlaybackRecorder {
+    fn start(&self) {
+        let mut playback = self.playback.lock().expect("playback lock panicked");
+        if let Playback::Pending(packets) = std::mem::replace(&mut *playback, Playback::Done) {
+            *playback = Playback::Injected(self.receiver.lookback.inject(packets));
+        }
+    }
+
+    fn stop(&self) {
+        let mut playback = self.playback.lock().expect("playback lock panicked");
+        if let Playback::Injected(injection) = std::mem::replace(&mut *playback, Playback::Done) {
+            injection.cancel();
+        }
+    }
+}
+
+#[async_trait]
+impl VoiceEventHandler for PlaybackRecorder {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        let EventContext::Track([(state, _), ..]) = ctx else {
+            return None;
+        };
+        match state.playing {
+            PlayMode::Stop | PlayMode::Errored(_) => self.stop(),
+            // ended on its own, the injection runs out by itself
+            PlayMode::End => {}
+            // became playable, later readies after a seek mustn't inject it again
+            _ => {
+                self.start();
+                return Some(Event::Cancel);
+            }
+        }
+        None
+    }
+}
+
+/// Records `packets`, the decoded audio of `track`, into the lookback for as long as the track
+/// plays.
+async fn record_playback(
+    receiver: &Arc<Receiver>,
+    track: &TrackHandle,
+    packets: Vec<RawAudioPacket>,
+) {
+    let recorder = Arc::new(PlaybackRecorder {
+        receiver: receiver.clone(),
+        playback: Mutex::new(Playback::Pending(packets)),
+    });
+    for event in [TrackEvent::Playable, TrackEvent::End] {
+        let handler = ArcEventHandlerInvoker {
+            delegate: recorder.clone(),
+        };
+        if let Err(e) = track.add_event(Event::Track(event), handler) {
+            tracing::warn!("not recording playback, the track is already gone {:?}", e);
+            return;
+        }
+    }
+    // it may have become playable before the event was registered
+    if track
+        .get_info()
+        .await
+        .is_ok_and(|state| state.ready == ReadyState::Playable && !state.playing.is_done())
+    {
+        recorder.start();
+    }
+}
+
+/// Plays a past window of the lookback into the voice channel, e.g. from 10m ago to 8m ago.
+///
+/// `from` and `to` are how long ago the window starts and ends, `to` defaults to now. Durations
+/// are written like `90s`, `5m` or `2m30s`. `gain` in dB boosts or cuts only what's played on top
+/// of the volume, as far as it goes without clipping, the recording stays as it is.
+#[poise::command(slash_command, prefix_command)]
+pub async fn replay_range(
+    ctx: Context<'_>,
+    from: String,
+    to: Option<String>,
+    gain: Option<f32>,
+) -> Result<(), Error> {
+    const MAX_REPLAY_GAIN_DB: f32 = 30.0;
+    let receiver = ctx.data();
+    if gain.is_some_and(|gain| !gain.is_finite()) {
+        return Err(anyhow!("gain must be a number of dB"));
+    }
+    let from = humantime::parse_duration(&from)?;
+    let to = match to {
+        Some(to) => humantime::parse_duration(&to)?,
+        None => Duration::ZERO,
+    };
+    if from <= to {
+        return Err(anyhow!("from must be further in the past than to"));
+    }
+    let from = from.min(receiver.lookback.buffered_duration());
+    if from <= to {
+        return Err(anyhow!("nothing buffered in that window"));
+    }
+    tracing::info!("replaying {:?} ago to {:?} ago", from, to);
+
+    let manager = songbird::get(ctx.serenity_context())
+        .await
+        .ok_or_else(|| anyhow!("Songbird Voice client placed in at initialisation."))?;
+    let handler_lock = manager
+        .get(receiver.guild_id)
+        .ok_or_else(|| anyhow!("not in a voice channel"))?;
+
+    let previous = receiver.replay.lock().expect("replay lock panicked").take();
+    if let Some(previous) = previous {
+        let still_playing = previous
+            .get_info()
+            .await
+            .map(|state| !state.playing.is_done())
+            .unwrap_or(false);
+        if still_playing {
+            let _ = previous.stop();
+            ctx.say("stopped the previous replay").await?;
+        }
+    }
+
+    let ogg_file = receiver
+        .lookback
+        .drain_range(from, to, &Default::default())?;
+    // the replay's own gain goes on top of the master volume
+    let volume = receiver.volume_db();
+    let gain = (gain.is_some() || volume != 0.0).then(|| gain.unwrap_or(0.0) + volume);
+    let keep_played = receiver.config.record_playback;
+    let (input, applied, played) = match gain {
+        None if !keep_played => (ogg_bytes_to_input(ogg_file), None, None),
+        gain => {
+            let queued = receiver.export_queue.enqueue(move || {
+                let pcm = decode::decode_opus(&decode::demux(&ogg_file)?)?;
+                // no further than brings the loudest sample to -1 dBFS
+                let applied = gain.map(|gain| {
+                    let applied = gain
+                        .clamp(-MAX_REPLAY_GAIN_DB, MAX_REPLAY_GAIN_DB)
+                        .min(normalize_gain_db(&pcm));
+                    (gain, applied)
+                });
+                let pcm = match applied {
+                    Some((_, applied)) => apply_gain(pcm, applied),
+                    None => pcm,
+                };
+                let wav = encode::encode_wav::<AUDIO_CHANNELS>(&pcm, AUDIO_FREQUENCY);
+                let played = keep_played.then(|| to_raw_audio_packets(&pcm, OversizedAudio::Split));
+                anyhow::Ok((wav, applied, played))
+            });
+            say_queue_position(ctx, queued.ahead).await?;
+            let (wav, applied, played) = queued.result().await??;
+            let mut hint = Hint::default();
+            hint.mime_type("audio/wav").with_extension("wav");
+            (bytes_to_input(wav, hint), applied, played)
+        }
+    };
+    let track = handler_lock.lock().await.play_input(input);
+    if let Some(packets) = played {
+        record_playback(receiver, &track, packets).await;
+    }
+    *receiver.replay.lock().expect("replay lock panicked") = Some(track);
+    let mut reply = format!(
+        "replaying from {} ago to {} ago",
+        humantime::format_duration(from),
+        humantime::format_duration(to)
+    );
+    match applied {
+        Some((gain, applied)) if applied < gain => reply.push_str(&format!(
+            " at {:+.1} dB, held back from {:+.1} dB",
+            applied, gain
+        )),
+        Some((_, applied)) => reply.push_str(&format!(" at {:+.1} dB", applied)),
+        None => {}
+    }
+    ctx.say(reply).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_listed_format_parses_back() {
+        let list = format_list();
+        let listed: Vec<_> = list
+            .lines()
+            .filter_map(|line| line.strip_prefix("- "))
+            .map(|line| line.split([' ', ':']).next().unwrap())
+            .collect();
+        assert_eq!(listed.len(), ExportFormat::available().count());
+        for (name, format) in listed.iter().zip(ExportFormat::available()) {
+            assert_eq!(ExportFormat::from_name(name), Some(format), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use songbird::model::id::UserId;
+
+use crate::receiver::{AUDIO_FREQUENCY, lock_recovering, Tick};
+
+/// RTP timestamp samples a client should advance per 20ms tick.
+const SAMPLES_PER_TICK: u64 = AUDIO_FREQUENCY as u64 / 50;
+
+/// Measures how far each client's RTP clock has drifted from the tick clock, and how far the tick
+/// clock has drifted from the wall clock, logging both every interval. Clients with a fast or
+/// slow sound card send slightly more or fewer than 50 packets a second, which the driver's jitter
+/// buffer absorbs by stretching silence or dropping packets, so this only measures and never
+/// corrects.
+pub struct DriftMonitor {
+    interval: Duration,
+    started_at: Instant,
+    ticks: AtomicU64,
+    last_report: Mutex<Instant>,
+    clocks: DashMap<u32, SsrcClock>,
+}
+
+struct SsrcClock {
+    first_tick: u64,
+    last_timestamp: u32,
+    /// RTP samples since `first_tick`, unwrapped.
+    elapsed_samples: u64,
+}
+
+impl SsrcClock {
+    /// RTP samples the client is ahead of the tick clock at `tick_index`, negative when behind,
+    /// and the samples it should have sent by then. `None` until a tick has passed.
+    fn drift(&self, tick_index: u64) -> Option<(i64, u64)> {
+        let expected_samples = (tick_index - self.first_tick) * SAMPLES_PER_TICK;
+        if expected_samples == 0 {
+            return None;
+        }
+        Some((
+            self.elapsed_samples as i64 - expected_samples as i64,
+            expected_samples,
+        ))
+    }
+}
+
+impl DriftMonitor {
+    pub fn new(interval: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            interval,
+            started_at: now,
+            ticks: Default::default(),
+            last_report: now.into(),
+            clocks: Default::default(),
+        }
+    }
+
+    pub fn on_tick(&self, tick: &Tick, ssrc_to_user: &DashMap<u32, UserId>) {
+        let tick_index = self.ticks.fetch_add(1, Ordering::Relaxed);
+        for (ssrc, timestamp) in &tick.rtp_timestamps {
+            self.clocks
+                .entry(*ssrc)
+                .and_modify(|clock| {
+                    clock.elapsed_samples += timestamp.wrapping_sub(clock.last_timestamp) as u64;
+                    clock.last_timestamp = *timestamp;
+                })
+                .or_insert(SsrcClock {
+                    first_tick: tick_index,
+                    last_timestamp: *timestamp,
+                    elapsed_samples: 0,
+                });
+        }
+
+        {
+            let mut last_report = lock_recovering(&self.last_report, "drift report");
+            if last_report.elapsed() < self.interval {
+                return;
+            }
+            *last_report = Instant::now();
+        }
+        self.report(tick_index, ssrc_to_user);
+    }
+
+    /// SSRCs are only valid for one voice session.
+    pub fn reset(&self) {
+        self.clocks.clear();
+    }
+
+    fn report(&self, tick_index: u64, ssrc_to_user: &DashMap<u32, UserId>) {
+        let tick_elapsed = Duration::from_millis(20 * tick_index);
+        let wall_elapsed = self.started_at.elapsed();
+        tracing::info!(
+            "tick clock drift {:+.1}ms over {}",
+            (tick_elapsed.as_secs_f64() - wall_elapsed.as_secs_f64()) * 1000.0,
+            humantime::format_duration(Duration::from_secs(wall_elapsed.as_secs()))
+        );
+        for clock in self.clocks.iter() {
+            let Some((drift_samples, expected_samples)) = clock.drift(tick_index) else {
+                continue;
+            };
+            let user = ssrc_to_user.get(clock.key()).map(|user| *user);
+            tracing::info!(
+                "ssrc {} user {:?} drift {:+.1}ms ({:+.0} ppm)",
+                clock.key(),
+                user,
+                drift_samples as f64 * 1000.0 / AUDIO_FREQUENCY as f64,
+                drift_samples as f64 * 1e6 / expected_samples as f64
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `ticks` ticks of one client whose RTP clock advances `samples_per_tick` a tick, starting
+    /// just short of the timestamp wrapping.
+    fn monitor_ticks(ticks: u64, samples_per_tick: u32) -> DriftMonitor {
+        let monitor = DriftMonitor::new(Duration::from_secs(3600));
+        let ssrc_to_user = DashMap::new();
+        let mut timestamp = u32::MAX - 1000;
+        for _ in 0..=ticks {
+            monitor.on_tick(
+                &Tick {
+                    rtp_timestamps: vec![(7, timestamp)],
+                    ..Default::default()
+                },
+                &ssrc_to_user,
+            );
+            timestamp = timestamp.wrapping_add(samples_per_tick);
+        }
+        monitor
+    }
+
+    #[test]
+    fn measures_a_fast_client() {
+        let monitor = monitor_ticks(500, SAMPLES_PER_TICK as u32 + 1);
+        let drift = monitor.clocks.get(&7).unwrap().drift(500);
+        assert_eq!(drift, Some((500, 500 * SAMPLES_PER_TICK)));
+    }
+
+    #[test]
+    fn measures_a_slow_client() {
+        let monitor = monitor_ticks(500, SAMPLES_PER_TICK as u32 - 2);
+        let drift = monitor.clocks.get(&7).unwrap().drift(500);
+        assert_eq!(drift, Some((-1000, 500 * SAMPLES_PER_TICK)));
+    }
+
+    #[test]
+    fn no_drift_before_a_tick_has_passed() {
+        let monitor = monitor_ticks(0, SAMPLES_PER_TICK as u32);
+        assert_eq!(monitor.clocks.get(&7).unwrap().drift(0), None);
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use bytes::Bytes;
+
+use crate::lookback::DumpResult;
+
+/// A finished export, kept so `redump` can send it again without draining and encoding twice.
+#[derive(Clone)]
+pub struct CachedDump {
+    pub id: u64,
+    pub extension: &'static str,
+    pub bytes: Bytes,
+    pub timestamps: Option<Bytes>,
+    pub chat: Option<Bytes>,
+    pub right_channel: Option<Bytes>,
+}
+
+impl CachedDump {
+    fn size(&self) -> usize {
+        self.bytes.len()
+            + self.timestamps.as_ref().map_or(0, Bytes::len)
+            + self.chat.as_ref().map_or(0, Bytes::len)
+            + self.right_channel.as_ref().map_or(0, Bytes::len)
+    }
+}
+
+/// The most recent exports, oldest evicted first once their total size passes `DUMP_CACHE_MB`.
+pub struct DumpCache {
+    max_bytes: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    dumps: VecDeque<CachedDump>,
+    bytes: usize,
+    next_id: u64,
+}
+
+impl DumpCache {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            inner: Mutex::new(Inner {
+                next_id: 1,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Returns the id to `redump` it with, `None` if it's too big to ever fit or caching is off.
+    pub fn insert(&self, dump: &DumpResult, extension: &'static str) -> Option<u64> {
+        let mut inner = self.inner.lock().expect("dump cache lock panicked");
+        let cached = CachedDump {
+            id: inner.next_id,
+            extension,
+            bytes: Bytes::copy_from_slice(&dump.bytes),
+            timestamps: dump.timestamps.clone().map(Bytes::from),
+            chat: dump.chat.clone().map(Bytes::from),
+            right_channel: dump.right_channel.as_deref().map(Bytes::copy_from_slice),
+        };
+        if cached.size() > self.max_bytes {
+            return None;
+        }
+        inner.next_id += 1;
+        while inner.bytes + cached.size() > self.max_bytes {
+            let Some(evicted) = inner.dumps.pop_front() else {
+                break;
+            };
+            inner.bytes -= evicted.size();
+        }
+        inner.bytes += cached.size();
+        let id = cached.id;
+        inner.dumps.push_back(cached);
+        Some(id)
+    }
+
+    pub fn get(&self, id: u64) -> Option<CachedDump> {
+        let inner = self.inner.lock().expect("dump cache lock panicked");
+        inner.dumps.iter().find(|dump| dump.id == id).cloned()
+    }
+
+    /// Ids still cached, oldest first.
+    pub fn ids(&self) -> Vec<u64> {
+        let inner = self.inner.lock().expect("dump cache lock panicked");
+        inner.dumps.iter().map(|dump| dump.id).collect()
+    }
+
+    /// Drops every cached dump, returning how many there were. Ids keep counting up so an old id
+    /// never refers to a different dump.
+    pub fn clear(&self) -> usize {
+        let mut inner = self.inner.lock().expect("dump cache lock panicked");
+        let cleared = inner.dumps.len();
+        inner.dumps = Default::default();
+        inner.bytes = 0;
+        cleared
+    }
+}
@@ -1,52 +1,261 @@
+use std::io::Cursor;
 use std::process;
+use std::time::Duration;
 
-use byteorder::{ByteOrder, LittleEndian};
-use ogg::PacketWriter;
+use anyhow::anyhow;
+use audiopus::coder::Encoder;
+use audiopus::packet::Packet;
+use audiopus::SampleRate;
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use ogg::{PacketReader, PacketWriter};
 use rand::Rng;
 
+use crate::receiver::MAX_OPUS_PACKET;
+
 //--- Code ---------------------------------------------------------------------
 
 const VER: &str = env!("CARGO_PKG_VERSION");
 
-const fn to_samples<const S_PS: u32>(ms: u32) -> usize {
-    ((S_PS * ms) / 1000) as usize
+/// ogg opus granule positions always count 48kHz samples, whatever the input rate was
+const OGG_OPUS_SPS: u32 = 48000;
+
+/// Samples per channel in an opus packet at 48kHz, read from its TOC byte so packets of any
+/// frame duration can be mixed in one stream.
+pub(crate) fn packet_samples(packet: &[u8]) -> usize {
+    Packet::try_from(packet)
+        .and_then(|packet| audiopus::packet::nb_samples(packet, SampleRate::Hz48000))
+        .unwrap_or_else(|_| {
+            tracing::warn!("unreadable opus packet, assuming 20ms");
+            (OGG_OPUS_SPS / 50) as usize
+        })
 }
 
-pub fn encode<const S_PS: u32, const NUM_CHANNELS: u8>(
-    packets: &[impl AsRef<[u8]>],
-) -> anyhow::Result<Vec<u8>> {
-    //NOTE: In the future the S_PS const generic will let us use const on a lot
-    // of things, until then we need to use variables
+/// Playback duration of an encoded opus packet.
+pub fn packet_duration(packet: &[u8]) -> Duration {
+    Duration::from_micros(packet_samples(packet) as u64 * 1_000_000 / OGG_OPUS_SPS as u64)
+}
 
-    // This should have a bitrate of 24 Kb/s, exactly what IBM recommends
+/// Longest opus packet allowed, 120ms.
+const MAX_PACKET_SAMPLES: usize = OGG_OPUS_SPS as usize * 120 / 1000;
 
-    // More frame time, slightly less overhead more problematic packet loses,
-    // a frame time of 20ms is considered good enough for most applications
+/// Shrinks runs of the silence packet for storage. The first silent frame of a run is kept so the
+/// decoder settles on silence, the rest of the run is packed into code 3 packets (RFC 6716 3.2.5)
+/// of up to 120ms whose frames are all zero length, which decoders fill in by concealment. The
+/// duration of every packet is unchanged, so the timeline and granule positions stay exact, but a
+/// silent minute takes about 1.5KB instead of 12KB.
+pub struct SilencePacker {
+    silence: bytes::Bytes,
+    /// frames in one silence packet.
+    silence_frames: usize,
+    /// frames one packed packet may hold, 0 if the silence packet can't be packed.
+    max_frames: usize,
+    in_run: bool,
+    /// silent frames of the current run not emitted yet.
+    held: usize,
+}
 
-    // Data
-    const FRAME_TIME_MS: u32 = 20;
-    const OGG_OPUS_SPS: u32 = 48000;
+impl SilencePacker {
+    pub fn new(silence: bytes::Bytes) -> Self {
+        // every frame of a packet shares the TOC's config, so the frame length comes from it
+        let silence_frames = Packet::try_from(&silence[..])
+            .and_then(audiopus::packet::nb_frames)
+            .unwrap_or(0);
+        let max_frames = match silence_frames {
+            0 => 0,
+            frames => MAX_PACKET_SAMPLES / (packet_samples(&silence) / frames),
+        };
+        Self {
+            silence,
+            silence_frames,
+            max_frames,
+            in_run: false,
+            held: 0,
+        }
+    }
 
-    let frame_samples: usize = to_samples::<S_PS>(FRAME_TIME_MS);
+    /// Packs a whole stream's packets at once.
+    pub fn pack(silence: bytes::Bytes, packets: &[bytes::Bytes]) -> Vec<bytes::Bytes> {
+        let mut packer = Self::new(silence);
+        let mut packed = Vec::new();
+        for packet in packets {
+            packer.push(packet.clone(), &mut packed);
+        }
+        packer.finish(&mut packed);
+        packed
+    }
 
-    // Generate the serial which is nothing but a value to identify a stream, we
-    // will also use the process id so that two programs don't use
-    // the same serial even if getting one at the same time
-    let mut rnd = rand::thread_rng();
-    let serial = rnd.gen::<u32>() ^ process::id();
-    let mut buffer: Vec<u8> = Vec::new();
+    pub fn push(&mut self, packet: bytes::Bytes, out: &mut Vec<bytes::Bytes>) {
+        if self.max_frames == 0 || packet != self.silence {
+            self.finish(out);
+            out.push(packet);
+            return;
+        }
+        if !self.in_run {
+            self.in_run = true;
+            out.push(packet);
+            return;
+        }
+        if self.held + self.silence_frames > self.max_frames {
+            self.flush_held(out);
+        }
+        self.held += self.silence_frames;
+    }
 
-    let mut packet_writer = PacketWriter::new(&mut buffer);
+    /// Whether `packet` is `silence`, or a run of it packed by a packer for the same silence.
+    pub fn is_silence(silence: &[u8], packet: &[u8]) -> bool {
+        packet == silence
+            || matches!((silence.first(), packet), (Some(toc), [packed_toc, _]) if *packed_toc == toc | 0b11)
+    }
+
+    /// Emits whatever of the current run is still held.
+    pub fn finish(&mut self, out: &mut Vec<bytes::Bytes>) {
+        self.flush_held(out);
+        self.in_run = false;
+    }
+
+    fn flush_held(&mut self, out: &mut Vec<bytes::Bytes>) {
+        if self.held == 0 {
+            return;
+        }
+        // same config as the silence frame, code 3, then a CBR frame count with no padding. With
+        // no bytes left over every frame is zero length.
+        let toc = self.silence[0] | 0b11;
+        out.push(bytes::Bytes::copy_from_slice(&[toc, self.held as u8]));
+        self.held = 0;
+    }
+}
+
+/// Averages interleaved stereo pcm into one channel.
+pub fn downmix_to_mono(pcm: &[i16]) -> Vec<i16> {
+    pcm.chunks_exact(2)
+        .map(|frame| ((frame[0] as i32 + frame[1] as i32) / 2) as i16)
+        .collect()
+}
 
-    let calc_samples = |counter: u32| -> usize { (counter as usize) * frame_samples };
+/// The left and right channels of interleaved stereo pcm as two mono signals.
+pub fn split_channels(pcm: &[i16]) -> (Vec<i16>, Vec<i16>) {
+    pcm.chunks_exact(2)
+        .map(|frame| (frame[0], frame[1]))
+        .unzip()
+}
 
-    const fn granule<const S_PS: u32>(val: usize) -> u64 {
-        const fn calc_sr_u64(val: u64, org_sr: u32, dest_sr: u32) -> u64 {
-            (val * dest_sr as u64) / (org_sr as u64)
+/// Mono opus packets of `pcm`, which was decoded from `packets`. Each source packet is encoded on
+/// its own in 20ms frames, so it ends at the same granule position as before and timestamp
+/// sidecars still line up. `encoder` has to be a mono encoder.
+pub fn encode_mono_like(
+    packets: &[bytes::Bytes],
+    pcm: &[i16],
+    encoder: &Encoder,
+) -> anyhow::Result<Vec<bytes::Bytes>> {
+    const FRAME_SAMPLES: usize = OGG_OPUS_SPS as usize / 50;
+    let mut output_scratch_space = [0; MAX_OPUS_PACKET];
+    let mut encoded = Vec::with_capacity(packets.len());
+    let mut offset = 0;
+    for packet in packets {
+        let end = (offset + packet_samples(packet)).min(pcm.len());
+        for frame in pcm[offset..end].chunks(FRAME_SAMPLES) {
+            let written_size = encoder.encode(frame, &mut output_scratch_space)?;
+            encoded.push(bytes::Bytes::copy_from_slice(
+                &output_scratch_space[..written_size],
+            ));
         }
-        calc_sr_u64(val as u64, S_PS, OGG_OPUS_SPS)
+        offset = end;
     }
+    Ok(encoded)
+}
+
+/// Generate the serial which is nothing but a value to identify a stream, we
+/// will also use the process id so that two programs don't use
+/// the same serial even if getting one at the same time
+fn new_serial() -> u32 {
+    let mut rnd = rand::thread_rng();
+    rnd.gen::<u32>() ^ process::id()
+}
+
+/// Per stream settings written into the ogg headers.
+#[derive(Clone, Copy)]
+pub struct StreamOptions {
+    /// lets the produced file be matched to the command that made it.
+    pub serial: u32,
+    /// OpusHead output gain in Q7.8 dB, applied losslessly by compliant players.
+    pub output_gain: i16,
+    /// OpusHead input sample rate, the stream's own rate if `None`. Only informational, players
+    /// that honor it resample to it, granule positions stay in 48kHz samples either way.
+    pub input_rate: Option<u32>,
+}
 
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self {
+            serial: new_serial(),
+            output_gain: 0,
+            input_rate: None,
+        }
+    }
+}
+
+/// Converts dB to the Q7.8 fixed point the OpusHead output gain field uses.
+pub fn output_gain_from_db(db: f32) -> anyhow::Result<i16> {
+    let q7_8 = (db * 256.0).round();
+    if !q7_8.is_finite() || q7_8 < i16::MIN as f32 || q7_8 > i16::MAX as f32 {
+        return Err(anyhow!(
+            "gain must be between {} and {} dB",
+            i16::MIN as f32 / 256.0,
+            i16::MAX as f32 / 256.0
+        ));
+    }
+    Ok(q7_8 as i16)
+}
+
+/// 16 bit pcm wav of interleaved samples at `sample_rate`.
+pub fn encode_wav<const NUM_CHANNELS: u8>(pcm: &[i16], sample_rate: u32) -> Vec<u8> {
+    const BYTES_PER_SAMPLE: u16 = 2;
+    let data_len = (pcm.len() * BYTES_PER_SAMPLE as usize) as u32;
+    let block_align = NUM_CHANNELS as u16 * BYTES_PER_SAMPLE;
+
+    let mut header = [0u8; 44];
+    header[0..4].copy_from_slice(b"RIFF");
+    LittleEndian::write_u32(&mut header[4..8], 36 + data_len);
+    header[8..12].copy_from_slice(b"WAVE");
+    header[12..16].copy_from_slice(b"fmt ");
+    LittleEndian::write_u32(&mut header[16..20], 16); // fmt chunk size
+    LittleEndian::write_u16(&mut header[20..22], 1); // integer pcm
+    LittleEndian::write_u16(&mut header[22..24], NUM_CHANNELS as u16);
+    LittleEndian::write_u32(&mut header[24..28], sample_rate);
+    LittleEndian::write_u32(&mut header[28..32], sample_rate * block_align as u32); // byte rate
+    LittleEndian::write_u16(&mut header[32..34], block_align);
+    LittleEndian::write_u16(&mut header[34..36], BYTES_PER_SAMPLE * 8);
+    header[36..40].copy_from_slice(b"data");
+    LittleEndian::write_u32(&mut header[40..44], data_len);
+
+    let mut wav = Vec::with_capacity(header.len() + data_len as usize);
+    wav.extend_from_slice(&header);
+    for sample in pcm {
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+    wav
+}
+
+/// Opus packets without any container, each prefixed with its length as a big endian u16.
+pub fn encode_length_prefixed(packets: &[impl AsRef<[u8]>]) -> anyhow::Result<Vec<u8>> {
+    let total = packets.iter().map(|packet| 2 + packet.as_ref().len()).sum();
+    let mut blob = Vec::with_capacity(total);
+    for packet in packets {
+        let packet = packet.as_ref();
+        let len = u16::try_from(packet.len())
+            .map_err(|_| anyhow!("opus packet of {} bytes is too long", packet.len()))?;
+        let mut len_bf = [0u8; 2];
+        BigEndian::write_u16(&mut len_bf, len);
+        blob.extend_from_slice(&len_bf);
+        blob.extend_from_slice(packet);
+    }
+    Ok(blob)
+}
+
+/// The OpusHead and OpusTags header packets every stream starts with.
+pub(crate) fn opus_headers<const S_PS: u32, const NUM_CHANNELS: u8>(
+    options: &StreamOptions,
+) -> ([u8; 19], Vec<u8>) {
     #[rustfmt::skip]
     let opus_head: [u8; 19] = [
         b'O', b'p', b'u', b's', b'H', b'e', b'a', b'd', // Magic header
@@ -59,18 +268,16 @@ pub fn encode<const S_PS: u32, const NUM_CHANNELS: u8>(
         // If Channel map != 0, here should go channel mapping table
     ];
 
-    fn is_end_of_stream(end: bool) -> ogg::PacketWriteEndInfo {
-        if end {
-            ogg::PacketWriteEndInfo::EndStream
-        } else {
-            ogg::PacketWriteEndInfo::NormalPacket
-        }
-    }
-
     let mut head = opus_head;
     LittleEndian::write_u16(&mut head[10..12], 0u16); // Write pre-skip
-    LittleEndian::write_u32(&mut head[12..16], S_PS); // Write Samples per second
+    LittleEndian::write_u32(&mut head[12..16], options.input_rate.unwrap_or(S_PS)); // Write Samples per second
+    LittleEndian::write_i16(&mut head[16..18], options.output_gain); // Write output gain
+
+    (head, opus_tags(&[]))
+}
 
+/// OpusTags with `comments` as its `KEY=value` user comments.
+pub(crate) fn opus_tags(comments: &[String]) -> Vec<u8> {
     let mut opus_tags: Vec<u8> = Vec::with_capacity(60);
     let vendor_str = format!("ogg-opus {}", VER);
     opus_tags.extend(b"OpusTags");
@@ -78,19 +285,463 @@ pub fn encode<const S_PS: u32, const NUM_CHANNELS: u8>(
     LittleEndian::write_u32(&mut len_bf, vendor_str.len() as u32);
     opus_tags.extend(&len_bf);
     opus_tags.extend(vendor_str.bytes());
-    opus_tags.extend(&[0]); // No user comments
+    LittleEndian::write_u32(&mut len_bf, comments.len() as u32);
+    opus_tags.extend(&len_bf);
+    for comment in comments {
+        LittleEndian::write_u32(&mut len_bf, comment.len() as u32);
+        opus_tags.extend(&len_bf);
+        opus_tags.extend(comment.bytes());
+    }
+    opus_tags
+}
+
+pub fn encode<const S_PS: u32, const NUM_CHANNELS: u8>(
+    packets: &[impl AsRef<[u8]>],
+) -> anyhow::Result<Vec<u8>> {
+    encode_with_options::<S_PS, NUM_CHANNELS>(packets, &StreamOptions::default())
+}
+
+pub fn encode_with_options<const S_PS: u32, const NUM_CHANNELS: u8>(
+    packets: &[impl AsRef<[u8]>],
+    options: &StreamOptions,
+) -> anyhow::Result<Vec<u8>> {
+    encode_with_comments::<S_PS, NUM_CHANNELS>(packets, options, &[])
+}
+
+/// Vorbis comment chapters, `CHAPTER001=00:01:23.456` and `CHAPTER001NAME=label`, which players
+/// and editors that understand chapters show as markers.
+pub fn chapter_comments(chapters: &[(Duration, String)]) -> Vec<String> {
+    chapters
+        .iter()
+        .enumerate()
+        .flat_map(|(i, (offset, label))| {
+            [
+                format!("CHAPTER{:03}={}", i + 1, chapter_time(*offset)),
+                format!("CHAPTER{:03}NAME={}", i + 1, label),
+            ]
+        })
+        .collect()
+}
+
+/// Comments saying whose a per user stream is, `ARTIST` for players to show and the id for
+/// finding them again whatever the file is called.
+pub fn user_comments(display_name: &str, user_id: u64) -> Vec<String> {
+    vec![
+        format!("ARTIST={}", display_name),
+        format!("DISCORD_USER_ID={}", user_id),
+    ]
+}
 
-    packet_writer.write_packet(&head[..], serial, ogg::PacketWriteEndInfo::EndPage, 0)?;
-    packet_writer.write_packet(opus_tags, serial, ogg::PacketWriteEndInfo::EndPage, 0)?;
+/// `HH:MM:SS.mmm` as chapters are written.
+pub fn chapter_time(offset: Duration) -> String {
+    let millis = offset.as_millis();
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+/// Like [`encode_with_options`] with `comments` in the OpusTags.
+pub fn encode_with_comments<const S_PS: u32, const NUM_CHANNELS: u8>(
+    packets: &[impl AsRef<[u8]>],
+    options: &StreamOptions,
+    comments: &[String],
+) -> anyhow::Result<Vec<u8>> {
+    let serial = options.serial;
+    //NOTE: In the future the S_PS const generic will let us use const on a lot
+    // of things, until then we need to use variables
+
+    // This should have a bitrate of 24 Kb/s, exactly what IBM recommends
+
+    // More frame time, slightly less overhead more problematic packet loses,
+    // the frame time is set by FRAME_DURATION and read back from each packet
+
+    tracing::debug!(
+        "encoding {} packets with stream serial {:#010x}",
+        packets.len(),
+        serial
+    );
+    let (head, _) = opus_headers::<S_PS, NUM_CHANNELS>(options);
+    write_stream(&head, opus_tags(comments), serial, packets)
+}
+
+/// Re-muxes an ogg opus stream with `title` as its `TITLE` comment, replacing any title it had.
+/// Its other comments, e.g. chapters, the OpusHead, so the output gain, the stream serial and the
+/// packets are kept as they are.
+pub fn retitle(ogg_data: &[u8], title: &str) -> anyhow::Result<Vec<u8>> {
+    let mut packet_reader = PacketReader::new(Cursor::new(ogg_data));
+    let head = packet_reader
+        .read_packet()?
+        .ok_or_else(|| anyhow!("empty ogg stream"))?;
+    if !head.data.starts_with(b"OpusHead") {
+        return Err(anyhow!("not an ogg opus stream"));
+    }
+    let tags = packet_reader
+        .read_packet()?
+        .ok_or_else(|| anyhow!("ogg opus stream is missing OpusTags"))?;
+    if !tags.data.starts_with(b"OpusTags") {
+        return Err(anyhow!("ogg opus stream is missing OpusTags"));
+    }
+    let mut comments: Vec<_> = parse_comments(&tags.data)?
+        .into_iter()
+        .filter(|comment| {
+            !comment
+                .split_once('=')
+                .is_some_and(|(key, _)| key.eq_ignore_ascii_case("TITLE"))
+        })
+        .collect();
+    comments.insert(0, format!("TITLE={}", title));
+    let mut packets = Vec::new();
+    while let Some(packet) = packet_reader.read_packet()? {
+        packets.push(packet.data);
+    }
+    write_stream(
+        &head.data,
+        opus_tags(&comments),
+        head.stream_serial(),
+        &packets,
+    )
+}
+
+/// The `KEY=value` user comments of an OpusTags packet, the reverse of [`opus_tags`].
+fn parse_comments(tags: &[u8]) -> anyhow::Result<Vec<String>> {
+    fn u32_field(data: &mut &[u8]) -> anyhow::Result<u32> {
+        let Some((value, rest)) = data.split_first_chunk::<4>() else {
+            return Err(anyhow!("OpusTags is truncated"));
+        };
+        *data = rest;
+        Ok(LittleEndian::read_u32(value))
+    }
+    fn field<'a>(data: &mut &'a [u8]) -> anyhow::Result<&'a [u8]> {
+        let len = u32_field(data)? as usize;
+        if data.len() < len {
+            return Err(anyhow!("OpusTags is truncated"));
+        }
+        let (value, rest) = data.split_at(len);
+        *data = rest;
+        Ok(value)
+    }
+    let mut data = tags
+        .strip_prefix(b"OpusTags")
+        .ok_or_else(|| anyhow!("ogg opus stream is missing OpusTags"))?;
+    field(&mut data)?; // vendor string
+    (0..u32_field(&mut data)?)
+        .map(|_| {
+            String::from_utf8(field(&mut data)?.to_vec())
+                .map_err(|_| anyhow!("OpusTags has a comment that isn't utf-8"))
+        })
+        .collect()
+}
+
+fn write_stream(
+    head: &[u8],
+    opus_tags: Vec<u8>,
+    serial: u32,
+    packets: &[impl AsRef<[u8]>],
+) -> anyhow::Result<Vec<u8>> {
+    let mut buffer: Vec<u8> = Vec::new();
+
+    let mut packet_writer = PacketWriter::new(&mut buffer);
+
+    fn is_end_of_stream(end: bool) -> ogg::PacketWriteEndInfo {
+        if end {
+            ogg::PacketWriteEndInfo::EndStream
+        } else {
+            ogg::PacketWriteEndInfo::NormalPacket
+        }
+    }
+
+    packet_writer.write_packet(head, serial, ogg::PacketWriteEndInfo::EndPage, 0)?;
+    // an empty dump still has to end the stream, so the tags page carries EOS
+    let tags_end = if packets.is_empty() {
+        ogg::PacketWriteEndInfo::EndStream
+    } else {
+        ogg::PacketWriteEndInfo::EndPage
+    };
+    packet_writer.write_packet(opus_tags, serial, tags_end, 0)?;
 
+    let mut granule = 0u64;
     for i in 0..packets.len() {
+        granule += packet_samples(packets[i].as_ref()) as u64;
         packet_writer.write_packet(
             packets[i].as_ref(),
             serial,
             is_end_of_stream(i == packets.len() - 1),
-            granule::<S_PS>(calc_samples((i + 1) as u32)),
+            granule,
         )?;
     }
 
     Ok(buffer)
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::decode;
+    use crate::receiver::tests::tone;
+    use crate::receiver::{
+        make_opus_encoder_with_channels, FrameEncoder, AUDIO_CHANNELS, AUDIO_FREQUENCY,
+    };
+
+    /// `frames` frames of a tone encoded at `frame_duration`.
+    fn encoded(frames: usize, frame_duration: Duration) -> Vec<bytes::Bytes> {
+        let mut settings = Config::for_tests().encoder;
+        settings.frame_duration = frame_duration;
+        let mut encoder = FrameEncoder::new(&settings);
+        let audio = tone();
+        (0..frames * settings.ticks_per_frame())
+            .filter_map(|_| encoder.push(Some(&audio)))
+            .collect()
+    }
+
+    /// Every ogg packet of the stream, headers included.
+    fn ogg_packets(ogg_data: &[u8]) -> Vec<ogg::Packet> {
+        let mut reader = PacketReader::new(Cursor::new(ogg_data));
+        let mut packets = Vec::new();
+        while let Some(packet) = reader.read_packet().unwrap() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    #[test]
+    fn user_comments_round_trip() {
+        let packets = encoded(3, Duration::from_millis(20));
+        let comments = user_comments("Zoë, the \"mod\"", 80351110224678912);
+        let ogg_data = encode_with_comments::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(
+            &packets,
+            &StreamOptions::default(),
+            &comments,
+        )
+        .unwrap();
+        assert_eq!(
+            parse_comments(&ogg_packets(&ogg_data)[1].data).unwrap(),
+            comments
+        );
+        assert_eq!(
+            comments,
+            [
+                "ARTIST=Zoë, the \"mod\"",
+                "DISCORD_USER_ID=80351110224678912"
+            ]
+        );
+        assert_eq!(decode::demux(&ogg_data).unwrap(), packets);
+    }
+
+    #[test]
+    fn retitling_keeps_chapters_and_user_comments() {
+        let packets = encoded(3, Duration::from_millis(20));
+        let mut comments = chapter_comments(&[
+            (Duration::ZERO, "intro".to_string()),
+            (Duration::from_millis(40), "outro".to_string()),
+        ]);
+        comments.extend(user_comments("Zoë", 80351110224678912));
+        let ogg_data = encode_with_comments::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(
+            &packets,
+            &StreamOptions::default(),
+            &comments,
+        )
+        .unwrap();
+
+        let retitled = retitle(&retitle(&ogg_data, "first").unwrap(), "second").unwrap();
+        let mut expected = vec!["TITLE=second".to_string()];
+        expected.extend(comments);
+        assert_eq!(
+            parse_comments(&ogg_packets(&retitled)[1].data).unwrap(),
+            expected
+        );
+        assert_eq!(decode::demux(&retitled).unwrap(), packets);
+    }
+
+    #[test]
+    fn output_gain_is_written_to_the_head() {
+        let options = StreamOptions {
+            output_gain: output_gain_from_db(-6.5).unwrap(),
+            ..Default::default()
+        };
+        let ogg_data = encode_with_options::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(
+            &encoded(3, Duration::from_millis(20)),
+            &options,
+        )
+        .unwrap();
+        let head = &ogg_packets(&ogg_data)[0].data;
+        assert!(head.starts_with(b"OpusHead"));
+        assert_eq!(LittleEndian::read_i16(&head[16..18]), -6 * 256 - 128);
+    }
+
+    #[test]
+    fn sixty_ms_granules_count_2880_samples_per_packet() {
+        let packets = encoded(5, Duration::from_millis(60));
+        assert_eq!(packets.len(), 5);
+        assert!(packets.iter().all(|packet| packet_samples(packet) == 2880));
+        let ogg_data = encode::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(&packets).unwrap();
+        let last = ogg_packets(&ogg_data).pop().unwrap();
+        assert!(last.last_in_stream());
+        assert_eq!(last.absgp_page(), 5 * 2880);
+        assert_eq!(
+            packet_duration(&packets[0]) * packets.len() as u32,
+            Duration::from_millis(300)
+        );
+    }
+
+    #[test]
+    fn export_rate_is_informational_and_granules_stay_48khz() {
+        let packets = encoded(100, Duration::from_millis(20));
+        for rate in [44100, 16000] {
+            let options = StreamOptions {
+                input_rate: Some(rate),
+                ..Default::default()
+            };
+            let ogg_data =
+                encode_with_options::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(&packets, &options).unwrap();
+            let mut muxed = ogg_packets(&ogg_data);
+            assert_eq!(LittleEndian::read_u32(&muxed[0].data[12..16]), rate);
+            // 2s of audio
+            assert_eq!(muxed.pop().unwrap().absgp_page(), 96000);
+        }
+    }
+
+    #[test]
+    fn packed_silence_is_smaller_and_keeps_the_timeline() {
+        let silence = FrameEncoder::new(&Config::for_tests().encoder)
+            .empty_encoded()
+            .clone();
+        let speech = encoded(1, Duration::from_millis(20)).pop().unwrap();
+        // a minute of 20ms frames, a word every 10s
+        let packets: Vec<_> = (0..3000)
+            .map(|i| match i % 500 {
+                250 => speech.clone(),
+                _ => silence.clone(),
+            })
+            .collect();
+        let packed = SilencePacker::pack(silence.clone(), &packets);
+        assert!(packed
+            .iter()
+            .all(|packet| *packet == speech || SilencePacker::is_silence(&silence, packet)));
+        assert_eq!(packed.iter().filter(|packet| **packet == speech).count(), 6);
+
+        let unpacked_ogg = encode::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(&packets).unwrap();
+        let packed_ogg = encode::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(&packed).unwrap();
+        assert!(
+            packed_ogg.len() * 3 < unpacked_ogg.len(),
+            "{} bytes packed, {} unpacked",
+            packed_ogg.len(),
+            unpacked_ogg.len()
+        );
+
+        let duration = |packets: &[bytes::Bytes]| {
+            packets
+                .iter()
+                .map(|packet| packet_duration(packet))
+                .sum::<Duration>()
+        };
+        assert_eq!(duration(&packets), Duration::from_secs(60));
+        assert_eq!(duration(&packed), duration(&packets));
+        let final_granule = |ogg_data: &[u8]| ogg_packets(ogg_data).pop().unwrap().absgp_page();
+        assert_eq!(final_granule(&packed_ogg), 60 * 48000);
+        assert_eq!(final_granule(&unpacked_ogg), final_granule(&packed_ogg));
+        assert_eq!(
+            decode::decode_opus(&packed).unwrap().len(),
+            decode::decode_opus(&packets).unwrap().len()
+        );
+    }
+
+    /// Single frame opus packets of any frame duration and payload length, long enough to need
+    /// more than one lacing value.
+    fn arbitrary_packets() -> impl Strategy<Value = Vec<bytes::Bytes>> {
+        let packet = (
+            0u8..32,
+            any::<bool>(),
+            prop::collection::vec(any::<u8>(), 0..1000),
+        )
+            .prop_map(|(config, stereo, payload)| {
+                let toc = config << 3 | (stereo as u8) << 2;
+                let mut packet = vec![toc];
+                packet.extend(payload);
+                bytes::Bytes::from(packet)
+            });
+        prop::collection::vec(packet, 0..200)
+    }
+
+    proptest! {
+        #[test]
+        fn muxed_packets_demux_unchanged(packets in arbitrary_packets()) {
+            let ogg_data = encode::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(&packets).unwrap();
+            prop_assert_eq!(decode::demux(&ogg_data).unwrap(), packets);
+        }
+
+        #[test]
+        fn every_packet_is_muxed_once_after_the_headers(packets in arbitrary_packets()) {
+            let ogg_data = encode::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(&packets).unwrap();
+            let muxed = ogg_packets(&ogg_data);
+            prop_assert_eq!(muxed.len(), packets.len() + 2);
+            prop_assert!(muxed[0].data.starts_with(b"OpusHead"));
+            prop_assert!(muxed[1].data.starts_with(b"OpusTags"));
+            prop_assert!(muxed.last().unwrap().last_in_stream());
+        }
+
+        #[test]
+        fn final_granule_is_the_sum_of_packet_samples(packets in arbitrary_packets()) {
+            let ogg_data = encode::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(&packets).unwrap();
+            let muxed = ogg_packets(&ogg_data);
+            let total: u64 = packets.iter().map(|packet| packet_samples(packet) as u64).sum();
+            prop_assert_eq!(muxed.last().unwrap().absgp_page(), total);
+            let granules: Vec<_> = muxed.iter().map(|packet| packet.absgp_page()).collect();
+            prop_assert!(granules.windows(2).all(|pair| pair[0] <= pair[1]));
+        }
+    }
+
+    #[test]
+    fn downmix_averages_left_and_right() {
+        assert_eq!(
+            downmix_to_mono(&[100, 300, -32768, -32768, 32767, -32768]),
+            [200, -32768, 0]
+        );
+    }
+
+    #[test]
+    fn split_channels_keep_their_own_samples() {
+        let left: Vec<i16> = (1..=100).collect();
+        let right: Vec<i16> = (1..=100).map(|sample| -sample * 300).collect();
+        let pcm: Vec<i16> = left
+            .iter()
+            .zip(&right)
+            .flat_map(|(left, right)| [*left, *right])
+            .collect();
+        assert_eq!(split_channels(&pcm), (left, right));
+    }
+
+    #[test]
+    fn mono_export_halves_the_samples_and_has_one_channel() {
+        let packets = encoded(5, Duration::from_millis(20));
+        let pcm = decode::decode_opus(&packets).unwrap();
+        let mono = downmix_to_mono(&pcm);
+        assert_eq!(mono.len(), pcm.len() / 2);
+
+        let encoder =
+            make_opus_encoder_with_channels(&Config::for_tests().encoder, audiopus::Channels::Mono);
+        let mono_packets = encode_mono_like(&packets, &mono, &encoder).unwrap();
+        assert_eq!(mono_packets.len(), packets.len());
+        // the TOC's stereo flag is clear
+        assert!(mono_packets.iter().all(|packet| packet[0] & 0b100 == 0));
+        let muxed = ogg_packets(&encode::<AUDIO_FREQUENCY, 1>(&mono_packets).unwrap());
+        assert_eq!(muxed[0].data[9], 1);
+        assert_eq!(muxed.last().unwrap().absgp_page(), 5 * 960);
+    }
+
+    #[test]
+    fn output_gain_range_is_q7_8() {
+        assert_eq!(output_gain_from_db(0.0).unwrap(), 0);
+        assert_eq!(output_gain_from_db(1.0).unwrap(), 256);
+        assert_eq!(output_gain_from_db(-128.0).unwrap(), i16::MIN);
+        assert!(output_gain_from_db(128.0).is_err());
+        assert!(output_gain_from_db(-128.1).is_err());
+        assert!(output_gain_from_db(f32::NAN).is_err());
+    }
+}
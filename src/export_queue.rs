@@ -0,0 +1,67 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tokio::sync::{mpsc, oneshot};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Runs expensive exports one at a time on a single worker, so a burst of dumps, waveforms and
+/// per user exports doesn't decode and encode everything at once and multiply the peak memory.
+pub struct ExportQueue {
+    sender: mpsc::UnboundedSender<Job>,
+    /// jobs queued or running.
+    pending: Arc<AtomicUsize>,
+}
+
+/// A job waiting for its turn.
+pub struct Queued<T> {
+    /// jobs that run before this one, including the one currently running.
+    pub ahead: usize,
+    result: oneshot::Receiver<T>,
+}
+
+impl<T> Queued<T> {
+    /// Waits for the job to run and hands back what it returned.
+    pub async fn result(self) -> anyhow::Result<T> {
+        // the sender is only dropped without sending if the job panicked
+        self.result
+            .await
+            .map_err(|_| anyhow::anyhow!("the export failed unexpectedly"))
+    }
+}
+
+impl ExportQueue {
+    /// Spawns the worker, must be called from within the tokio runtime.
+    pub fn new() -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Job>();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let worker_pending = pending.clone();
+        tokio::spawn(async move {
+            while let Some(job) = receiver.recv().await {
+                if let Err(e) = tokio::task::spawn_blocking(job).await {
+                    tracing::error!("export job panicked {:?}", e);
+                }
+                worker_pending.fetch_sub(1, Ordering::SeqCst);
+            }
+        });
+        Self { sender, pending }
+    }
+
+    /// Queues `job` behind every export queued before it.
+    pub fn enqueue<T: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> T + Send + 'static,
+    ) -> Queued<T> {
+        let (sender, result) = oneshot::channel();
+        let ahead = self.pending.fetch_add(1, Ordering::SeqCst);
+        let job: Job = Box::new(move || {
+            // the command may have given up waiting, then nobody wants the result
+            let _ = sender.send(job());
+        });
+        if self.sender.send(job).is_err() {
+            // only once the worker is gone, the dropped job's sender fails the result
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+        }
+        Queued { ahead, result }
+    }
+}
@@ -0,0 +1,211 @@
+use std::f32::consts::PI;
+
+use crate::config::Config;
+use crate::receiver::{
+    AUDIO_CHANNELS, AUDIO_FREQUENCY, from_mix_audio, MixAudioPacket, RawAudioPacket, to_mix_audio,
+};
+
+/// How long the gate stays open after the audio drops below the threshold, so the quiet ends of
+/// words aren't cut off.
+const GATE_HOLD_PACKETS: u32 = 10;
+
+/// Optional cleanup of one speaker's audio before it's encoded: a high-pass to remove hum and
+/// rumble, then a gate that silences packets quieter than a threshold, e.g. keyboard clatter
+/// between sentences. Holds filter state, so each stream needs its own clone.
+#[derive(Clone)]
+pub struct VoiceFilter {
+    highpass: Option<[Biquad; AUDIO_CHANNELS as usize]>,
+    gate: Option<NoiseGate>,
+}
+
+impl VoiceFilter {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            highpass: config
+                .highpass_cutoff
+                .map(|cutoff| [Biquad::highpass(cutoff); AUDIO_CHANNELS as usize]),
+            gate: config.noise_gate_threshold.map(NoiseGate::new),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.highpass.is_some() || self.gate.is_some()
+    }
+
+    /// Filters the packet, `None` if the gate turned it into silence.
+    pub fn process(&mut self, packet: RawAudioPacket) -> Option<RawAudioPacket> {
+        self.process_mix(to_mix_audio(&packet))
+            .map(|packet| from_mix_audio(&packet))
+    }
+
+    /// Same as [`Self::process`] on float audio, which isn't clipped.
+    pub fn process_mix(&mut self, mut packet: MixAudioPacket) -> Option<MixAudioPacket> {
+        if let Some(highpass) = &mut self.highpass {
+            for frame in packet.chunks_exact_mut(AUDIO_CHANNELS as usize) {
+                for (sample, filter) in frame.iter_mut().zip(highpass.iter_mut()) {
+                    *sample = filter.process(*sample);
+                }
+            }
+        }
+        match &mut self.gate {
+            Some(gate) => gate.process(packet),
+            None => Some(packet),
+        }
+    }
+}
+
+/// Second order IIR filter, transposed direct form II.
+#[derive(Clone, Copy)]
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    z1: f32,
+    z2: f32,
+}
+
+impl Biquad {
+    /// Butterworth high-pass from the Audio EQ Cookbook.
+    fn highpass(cutoff: f32) -> Self {
+        let w0 = 2.0 * PI * cutoff / AUDIO_FREQUENCY as f32;
+        let alpha = w0.sin() / (2.0 * std::f32::consts::FRAC_1_SQRT_2);
+        let cos_w0 = w0.cos();
+        let a0 = 1.0 + alpha;
+        Self {
+            b0: (1.0 + cos_w0) / 2.0 / a0,
+            b1: -(1.0 + cos_w0) / a0,
+            b2: (1.0 + cos_w0) / 2.0 / a0,
+            a1: -2.0 * cos_w0 / a0,
+            a2: (1.0 - alpha) / a0,
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = self.b0 * input + self.z1;
+        self.z1 = self.b1 * input - self.a1 * output + self.z2;
+        self.z2 = self.b2 * input - self.a2 * output;
+        output
+    }
+}
+
+#[derive(Clone)]
+struct NoiseGate {
+    /// rms relative to full scale below which the gate closes.
+    threshold: f32,
+    gain: f32,
+    hold: u32,
+}
+
+impl NoiseGate {
+    fn new(threshold_dbfs: f32) -> Self {
+        Self {
+            threshold: 10f32.powf(threshold_dbfs / 20.0),
+            gain: 0.0,
+            hold: 0,
+        }
+    }
+
+    fn process(&mut self, mut packet: MixAudioPacket) -> Option<MixAudioPacket> {
+        let rms =
+            (packet.iter().map(|sample| sample.powi(2)).sum::<f32>() / packet.len() as f32).sqrt();
+        if rms >= self.threshold {
+            self.hold = GATE_HOLD_PACKETS;
+        } else {
+            self.hold = self.hold.saturating_sub(1);
+        }
+        let target = if self.hold > 0 { 1.0 } else { 0.0 };
+        if self.gain == 0.0 && target == 0.0 {
+            return None;
+        }
+        // ramp over the packet instead of jumping, a hard switch clicks
+        let frames = packet.len() / AUDIO_CHANNELS as usize;
+        for (i, frame) in packet.chunks_exact_mut(AUDIO_CHANNELS as usize).enumerate() {
+            let gain = self.gain + (target - self.gain) * (i + 1) as f32 / frames as f32;
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+        self.gain = target;
+        Some(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::receiver::{AUDIO_PACKET_SIZE, empty_raw_audio};
+
+    fn filter(highpass_cutoff: Option<f32>, noise_gate_threshold: Option<f32>) -> VoiceFilter {
+        let mut config = Config::for_tests();
+        config.highpass_cutoff = highpass_cutoff;
+        config.noise_gate_threshold = noise_gate_threshold;
+        VoiceFilter::new(&config)
+    }
+
+    /// `packets` packets of a sine at `frequency` on both channels, offset by `dc`.
+    fn sine(frequency: f32, amplitude: f32, dc: f32, packets: usize) -> Vec<RawAudioPacket> {
+        let frames_per_packet = AUDIO_PACKET_SIZE / AUDIO_CHANNELS as usize;
+        (0..packets)
+            .map(|packet_index| {
+                let mut packet = empty_raw_audio();
+                for (i, frame) in packet.chunks_exact_mut(AUDIO_CHANNELS as usize).enumerate() {
+                    let t = (packet_index * frames_per_packet + i) as f32 / AUDIO_FREQUENCY as f32;
+                    frame.fill((dc + amplitude * (2.0 * PI * frequency * t).sin()) as i16);
+                }
+                packet
+            })
+            .collect()
+    }
+
+    fn rms(packet: &RawAudioPacket) -> f32 {
+        (packet
+            .iter()
+            .map(|&sample| (sample as f32).powi(2))
+            .sum::<f32>()
+            / packet.len() as f32)
+            .sqrt()
+    }
+
+    /// RMS of the last packet out of the filter, once it has settled.
+    fn filtered_rms(filter: &mut VoiceFilter, input: Vec<RawAudioPacket>) -> f32 {
+        let output = input.into_iter().map(|packet| filter.process(packet));
+        rms(&output.last().unwrap().unwrap())
+    }
+
+    #[test]
+    fn highpass_removes_dc_offset() {
+        let mut filter = filter(Some(80.0), None);
+        let level = filtered_rms(&mut filter, sine(0.0, 0.0, 8000.0, 25));
+        assert!(level < 80.0, "{}", level);
+    }
+
+    #[test]
+    fn highpass_attenuates_rumble_and_keeps_voice() {
+        let rumble = filtered_rms(&mut filter(Some(100.0), None), sine(20.0, 8000.0, 0.0, 25));
+        let voice = filtered_rms(
+            &mut filter(Some(100.0), None),
+            sine(1000.0, 8000.0, 0.0, 25),
+        );
+        let unfiltered = 8000.0 * std::f32::consts::FRAC_1_SQRT_2;
+        assert!(rumble < unfiltered * 0.1, "{}", rumble);
+        assert!(voice > unfiltered * 0.95, "{}", voice);
+    }
+
+    #[test]
+    fn gate_silences_quiet_packets_after_the_hold() {
+        let mut filter = filter(None, Some(-40.0));
+        let loud = sine(1000.0, 8000.0, 0.0, 1).remove(0);
+        let quiet = sine(1000.0, 50.0, 0.0, 1).remove(0);
+        assert!(filter.process(loud).is_some());
+        for _ in 0..GATE_HOLD_PACKETS - 1 {
+            assert!(filter.process(quiet).is_some());
+        }
+        // ramps down over one more packet, then stays shut
+        assert!(filter.process(quiet).is_some());
+        assert!(filter.process(quiet).is_none());
+    }
+}
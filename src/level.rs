@@ -0,0 +1,149 @@
+use songbird::model::id::UserId;
+
+use crate::config::EncoderSettings;
+use crate::decode;
+use crate::pan;
+use crate::receiver::{AUDIO_PACKET_SIZE, FrameEncoder, MixAudioPacket, to_mix_audio};
+
+/// Speech loudness every user is brought to, as the rms of their speech in dBFS.
+const TARGET_DBFS: f32 = -20.0;
+
+/// Most a user is boosted or cut, enough to bring anything above the speech floor to the target.
+const MAX_GAIN_DB: f32 = TARGET_DBFS - SPEECH_FLOOR_DBFS;
+
+/// Ticks quieter than this don't count as speech when measuring a user.
+const SPEECH_FLOOR_DBFS: f32 = -50.0;
+
+/// How loud a user was and what the leveled mix did about it.
+pub struct UserLevel {
+    pub user: UserId,
+    /// rms of the user's speech in dBFS, `None` if nothing crossed the speech floor.
+    pub loudness: Option<f32>,
+    pub gain_db: f32,
+}
+
+/// Mixes aligned per user tracks, see
+/// [`crate::tts::PerUserSoundBuffer::get_aligned_packets`], after bringing each user's speech to
+/// the same loudness. Users are panned like the lookback mix. Returns the mix as opus packets of
+/// `encoder`'s frame duration.
+pub fn leveled_mix(
+    tracks: &[(UserId, Vec<bytes::Bytes>)],
+    encoder: &EncoderSettings,
+    pan: impl Fn(UserId) -> Option<f32>,
+) -> anyhow::Result<(Vec<bytes::Bytes>, Vec<UserLevel>)> {
+    let mut levels = Vec::with_capacity(tracks.len());
+    let mut decoded = Vec::with_capacity(tracks.len());
+    for (user, packets) in tracks {
+        let pcm = decode::decode_opus(packets)?;
+        let loudness = speech_loudness_db(&pcm);
+        let gain_db = loudness.map_or(0.0, |loudness| {
+            (TARGET_DBFS - loudness).clamp(-MAX_GAIN_DB, MAX_GAIN_DB)
+        });
+        levels.push(UserLevel {
+            user: *user,
+            loudness,
+            gain_db,
+        });
+        decoded.push(pcm);
+    }
+    let ticks = decoded.iter().map(|pcm| pcm.len()).min().unwrap_or(0) / AUDIO_PACKET_SIZE;
+    let mut frame_encoder = FrameEncoder::new(encoder);
+    let mut packets = Vec::new();
+    for tick in 0..ticks {
+        let range = tick * AUDIO_PACKET_SIZE..(tick + 1) * AUDIO_PACKET_SIZE;
+        let mut mix: Option<MixAudioPacket> = None;
+        for (pcm, level) in decoded.iter().zip(&levels) {
+            let chunk = &pcm[range.clone()];
+            if chunk.iter().all(|sample| *sample == 0) {
+                continue;
+            }
+            let mut packet = to_mix_audio(chunk.try_into().expect("chunk is one tick"));
+            let factor = 10f32.powf(level.gain_db / 20.0);
+            packet.iter_mut().for_each(|sample| *sample *= factor);
+            if let Some(position) = pan(level.user) {
+                pan::apply(&mut packet, position);
+            }
+            match &mut mix {
+                Some(mix) => mix
+                    .iter_mut()
+                    .zip(packet)
+                    .for_each(|(mixed, sample)| *mixed += sample),
+                None => mix = Some(packet),
+            }
+        }
+        packets.extend(frame_encoder.push_mix(mix.as_ref()));
+    }
+    Ok((packets, levels))
+}
+
+/// Rms in dBFS over the ticks of interleaved pcm loud enough to be speech, so pauses don't make a
+/// user seem quieter than they talk.
+pub fn speech_loudness_db(pcm: &[i16]) -> Option<f32> {
+    let floor = 10f64.powf(SPEECH_FLOOR_DBFS as f64 / 10.0);
+    let (sum, ticks) = pcm
+        .chunks_exact(AUDIO_PACKET_SIZE)
+        .map(|tick| {
+            tick.iter()
+                .map(|sample| (*sample as f64 / i16::MAX as f64).powi(2))
+                .sum::<f64>()
+                / tick.len() as f64
+        })
+        .filter(|mean_square| *mean_square >= floor)
+        .fold((0.0, 0), |(sum, ticks), mean_square| {
+            (sum + mean_square, ticks + 1)
+        });
+    (ticks > 0).then(|| (10.0 * (sum / ticks as f64).log10()) as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::receiver::tests::tone;
+
+    /// `user` silent for `before` ticks, then speaking `audio` for `speaking` ticks, then silent
+    /// for `after` ticks.
+    fn track(
+        user: u64,
+        audio: &[i16; AUDIO_PACKET_SIZE],
+        (before, speaking, after): (usize, usize, usize),
+    ) -> (UserId, Vec<bytes::Bytes>) {
+        let mut encoder = FrameEncoder::new(&Config::for_tests().encoder);
+        let packets = (0..before)
+            .map(|_| None)
+            .chain((0..speaking).map(|_| Some(audio)))
+            .chain((0..after).map(|_| None))
+            .filter_map(|audio| encoder.push(audio))
+            .collect();
+        (UserId(user), packets)
+    }
+
+    #[test]
+    fn quiet_and_loud_users_come_out_alike() {
+        let loud = tone();
+        // 24dB down
+        let quiet = loud.map(|sample| sample / 16);
+        let tracks = [track(1, &loud, (0, 25, 25)), track(2, &quiet, (25, 25, 0))];
+        let settings = Config::for_tests().encoder;
+        let (packets, levels) = leveled_mix(&tracks, &settings, |_| None).unwrap();
+        let measured: Vec<f32> = levels.iter().map(|level| level.loudness.unwrap()).collect();
+        assert!(measured[0] - measured[1] > 20.0, "{:?}", measured);
+
+        let pcm = decode::decode_opus(&packets).unwrap();
+        // clear of each user's start and end
+        let ticks = |range: std::ops::Range<usize>| {
+            speech_loudness_db(&pcm[range.start * AUDIO_PACKET_SIZE..range.end * AUDIO_PACKET_SIZE])
+                .unwrap()
+        };
+        let (first, second) = (ticks(5..20), ticks(30..45));
+        assert!((first - second).abs() < 3.0, "{} vs {}", first, second);
+        assert!((first - TARGET_DBFS).abs() < 3.0, "{}", first);
+    }
+
+    #[test]
+    fn silence_has_no_speech_loudness() {
+        assert_eq!(speech_loudness_db(&[0; AUDIO_PACKET_SIZE * 3]), None);
+        let quiet = [1; AUDIO_PACKET_SIZE];
+        assert_eq!(speech_loudness_db(&quiet), None);
+    }
+}
@@ -0,0 +1,31 @@
+#![warn(clippy::all)]
+#![deny(warnings)]
+
+mod archive;
+mod clip_trigger;
+pub mod config;
+mod crypt;
+mod decode;
+pub mod discord;
+mod drift;
+mod dump_cache;
+mod encode;
+mod export_queue;
+mod filter;
+mod level;
+mod live;
+mod lookback;
+mod pan;
+mod premux;
+pub mod receiver;
+mod resample;
+mod self_check;
+mod storage;
+mod tick_watchdog;
+mod tts;
+mod voice_match;
+mod waveform;
+
+/// Reached into by `benches/drain.rs`, not part of the bot.
+#[doc(hidden)]
+pub use lookback::Lookback;
@@ -0,0 +1,228 @@
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use ogg::{PacketWriteEndInfo, PacketWriter};
+
+use crate::config::{Config, LiveTarget};
+use crate::encode::{opus_headers, packet_samples, StreamOptions};
+use crate::receiver::{AUDIO_CHANNELS, AUDIO_FREQUENCY};
+
+/// Packets waiting for the consumer, about 5s of 20ms frames. Anything past that is dropped so a
+/// slow consumer never holds up the tick.
+const QUEUE_PACKETS: usize = 250;
+
+/// Ends a page, and flushes it, every 100ms of 20ms frames so the consumer lags little behind.
+const PACKETS_PER_PAGE: u64 = 5;
+
+/// Wait before trying again when no consumer can be waited for, doubled on every failure in a row.
+const RETRY_MIN: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(60);
+
+/// Streams the lookback mix as ogg opus to `LIVE_STREAM` as it's encoded, e.g. for OBS or ffmpeg
+/// to pick up. A worker thread does the writing, the tick only queues packets. While nobody reads
+/// the packets are thrown away, every consumer gets a fresh stream starting at its connection.
+pub struct LiveStream {
+    sender: Option<SyncSender<bytes::Bytes>>,
+    /// packets dropped because the consumer fell behind.
+    dropped: AtomicU64,
+    /// set once the worker thread is found gone, so that's only warned about once.
+    stopped: AtomicBool,
+}
+
+impl LiveStream {
+    pub fn new(config: &Config) -> Self {
+        let Some(target) = config.live_stream.clone() else {
+            return Self {
+                sender: None,
+                dropped: Default::default(),
+                stopped: Default::default(),
+            };
+        };
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_PACKETS);
+        std::thread::Builder::new()
+            .name("live stream".to_string())
+            .spawn(move || stream(target, receiver))
+            .expect("failed to spawn live stream thread");
+        Self {
+            sender: Some(sender),
+            dropped: Default::default(),
+            stopped: Default::default(),
+        }
+    }
+
+    pub fn write(&self, packet: &bytes::Bytes) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        match sender.try_send(packet.clone()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                // once a second of 20ms frames, not every tick
+                if dropped % 50 == 1 {
+                    tracing::warn!(
+                        "live stream consumer is too slow, {} packets dropped so far",
+                        dropped
+                    );
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                if !self.stopped.swap(true, Ordering::Relaxed) {
+                    tracing::warn!("live stream stopped, the mix is no longer streamed");
+                }
+            }
+        }
+    }
+}
+
+/// Waits for a consumer, streams to it until it goes away, and waits for the next one.
+fn stream(target: LiveTarget, packets: Receiver<bytes::Bytes>) {
+    let listener = match &target {
+        LiveTarget::Tcp(address) => match TcpListener::bind(address) {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                tracing::error!(
+                    "failed to listen on {} for the live stream {:?}",
+                    address,
+                    e
+                );
+                return;
+            }
+        },
+        LiveTarget::Fifo(_) => None,
+    };
+    let mut retry = RETRY_MIN;
+    loop {
+        tracing::info!("waiting for a live stream consumer on {:?}", target);
+        // blocks until someone reads, the tick keeps queueing and dropping meanwhile
+        let consumer: anyhow::Result<Box<dyn Write>> = match (&target, &listener) {
+            (LiveTarget::Fifo(path), _) => match OpenOptions::new().write(true).open(path) {
+                Ok(fifo) => Ok(Box::new(fifo)),
+                Err(e) => Err(anyhow!("failed to open {}: {:?}", path.display(), e)),
+            },
+            (LiveTarget::Tcp(_), Some(listener)) => match listener.accept() {
+                Ok((socket, peer)) => {
+                    tracing::info!("live stream consumer connected from {}", peer);
+                    Ok(Box::new(socket))
+                }
+                Err(e) => Err(anyhow!("failed to accept a consumer: {:?}", e)),
+            },
+            (LiveTarget::Tcp(_), None) => unreachable!("the listener is bound before the loop"),
+        };
+        let consumer = match consumer {
+            Ok(consumer) => {
+                retry = RETRY_MIN;
+                consumer
+            }
+            Err(e) => {
+                tracing::error!("live stream {}, retrying in {:?}", e, retry);
+                std::thread::sleep(retry);
+                retry = (retry * 2).min(RETRY_MAX);
+                continue;
+            }
+        };
+        // what queued up while waiting is stale, the consumer starts at the live edge
+        while packets.try_recv().is_ok() {}
+        match write_stream(consumer, &packets) {
+            Ok(()) => return,
+            Err(e) => tracing::info!("live stream consumer went away {:?}", e),
+        }
+    }
+}
+
+/// Writes packets to `consumer` until it fails, `Ok` only once the bot stops sending packets.
+fn write_stream(consumer: Box<dyn Write>, packets: &Receiver<bytes::Bytes>) -> anyhow::Result<()> {
+    let options = StreamOptions::default();
+    let mut writer = PacketWriter::new(BufWriter::new(consumer));
+    let (head, tags) = opus_headers::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(&options);
+    writer.write_packet(
+        head.to_vec(),
+        options.serial,
+        PacketWriteEndInfo::EndPage,
+        0,
+    )?;
+    writer.write_packet(tags, options.serial, PacketWriteEndInfo::EndPage, 0)?;
+    writer.inner_mut().flush()?;
+    let mut granule = 0;
+    for (written, packet) in packets.iter().enumerate() {
+        granule += packet_samples(&packet) as u64;
+        let end_page = written as u64 % PACKETS_PER_PAGE == PACKETS_PER_PAGE - 1;
+        let end_info = if end_page {
+            PacketWriteEndInfo::EndPage
+        } else {
+            PacketWriteEndInfo::NormalPacket
+        };
+        writer.write_packet(packet.to_vec(), options.serial, end_info, granule)?;
+        if end_page {
+            writer.inner_mut().flush()?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+    use std::net::TcpStream;
+
+    use ogg::PacketReader;
+
+    use super::*;
+    use crate::decode;
+    use crate::receiver::FrameEncoder;
+
+    #[test]
+    fn streams_to_a_tcp_consumer() {
+        // a free port, released again for the stream to bind
+        let address = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let mut config = Config::for_tests();
+        config.live_stream = Some(LiveTarget::Tcp(address.clone()));
+        let silence = FrameEncoder::new(&config.encoder).empty_encoded().clone();
+        let live = LiveStream::new(&config);
+        let mut consumer = loop {
+            match TcpStream::connect(&address) {
+                Ok(consumer) => break consumer,
+                Err(_) => std::thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        consumer
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        // written over a while, what queues up before the consumer is picked up is dropped
+        let samples = packet_samples(&silence) as u64;
+        std::thread::spawn(move || {
+            for _ in 0..200 {
+                live.write(&silence);
+                std::thread::sleep(Duration::from_millis(2));
+            }
+            // dropping the stream ends it, closing the connection
+        });
+        let mut ogg_data = Vec::new();
+        consumer.read_to_end(&mut ogg_data).unwrap();
+
+        let audio = decode::demux(&ogg_data).unwrap();
+        assert!(audio.len() >= PACKETS_PER_PAGE as usize);
+        let mut reader = PacketReader::new(Cursor::new(&ogg_data));
+        let mut granules = Vec::new();
+        while let Some(packet) = reader.read_packet().unwrap() {
+            if packet.last_in_page() && packet.absgp_page() > 0 {
+                granules.push(packet.absgp_page());
+            }
+        }
+        assert!(
+            granules.windows(2).all(|pair| pair[0] < pair[1]),
+            "{:?}",
+            granules
+        );
+        assert_eq!(granules.last(), Some(&(audio.len() as u64 * samples)));
+    }
+}
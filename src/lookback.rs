@@ -1,112 +1,1301 @@
-use std::sync::Mutex;
-use std::time::Duration;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
-use audiopus::coder::Encoder;
 use circular_queue::CircularQueue;
-use songbird::events::context_data::VoiceTick;
+use rayon::prelude::*;
+use songbird::model::id::UserId;
 
-use crate::encode;
+use crate::config::{Config, EncoderSettings, MixMode, OversizedAudio};
+use crate::{decode, encode};
+use crate::encode::{packet_duration, packet_samples, SilencePacker, StreamOptions};
+use crate::filter::VoiceFilter;
+use crate::pan;
+use crate::premux::Premuxer;
 use crate::receiver::{
-    AUDIO_CHANNELS, AUDIO_FREQUENCY, AUDIO_PACKET_SIZE, empty_raw_audio, make_opus_encoder,
-    MAX_OPUS_PACKET, to_raw_audio_packet,
+    AUDIO_CHANNELS, AUDIO_FREQUENCY, AUDIO_PACKET_SIZE, empty_raw_audio, FrameEncoder,
+    lock_recovering, make_opus_encoder, MAX_OPUS_PACKET, MixAudioPacket, RawAudioPacket, Tick,
+    to_mix_audio, to_raw_audio_packets,
 };
 
-const BUFFER_SIZE: usize = (1000 / 20) * 60 * 30;
-/// 20ms @ 48kHz of 2ch 16 bit pcm
-const PACKET_DURATION: Duration = Duration::from_millis(20);
+/// 20ms @ 48kHz of 2ch 16 bit pcm, one driver tick
+pub(crate) const PACKET_DURATION: Duration = Duration::from_millis(20);
 
-pub struct Lookback {
-    encoded_opus_buf: Mutex<CircularQueue<bytes::Bytes>>,
-    opus_encoder: Mutex<Encoder>, // will never actually be contested
-    empty_encoded: bytes::Bytes,
-    output_scratch_space: Mutex<[u8; MAX_OPUS_PACKET]>,
+/// Whole packets of `packet_duration` that fit in `duration`, rounded down so a buffer never
+/// covers more than asked for. Counted in nanoseconds so a duration that isn't a whole number of
+/// milliseconds isn't truncated first.
+pub(crate) fn packets_for_duration(duration: Duration, packet_duration: Duration) -> usize {
+    (duration.as_nanos() / packet_duration.as_nanos()) as usize
 }
 
-impl Default for Lookback {
-    fn default() -> Self {
-        let opus_encoder = make_opus_encoder();
-        let mut output_scratch_space = [0; MAX_OPUS_PACKET];
-        let empty_encoded = {
-            let empty = empty_raw_audio();
-            let result = opus_encoder
-                .encode(&empty, &mut output_scratch_space)
-                .unwrap();
-            bytes::Bytes::copy_from_slice(&output_scratch_space[..result])
-        };
+/// Packets of `packet_duration` needed to cover all of `duration`, rounded up.
+pub(crate) fn packets_covering(duration: Duration, packet_duration: Duration) -> usize {
+    duration.as_nanos().div_ceil(packet_duration.as_nanos()) as usize
+}
+
+/// An encoded dump and what ended up in it.
+pub struct DumpResult {
+    pub bytes: Vec<u8>,
+    /// opus packets in the file, including the marker.
+    pub packets: usize,
+    /// playback length of the file.
+    pub duration: Duration,
+    /// see [`timestamp_sidecar`], only produced on request.
+    pub timestamps: Option<String>,
+    /// offset and label of each cue that falls in the file.
+    pub chapters: Vec<(Duration, String)>,
+    /// see [`chat_sidecar`], only when chat messages fall in the file.
+    pub chat: Option<String>,
+    /// the right channel as its own mono stream when an export is split, `bytes` then holds the
+    /// left.
+    pub right_channel: Option<Vec<u8>>,
+}
+
+impl DumpResult {
+    fn new(bytes: Vec<u8>, drained: Drained) -> Self {
         Self {
-            encoded_opus_buf: CircularQueue::with_capacity(BUFFER_SIZE).into(),
-            opus_encoder: opus_encoder.into(),
-            empty_encoded,
-            output_scratch_space: output_scratch_space.into(),
+            bytes,
+            packets: drained.packets.len(),
+            duration: drained
+                .packets
+                .iter()
+                .map(|packet| packet_duration(packet))
+                .sum(),
+            timestamps: drained.timestamps,
+            chapters: drained.chapters,
+            chat: drained.chat,
+            right_channel: None,
         }
     }
 }
 
+/// What [`Lookback::drain_packets`] collected for a dump.
+struct Drained {
+    /// marker included.
+    packets: Vec<bytes::Bytes>,
+    timestamps: Option<String>,
+    chapters: Vec<(Duration, String)>,
+    chat: Option<String>,
+}
+
+/// Outcome of [`Lookback::self_check`].
+pub struct SelfCheck {
+    pub buffered: Duration,
+    /// packets drained and encoded for the check.
+    pub packets: usize,
+    pub bytes: usize,
+    /// packets of the check the decoder rejected, anything but 0 means a corrupt encoder.
+    pub undecodable: usize,
+}
+
+/// Result of [`Lookback::trim_preview`].
+pub struct TrimPreview {
+    pub before: Duration,
+    pub after: Duration,
+    /// leading and trailing silence dropped.
+    pub edges: Duration,
+    /// silence cut out of gaps longer than the trim gap.
+    pub gaps: Duration,
+}
+
+/// What `drain_buffer` should include.
+#[derive(Default)]
+pub struct DrainOptions {
+    /// how far back to go, everything if `None`.
+    pub duration: Option<Duration>,
+    /// prepend the alignment beep.
+    pub with_marker: bool,
+    /// also produce the timestamp sidecar for the same packets.
+    pub with_timestamps: bool,
+    /// drop leading and trailing silent packets.
+    pub trim_silence: bool,
+    /// only frames captured after this, applied on top of `duration`.
+    pub since: Option<SystemTime>,
+    /// only frames this user was heard in, cut together back to back.
+    pub speaker: Option<UserId>,
+    /// time and label of cues to write as chapters where they fall in the dump, oldest first.
+    pub cues: Vec<(SystemTime, String)>,
+    /// logged chat messages to write into the chat sidecar where they fall in the dump, oldest
+    /// first.
+    pub chat: Vec<ChatMessage>,
+}
+
+/// A text message posted while recording, see [`crate::receiver::Receiver::log_chat`].
+#[derive(Clone)]
+pub struct ChatMessage {
+    /// when the bot received it, on the same clock frames are captured by.
+    pub posted_at: SystemTime,
+    pub author: String,
+    pub content: String,
+}
+
+/// One encoded frame of the mix.
+#[derive(Clone)]
+struct Frame {
+    /// wall clock time the frame finished capturing. Kept for every frame rather than only with
+    /// timestamps turned on, cues, chat, `since`, `mark`, latency and speaker cuts all place frames
+    /// by it. 16 bytes a frame, about 1.4MB for 30 minutes of 20ms frames.
+    captured_at: SystemTime,
+    packet: bytes::Bytes,
+    /// users heard in any tick of the frame. Shared so snapshots don't allocate a copy per frame
+    /// under the buffer lock, and the empty default most frames have doesn't allocate at all.
+    speakers: Arc<[UserId]>,
+}
+
+struct Injection {
+    packets: VecDeque<MixAudioPacket>,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// Stops an injection from being mixed into any further ticks, see [`Lookback::inject`].
+#[derive(Clone, Debug)]
+pub struct InjectionHandle(Arc<AtomicBool>);
+
+impl InjectionHandle {
+    /// Drops whatever of the injection hasn't been mixed yet.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+pub struct Lookback {
+    encoded_opus_buf: Mutex<CircularQueue<Frame>>,
+    frame_encoder: Mutex<FrameEncoder>, // will never actually be contested
+    /// users heard in the ticks of the frame currently being encoded.
+    pending_speakers: Mutex<Vec<UserId>>,
+    empty_encoded: bytes::Bytes,
+    encoder_settings: EncoderSettings,
+    /// pre encoded alignment beep, optionally prepended to dumps.
+    marker_encoded: Vec<bytes::Bytes>,
+    /// audio from outside the voice connection, e.g. the bot's own playback, each mixed in one
+    /// packet per tick until it runs out or is cancelled.
+    injected: Mutex<Vec<Injection>>,
+    mix_mode: MixMode,
+    compact_silence: bool,
+    /// with `PREMUX_LOOKBACK`, always locked after `encoded_opus_buf` so both hold the same frames.
+    premuxer: Option<Mutex<Premuxer>>,
+    /// `config.trim_max_gap` with any change made through `trimpreview`.
+    trim_max_gap: Mutex<Option<Duration>>,
+    oversized_audio: OversizedAudio,
+    /// only with `FILTER_MIX` and a filter configured.
+    mix_filter: Option<Mutex<VoiceFilter>>,
+    /// when `tick` last ran, to notice the driver no longer delivering them.
+    last_tick: Mutex<Instant>,
+}
+
 impl Lookback {
-    pub fn tick(&self, data: &VoiceTick) {
-        let packet = if data.speaking.is_empty() {
+    pub fn new(config: &Config) -> Self {
+        let buffer_size = config
+            .encoder
+            .packets_for_duration(config.lookback_duration);
+        let frame_encoder = FrameEncoder::new(&config.encoder);
+        Self {
+            encoded_opus_buf: CircularQueue::with_capacity(buffer_size).into(),
+            empty_encoded: frame_encoder.empty_encoded().clone(),
+            frame_encoder: frame_encoder.into(),
+            pending_speakers: Default::default(),
+            encoder_settings: config.encoder,
+            marker_encoded: encode_marker(config),
+            injected: Default::default(),
+            mix_mode: config.mix_mode,
+            compact_silence: config.compact_silence,
+            premuxer: config
+                .premux_lookback
+                .then(|| Mutex::new(Premuxer::new(buffer_size))),
+            trim_max_gap: config.trim_max_gap.into(),
+            oversized_audio: config.oversized_audio,
+            mix_filter: Some(VoiceFilter::new(config))
+                .filter(|filter| config.filter_mix && filter.is_enabled())
+                .map(Mutex::new),
+            last_tick: Instant::now().into(),
+        }
+    }
+
+    /// Rebuilds the mix encoder with new settings, see [`FrameEncoder::reconfigure`].
+    pub fn reconfigure_encoder(&self, settings: &EncoderSettings) {
+        lock_recovering(&self.frame_encoder, "encoder").reconfigure(settings);
+    }
+
+    /// Queues externally provided audio to be mixed into the following ticks, one packet per
+    /// tick, independent of songbird. Overlapping injections are mixed together just like the
+    /// driver does. Without a voice connection, ticking with `Tick::default()` drives it through.
+    /// The handle cancels what's left of it, e.g. when the playback is stopped early.
+    pub fn inject(&self, packets: Vec<RawAudioPacket>) -> InjectionHandle {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        lock_recovering(&self.injected, "injected audio").push(Injection {
+            packets: packets.iter().map(to_mix_audio).collect(),
+            cancelled: cancelled.clone(),
+        });
+        InjectionHandle(cancelled)
+    }
+
+    /// The next packet of every live injection summed together, dropping finished and cancelled
+    /// ones.
+    fn pop_injected(&self) -> Option<MixAudioPacket> {
+        let mut injected = lock_recovering(&self.injected, "injected audio");
+        injected.retain(|injection| !injection.cancelled.load(Ordering::Relaxed));
+        let mut mix: Option<MixAudioPacket> = None;
+        for packet in injected
+            .iter_mut()
+            .filter_map(|injection| injection.packets.pop_front())
+        {
+            match &mut mix {
+                Some(mix) => mix_into(mix, &packet),
+                None => mix = Some(packet),
+            }
+        }
+        injected.retain(|injection| !injection.packets.is_empty());
+        mix
+    }
+
+    /// Returns the frame encoded this tick, if one was completed. `user_of` maps ssrcs to users
+    /// where known, `pan` gives the stereo position of each user, see [`pan::apply`].
+    pub fn tick(
+        &self,
+        data: &Tick,
+        user_of: impl Fn(u32) -> Option<UserId> + Sync,
+        pan: impl Fn(UserId) -> Option<f32> + Sync,
+    ) -> Option<bytes::Bytes> {
+        *lock_recovering(&self.last_tick, "last tick") = Instant::now();
+        {
+            let mut pending_speakers = lock_recovering(&self.pending_speakers, "pending speakers");
+            for (ssrc, _) in data.speaking.iter().filter(|(_, audio)| audio.is_some()) {
+                if let Some(user) = user_of(*ssrc).filter(|user| !pending_speakers.contains(user)) {
+                    pending_speakers.push(user);
+                }
+            }
+        }
+        let injected = self.pop_injected();
+        let mix = if data.speaking.is_empty() && injected.is_none() {
             // early exit, empty packet
-            self.empty_encoded.clone()
+            None
         } else {
-            let mut mix_buf = empty_raw_audio();
-
-            for data in data.speaking.values() {
-                if let Some(audio) = &data.decoded_voice {
-                    if let Some(audio) = to_raw_audio_packet(audio) {
-                        for i in 0..AUDIO_PACKET_SIZE {
-                            mix_buf[i] = mix_buf[i].saturating_add(audio[i]);
-                        }
+            // summed as floats all the way to the encoder, so overlapping speakers neither clip
+            // nor get requantized, and normalizing doesn't scale already clipped audio
+            let (mix, speakers) = data
+                .speaking
+                .par_iter()
+                .filter_map(|(ssrc, audio)| {
+                    let packet = to_raw_audio_packets(audio.as_ref()?, self.oversized_audio)
+                        .into_iter()
+                        .next()?;
+                    let mut packet = to_mix_audio(&packet);
+                    if let Some(position) = user_of(*ssrc).and_then(&pan) {
+                        pan::apply(&mut packet, position);
                     }
+                    Some(packet)
+                })
+                .chain(injected)
+                .fold(
+                    || ([0f32; AUDIO_PACKET_SIZE], 0usize),
+                    |(mut mix, speakers), audio| {
+                        mix_into(&mut mix, &audio);
+                        (mix, speakers + 1)
+                    },
+                )
+                .reduce(
+                    || ([0f32; AUDIO_PACKET_SIZE], 0usize),
+                    |(mut mix, speakers), (other, other_speakers)| {
+                        mix_into(&mut mix, &other);
+                        (mix, speakers + other_speakers)
+                    },
+                );
+            let gain = match self.mix_mode {
+                MixMode::Sum => 1.0,
+                MixMode::Normalize => 1.0 / (speakers.max(1) as f32).sqrt(),
+            };
+            Some(mix.map(|sample| sample * gain))
+        };
+        // the rest of anything longer than a tick plays over the following ticks
+        if self.oversized_audio == OversizedAudio::Split {
+            for (_, audio) in &data.speaking {
+                if let Some(overflow) = audio
+                    .and_then(|audio| audio.get(AUDIO_PACKET_SIZE..))
+                    .filter(|overflow| !overflow.is_empty())
+                {
+                    self.inject(to_raw_audio_packets(overflow, self.oversized_audio));
                 }
             }
-
-            let mut scratch_space = self
-                .output_scratch_space
-                .lock()
-                .expect("encoder lock panicked");
-            self.opus_encoder
-                .lock()
-                .expect("encoder lock panicked")
-                .encode(&mix_buf, scratch_space.as_mut())
-                .map(|written_size| bytes::Bytes::copy_from_slice(&scratch_space[..written_size]))
-                .unwrap_or_else(|_| self.empty_encoded.clone())
+        }
+        let mix = match (mix, &self.mix_filter) {
+            (Some(mix), Some(filter)) => lock_recovering(filter, "mix filter").process_mix(mix),
+            (mix, _) => mix,
+        };
+        let Some(packet) = lock_recovering(&self.frame_encoder, "encoder").push_mix(mix.as_ref())
+        else {
+            // frame not complete yet
+            return None;
         };
-        self.encoded_opus_buf
-            .lock()
-            .expect("encoded opus buf lock panicked")
-            .push(packet);
+        let speakers = std::mem::take(&mut *lock_recovering(
+            &self.pending_speakers,
+            "pending speakers",
+        ));
+        let mut encoded_opus_buf = lock_recovering(&self.encoded_opus_buf, "encoded opus buf");
+        encoded_opus_buf.push(Frame {
+            captured_at: SystemTime::now(),
+            packet: packet.clone(),
+            speakers: if speakers.is_empty() {
+                Default::default()
+            } else {
+                speakers.into()
+            },
+        });
+        if let Some(premuxer) = &self.premuxer {
+            lock_recovering(premuxer, "premuxer").push(packet.clone());
+        }
+        Some(packet)
     }
 
     pub fn drain_buffer(
         &self,
-        duration_to_dump: Option<Duration>,
-    ) -> anyhow::Result<Vec<u8>> {
+        drain: &DrainOptions,
+        options: &StreamOptions,
+    ) -> anyhow::Result<DumpResult> {
+        if let Some(premuxer) = &self.premuxer {
+            // anything but a duration needs the frames themselves
+            let plain = !drain.with_marker
+                && !drain.with_timestamps
+                && !drain.trim_silence
+                && drain.since.is_none()
+                && drain.speaker.is_none();
+            if plain {
+                return Ok(self.drain_premuxed(premuxer, drain, options));
+            }
+        }
+        let drained = self.drain_packets(drain)?;
+        let bytes = encode::encode_with_comments::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(
+            &drained.packets,
+            options,
+            &encode::chapter_comments(&drained.chapters),
+        )?;
+        tracing::info!("done");
+        Ok(DumpResult::new(bytes, drained))
+    }
+
+    /// Like [`Self::drain_buffer`] from the premuxed pages, which are copied instead of muxed.
+    fn drain_premuxed(
+        &self,
+        premuxer: &Mutex<Premuxer>,
+        drain: &DrainOptions,
+        options: &StreamOptions,
+    ) -> DumpResult {
+        let encoded_opus_buf = lock_recovering(&self.encoded_opus_buf, "encoded opus buf");
+        let buffered = encoded_opus_buf.len();
+        let count = drain.duration.map_or(buffered, |duration| {
+            self.encoder_settings
+                .packets_for_duration(duration)
+                .min(buffered)
+        });
+        let chapters = chapters(
+            0,
+            encoded_opus_buf.asc_iter().skip(buffered - count),
+            &drain.cues,
+        );
+        let chat = chat_sidecar(
+            0,
+            encoded_opus_buf.asc_iter().skip(buffered - count),
+            &drain.chat,
+        );
+        let (head, _) = encode::opus_headers::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(options);
+        let tags = encode::opus_tags(&encode::chapter_comments(&chapters));
+        let (bytes, packets, samples) =
+            lock_recovering(premuxer, "premuxer").stream(count, &head, &tags, options.serial);
+        drop(encoded_opus_buf);
+        tracing::info!("copied {} premuxed packets", packets);
+        DumpResult {
+            bytes,
+            packets,
+            duration: Duration::from_micros(samples * 1_000_000 / AUDIO_FREQUENCY as u64),
+            timestamps: None,
+            chapters,
+            chat,
+            right_channel: None,
+        }
+    }
+
+    /// Drains and encodes the most recent `duration` the way a dump does, then decodes it back.
+    /// Only a short slice, so it's cheap enough to run periodically. Poisoned locks are recovered
+    /// like they are on a dump, so they can't fail it, [`crate::self_check`] reports them instead.
+    pub fn self_check(&self, duration: Duration) -> anyhow::Result<SelfCheck> {
+        let dump = self.drain_buffer(
+            &DrainOptions {
+                duration: Some(duration),
+                ..Default::default()
+            },
+            &Default::default(),
+        )?;
+        let packets = decode::demux(&dump.bytes)?;
+        Ok(SelfCheck {
+            buffered: self.buffered_duration(),
+            packets: packets.len(),
+            bytes: dump.bytes.len(),
+            undecodable: decode::count_undecodable(&packets)?,
+        })
+    }
+
+    /// Like [`Self::drain_buffer`] without the ogg container, see
+    /// [`encode::encode_length_prefixed`].
+    pub fn drain_raw_packets(&self, drain: &DrainOptions) -> anyhow::Result<DumpResult> {
+        let drained = self.drain_packets(drain)?;
+        let bytes = encode::encode_length_prefixed(&drained.packets)?;
+        Ok(DumpResult::new(bytes, drained))
+    }
+
+    /// Like [`Self::drain_buffer`] cut into standalone streams of `segment` each, the last one
+    /// shorter. Cuts fall between packets and every stream's granule positions start at 0, so
+    /// played back to back they cover the same timeline as one dump would.
+    pub fn drain_segments(
+        &self,
+        drain: &DrainOptions,
+        segment: Duration,
+    ) -> anyhow::Result<Vec<DumpResult>> {
+        let drained = self.drain_packets(drain)?;
+        let mut segments = Vec::new();
         let mut packets = Vec::new();
-        {
-            // closure to limit lock scope
-            let encoded_opus_buf = self
-                .encoded_opus_buf
-                .lock()
-                .expect("encoded opus buf lock panicked");
-            tracing::info!("buf size before wav write {}", encoded_opus_buf.len());
-            packets.reserve(encoded_opus_buf.len());
-            for sample in encoded_opus_buf.asc_iter() {
-                packets.push(sample.clone());
+        let mut duration = Duration::ZERO;
+        for packet in drained.packets {
+            duration += packet_duration(&packet);
+            packets.push(packet);
+            if duration >= segment {
+                segments.push(std::mem::take(&mut packets));
+                duration = Duration::ZERO;
             }
         }
-        tracing::info!("dumped circ buff");
+        if !packets.is_empty() {
+            segments.push(packets);
+        }
+        segments
+            .into_iter()
+            .map(|packets| {
+                let bytes = encode::encode::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(&packets)?;
+                Ok(DumpResult::new(
+                    bytes,
+                    Drained {
+                        packets,
+                        timestamps: None,
+                        chapters: Vec::new(),
+                        chat: None,
+                    },
+                ))
+            })
+            .collect()
+    }
 
-        let trimmed_packets = if let Some(duration_to_dump) = duration_to_dump {
-            let packets_to_dump =
-                (duration_to_dump.as_millis() / PACKET_DURATION.as_millis()) as usize;
-            let packets_start_index = packets.len().saturating_sub(packets_to_dump);
-            &packets[packets_start_index..]
+    /// The packets a drain covers, marker included, their timestamp sidecar if asked for and
+    /// where the cues and chat messages fall.
+    fn drain_packets(&self, drain: &DrainOptions) -> anyhow::Result<Drained> {
+        let mut frames = self.snapshot_range(drain.duration, Duration::ZERO);
+        if let Some(since) = drain.since {
+            let start = frames.partition_point(|frame| frame.captured_at <= since);
+            frames.drain(..start);
+        }
+        if let Some(speaker) = drain.speaker {
+            frames.retain(|frame| frame.speakers.contains(&speaker));
+            if frames.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "the speaker wasn't heard in the exported range"
+                ));
+            }
+        }
+        if drain.trim_silence {
+            self.trim_frames(&mut frames);
+        }
+        let marker: &[bytes::Bytes] = if drain.with_marker {
+            &self.marker_encoded
         } else {
-            &packets
+            &[]
         };
+        let packets: Vec<_> = marker
+            .iter()
+            .cloned()
+            .chain(frames.iter().map(|frame| frame.packet.clone()))
+            .collect();
+        let packets = self.compact(packets);
+        let timestamps = drain
+            .with_timestamps
+            .then(|| timestamp_sidecar(marker, &frames));
+        let marker_samples = marker
+            .iter()
+            .map(|packet| packet_samples(packet) as u64)
+            .sum();
+        Ok(Drained {
+            packets,
+            timestamps,
+            chapters: chapters(marker_samples, &frames, &drain.cues),
+            chat: chat_sidecar(marker_samples, &frames, &drain.chat),
+        })
+    }
 
-        let ogg_data = encode::encode::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(trimmed_packets)?;
-        tracing::info!("done");
-        Ok(ogg_data)
+    /// Drops leading and trailing silent frames and shortens silent runs between them to the trim
+    /// gap. Returns how many frames went from the ends and how many from the gaps.
+    fn trim_frames(&self, frames: &mut Vec<Frame>) -> (usize, usize) {
+        let before = frames.len();
+        let is_speech = |frame: &Frame| frame.packet != self.empty_encoded;
+        let end = frames
+            .iter()
+            .rposition(is_speech)
+            .map_or(0, |last| last + 1);
+        frames.truncate(end);
+        let start = frames.iter().position(is_speech).unwrap_or(end);
+        frames.drain(..start);
+        let edges = before - frames.len();
+        let Some(max_gap) = self.trim_max_gap() else {
+            return (edges, 0);
+        };
+        let max_gap_frames = self.encoder_settings.packets_for_duration(max_gap);
+        let mut silent_run = 0;
+        frames.retain(|frame| {
+            if is_speech(frame) {
+                silent_run = 0;
+                return true;
+            }
+            silent_run += 1;
+            silent_run <= max_gap_frames
+        });
+        (edges, before - edges - frames.len())
+    }
+
+    /// What trimming the last `duration`, or the whole lookback, would remove, without encoding.
+    pub fn trim_preview(&self, duration: Option<Duration>) -> TrimPreview {
+        let mut frames = self.snapshot_range(duration, Duration::ZERO);
+        let before = frames.len();
+        let (edges, gaps) = self.trim_frames(&mut frames);
+        let frame_duration = self.encoder_settings.frame_duration;
+        TrimPreview {
+            before: frame_duration * before as u32,
+            after: frame_duration * frames.len() as u32,
+            edges: frame_duration * edges as u32,
+            gaps: frame_duration * gaps as u32,
+        }
+    }
+
+    pub fn trim_max_gap(&self) -> Option<Duration> {
+        *lock_recovering(&self.trim_max_gap, "trim max gap")
+    }
+
+    pub fn set_trim_max_gap(&self, max_gap: Option<Duration>) {
+        *lock_recovering(&self.trim_max_gap, "trim max gap") = max_gap;
+    }
+
+    /// Encodes the window from `start_ago` to `end_ago` before now, e.g. 10m ago to 8m ago.
+    pub fn drain_range(
+        &self,
+        start_ago: Duration,
+        end_ago: Duration,
+        options: &StreamOptions,
+    ) -> anyhow::Result<Vec<u8>> {
+        let packets: Vec<_> = self
+            .snapshot_range(Some(start_ago), end_ago)
+            .into_iter()
+            .map(|frame| frame.packet)
+            .collect();
+        let packets = self.compact(packets);
+        encode::encode_with_options::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(&packets, options)
+    }
+
+    /// Appends silence covering `gap`, e.g. while the voice connection was down, so audio after it
+    /// stays the right distance from audio before it. Returns the padding frames.
+    pub fn pad_silence(&self, gap: Duration) -> Vec<bytes::Bytes> {
+        let mut encoded_opus_buf = lock_recovering(&self.encoded_opus_buf, "encoded opus buf");
+        let frames = self
+            .encoder_settings
+            .packets_for_duration(gap)
+            .min(encoded_opus_buf.capacity());
+        let now = SystemTime::now();
+        for remaining in (0..frames).rev() {
+            let ago = self.encoder_settings.frame_duration * remaining as u32;
+            encoded_opus_buf.push(Frame {
+                captured_at: now.checked_sub(ago).unwrap_or(now),
+                packet: self.empty_encoded.clone(),
+                speakers: Default::default(),
+            });
+        }
+        if let Some(premuxer) = &self.premuxer {
+            let mut premuxer = lock_recovering(premuxer, "premuxer");
+            for _ in 0..frames {
+                premuxer.push(self.empty_encoded.clone());
+            }
+        }
+        vec![self.empty_encoded.clone(); frames]
+    }
+
+    /// Forgets all buffered audio, the lookback fills up again from the next tick. The packets are
+    /// dropped, only the queue's fixed slots stay allocated.
+    pub fn clear(&self) {
+        let mut encoded_opus_buf = lock_recovering(&self.encoded_opus_buf, "encoded opus buf");
+        encoded_opus_buf.clear();
+        if let Some(premuxer) = &self.premuxer {
+            lock_recovering(premuxer, "premuxer").clear();
+        }
+        drop(encoded_opus_buf);
+        lock_recovering(&self.injected, "injected audio").clear();
+        lock_recovering(&self.pending_speakers, "pending speakers").clear();
+    }
+
+    /// Time since the last tick, or since the lookback was created before the first.
+    pub fn since_last_tick(&self) -> Duration {
+        lock_recovering(&self.last_tick, "last tick").elapsed()
+    }
+
+    pub fn buffered_duration(&self) -> Duration {
+        let buffered_packets = lock_recovering(&self.encoded_opus_buf, "encoded opus buf").len();
+        self.encoder_settings.frame_duration * buffered_packets as u32
+    }
+
+    /// The alignment beep on its own as an ogg file, to play into the channel.
+    pub fn marker_ogg(&self) -> anyhow::Result<Vec<u8>> {
+        encode::encode::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(&self.marker_encoded)
+    }
+
+    /// Start of the first frame captured after `since` where `frequency` dominates, e.g. the marker
+    /// coming back through someone's mic. Accurate to a frame.
+    pub fn find_tone(
+        &self,
+        since: SystemTime,
+        frequency: f32,
+    ) -> anyhow::Result<Option<SystemTime>> {
+        let mut frames: Vec<Frame> = lock_recovering(&self.encoded_opus_buf, "encoded opus buf")
+            .iter()
+            .take_while(|frame| frame.captured_at > since)
+            .cloned()
+            .collect();
+        frames.reverse();
+        let packets: Vec<_> = frames.iter().map(|frame| frame.packet.clone()).collect();
+        let pcm = decode::decode_opus(&packets)?;
+        let mut offset = 0;
+        for frame in &frames {
+            let len = packet_samples(&frame.packet) * AUDIO_CHANNELS as usize;
+            let Some(frame_pcm) = pcm.get(offset..offset + len) else {
+                break;
+            };
+            offset += len;
+            if is_tone(frame_pcm, frequency) {
+                return Ok(Some(frame.captured_at - packet_duration(&frame.packet)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn compact(&self, packets: Vec<bytes::Bytes>) -> Vec<bytes::Bytes> {
+        if !self.compact_silence {
+            return packets;
+        }
+        SilencePacker::pack(self.empty_encoded.clone(), &packets)
+    }
+
+    /// [`Self::snapshot_range`] of the whole buffer, the copy every dump starts with, for
+    /// `benches/drain.rs`. Returns how many frames it copied.
+    #[doc(hidden)]
+    pub fn snapshot_all(&self) -> usize {
+        self.snapshot_range(None, Duration::ZERO).len()
+    }
+
+    /// Clones out the packets from `start_ago` (the oldest packet if `None`) up to `end_ago`
+    /// before now, oldest first.
+    fn snapshot_range(&self, start_ago: Option<Duration>, end_ago: Duration) -> Vec<Frame> {
+        let to_packets = |duration: Duration| self.encoder_settings.packets_for_duration(duration);
+        let encoded_opus_buf = lock_recovering(&self.encoded_opus_buf, "encoded opus buf");
+        tracing::info!("buf size before wav write {}", encoded_opus_buf.len());
+        let len = encoded_opus_buf.len();
+        let start = start_ago.map_or(0, |start_ago| len.saturating_sub(to_packets(start_ago)));
+        let end = len.saturating_sub(to_packets(end_ago)).max(start);
+        let packets = encoded_opus_buf
+            .asc_iter()
+            .skip(start)
+            .take(end - start)
+            .cloned()
+            .collect();
+        tracing::info!("dumped circ buff");
+        packets
+    }
+}
+
+/// CSV mapping each recorded packet to when it was captured, one `granule_position,captured_at`
+/// row per packet. The granule position is the packet's end position in the dumped ogg file (48kHz
+/// samples, counting the marker if there is one) and `captured_at` is the UTC wall clock time the
+/// frame finished capturing in RFC 3339 with milliseconds.
+fn timestamp_sidecar(marker: &[bytes::Bytes], frames: &[Frame]) -> String {
+    let mut granule: u64 = marker
+        .iter()
+        .map(|packet| packet_samples(packet) as u64)
+        .sum();
+    let mut sidecar = String::from("granule_position,captured_at\n");
+    for frame in frames {
+        granule += packet_samples(&frame.packet) as u64;
+        let captured_at = chrono::DateTime::<chrono::Utc>::from(frame.captured_at)
+            .to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        sidecar.push_str(&format!("{},{}\n", granule, captured_at));
+    }
+    sidecar
+}
+
+/// Offset of each cue in a dump of `frames` starting at granule `start`, at the start of the first
+/// frame that finished capturing after the cue. Cues from before the first frame are left out,
+/// ones after the last one, e.g. placed right before dumping, go at the end.
+fn chapters<'a>(
+    start: u64,
+    frames: impl IntoIterator<Item = &'a Frame>,
+    cues: &[(SystemTime, String)],
+) -> Vec<(Duration, String)> {
+    let to_duration =
+        |granule: u64| Duration::from_micros(granule * 1_000_000 / AUDIO_FREQUENCY as u64);
+    let mut cues = cues.iter().peekable();
+    let mut chapters = Vec::new();
+    let mut granule = start;
+    let mut frames = frames.into_iter().peekable();
+    let Some(first) = frames.peek() else {
+        return chapters;
+    };
+    let first_start = first.captured_at - packet_duration(&first.packet);
+    while cues.next_if(|(at, _)| *at < first_start).is_some() {}
+    for frame in frames {
+        while let Some((_, label)) = cues.next_if(|(at, _)| *at <= frame.captured_at) {
+            chapters.push((to_duration(granule), label.clone()));
+        }
+        granule += packet_samples(&frame.packet) as u64;
+    }
+    chapters.extend(cues.map(|(_, label)| (to_duration(granule), label.clone())));
+    chapters
+}
+
+/// CSV of the chat messages posted while a dump of `frames` starting at granule `start` was
+/// captured, one `offset,posted_at,author,content` row per message, oldest first. The offset is in
+/// seconds into the dump, placed like cues are by [`chapters`], and `posted_at` is the UTC wall
+/// clock time in RFC 3339 with milliseconds. `None` if no message falls in the dump.
+fn chat_sidecar<'a>(
+    start: u64,
+    frames: impl IntoIterator<Item = &'a Frame>,
+    chat: &[ChatMessage],
+) -> Option<String> {
+    let rows: Vec<_> = chat
+        .iter()
+        .map(|message| {
+            let posted_at = chrono::DateTime::<chrono::Utc>::from(message.posted_at)
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+            let row = format!(
+                "{},{},{}",
+                posted_at,
+                csv_field(&message.author),
+                csv_field(&message.content)
+            );
+            (message.posted_at, row)
+        })
+        .collect();
+    let placed = chapters(start, frames, &rows);
+    if placed.is_empty() {
+        return None;
+    }
+    let mut sidecar = String::from("offset,posted_at,author,content\n");
+    for (offset, row) in placed {
+        sidecar.push_str(&format!("{:.3},{}\n", offset.as_secs_f64(), row));
+    }
+    Some(sidecar)
+}
+
+/// Quotes a CSV field if it holds a separator, quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn mix_into(mix_buf: &mut MixAudioPacket, audio: &MixAudioPacket) {
+    for i in 0..AUDIO_PACKET_SIZE {
+        mix_buf[i] += audio[i];
+    }
+}
+
+/// Whether most of the energy of interleaved pcm is at `frequency`, by a Goertzel filter over the
+/// mono downmix. Quiet frames never count so noise that happens to sit there is left out.
+fn is_tone(pcm: &[i16], frequency: f32) -> bool {
+    const FLOOR_DBFS: f64 = -50.0;
+    const MIN_SHARE: f64 = 0.5;
+    let mono: Vec<f64> = pcm
+        .chunks_exact(AUDIO_CHANNELS as usize)
+        .map(|frame| frame.iter().map(|sample| *sample as f64).sum::<f64>() / frame.len() as f64)
+        .collect();
+    let energy: f64 = mono.iter().map(|sample| sample * sample).sum();
+    let floor = 10f64.powf(FLOOR_DBFS / 10.0) * (i16::MAX as f64).powi(2) * mono.len() as f64;
+    if mono.is_empty() || energy < floor {
+        return false;
+    }
+    let coefficient =
+        2.0 * (std::f64::consts::TAU * frequency as f64 / AUDIO_FREQUENCY as f64).cos();
+    let (mut previous, mut before) = (0.0, 0.0);
+    for sample in &mono {
+        let current = sample + coefficient * previous - before;
+        before = previous;
+        previous = current;
+    }
+    let power = previous * previous + before * before - coefficient * previous * before;
+    // a pure tone's power is N/2 times its energy
+    power / (energy * mono.len() as f64 / 2.0) >= MIN_SHARE
+}
+
+/// Full scale sine beep rounded up to whole packets. Uses its own encoder so the live mix encoder
+/// state is left alone.
+fn encode_marker(config: &Config) -> Vec<bytes::Bytes> {
+    let opus_encoder = make_opus_encoder(&config.encoder);
+    let mut output_scratch_space = [0; MAX_OPUS_PACKET];
+    let packet_count = packets_covering(config.marker_duration, PACKET_DURATION).max(1);
+    let frames_per_packet = AUDIO_PACKET_SIZE / AUDIO_CHANNELS as usize;
+
+    let mut packets = Vec::with_capacity(packet_count);
+    for packet_index in 0..packet_count {
+        let mut pcm = empty_raw_audio();
+        for (frame_index, frame) in pcm.chunks_exact_mut(AUDIO_CHANNELS as usize).enumerate() {
+            let t =
+                (packet_index * frames_per_packet + frame_index) as f32 / AUDIO_FREQUENCY as f32;
+            let sample = (std::f32::consts::TAU * config.marker_frequency * t).sin();
+            frame.fill((sample * i16::MAX as f32) as i16);
+        }
+        let written_size = opus_encoder
+            .encode(&pcm, &mut output_scratch_space)
+            .expect("failed to encode marker");
+        packets.push(bytes::Bytes::copy_from_slice(
+            &output_scratch_space[..written_size],
+        ));
+    }
+    packets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::receiver::tests::tone;
+    use crate::receiver::Tick;
+
+    fn lookback(configure: impl FnOnce(&mut Config)) -> Lookback {
+        let mut config = Config::for_tests();
+        configure(&mut config);
+        Lookback::new(&config)
+    }
+
+    /// One tick of `ssrc` speaking `audio`, nobody mapped or panned.
+    fn tick(lookback: &Lookback, ssrc: u32, audio: &RawAudioPacket) -> Option<bytes::Bytes> {
+        lookback.tick(
+            &Tick {
+                speaking: vec![(ssrc, Some(audio))],
+                ..Default::default()
+            },
+            |_| None,
+            |_| None,
+        )
+    }
+
+    #[test]
+    fn packet_counts_round_at_partial_frames() {
+        let ms = Duration::from_millis;
+        assert_eq!(packets_for_duration(ms(59), ms(20)), 2);
+        assert_eq!(packets_for_duration(ms(60), ms(20)), 3);
+        assert_eq!(packets_for_duration(ms(61), ms(20)), 3);
+        assert_eq!(packets_for_duration(ms(19), ms(20)), 0);
+        assert_eq!(packets_covering(ms(59), ms(20)), 3);
+        assert_eq!(packets_covering(ms(60), ms(20)), 3);
+        assert_eq!(packets_covering(ms(61), ms(20)), 4);
+        assert_eq!(packets_covering(Duration::ZERO, ms(20)), 0);
+        // not truncated to whole milliseconds before dividing
+        assert_eq!(
+            packets_for_duration(Duration::from_micros(39_999), ms(20)),
+            1
+        );
+        assert_eq!(packets_covering(Duration::from_micros(40_001), ms(20)), 3);
+        assert_eq!(packets_for_duration(ms(179), ms(60)), 2);
+        assert_eq!(packets_covering(ms(121), ms(60)), 3);
+    }
+
+    /// Decoded pcm of everything in the lookback.
+    fn drained_pcm(lookback: &Lookback) -> Vec<i16> {
+        let dump = lookback
+            .drain_buffer(&Default::default(), &Default::default())
+            .unwrap();
+        decode::decode_opus(&decode::demux(&dump.bytes).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn injected_audio_is_in_the_drained_mix() {
+        let lookback = lookback(|_| {});
+        lookback.inject(vec![tone(); 5]);
+        for _ in 0..5 {
+            lookback.tick(&Tick::default(), |_| None, |_| None);
+        }
+
+        let pcm = drained_pcm(&lookback);
+        assert_eq!(pcm.len(), 5 * AUDIO_PACKET_SIZE);
+        let peak = pcm
+            .iter()
+            .map(|sample| sample.unsigned_abs())
+            .max()
+            .unwrap();
+        assert!(peak > 4000, "peak {}", peak);
+    }
+
+    #[test]
+    fn cancelled_injection_is_no_longer_mixed() {
+        let lookback = lookback(|_| {});
+        let cancelled = lookback.inject(vec![tone(); 10]);
+        lookback.inject(vec![tone(); 5]);
+        for _ in 0..5 {
+            lookback.tick(&Tick::default(), |_| None, |_| None);
+        }
+        cancelled.cancel();
+        for _ in 0..5 {
+            lookback.tick(&Tick::default(), |_| None, |_| None);
+        }
+
+        let pcm = drained_pcm(&lookback);
+        assert_eq!(pcm.len(), 10 * AUDIO_PACKET_SIZE);
+        let peak = |pcm: &[i16]| {
+            pcm.iter()
+                .map(|sample| sample.unsigned_abs())
+                .max()
+                .unwrap()
+        };
+        assert!(peak(&pcm[..4 * AUDIO_PACKET_SIZE]) > 4000);
+        // a couple of packets of slack for the codec's delay
+        let after = peak(&pcm[7 * AUDIO_PACKET_SIZE..]);
+        assert!(after < 100, "peak after cancelling {}", after);
+    }
+
+    /// RMS of `speakers` each saying their packet for a few ticks, mixed with `mix_mode`.
+    fn mixed_level(mix_mode: MixMode, speakers: &[RawAudioPacket]) -> f32 {
+        let lookback = lookback(|config| config.mix_mode = mix_mode);
+        for _ in 0..10 {
+            lookback.tick(
+                &Tick {
+                    speaking: (0..)
+                        .zip(speakers)
+                        .map(|(ssrc, audio)| (ssrc, Some(&audio[..])))
+                        .collect(),
+                    ..Default::default()
+                },
+                |_| None,
+                |_| None,
+            );
+        }
+        // past the encoder's startup
+        let pcm = drained_pcm(&lookback);
+        let settled = &pcm[pcm.len() / 2..];
+        let power = settled
+            .iter()
+            .map(|&sample| (sample as f32).powi(2))
+            .sum::<f32>()
+            / settled.len() as f32;
+        power.sqrt()
+    }
+
+    /// `speakers` people saying the same thing.
+    fn chorus(speakers: usize) -> Vec<RawAudioPacket> {
+        vec![tone(); speakers]
+    }
+
+    #[test]
+    fn normalized_mix_level_is_bounded_by_speaker_count() {
+        let single = mixed_level(MixMode::Normalize, &chorus(1));
+        for speakers in 2..=8 {
+            let level = mixed_level(MixMode::Normalize, &chorus(speakers));
+            // identical voices add up coherently, so sqrt(n) louder is the most normalizing allows
+            assert!(
+                level <= single * (speakers as f32).sqrt() * 1.1,
+                "{} speakers at {} vs {} alone",
+                speakers,
+                level,
+                single
+            );
+            assert!(
+                level < i16::MAX as f32 * 0.8,
+                "{} speakers at {}",
+                speakers,
+                level
+            );
+        }
+        // compared below full scale, at 8 the plain sum is already held down by clipping
+        assert!(
+            mixed_level(MixMode::Normalize, &chorus(4))
+                < mixed_level(MixMode::Sum, &chorus(4)) * 0.8
+        );
+    }
+
+    #[test]
+    fn overlap_is_not_clipped_before_it_cancels() {
+        let loud = tone().map(|sample| sample / 2 * 5);
+        let inverted = loud.map(|sample| -sample);
+        // summed as i16 the first two saturate, and the third then takes the peak well below
+        // what one speaker alone reaches
+        let saturating = loud[0].saturating_add(loud[0]).saturating_add(inverted[0]);
+        assert_eq!(saturating, i16::MAX - loud[0]);
+
+        let alone = mixed_level(MixMode::Sum, &[loud]);
+        let overlapped = mixed_level(MixMode::Sum, &[loud, loud, inverted]);
+        assert!(
+            (overlapped - alone).abs() < alone * 0.05,
+            "{} overlapped vs {} alone",
+            overlapped,
+            alone
+        );
+    }
+
+    #[test]
+    fn speaker_dump_keeps_only_their_frames() {
+        let lookback = lookback(|_| {});
+        let audio = tone();
+        let user_of = |ssrc| Some(UserId(ssrc as u64));
+        let mut theirs = Vec::new();
+        for i in 0..8 {
+            let ssrc = if i % 2 == 0 { 1 } else { 2 };
+            let tick = Tick {
+                speaking: vec![(ssrc, Some(&audio[..]))],
+                ..Default::default()
+            };
+            let packet = lookback.tick(&tick, user_of, |_| None).unwrap();
+            if ssrc == 1 {
+                theirs.push(packet);
+            }
+        }
+
+        let dump = lookback
+            .drain_buffer(
+                &DrainOptions {
+                    speaker: Some(UserId(1)),
+                    ..Default::default()
+                },
+                &Default::default(),
+            )
+            .unwrap();
+        assert_eq!(dump.packets, 4);
+        assert_eq!(decode::demux(&dump.bytes).unwrap(), theirs);
+    }
+
+    #[test]
+    fn cues_become_chapters_in_the_dump() {
+        for premux_lookback in [false, true] {
+            let lookback = lookback(|config| config.premux_lookback = premux_lookback);
+            let audio = tone();
+            for _ in 0..2 {
+                tick(&lookback, 1, &audio);
+            }
+            std::thread::sleep(Duration::from_millis(2));
+            let cue = SystemTime::now();
+            std::thread::sleep(Duration::from_millis(2));
+            for _ in 0..3 {
+                tick(&lookback, 1, &audio);
+            }
+
+            let dump = lookback
+                .drain_buffer(
+                    &DrainOptions {
+                        cues: vec![(cue, "topic change".to_string())],
+                        ..Default::default()
+                    },
+                    &Default::default(),
+                )
+                .unwrap();
+            let chapter = (Duration::from_millis(40), "topic change".to_string());
+            assert_eq!(dump.chapters, [chapter]);
+            let mut reader = ogg::PacketReader::new(std::io::Cursor::new(&dump.bytes));
+            reader.read_packet().unwrap();
+            let tags = reader.read_packet().unwrap().unwrap().data;
+            for comment in ["CHAPTER001=00:00:00.040", "CHAPTER001NAME=topic change"] {
+                assert!(
+                    tags.windows(comment.len())
+                        .any(|window| window == comment.as_bytes()),
+                    "{} missing with premux {}",
+                    comment,
+                    premux_lookback
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn chat_lands_in_the_sidecar_in_order() {
+        let lookback = lookback(|_| {});
+        let audio = tone();
+        let mut chat = Vec::new();
+        let mut post = |author: &str, content: &str| {
+            std::thread::sleep(Duration::from_millis(2));
+            chat.push(ChatMessage {
+                posted_at: SystemTime::now(),
+                author: author.to_string(),
+                content: content.to_string(),
+            });
+            std::thread::sleep(Duration::from_millis(2));
+        };
+        for _ in 0..2 {
+            tick(&lookback, 1, &audio);
+        }
+        post("alice", "first");
+        for _ in 0..3 {
+            tick(&lookback, 1, &audio);
+        }
+        post("bob", "second, with a comma");
+        post("alice", "third");
+        tick(&lookback, 1, &audio);
+
+        let dump = lookback
+            .drain_buffer(
+                &DrainOptions {
+                    chat,
+                    ..Default::default()
+                },
+                &Default::default(),
+            )
+            .unwrap();
+        let sidecar = dump.chat.unwrap();
+        let mut rows = sidecar.lines();
+        assert_eq!(rows.next(), Some("offset,posted_at,author,content"));
+        let rows: Vec<_> = rows
+            .map(|row| {
+                let (offset, rest) = row.split_once(',').unwrap();
+                let (_posted_at, message) = rest.split_once(',').unwrap();
+                (offset, message)
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                ("0.040", "alice,first"),
+                ("0.100", "bob,\"second, with a comma\""),
+                ("0.100", "alice,third"),
+            ]
+        );
+    }
+
+    #[test]
+    fn segments_concatenate_to_the_full_timeline() {
+        let lookback = lookback(|_| {});
+        let audio = tone();
+        for i in 0..25 {
+            match i % 3 {
+                0 => lookback.tick(&Tick::default(), |_| None, |_| None),
+                _ => tick(&lookback, 1, &audio),
+            };
+        }
+
+        let drain = DrainOptions::default();
+        let whole = lookback.drain_buffer(&drain, &Default::default()).unwrap();
+        let segments = lookback
+            .drain_segments(&drain, Duration::from_millis(200))
+            .unwrap();
+        assert_eq!(segments.len(), 3);
+        assert!(segments[2].duration < segments[0].duration);
+        let mut concatenated = Vec::new();
+        for segment in &segments {
+            let packets = decode::demux(&segment.bytes).unwrap();
+            // every segment's granule positions start over
+            let samples: u64 = packets
+                .iter()
+                .map(|packet| packet_samples(packet) as u64)
+                .sum();
+            let mut reader = ogg::PacketReader::new(std::io::Cursor::new(&segment.bytes));
+            let mut last = None;
+            while let Some(packet) = reader.read_packet().unwrap() {
+                last = Some(packet);
+            }
+            assert_eq!(last.unwrap().absgp_page(), samples);
+            concatenated.extend(packets);
+        }
+        assert_eq!(concatenated, decode::demux(&whole.bytes).unwrap());
+        let total: Duration = segments.iter().map(|segment| segment.duration).sum();
+        assert_eq!(total, whole.duration);
+    }
+
+    #[test]
+    fn drained_bytes_are_the_muxed_packets() {
+        let lookback = lookback(|_| {});
+        let audio = tone();
+        let user_of = |ssrc| Some(UserId(ssrc as u64));
+        let mut packets = Vec::new();
+        let mut theirs = Vec::new();
+        for i in 0..12 {
+            let tick = match i % 3 {
+                0 => Tick::default(),
+                ssrc => Tick {
+                    speaking: vec![(ssrc, Some(&audio[..]))],
+                    ..Default::default()
+                },
+            };
+            let packet = lookback.tick(&tick, user_of, |_| None).unwrap();
+            if i % 3 == 1 {
+                theirs.push(packet.clone());
+            }
+            packets.push(packet);
+        }
+
+        // a fixed serial, so the bytes only depend on the packets and not on how the frames keep
+        // their speakers
+        let options = StreamOptions {
+            serial: 0x5eed_0198,
+            ..Default::default()
+        };
+        let muxed =
+            encode::encode_with_options::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(&packets, &options)
+                .unwrap();
+        for _ in 0..2 {
+            let dump = lookback
+                .drain_buffer(&Default::default(), &options)
+                .unwrap();
+            assert_eq!(dump.bytes, muxed);
+        }
+        let dump = lookback
+            .drain_buffer(
+                &DrainOptions {
+                    speaker: Some(UserId(1)),
+                    ..Default::default()
+                },
+                &options,
+            )
+            .unwrap();
+        let muxed =
+            encode::encode_with_options::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(&theirs, &options)
+                .unwrap();
+        assert_eq!(dump.bytes, muxed);
+    }
+
+    #[test]
+    fn dump_and_clear_recover_a_poisoned_buffer() {
+        let lookback = lookback(|config| config.premux_lookback = true);
+        for _ in 0..5 {
+            tick(&lookback, 1, &tone());
+        }
+        std::thread::scope(|scope| {
+            let poisoner = scope.spawn(|| {
+                let _buf = lookback.encoded_opus_buf.lock().unwrap();
+                let _premuxer = lookback.premuxer.as_ref().unwrap().lock().unwrap();
+                panic!("poisoning the lookback");
+            });
+            assert!(poisoner.join().is_err());
+        });
+        assert!(lookback.encoded_opus_buf.is_poisoned());
+
+        let dump = lookback
+            .drain_buffer(&Default::default(), &Default::default())
+            .unwrap();
+        assert_eq!(dump.packets, 5);
+        lookback.clear();
+        assert_eq!(lookback.buffered_duration(), Duration::ZERO);
+        assert!(!lookback.encoded_opus_buf.is_poisoned());
+        assert!(!lookback.premuxer.as_ref().unwrap().is_poisoned());
+    }
+
+    #[test]
+    fn sixty_ms_frames_cover_three_ticks() {
+        let lookback = lookback(|config| config.encoder.frame_duration = Duration::from_millis(60));
+        let audio = tone();
+        let frames: Vec<_> = (0..9).filter_map(|_| tick(&lookback, 1, &audio)).collect();
+        assert_eq!(frames.len(), 3);
+        assert!(frames
+            .iter()
+            .all(|frame| packet_duration(frame) == Duration::from_millis(60)));
+        assert_eq!(lookback.buffered_duration(), Duration::from_millis(180));
+
+        let dump = lookback
+            .drain_buffer(&Default::default(), &Default::default())
+            .unwrap();
+        assert_eq!(dump.packets, 3);
+        assert_eq!(dump.duration, Duration::from_millis(180));
     }
 }
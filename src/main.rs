@@ -9,18 +9,12 @@ use serenity::client::Client;
 use serenity::model::id::{ChannelId, GuildId};
 use serenity::prelude::GatewayIntents;
 use songbird::{Config, driver::DecodeMode, SerenityInit};
-#[cfg(not(target_env = "msvc"))]
+#[cfg(all(feature = "jemalloc", not(target_env = "msvc")))]
 use tikv_jemallocator::Jemalloc;
 
-use receiver::Receiver;
+use rust_discord_record::{config, discord, receiver::Receiver};
 
-mod discord;
-mod encode;
-mod receiver;
-mod tts;
-mod lookback;
-
-#[cfg(not(target_env = "msvc"))]
+#[cfg(all(feature = "jemalloc", not(target_env = "msvc")))]
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
@@ -39,36 +33,101 @@ async fn main() -> anyhow::Result<()> {
     let text_channel_id = env::var("TEXT_CHANNEL_ID")
         .expect("Expected a text channel id in the environment")
         .parse()?;
+    // status messages go to their own channel if there is one
+    let log_channel_id = match env::var("LOG_CHANNEL_ID") {
+        Ok(log_channel_id) => log_channel_id.parse()?,
+        Err(_) => text_channel_id,
+    };
+    let config = config::Config::from_env()?;
+    tracing::info!(
+        "opus encoders using application mode {:?}",
+        config.encoder.application
+    );
+    let receiver = Arc::new(Receiver::new(GuildId::new(guild_id), config));
+
+    let mut commands = vec![
+        discord::export(),
+        discord::dump(),
+        discord::redump(),
+        discord::formats(),
+        discord::info(),
+        discord::ssrc(),
+        discord::encoder(),
+        discord::volume(),
+        discord::ping(),
+        discord::latency(),
+        discord::trim(),
+        discord::tag(),
+        discord::lastclip(),
+        discord::compact(),
+        discord::replay_range(),
+        discord::export_users(),
+        discord::export_leveled(),
+        discord::export_segments(),
+        discord::waveform(),
+        discord::duration(),
+        discord::trimpreview(),
+        discord::mark(),
+        discord::cue(),
+        discord::reset(),
+        discord::follow(),
+        discord::unfollow(),
+        discord::help(),
+    ];
+    // voice cloning is only useful with a tts backend to send the reference to
+    if receiver.config.tts_host.is_some() {
+        commands.extend([discord::clone(), discord::ctts(), discord::verify()]);
+    } else {
+        tracing::info!("TTS_HOST is not set, clone, ctts and verify commands are disabled");
+    }
+
+    for name in &receiver.config.slash_only_commands {
+        match commands.iter_mut().find(|command| &command.name == name) {
+            Some(command) => discord::make_slash_only(command),
+            None => tracing::warn!("SLASH_ONLY_COMMANDS names unknown command '{}'", name),
+        }
+    }
+
+    let intents = receiver.config.intents;
+    // reading `!` commands needs the privileged message content intent, slash commands don't
+    let prefix_intents = GatewayIntents::GUILD_MESSAGES | GatewayIntents::MESSAGE_CONTENT;
+    let prefix = intents.contains(prefix_intents).then(|| "!".to_string());
+    if prefix.is_none() {
+        tracing::info!("message content intent not enabled, only slash commands are available");
+    }
 
     let framework = poise::Framework::builder()
         .options(poise::FrameworkOptions {
-            commands: vec![discord::dump(), discord::clone(), discord::ctts()],
+            commands,
             prefix_options: poise::PrefixFrameworkOptions {
-                prefix: Some("!".into()),
+                prefix,
                 ..Default::default()
             },
+            event_handler: |ctx, event, _framework, receiver| {
+                Box::pin(discord::on_event(ctx, event, receiver))
+            },
             ..Default::default()
         })
-        .setup(move |ctx, ready, framework| {
-            Box::pin(async move {
-                let receiver = Arc::new(Receiver::new(GuildId::new(guild_id)));
-                discord::on_ready(
-                    ctx,
-                    ready,
-                    GuildId::new(guild_id),
-                    ChannelId::new(voice_channel_id),
-                    ChannelId::new(text_channel_id),
-                    receiver.clone(),
-                )
-                .await?;
-                poise::builtins::register_globally(ctx, &framework.options().commands).await?;
-                Ok(receiver)
-            })
+        .setup({
+            let receiver = receiver.clone();
+            move |ctx, ready, framework| {
+                Box::pin(async move {
+                    discord::on_ready(
+                        ctx,
+                        ready,
+                        GuildId::new(guild_id),
+                        ChannelId::new(voice_channel_id),
+                        ChannelId::new(log_channel_id),
+                        receiver.clone(),
+                    )
+                    .await?;
+                    poise::builtins::register_globally(ctx, &framework.options().commands).await?;
+                    Ok(receiver)
+                })
+            }
         })
         .build();
 
-    let intents = GatewayIntents::non_privileged() | GatewayIntents::MESSAGE_CONTENT;
-
     // Here, we need to configure Songbird to decode all incoming voice packets.
     // If you want, you can do this on a per-call basis---here, we need it to
     // read the audio data that other people are sending us!
@@ -80,5 +139,29 @@ async fn main() -> anyhow::Result<()> {
         .await
         .expect("Err creating client");
 
-    client.start().await.context("Client ended: {:?}")
+    let result = tokio::select! {
+        result = client.start() => result.context("Client ended: {:?}"),
+        _ = shutdown_signal() => {
+            tracing::info!("shutting down");
+            Ok(())
+        }
+    };
+    receiver.shutdown();
+    result
+}
+
+/// Resolves on ctrl-c, or SIGTERM on unix, so open archives can be finished before exiting.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("failed to install SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
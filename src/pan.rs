@@ -0,0 +1,123 @@
+use std::f32::consts::FRAC_PI_4;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use dashmap::DashMap;
+use songbird::model::id::UserId;
+
+use crate::config::{Config, PanMode};
+use crate::receiver::{AUDIO_CHANNELS, MixAudioPacket};
+
+/// Places each user somewhere in the stereo field of the lookback mix so overlapping speakers can
+/// be told apart. Positions run from -1 (left) to 1 (right), scaled by `PAN_WIDTH`.
+pub struct Panner {
+    mode: PanMode,
+    width: f32,
+    /// `spread` slot of each user, in the order they were first heard.
+    slots: DashMap<UserId, usize>,
+    next_slot: AtomicUsize,
+}
+
+impl Panner {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            mode: config.pan,
+            width: config.pan_width,
+            slots: Default::default(),
+            next_slot: Default::default(),
+        }
+    }
+
+    /// Where the user sits, `None` if panning is off.
+    pub fn position(&self, user: UserId) -> Option<f32> {
+        let position = match self.mode {
+            PanMode::Off => return None,
+            PanMode::Spread => {
+                let slot = *self
+                    .slots
+                    .entry(user)
+                    .or_insert_with(|| self.next_slot.fetch_add(1, Ordering::Relaxed));
+                spread_position(slot)
+            }
+            PanMode::User => user_position(user),
+        };
+        Some(position * self.width)
+    }
+}
+
+/// Fills the field without moving anyone already placed: center, hard left, hard right, then the
+/// midpoints of the widest gaps, i.e. 0, -1, 1, -0.5, 0.5, -0.75, -0.25, 0.25, 0.75, ...
+fn spread_position(slot: usize) -> f32 {
+    match slot {
+        0 => 0.0,
+        1 => -1.0,
+        2 => 1.0,
+        _ => {
+            // slot 3 onwards walks the odd multiples of 1/2^level across -1..1
+            let index = slot - 3;
+            let level = (usize::BITS - (index / 2 + 1).leading_zeros()) as i32;
+            let first_in_level = 2 * ((1 << (level - 1)) - 1);
+            let step = (index - first_in_level) as f32;
+            let segments = (1usize << level) as f32;
+            -1.0 + (2.0 * step + 1.0) / segments
+        }
+    }
+}
+
+/// Stable across sessions and restarts, the same user is always in the same place.
+fn user_position(user: UserId) -> f32 {
+    // splitmix64, std's hashers make no promise of staying the same between releases
+    let mut hash = user.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^= hash >> 31;
+    (hash >> 40) as f32 / (1u64 << 24) as f32 * 2.0 - 1.0
+}
+
+/// Constant power pan, the sides never get louder so panned audio can't clip.
+pub fn apply(packet: &mut MixAudioPacket, position: f32) {
+    let angle = (position.clamp(-1.0, 1.0) + 1.0) * FRAC_PI_4;
+    let gains = [angle.cos(), angle.sin()];
+    for frame in packet.chunks_exact_mut(AUDIO_CHANNELS as usize) {
+        for (sample, gain) in frame.iter_mut().zip(gains) {
+            *sample *= gain;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_position_is_stable() {
+        let user = UserId(80351110224678912);
+        assert_eq!(user_position(user), user_position(user));
+        // a change here moves everyone between releases
+        assert!((user_position(user) - 0.344149).abs() < 1e-5);
+    }
+
+    #[test]
+    fn user_positions_spread_across_the_field() {
+        // snowflakes of users who joined around the same time
+        let mut buckets = [0; 8];
+        for i in 0..1000 {
+            let position = user_position(UserId(80351110224678912 + (i << 22)));
+            assert!((-1.0..1.0).contains(&position));
+            buckets[((position + 1.0) * 4.0) as usize] += 1;
+        }
+        assert!(
+            buckets.iter().all(|count| (60..200).contains(count)),
+            "{:?}",
+            buckets
+        );
+    }
+
+    #[test]
+    fn spread_fills_the_widest_gaps_first() {
+        let positions: Vec<_> = (0..9).map(spread_position).collect();
+        assert_eq!(
+            positions,
+            [0.0, -1.0, 1.0, -0.5, 0.5, -0.75, -0.25, 0.25, 0.75]
+        );
+    }
+}
@@ -0,0 +1,402 @@
+use std::collections::VecDeque;
+
+use crate::encode::packet_samples;
+
+/// Most packets in one premuxed page, 1s of 20ms frames. A dump cuts into at most one page, which
+/// is muxed again, so smaller pages make that cheaper but store more page headers.
+const PACKETS_PER_PAGE: usize = 50;
+
+/// An ogg page holds at most 255 lacing values.
+const MAX_SEGMENTS: usize = 255;
+
+/// Bytes of the page header before the lacing values.
+const HEADER_LEN: usize = 27;
+
+/// Header bytes up to the CRC, the fields patched per dump all lie in them.
+const PATCHED_LEN: usize = 22;
+
+const HEADER_TYPE_BOS: u8 = 0x02;
+const HEADER_TYPE_EOS: u8 = 0x04;
+
+/// Generator polynomial of the ogg page CRC, not reflected, no initial value or final xor.
+const CRC_POLYNOMIAL: u32 = 0x04c1_1db7;
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+/// A page muxed once as packets arrive. The header type, granule position, serial and sequence
+/// number are left zero and filled in per dump, together with a CRC derived from `crc` without
+/// reading the page again, see [`Page::write_patched`].
+struct Page {
+    bytes: Vec<u8>,
+    /// CRC of the page with those fields zero.
+    crc: u32,
+    /// x^(8 * (len - PATCHED_LEN)) mod the CRC polynomial, shifts a CRC of the header fields
+    /// past the rest of the page.
+    shift: u32,
+    packets: Vec<bytes::Bytes>,
+    samples: u64,
+}
+
+impl Page {
+    fn new(packets: Vec<bytes::Bytes>) -> Self {
+        let mut bytes = Vec::new();
+        write_page(&mut bytes, &packets, 0, 0, 0, 0);
+        Self {
+            crc: u32::from_le_bytes(bytes[PATCHED_LEN..PATCHED_LEN + 4].try_into().unwrap()),
+            shift: crc_shift(bytes.len() - PATCHED_LEN),
+            samples: packets
+                .iter()
+                .map(|packet| packet_samples(packet) as u64)
+                .sum(),
+            bytes,
+            packets,
+        }
+    }
+
+    /// Appends the page with the fields of its place in a dump. The CRC is linear, so the CRC of
+    /// the patched page is the stored one xor the CRC of just the changed header bytes carried
+    /// past the rest of the page, which is independent of how long the page is.
+    fn write_patched(
+        &self,
+        out: &mut Vec<u8>,
+        header_type: u8,
+        granule: u64,
+        serial: u32,
+        sequence: u32,
+    ) {
+        let mut fields = [0u8; PATCHED_LEN];
+        fields[5] = header_type;
+        fields[6..14].copy_from_slice(&granule.to_le_bytes());
+        fields[14..18].copy_from_slice(&serial.to_le_bytes());
+        fields[18..22].copy_from_slice(&sequence.to_le_bytes());
+        let crc = self.crc ^ crc_mul(crc(&fields), self.shift);
+        let start = out.len();
+        out.extend_from_slice(&self.bytes);
+        for (byte, field) in out[start..start + PATCHED_LEN].iter_mut().zip(fields) {
+            *byte |= field;
+        }
+        out[start + PATCHED_LEN..start + PATCHED_LEN + 4].copy_from_slice(&crc.to_le_bytes());
+    }
+}
+
+/// The lookback's packets kept as ready made ogg pages as they're encoded, so a dump copies whole
+/// pages instead of muxing and checksumming every packet again. Costs about as much memory again
+/// as the lookback's encoded audio, the packets themselves are shared with it.
+pub struct Premuxer {
+    pages: VecDeque<Page>,
+    /// packets of the page being filled.
+    pending: Vec<bytes::Bytes>,
+    pending_segments: usize,
+    /// packets in `pages`.
+    packets: usize,
+    /// packets kept at least, the lookback's capacity.
+    capacity: usize,
+}
+
+impl Premuxer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            pages: Default::default(),
+            pending: Vec::new(),
+            pending_segments: 0,
+            packets: 0,
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, packet: bytes::Bytes) {
+        let segments = lacing_len(packet.len());
+        if self.pending.len() == PACKETS_PER_PAGE || self.pending_segments + segments > MAX_SEGMENTS
+        {
+            let packets = std::mem::take(&mut self.pending);
+            self.packets += packets.len();
+            self.pages.push_back(Page::new(packets));
+            self.pending_segments = 0;
+        }
+        self.pending.push(packet);
+        self.pending_segments += segments;
+        // the oldest page goes once the others hold the lookback's capacity without it
+        while let Some(oldest) = self.pages.front() {
+            if self.packets - oldest.packets.len() + self.pending.len() < self.capacity {
+                break;
+            }
+            self.packets -= oldest.packets.len();
+            self.pages.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pages.clear();
+        self.pending.clear();
+        self.pending_segments = 0;
+        self.packets = 0;
+    }
+
+    /// The most recent `count` packets as an ogg stream after the `head` and `tags` header
+    /// packets, with how many packets and samples it holds. The oldest page is muxed again if
+    /// only part of it is wanted, as is the page still being filled.
+    pub fn stream(
+        &self,
+        count: usize,
+        head: &[u8],
+        tags: &[u8],
+        serial: u32,
+    ) -> (Vec<u8>, usize, u64) {
+        let from_pending = count.min(self.pending.len());
+        let mut remaining = count - from_pending;
+        let mut whole_pages = 0;
+        let mut partial: &[bytes::Bytes] = &[];
+        for page in self.pages.iter().rev() {
+            if remaining == 0 {
+                break;
+            }
+            if page.packets.len() <= remaining {
+                remaining -= page.packets.len();
+                whole_pages += 1;
+            } else {
+                partial = &page.packets[page.packets.len() - remaining..];
+                remaining = 0;
+            }
+        }
+        let pending = &self.pending[self.pending.len() - from_pending..];
+        let parts: Vec<Part> = std::iter::once(Part::Fresh(partial))
+            .chain(
+                self.pages
+                    .range(self.pages.len() - whole_pages..)
+                    .map(Part::Stored),
+            )
+            .chain(std::iter::once(Part::Fresh(pending)))
+            .filter(|part| !matches!(part, Part::Fresh(packets) if packets.is_empty()))
+            .collect();
+
+        let stored_bytes: usize = parts
+            .iter()
+            .map(|part| match part {
+                Part::Stored(page) => page.bytes.len(),
+                Part::Fresh(_) => 0,
+            })
+            .sum();
+        let mut out = Vec::with_capacity(stored_bytes + 4096);
+        write_page(&mut out, &[head], HEADER_TYPE_BOS, 0, serial, 0);
+        let tags_type = if parts.is_empty() { HEADER_TYPE_EOS } else { 0 };
+        write_page(&mut out, &[tags], tags_type, 0, serial, 1);
+        let mut granule = 0;
+        let mut packets = 0;
+        for (i, part) in parts.iter().enumerate() {
+            let header_type = if i == parts.len() - 1 {
+                HEADER_TYPE_EOS
+            } else {
+                0
+            };
+            let sequence = i as u32 + 2;
+            match part {
+                Part::Fresh(page) => {
+                    granule += page
+                        .iter()
+                        .map(|packet| packet_samples(packet) as u64)
+                        .sum::<u64>();
+                    packets += page.len();
+                    write_page(&mut out, page, header_type, granule, serial, sequence);
+                }
+                Part::Stored(page) => {
+                    granule += page.samples;
+                    packets += page.packets.len();
+                    page.write_patched(&mut out, header_type, granule, serial, sequence);
+                }
+            }
+        }
+        (out, packets, granule)
+    }
+}
+
+/// A page of a dump, muxed for it or premuxed.
+enum Part<'a> {
+    Fresh(&'a [bytes::Bytes]),
+    Stored(&'a Page),
+}
+
+/// Lacing values of a packet, a run of 255s and the remainder, 0 included.
+fn lacing_len(len: usize) -> usize {
+    len / 255 + 1
+}
+
+/// Appends one page holding whole `packets`, which must fit in its 255 lacing values.
+fn write_page(
+    out: &mut Vec<u8>,
+    packets: &[impl AsRef<[u8]>],
+    header_type: u8,
+    granule: u64,
+    serial: u32,
+    sequence: u32,
+) {
+    let start = out.len();
+    out.extend_from_slice(b"OggS");
+    out.push(0); // version
+    out.push(header_type);
+    out.extend_from_slice(&granule.to_le_bytes());
+    out.extend_from_slice(&serial.to_le_bytes());
+    out.extend_from_slice(&sequence.to_le_bytes());
+    out.extend_from_slice(&[0; 4]); // CRC, computed over the page with these zero
+    let segments: usize = packets
+        .iter()
+        .map(|packet| lacing_len(packet.as_ref().len()))
+        .sum();
+    debug_assert!(segments <= MAX_SEGMENTS);
+    out.push(segments as u8);
+    for packet in packets {
+        let len = packet.as_ref().len();
+        out.extend(std::iter::repeat(255).take(len / 255));
+        out.push((len % 255) as u8);
+    }
+    debug_assert_eq!(out.len() - start, HEADER_LEN + segments);
+    for packet in packets {
+        out.extend_from_slice(packet.as_ref());
+    }
+    let crc = crc(&out[start..]);
+    out[start + PATCHED_LEN..start + PATCHED_LEN + 4].copy_from_slice(&crc.to_le_bytes());
+}
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = crc_times_x(crc);
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn crc(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, byte| {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ byte) as usize]
+    })
+}
+
+/// `value` times x, mod the CRC polynomial.
+const fn crc_times_x(value: u32) -> u32 {
+    if value & 0x8000_0000 != 0 {
+        (value << 1) ^ CRC_POLYNOMIAL
+    } else {
+        value << 1
+    }
+}
+
+/// `a` times `b` as polynomials, mod the CRC polynomial.
+fn crc_mul(a: u32, b: u32) -> u32 {
+    (0..32).rev().fold(0, |product, bit| {
+        let product = crc_times_x(product);
+        if b >> bit & 1 == 1 {
+            product ^ a
+        } else {
+            product
+        }
+    })
+}
+
+/// x^(8 * bytes) mod the CRC polynomial, what a CRC is multiplied by when `bytes` zeros follow.
+fn crc_shift(bytes: usize) -> u32 {
+    let mut shift = 1;
+    let mut square = 1 << 8;
+    let mut bytes = bytes;
+    while bytes > 0 {
+        if bytes & 1 == 1 {
+            shift = crc_mul(shift, square);
+        }
+        square = crc_mul(square, square);
+        bytes >>= 1;
+    }
+    shift
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use ogg::PacketReader;
+
+    use super::*;
+    use crate::decode;
+    use crate::encode::{self, opus_headers};
+    use crate::receiver::{AUDIO_CHANNELS, AUDIO_FREQUENCY};
+
+    /// 20ms stereo CELT packets of varying lengths, some needing more than one lacing value.
+    fn packets(count: usize) -> Vec<bytes::Bytes> {
+        (0..count)
+            .map(|i| {
+                let mut packet = vec![i as u8; i * 37 % 700 + 1];
+                packet[0] = 31 << 3 | 1 << 2;
+                packet.into()
+            })
+            .collect()
+    }
+
+    fn premuxer(packets: &[bytes::Bytes], capacity: usize) -> Premuxer {
+        let mut premuxer = Premuxer::new(capacity);
+        for packet in packets {
+            premuxer.push(packet.clone());
+        }
+        premuxer
+    }
+
+    fn stream(premuxer: &Premuxer, count: usize) -> (Vec<u8>, usize, u64) {
+        let (head, tags) = opus_headers::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(&Default::default());
+        premuxer.stream(count, &head, &tags, 0x1234_5678)
+    }
+
+    /// Granule position of the last page, which has to end the stream.
+    fn final_granule(ogg_data: &[u8]) -> u64 {
+        let mut reader = PacketReader::new(Cursor::new(ogg_data));
+        let mut last = None;
+        while let Some(packet) = reader.read_packet().unwrap() {
+            last = Some(packet);
+        }
+        let last = last.unwrap();
+        assert!(last.last_in_stream());
+        last.absgp_page()
+    }
+
+    #[test]
+    fn stream_holds_the_most_recent_packets() {
+        let packets = packets(500);
+        let premuxer = premuxer(&packets, 300);
+        // nothing, a packet of the pending page, whole pages, cutting into the oldest page, all
+        for count in [0, 1, 37, 100, 163, 300] {
+            let (ogg_data, muxed, samples) = stream(&premuxer, count);
+            // the reader checks every page's CRC
+            let demuxed = decode::demux(&ogg_data).unwrap();
+            assert_eq!(demuxed, packets[packets.len() - count..], "{}", count);
+            assert_eq!(muxed, count);
+            assert_eq!(samples, count as u64 * 960);
+            assert_eq!(final_granule(&ogg_data), samples);
+        }
+    }
+
+    #[test]
+    fn stream_matches_muxing_from_scratch() {
+        let packets = packets(500);
+        let (premuxed, _, _) = stream(&premuxer(&packets, 500), 500);
+        let muxed = encode::encode::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(&packets).unwrap();
+        assert_eq!(final_granule(&premuxed), final_granule(&muxed));
+        assert_eq!(
+            decode::demux(&premuxed).unwrap(),
+            decode::demux(&muxed).unwrap()
+        );
+    }
+
+    #[test]
+    fn oldest_page_goes_once_the_rest_cover_the_capacity() {
+        let mut premuxer = Premuxer::new(120);
+        for (i, packet) in packets(1000).into_iter().enumerate() {
+            premuxer.push(packet);
+            let held = premuxer.packets + premuxer.pending.len();
+            assert!(held >= (i + 1).min(120), "{} after {}", held, i + 1);
+            assert!(held < 120 + PACKETS_PER_PAGE, "{} after {}", held, i + 1);
+        }
+    }
+}
@@ -1,139 +1,1316 @@
+use std::collections::VecDeque;
 use std::env;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
 
+use anyhow::Context;
 use async_trait::async_trait;
 use audiopus::Bitrate;
 use audiopus::coder::Encoder;
 use dashmap::DashMap;
+use rayon::prelude::*;
 use serenity::all::GuildId;
 use songbird::{Event, EventContext, EventHandler as VoiceEventHandler};
+use songbird::events::context_data::VoiceTick;
+use songbird::id::ChannelId;
 use songbird::model::id::UserId;
+use songbird::tracks::TrackHandle;
 
+use crate::archive::Archive;
+use crate::config::{
+    Config, EmptyChannelMode, EncoderSettings, MAX_VOLUME_DB, MissingAudio, OversizedAudio,
+};
+use crate::crypt;
+use crate::drift::DriftMonitor;
+use crate::dump_cache::DumpCache;
+use crate::export_queue::ExportQueue;
+use crate::live::LiveStream;
+use crate::lookback::{ChatMessage, PACKET_DURATION, packets_for_duration};
+use crate::pan::Panner;
+use crate::storage::RecordingSink;
 use crate::{lookback, tts};
 
 pub(crate) const AUDIO_FREQUENCY: u32 = 48000;
 pub(crate) const AUDIO_CHANNELS: u8 = 2;
 
-/// 20ms @ 48kHz of 2ch 16 bit pcm
-pub(crate) const AUDIO_PACKET_SIZE: usize = 1920;
+/// 20ms @ 48kHz of 2ch 16 bit pcm, one driver tick
+pub const AUDIO_PACKET_SIZE: usize = 1920;
 pub(crate) const MAX_OPUS_PACKET: usize = 4000;
 
 pub(crate) type RawAudioPacket = [i16; AUDIO_PACKET_SIZE];
 
+/// One tick as floats where 1.0 is i16 full scale. Mixing, panning and filtering happen in this
+/// form, so overlapping speakers can go past full scale without clipping and nothing is rounded
+/// back to 16 bits before the encoder.
+pub(crate) type MixAudioPacket = [f32; AUDIO_PACKET_SIZE];
+
+/// Scale between i16 samples and the float samples of [`MixAudioPacket`].
+const I16_FULL_SCALE: f32 = 32768.0;
+
+/// One 20ms tick of received audio. Songbird's `VoiceTick` can't be built outside of songbird, so
+/// everything downstream of the event handler works on this instead, which lets ticks be
+/// synthesized without a live voice connection.
+#[derive(Default)]
+pub struct Tick<'a> {
+    /// ssrc and decoded pcm, the pcm is `None` if the driver didn't decode the packet.
+    pub speaking: Vec<(u32, Option<&'a [i16]>)>,
+    pub silent: Vec<u32>,
+    /// ssrc and RTP timestamp of the packets clocked out this tick, lost packets have none.
+    pub rtp_timestamps: Vec<(u32, u32)>,
+}
+
+impl<'a> From<&'a VoiceTick> for Tick<'a> {
+    fn from(data: &'a VoiceTick) -> Self {
+        Self {
+            speaking: data
+                .speaking
+                .iter()
+                .map(|(ssrc, data)| (*ssrc, data.decoded_voice.as_deref()))
+                .collect(),
+            silent: data.silent.iter().copied().collect(),
+            rtp_timestamps: data
+                .speaking
+                .iter()
+                .filter_map(|(ssrc, data)| {
+                    let packet = data.packet.as_ref()?;
+                    Some((*ssrc, packet.rtp().get_timestamp().0 .0))
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Packets the driver received for one ssrc, and the ones it had to conceal because they were
+/// lost or arrived too late for the jitter buffer.
+#[derive(Clone, Copy, Default)]
+pub struct PacketCounts {
+    pub received: u64,
+    pub lost: u64,
+}
+
+/// What `Receiver::clear_all` threw away.
+pub struct Cleared {
+    pub lookback: Duration,
+    pub users: usize,
+    pub held_ssrcs: usize,
+    pub dumps: usize,
+}
+
 pub struct Receiver {
     ssrc_to_user: DashMap<u32, UserId>,
     user_to_ssrc: DashMap<UserId, u32>,
     pub tts: tts::Tts,
     pub guild_id: GuildId,
     pub lookback: lookback::Lookback,
+    pub config: Config,
+    /// `config.encoder` with any changes made through the `encoder` command.
+    encoder: Mutex<EncoderSettings>,
+    /// `config.volume_db` with any change made through the `volume` command, as f32 bits.
+    volume_db: AtomicU32,
+    pub started_at: Instant,
+    pub sink: Box<dyn RecordingSink>,
+    /// the replay currently playing into the channel, so a new one can replace it.
+    pub replay: Mutex<Option<TrackHandle>>,
+    /// start of the running `mark`, if there is one.
+    pub mark: Mutex<Option<SystemTime>>,
+    /// labelled moments set by `cue`, oldest first, written as chapters into dumps covering them.
+    cues: Mutex<VecDeque<(SystemTime, String)>>,
+    /// messages posted in the chat channel with `CHAT_LOG`, oldest first, written into the chat
+    /// sidecar of dumps covering them.
+    chat: Mutex<VecDeque<ChatMessage>>,
+    /// user the bot moves along with, set by `follow`.
+    pub following: Mutex<Option<UserId>>,
+    /// voice channel the driver last connected to.
+    channel: Mutex<Option<ChannelId>>,
+    /// when the driver lost its connection, until it reconnects.
+    disconnected_at: Mutex<Option<Instant>>,
+    /// whether anyone besides the bot is in the voice channel.
+    occupied: AtomicBool,
+    drift: Option<DriftMonitor>,
+    /// recent audio of ssrcs with no user yet, e.g. right after a restart before anyone's
+    /// speaking update arrives. Handed to the user's buffer once the mapping shows up.
+    unmapped: DashMap<u32, VecDeque<Option<RawAudioPacket>>>,
+    unmapped_capacity: usize,
+    /// audio packets of unmapped ssrcs that fell out of, or never made it into, `unmapped`.
+    unmapped_dropped: AtomicU64,
+    /// speaking updates that took an ssrc or user away from another mapping, since startup.
+    mapping_conflicts: AtomicU64,
+    /// last decoded packet of each ssrc still speaking, with `MISSING_AUDIO=repeat`.
+    last_audio: DashMap<u32, RawAudioPacket>,
+    pub archive: Archive,
+    live: LiveStream,
+    pub dump_cache: DumpCache,
+    /// serializes the expensive exports.
+    pub export_queue: ExportQueue,
+    /// per ssrc since the driver last connected.
+    packet_counts: DashMap<u32, PacketCounts>,
+    pub panner: Panner,
 }
 
 impl Receiver {
-    pub fn new(guild_id: GuildId) -> Self {
+    pub fn new(guild_id: GuildId, config: Config) -> Self {
         Self {
-            tts: Default::default(),
-            lookback: Default::default(),
+            tts: tts::Tts::new(&config),
+            lookback: lookback::Lookback::new(&config),
             ssrc_to_user: Default::default(),
             user_to_ssrc: Default::default(),
             guild_id,
+            sink: config.storage.make_sink(config.recording_key),
+            drift: config.drift_log_interval.map(DriftMonitor::new),
+            archive: Archive::new(&config),
+            live: LiveStream::new(&config),
+            dump_cache: DumpCache::new(config.dump_cache_bytes),
+            export_queue: ExportQueue::new(),
+            packet_counts: Default::default(),
+            panner: Panner::new(&config),
+            unmapped: Default::default(),
+            unmapped_capacity: packets_for_duration(config.unmapped_grace, PACKET_DURATION),
+            unmapped_dropped: Default::default(),
+            mapping_conflicts: Default::default(),
+            last_audio: Default::default(),
+            encoder: Mutex::new(config.encoder),
+            volume_db: AtomicU32::new(config.volume_db.to_bits()),
+            config,
+            started_at: Instant::now(),
+            replay: Default::default(),
+            mark: Default::default(),
+            cues: Default::default(),
+            chat: Default::default(),
+            following: Default::default(),
+            channel: Default::default(),
+            disconnected_at: Default::default(),
+            occupied: AtomicBool::new(true),
         }
     }
-}
 
-#[async_trait]
-impl VoiceEventHandler for Receiver {
-    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
-        use songbird::EventContext as Ctx;
-        match ctx {
-            Ctx::VoiceTick(data) => {
-                self.lookback.tick(data);
-
-                let mut tts = self.tts.per_user_sound_buffer.write().await;
-                for (ssrc, data) in &data.speaking {
-                    let user = self.ssrc_to_user.get(ssrc);
-                    if let Some(user) = user {
-                        if let Some(audio) = &data.decoded_voice {
-                            let packet = to_raw_audio_packet(audio);
-                            tts.push(*user, packet);
-                        } else {
+    pub fn on_tick(&self, tick: &Tick) {
+        // the mix and each speaking user are encoded independently, so spread them
+        // across the rayon pool rather than encoding one after another.
+        rayon::join(
+            || {
+                if !self.is_recording() {
+                    return;
+                }
+                let user_of = |ssrc| self.ssrc_to_user.get(&ssrc).map(|user| *user);
+                let pan = |user| self.panner.position(user);
+                if let Some(packet) = self.lookback.tick(tick, user_of, pan) {
+                    self.archive.write_mix(&packet);
+                    self.live.write(&packet);
+                }
+            },
+            || {
+                tick.speaking.par_iter().for_each(|(ssrc, audio)| {
+                    let packets = match audio {
+                        Some(audio) => {
+                            let packets = to_raw_audio_packets(audio, self.config.oversized_audio);
+                            if let Some(last) = packets.last() {
+                                self.remember_audio(*ssrc, last);
+                            }
+                            if packets.is_empty() {
+                                vec![None]
+                            } else {
+                                packets.into_iter().map(Some).collect()
+                            }
+                        }
+                        None => {
                             tracing::warn!(
                                 "RTP packet, but no audio. Driver may not be configured to decode."
                             );
-                            tts.push(*user, None);
+                            self.conceal_missing_audio(*ssrc)
+                        }
+                    };
+                    let user = self.ssrc_to_user.get(ssrc).map(|user| *user);
+                    for packet in packets {
+                        match user {
+                            Some(user) => self.push_user(user, packet),
+                            None => self.hold_unmapped(*ssrc, packet),
                         }
                     }
-                }
-                for ssrc in &data.silent {
-                    if let Some(user) = self.ssrc_to_user.get(ssrc) {
-                        tts.push(*user, None);
-                    }
-                }
+                });
+            },
+        );
+        for ssrc in &tick.silent {
+            // a dropout after a pause isn't covered with speech from before it
+            self.last_audio.remove(ssrc);
+            match self.ssrc_to_user.get(ssrc).map(|user| *user) {
+                Some(user) => self.push_user(user, None),
+                None => self.hold_unmapped(*ssrc, None),
+            }
+        }
+        if let Some(drift) = &self.drift {
+            drift.on_tick(tick, &self.ssrc_to_user);
+        }
+        for (ssrc, _) in &tick.speaking {
+            let mut counts = self.packet_counts.entry(*ssrc).or_default();
+            if tick
+                .rtp_timestamps
+                .iter()
+                .any(|(received, _)| received == ssrc)
+            {
+                counts.received += 1;
+            } else {
+                counts.lost += 1;
+            }
+        }
+    }
+
+    /// Packet counts of every ssrc heard since the driver last connected, with its user if known.
+    pub fn packet_counts(&self) -> Vec<(Option<UserId>, PacketCounts)> {
+        self.packet_counts
+            .iter()
+            .map(|entry| {
+                let user = self.ssrc_to_user.get(entry.key()).map(|user| *user);
+                (user, *entry.value())
+            })
+            .collect()
+    }
+
+    fn remember_audio(&self, ssrc: u32, packet: &RawAudioPacket) {
+        if self.config.missing_audio == MissingAudio::Repeat {
+            self.last_audio.insert(ssrc, *packet);
+        }
+    }
+
+    /// Packets standing in for a tick the ssrc spoke in but the driver didn't decode, see
+    /// `MISSING_AUDIO`.
+    fn conceal_missing_audio(&self, ssrc: u32) -> Vec<Option<RawAudioPacket>> {
+        match self.config.missing_audio {
+            MissingAudio::Silence => vec![None],
+            // only once per gap, a packet repeated for longer than a hiccup turns into a buzz
+            MissingAudio::Repeat => vec![self.last_audio.remove(&ssrc).map(|(_, packet)| packet)],
+            MissingAudio::Skip => Vec::new(),
+        }
+    }
+
+    fn push_user(&self, user: UserId, packet: Option<RawAudioPacket>) {
+        if let Some(encoded) = self.tts.per_user_sound_buffer.push(user, packet) {
+            self.archive.write_user(user, &encoded);
+        }
+    }
+
+    /// Keeps the most recent `UNMAPPED_GRACE` of an unmapped ssrc. Silence is only held once
+    /// there is held audio to keep in time with.
+    fn hold_unmapped(&self, ssrc: u32, packet: Option<RawAudioPacket>) {
+        if self.unmapped_capacity == 0 || (packet.is_none() && !self.unmapped.contains_key(&ssrc)) {
+            if packet.is_some() {
+                self.unmapped_dropped.fetch_add(1, Ordering::Relaxed);
             }
+            return;
+        }
+        let mut held = self.unmapped.entry(ssrc).or_default();
+        if held.len() >= self.unmapped_capacity && held.pop_front().flatten().is_some() {
+            self.unmapped_dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        held.push_back(packet);
+    }
+
+    /// Audio packets lost to ssrcs that weren't mapped to a user in time, since startup.
+    pub fn unmapped_dropped(&self) -> u64 {
+        self.unmapped_dropped.load(Ordering::Relaxed)
+    }
+
+    /// Speaking updates that contradicted an existing mapping, since startup.
+    pub fn mapping_conflicts(&self) -> u64 {
+        self.mapping_conflicts.load(Ordering::Relaxed)
+    }
+
+    pub fn encoder(&self) -> EncoderSettings {
+        *lock_recovering(&self.encoder, "encoder settings")
+    }
+
+    pub fn volume_db(&self) -> f32 {
+        f32::from_bits(self.volume_db.load(Ordering::Relaxed))
+    }
+
+    /// Sets the master volume, clamped to [`MAX_VOLUME_DB`] either way, and saves it to
+    /// [`VOLUME_FILE`] so it outlasts a restart. Returns the volume set.
+    pub async fn set_volume_db(&self, volume_db: f32) -> anyhow::Result<f32> {
+        if !volume_db.is_finite() {
+            return Err(anyhow::anyhow!("volume must be a number of dB"));
+        }
+        let volume_db = volume_db.clamp(-MAX_VOLUME_DB, MAX_VOLUME_DB);
+        self.volume_db.store(volume_db.to_bits(), Ordering::Relaxed);
+        let saved = audio_dir().join(VOLUME_FILE);
+        tokio::fs::write(&saved, volume_db.to_string())
+            .await
+            .with_context(|| format!("volume changed but not saved to {}", saved.display()))?;
+        Ok(volume_db)
+    }
+
+    /// Rebuilds the lookback's and every user's encoder with new settings. Frames already encoded
+    /// are kept as they are, opus packets carry their own mode so old and new mix fine.
+    pub fn set_encoder(&self, settings: EncoderSettings) -> anyhow::Result<()> {
+        settings.validate()?;
+        let mut encoder = lock_recovering(&self.encoder, "encoder settings");
+        if settings.frame_duration != encoder.frame_duration {
+            return Err(anyhow::anyhow!(
+                "the frame duration can't be changed at runtime, set FRAME_DURATION instead"
+            ));
+        }
+        self.lookback.reconfigure_encoder(&settings);
+        self.tts
+            .per_user_sound_buffer
+            .reconfigure_encoders(&settings);
+        *encoder = settings;
+        Ok(())
+    }
+
+    /// False while the channel is empty and `EMPTY_CHANNEL` pauses the lookback.
+    pub fn is_recording(&self) -> bool {
+        self.config.empty_channel == EmptyChannelMode::Record
+            || self.occupied.load(Ordering::Relaxed)
+    }
+
+    pub fn channel(&self) -> Option<ChannelId> {
+        *lock_recovering(&self.channel, "channel")
+    }
+
+    /// Whether the driver has a voice connection, as far as songbird has told us.
+    pub fn is_connected(&self) -> bool {
+        self.channel().is_some()
+            && lock_recovering(&self.disconnected_at, "disconnected at").is_none()
+    }
+
+    /// Whether the user has a saved clone reference for `ctts` to speak with. A missing or
+    /// unreadable audio dir counts as no reference.
+    pub async fn has_clone_reference(&self, user: UserId) -> bool {
+        tokio::fs::try_exists(audio_dir().join(user_to_ogg_file(user)))
+            .await
+            .unwrap_or_else(|e| {
+                tracing::warn!("couldn't check the clone reference of {} {:?}", user, e);
+                false
+            })
+    }
+
+    /// Called when the set of users in the voice channel may have changed.
+    pub fn set_occupied(&self, occupied: bool) {
+        if self.occupied.swap(occupied, Ordering::Relaxed) == occupied {
+            return;
+        }
+        if occupied {
+            tracing::info!("voice channel occupied, resuming lookback");
+            return;
+        }
+        match self.config.empty_channel {
+            EmptyChannelMode::Record => tracing::info!("voice channel empty, still recording"),
+            EmptyChannelMode::Pause => tracing::info!("voice channel empty, pausing lookback"),
+            EmptyChannelMode::Clear => {
+                tracing::info!("voice channel empty, pausing and clearing lookback");
+                self.lookback.clear();
+            }
+        }
+    }
+
+    pub fn on_speaking_update(&self, ssrc: u32, user: UserId) {
+        tracing::info!("recording ssrc mapping uid {} -> ssrc {}", user, ssrc);
+        // the latest update wins, whatever it replaces is removed from the other map too so both
+        // always describe the same pairs
+        match self.user_to_ssrc.insert(user, ssrc) {
+            Some(prev_ssrc) if prev_ssrc != ssrc => {
+                self.ssrc_to_user
+                    .remove_if(&prev_ssrc, |_, mapped| *mapped == user);
+            }
+            _ => {}
+        }
+        match self.ssrc_to_user.insert(ssrc, user) {
+            Some(prev_user) if prev_user != user => {
+                tracing::warn!(
+                    "ssrc {} moved from uid {} to uid {}, likely a reconnection race",
+                    ssrc,
+                    prev_user,
+                    user
+                );
+                self.mapping_conflicts.fetch_add(1, Ordering::Relaxed);
+                self.user_to_ssrc
+                    .remove_if(&prev_user, |_, mapped| *mapped == ssrc);
+            }
+            _ => {}
+        }
+        if let Some((_, held)) = self.unmapped.remove(&ssrc) {
+            tracing::info!(
+                "attributing {} held packets from ssrc {} to uid {}",
+                held.len(),
+                ssrc,
+                user
+            );
+            for packet in held {
+                self.push_user(user, packet);
+            }
+        }
+    }
+
+    /// Snapshot of `ssrc_to_user`, sorted by ssrc.
+    pub fn ssrc_to_user(&self) -> Vec<(u32, UserId)> {
+        let mut mappings: Vec<_> = self
+            .ssrc_to_user
+            .iter()
+            .map(|entry| (*entry.key(), *entry.value()))
+            .collect();
+        mappings.sort_by_key(|(ssrc, _)| *ssrc);
+        mappings
+    }
+
+    /// Snapshot of `user_to_ssrc`, sorted by user.
+    pub fn user_to_ssrc(&self) -> Vec<(UserId, u32)> {
+        let mut mappings: Vec<_> = self
+            .user_to_ssrc
+            .iter()
+            .map(|entry| (*entry.key(), *entry.value()))
+            .collect();
+        mappings.sort_by_key(|(user, _)| user.0);
+        mappings
+    }
+
+    /// Ssrcs with audio held until their user is known, and how many ticks each.
+    pub fn unmapped_ssrcs(&self) -> Vec<(u32, usize)> {
+        let mut held: Vec<_> = self
+            .unmapped
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().len()))
+            .collect();
+        held.sort_unstable();
+        held
+    }
+
+    /// Cues the current moment with `label`, forgetting cues older than the lookback. Returns how
+    /// many cues are held.
+    pub fn add_cue(&self, label: String) -> usize {
+        let now = SystemTime::now();
+        let mut cues = lock_recovering(&self.cues, "cues");
+        while cues.front().is_some_and(|(at, _)| {
+            now.duration_since(*at).unwrap_or_default() > self.config.lookback_duration
+        }) {
+            cues.pop_front();
+        }
+        cues.push_back((now, label));
+        cues.len()
+    }
+
+    pub fn cues(&self) -> Vec<(SystemTime, String)> {
+        lock_recovering(&self.cues, "cues")
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Logs a chat message posted now, forgetting messages older than the lookback.
+    pub fn log_chat(&self, author: String, content: String) {
+        let now = SystemTime::now();
+        let mut chat = lock_recovering(&self.chat, "chat");
+        while chat.front().is_some_and(|message| {
+            now.duration_since(message.posted_at).unwrap_or_default()
+                > self.config.lookback_duration
+        }) {
+            chat.pop_front();
+        }
+        chat.push_back(ChatMessage {
+            posted_at: now,
+            author,
+            content,
+        });
+    }
+
+    pub fn chat(&self) -> Vec<ChatMessage> {
+        lock_recovering(&self.chat, "chat")
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Called whenever the driver (re)connects. SSRCs are only valid for one voice session so the
+    /// mappings are rebuilt from the speaking updates that follow, and the outage since the last
+    /// disconnect is bridged. Returns true if this connection is to a different channel than the
+    /// last one, i.e. the bot was moved.
+    pub fn on_connect(&self, channel: Option<ChannelId>) -> bool {
+        self.ssrc_to_user.clear();
+        self.user_to_ssrc.clear();
+        self.unmapped.clear();
+        self.packet_counts.clear();
+        self.last_audio.clear();
+        if let Some(drift) = &self.drift {
+            drift.reset();
+        }
+        let disconnected_at = lock_recovering(&self.disconnected_at, "disconnected at").take();
+        if let Some(disconnected_at) = disconnected_at {
+            self.bridge_outage(disconnected_at.elapsed());
+        }
+        let previous = {
+            let mut current = lock_recovering(&self.channel, "channel");
+            std::mem::replace(&mut *current, channel)
+        };
+        if previous.is_none() || previous == channel {
+            return false;
+        }
+        tracing::info!("moved from voice channel {:?} to {:?}", previous, channel);
+        if self.config.clear_lookback_on_move {
+            tracing::info!("clearing lookback after channel move");
+            self.lookback.clear();
+        }
+        true
+    }
+
+    /// Wipes all buffered audio: the lookback, every per user buffer, audio held for unmapped
+    /// ssrcs, cached exports and the packet counts. Files on disk and the ssrc mappings are kept,
+    /// so recording carries on from the next tick. A tick racing the reset just lands in the fresh
+    /// buffers.
+    pub fn clear_all(&self) -> Cleared {
+        let lookback = self.lookback.buffered_duration();
+        self.lookback.clear();
+        let users = self.tts.per_user_sound_buffer.clear();
+        let held_ssrcs = self.unmapped.len();
+        self.unmapped.clear();
+        self.unmapped.shrink_to_fit();
+        self.packet_counts.clear();
+        lock_recovering(&self.cues, "cues").clear();
+        lock_recovering(&self.chat, "chat").clear();
+        let dumps = self.dump_cache.clear();
+        tracing::info!(
+            "cleared {:?} of lookback, {} user buffers, {} held ssrcs and {} cached dumps",
+            lookback,
+            users,
+            held_ssrcs,
+            dumps
+        );
+        Cleared {
+            lookback,
+            users,
+            held_ssrcs,
+            dumps,
+        }
+    }
+
+    /// Called when the driver loses its voice connection, songbird reconnects on its own.
+    pub fn on_driver_disconnect(&self) {
+        lock_recovering(&self.disconnected_at, "disconnected at").get_or_insert_with(Instant::now);
+    }
+
+    /// No ticks arrive while disconnected, so without padding the audio either side of an outage
+    /// would be glued together. Outages past `RECONNECT_GRACE` clear the lookback instead.
+    fn bridge_outage(&self, outage: Duration) {
+        if outage > self.config.reconnect_grace {
+            tracing::info!(
+                "voice was disconnected for {:?}, longer than the reconnect grace, clearing lookback",
+                outage
+            );
+            self.lookback.clear();
+            return;
+        }
+        if !self.is_recording() {
+            return;
+        }
+        let padding = self.lookback.pad_silence(outage);
+        tracing::info!(
+            "padded {} frames of silence over a {:?} voice outage",
+            padding.len(),
+            outage
+        );
+        for packet in &padding {
+            self.archive.write_mix(packet);
+            self.live.write(packet);
+        }
+    }
+
+    /// Called when a user leaves the voice channel.
+    pub fn on_client_disconnect(&self, user: UserId) {
+        self.archive.finish_user(user);
+    }
+
+    /// Finishes any archive files so they end cleanly, called before the bot exits.
+    pub fn shutdown(&self) {
+        self.archive.finish_all();
+    }
+}
+
+#[async_trait]
+impl VoiceEventHandler for Receiver {
+    async fn act(&self, ctx: &EventContext<'_>) -> Option<Event> {
+        use songbird::EventContext as Ctx;
+        match ctx {
+            // the tick fans out over the rayon pool and waits for it, so the runtime is told to
+            // move its other tasks off this worker until then
+            Ctx::VoiceTick(data) => tokio::task::block_in_place(|| self.on_tick(&data.into())),
             Ctx::SpeakingStateUpdate(speaking) => {
                 if let Some(user) = speaking.user_id {
-                    tracing::info!(
-                        "recording ssrc mapping uid {} -> ssrc {}",
-                        user,
-                        speaking.ssrc
-                    );
-                    match self.user_to_ssrc.insert(user, speaking.ssrc) {
-                        Some(prev_ssrc) if prev_ssrc != speaking.ssrc => {
-                            self.ssrc_to_user.remove(&prev_ssrc);
-                        }
-                        _ => {}
-                    }
-                    self.ssrc_to_user.insert(speaking.ssrc, user);
+                    self.on_speaking_update(speaking.ssrc, user);
                 }
             }
+            Ctx::ClientDisconnect(disconnect) => self.on_client_disconnect(disconnect.user_id),
+            Ctx::DriverDisconnect(_) => self.on_driver_disconnect(),
             _ => {}
         }
         None
     }
 }
 
-pub async fn write_ogg_to_disk(ogg_data: &[u8]) -> anyhow::Result<()> {
-    let date = chrono::prelude::Local::now()
-        .format("%Y-%m-%d_%H-%M-%S.ogg")
-        .to_string();
-    write_ogg_to_disk_named(ogg_data, date.into()).await
+/// Names the recording by date and length, e.g. `2024-05-01_18-03-27_4m37s.ogg`. Returns the name
+/// it was stored under.
+pub async fn write_ogg_to_disk(
+    sink: &dyn RecordingSink,
+    ogg_data: &[u8],
+    duration: Duration,
+    extension: &str,
+) -> anyhow::Result<String> {
+    let date = chrono::prelude::Local::now().format("%Y-%m-%d_%H-%M-%S");
+    let length = humantime::format_duration(Duration::from_secs(duration.as_secs()))
+        .to_string()
+        .replace(' ', "");
+    let file_name = format!("{}_{}.{}", date, length, extension);
+    sink.put(&file_name, ogg_data).await?;
+    Ok(file_name)
+}
+
+/// File in the audio dir the `volume` command saves the master volume to, read back over `VOLUME`
+/// at startup.
+pub const VOLUME_FILE: &str = "volume_db.txt";
+
+/// `DISCORD_AUDIO_DIR`, or the working directory if unset.
+pub fn audio_dir() -> PathBuf {
+    env::var("DISCORD_AUDIO_DIR")
+        .unwrap_or_else(|_| ".".to_string())
+        .into()
 }
 
-pub async fn write_ogg_to_disk_named(ogg_data: &[u8], file_name: PathBuf) -> anyhow::Result<()> {
-    let root_dir = env::var("DISCORD_AUDIO_DIR").unwrap_or_else(|_| ".".to_string());
-    let ogg_path = PathBuf::from(root_dir).join(file_name);
+/// Encrypted with `recording_key` if one is set, see [`crypt::seal`].
+pub async fn write_ogg_to_disk_named(
+    ogg_data: &[u8],
+    file_name: PathBuf,
+    recording_key: Option<&crypt::Key>,
+) -> anyhow::Result<()> {
+    let ogg_path = audio_dir().join(file_name);
     tracing::info!("writing {}", ogg_path.display());
-    tokio::fs::write(&ogg_path, &ogg_data).await?;
+    tokio::fs::write(&ogg_path, crypt::seal(recording_key, ogg_data)?).await?;
     tracing::info!("done writing {}", ogg_path.display());
     Ok(())
 }
 
-pub async fn read_ogg_file(file_name: PathBuf) -> anyhow::Result<Vec<u8>> {
-    let root_dir = env::var("DISCORD_AUDIO_DIR").unwrap_or_else(|_| ".".to_string());
-    let ogg_path = PathBuf::from(root_dir).join(file_name);
-    Ok(tokio::fs::read(ogg_path).await?)
+pub async fn read_ogg_file(
+    file_name: PathBuf,
+    recording_key: Option<&crypt::Key>,
+) -> anyhow::Result<Vec<u8>> {
+    let ogg_path = audio_dir().join(file_name);
+    crypt::open(recording_key, tokio::fs::read(ogg_path).await?)
 }
 
 pub fn user_to_ogg_file(user_id: UserId) -> PathBuf {
     format!("{}.ogg", user_id).into()
 }
 
+/// Filesystem safe label for exported per user files. The display name keeps it readable, the
+/// base36 id suffix keeps users sharing a display name distinct.
+pub fn user_label(display_name: &str, user_id: UserId) -> String {
+    const MAX_NAME_LEN: usize = 32;
+    let name = sanitize_file_name(display_name, MAX_NAME_LEN);
+    let name = if name.is_empty() { "user" } else { &name };
+
+    let mut id = user_id.0;
+    let mut suffix = Vec::new();
+    loop {
+        suffix.push(char::from_digit((id % 36) as u32, 36).expect("digit below radix"));
+        id /= 36;
+        if id == 0 {
+            break;
+        }
+    }
+    format!("{}_{}", name, suffix.iter().rev().collect::<String>())
+}
+
+/// Ascii alphanumerics and dashes of `text`, every other run of characters collapsed into a single
+/// underscore, at most `max_len` long. Empty if nothing usable is left.
+pub fn sanitize_file_name(text: &str, max_len: usize) -> String {
+    let mut name = String::with_capacity(max_len);
+    for c in text.chars() {
+        if name.len() >= max_len {
+            break;
+        }
+        if c.is_ascii_alphanumeric() || c == '-' {
+            name.push(c);
+        } else if !name.is_empty() && !name.ends_with('_') {
+            name.push('_');
+        }
+    }
+    name.trim_end_matches('_').to_string()
+}
+
+/// Poisoned locks [`lock_recovering`] took back since startup.
+static RECOVERED_POISONINGS: AtomicU64 = AtomicU64::new(0);
+
+/// Locks a mutex on the recording path, taking it back if a panic poisoned it. Recording goes on
+/// with whatever state the panicking code left behind, which beats every later tick panicking on
+/// the lock and nothing being recorded until a restart. Each recovery is counted, see
+/// [`recovered_poisonings`].
+pub(crate) fn lock_recovering<'a, T>(mutex: &'a Mutex<T>, name: &str) -> MutexGuard<'a, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        tracing::error!("{} lock was poisoned by a panic, recovering it", name);
+        RECOVERED_POISONINGS.fetch_add(1, Ordering::Relaxed);
+        mutex.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+/// How many times [`lock_recovering`] found a lock poisoned since startup, so the self check can
+/// still report a panic on the recording path after the lock was taken back.
+pub(crate) fn recovered_poisonings() -> u64 {
+    RECOVERED_POISONINGS.load(Ordering::Relaxed)
+}
+
 pub(crate) fn to_raw_audio_packet(data: impl AsRef<[i16]>) -> Option<RawAudioPacket> {
     data.as_ref().try_into().ok()
 }
 
-pub fn make_opus_encoder() -> Encoder {
+pub(crate) fn to_mix_audio(packet: &RawAudioPacket) -> MixAudioPacket {
+    packet.map(|sample| sample as f32 / I16_FULL_SCALE)
+}
+
+/// Back to 16 bits, clipping anything past full scale.
+pub(crate) fn from_mix_audio(packet: &MixAudioPacket) -> RawAudioPacket {
+    packet.map(|sample| {
+        (sample * I16_FULL_SCALE)
+            .round()
+            .clamp(i16::MIN as f32, i16::MAX as f32) as i16
+    })
+}
+
+/// Decoded audio as tick sized packets. Songbird normally hands over exactly one tick, but a
+/// client sending 40 or 60ms frames can arrive decoded in one piece, which is split in order with
+/// the last packet padded with silence, or dropped, depending on `OVERSIZED_AUDIO`.
+pub(crate) fn to_raw_audio_packets(data: &[i16], oversized: OversizedAudio) -> Vec<RawAudioPacket> {
+    if let Some(packet) = to_raw_audio_packet(data) {
+        return vec![packet];
+    }
+    if oversized == OversizedAudio::Drop {
+        return Vec::new();
+    }
+    data.chunks(AUDIO_PACKET_SIZE)
+        .map(|chunk| {
+            let mut packet = empty_raw_audio();
+            packet[..chunk.len()].copy_from_slice(chunk);
+            packet
+        })
+        .collect()
+}
+
+pub fn make_opus_encoder(settings: &EncoderSettings) -> Encoder {
+    make_opus_encoder_with_channels(settings, audiopus::Channels::Stereo)
+}
+
+/// Same as [`make_opus_encoder`] for other channel counts, e.g. mono exports.
+pub fn make_opus_encoder_with_channels(
+    settings: &EncoderSettings,
+    channels: audiopus::Channels,
+) -> Encoder {
     let mut opus_encoder = Encoder::new(
         audiopus::SampleRate::Hz48000,
-        audiopus::Channels::Stereo,
-        audiopus::Application::Audio,
+        channels,
+        settings.application,
     )
     .expect("failed to create opus encoder");
+    // libopus only spends bits on fec when told to expect loss
+    const FEC_PACKET_LOSS_PERC: u8 = 10;
     opus_encoder
-        .set_bitrate(Bitrate::BitsPerSecond(24000))
+        .set_bitrate(Bitrate::BitsPerSecond(settings.bitrate))
         .expect("failed to set opus encoder bitrate");
     opus_encoder
+        .set_complexity(settings.complexity)
+        .expect("failed to set opus encoder complexity");
+    opus_encoder
+        .set_vbr(settings.vbr)
+        .expect("failed to set opus encoder vbr");
+    opus_encoder
+        .set_inband_fec(settings.fec)
+        .expect("failed to set opus encoder fec");
+    if settings.fec {
+        opus_encoder
+            .set_packet_loss_perc(FEC_PACKET_LOSS_PERC)
+            .expect("failed to set opus encoder packet loss");
+    }
+    opus_encoder
+        .set_dtx(settings.dtx)
+        .expect("failed to set opus encoder dtx");
+    opus_encoder
+}
+
+/// Buffers driver ticks until a whole frame of the configured frame duration is pending, then
+/// encodes it. Frames where every tick was silent become the shared silence packet. Audio is handed
+/// to opus as floats, see [`MixAudioPacket`].
+pub struct FrameEncoder {
+    opus_encoder: Encoder,
+    output_scratch_space: [u8; MAX_OPUS_PACKET],
+    pending: Vec<f32>,
+    pending_speech: bool,
+    frame_size: usize,
+    empty_encoded: bytes::Bytes,
+}
+
+impl FrameEncoder {
+    pub fn new(settings: &EncoderSettings) -> Self {
+        let mut encoder = Self::with_silence(settings, bytes::Bytes::new());
+        // a dtx frame is just the toc byte, which still tells the decoder how long it is
+        const DTX_FRAME_SIZE: usize = 1;
+        const MAX_DTX_WARMUP_FRAMES: usize = 100;
+        let silence = vec![0.0; encoder.frame_size];
+        let mut encode_silence = || {
+            let written_size = encoder
+                .opus_encoder
+                .encode_float(&silence, &mut encoder.output_scratch_space)
+                .expect("failed to encode silence");
+            bytes::Bytes::copy_from_slice(&encoder.output_scratch_space[..written_size])
+        };
+        let mut empty_encoded = encode_silence();
+        // opus only switches to dtx frames after a stretch of silence, the shared silence
+        // packet is taken once it has
+        if settings.dtx {
+            for _ in 0..MAX_DTX_WARMUP_FRAMES {
+                if empty_encoded.len() <= DTX_FRAME_SIZE {
+                    break;
+                }
+                empty_encoded = encode_silence();
+            }
+        }
+        encoder.empty_encoded = empty_encoded;
+        encoder
+    }
+
+    /// An encoder built from `settings` that encodes silent frames as `empty_encoded`, for one
+    /// that has to match packets encoded before the settings changed.
+    pub fn with_silence(settings: &EncoderSettings, empty_encoded: bytes::Bytes) -> Self {
+        let frame_size = settings.ticks_per_frame() * AUDIO_PACKET_SIZE;
+        Self {
+            opus_encoder: make_opus_encoder(settings),
+            output_scratch_space: [0; MAX_OPUS_PACKET],
+            pending: Vec::with_capacity(frame_size),
+            pending_speech: false,
+            frame_size,
+            empty_encoded,
+        }
+    }
+
+    /// Swaps in an encoder built from `settings`. Pending audio and the silence packet are kept,
+    /// so does the frame duration, everything downstream is sized for it.
+    pub fn reconfigure(&mut self, settings: &EncoderSettings) {
+        self.opus_encoder = make_opus_encoder(settings);
+    }
+
+    /// The packet every fully silent frame is encoded as.
+    pub fn empty_encoded(&self) -> &bytes::Bytes {
+        &self.empty_encoded
+    }
+
+    /// Adds one tick of audio, `None` being silence. Returns the encoded frame once this tick
+    /// completes one.
+    pub fn push(&mut self, data: Option<&RawAudioPacket>) -> Option<bytes::Bytes> {
+        self.push_mix(data.map(to_mix_audio).as_ref())
+    }
+
+    /// Same as [`Self::push`] for float audio, which is passed to opus as is, unclipped.
+    pub fn push_mix(&mut self, data: Option<&MixAudioPacket>) -> Option<bytes::Bytes> {
+        match data {
+            Some(data) => {
+                self.pending.extend_from_slice(data);
+                self.pending_speech = true;
+            }
+            None => self
+                .pending
+                .resize(self.pending.len() + AUDIO_PACKET_SIZE, 0.0),
+        }
+        if self.pending.len() < self.frame_size {
+            return None;
+        }
+        let encoded = if self.pending_speech {
+            self.opus_encoder
+                .encode_float(&self.pending, &mut self.output_scratch_space)
+                .map(|written_size| {
+                    bytes::Bytes::copy_from_slice(&self.output_scratch_space[..written_size])
+                })
+                .unwrap_or_else(|_| self.empty_encoded.clone())
+        } else {
+            self.empty_encoded.clone()
+        };
+        self.pending.clear();
+        self.pending_speech = false;
+        Some(encoded)
+    }
 }
 
 pub fn empty_raw_audio() -> RawAudioPacket {
     [0i16; AUDIO_PACKET_SIZE]
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::lookback::DrainOptions;
+
+    /// A loud square wave, nothing a filter or gate would take for silence.
+    pub(crate) fn tone() -> RawAudioPacket {
+        let mut packet = empty_raw_audio();
+        for (i, sample) in packet.iter_mut().enumerate() {
+            *sample = if i / 40 % 2 == 0 { 8000 } else { -8000 };
+        }
+        packet
+    }
+
+    /// Needs a tokio runtime for the export queue's worker.
+    fn receiver() -> Receiver {
+        Receiver::new(GuildId::new(1), Config::for_tests())
+    }
+
+    /// Mix frames the user was heard in.
+    fn frames_with(receiver: &Receiver, user: UserId) -> usize {
+        receiver
+            .lookback
+            .drain_buffer(
+                &DrainOptions {
+                    speaker: Some(user),
+                    ..Default::default()
+                },
+                &Default::default(),
+            )
+            .map_or(0, |dump| dump.packets)
+    }
+
+    #[tokio::test]
+    async fn speaking_tick_reaches_mix_and_user_buffer() {
+        let receiver = receiver();
+        let audio = tone();
+        receiver.on_speaking_update(7, UserId(70));
+        receiver.on_tick(&Tick {
+            speaking: vec![(7, Some(&audio))],
+            rtp_timestamps: vec![(7, 960)],
+            ..Default::default()
+        });
+
+        assert_eq!(receiver.lookback.buffered_duration(), PACKET_DURATION);
+        assert_eq!(frames_with(&receiver, UserId(70)), 1);
+        assert_eq!(
+            receiver
+                .tts
+                .per_user_sound_buffer
+                .buffered_duration(UserId(70)),
+            Some((PACKET_DURATION, PACKET_DURATION))
+        );
+        let counts = receiver.packet_counts();
+        assert_eq!(counts.len(), 1);
+        assert_eq!(counts[0].0, Some(UserId(70)));
+        assert_eq!((counts[0].1.received, counts[0].1.lost), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn silent_tick_keeps_time_without_speech() {
+        let receiver = receiver();
+        receiver.on_speaking_update(7, UserId(70));
+        receiver.on_tick(&Tick {
+            silent: vec![7],
+            ..Default::default()
+        });
+
+        assert_eq!(receiver.lookback.buffered_duration(), PACKET_DURATION);
+        assert_eq!(frames_with(&receiver, UserId(70)), 0);
+        assert_eq!(
+            receiver
+                .tts
+                .per_user_sound_buffer
+                .buffered_duration(UserId(70)),
+            Some((PACKET_DURATION, Duration::ZERO))
+        );
+    }
+
+    #[tokio::test]
+    async fn driver_events_recover_poisoned_locks() {
+        let receiver = receiver();
+        std::thread::scope(|scope| {
+            let poisoner = scope.spawn(|| {
+                let _channel = receiver.channel.lock().unwrap();
+                let _disconnected_at = receiver.disconnected_at.lock().unwrap();
+                panic!("poisoning the connection state");
+            });
+            assert!(poisoner.join().is_err());
+        });
+        let poisonings = recovered_poisonings();
+        let channel = ChannelId::from(serenity::model::id::ChannelId::new(5));
+
+        receiver.on_driver_disconnect();
+        assert!(!receiver.on_connect(Some(channel)));
+        assert_eq!(receiver.channel(), Some(channel));
+        assert!(receiver.is_connected());
+        assert!(recovered_poisonings() >= poisonings + 2);
+    }
+
+    #[tokio::test]
+    async fn unmapped_ssrc_is_mixed_and_attributed_once_mapped() {
+        let receiver = receiver();
+        let audio = tone();
+        for _ in 0..3 {
+            receiver.on_tick(&Tick {
+                speaking: vec![(9, Some(&audio))],
+                ..Default::default()
+            });
+        }
+        receiver.on_tick(&Tick {
+            silent: vec![9],
+            ..Default::default()
+        });
+
+        // in the mix straight away, but nobody to credit it to yet
+        assert_eq!(receiver.lookback.buffered_duration(), PACKET_DURATION * 4);
+        assert!(receiver
+            .tts
+            .per_user_sound_buffer
+            .tracked_users()
+            .is_empty());
+
+        receiver.on_speaking_update(9, UserId(90));
+        // the trailing silent tick falls within the speech hangover
+        assert_eq!(
+            receiver
+                .tts
+                .per_user_sound_buffer
+                .buffered_duration(UserId(90)),
+            Some((PACKET_DURATION * 4, PACKET_DURATION * 4))
+        );
+        assert_eq!(receiver.unmapped_dropped(), 0);
+    }
+
+    #[test]
+    fn oversized_audio_splits_into_ordered_packets() {
+        let audio: Vec<i16> = (0..2 * AUDIO_PACKET_SIZE)
+            .map(|i| (i / AUDIO_PACKET_SIZE) as i16 + 1)
+            .collect();
+        let packets = to_raw_audio_packets(&audio, OversizedAudio::Split);
+        assert_eq!(packets.len(), 2);
+        assert!(packets[0].iter().all(|sample| *sample == 1));
+        assert!(packets[1].iter().all(|sample| *sample == 2));
+        assert!(to_raw_audio_packets(&audio, OversizedAudio::Drop).is_empty());
+    }
+
+    #[test]
+    fn dtx_shrinks_silence_and_keeps_the_timeline() {
+        const SILENT_TICKS: usize = 500;
+        let encoded = |dtx: bool| {
+            let mut settings = Config::for_tests().encoder;
+            settings.dtx = dtx;
+            let mut encoder = FrameEncoder::new(&settings);
+            let audio = tone();
+            let mut packets: Vec<_> = (0..5).filter_map(|_| encoder.push(Some(&audio))).collect();
+            packets.extend((0..SILENT_TICKS).filter_map(|_| encoder.push(None)));
+            (encoder.empty_encoded().len(), packets)
+        };
+        let (plain_silence, plain) = encoded(false);
+        let (dtx_silence, dtx) = encoded(true);
+        assert_eq!(dtx_silence, 1);
+        assert!(plain_silence > dtx_silence);
+
+        let size = |packets: &[bytes::Bytes]| packets.iter().map(bytes::Bytes::len).sum::<usize>();
+        let (plain_size, dtx_size) = (size(&plain), size(&dtx));
+        assert!(
+            plain_size - dtx_size >= SILENT_TICKS,
+            "{} bytes with dtx, {} without",
+            dtx_size,
+            plain_size
+        );
+        assert_eq!(crate::decode::count_undecodable(&dtx).unwrap(), 0);
+        assert_eq!(
+            crate::decode::decode_opus(&dtx).unwrap().len(),
+            crate::decode::decode_opus(&plain).unwrap().len()
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_tick_is_stored_as_two_packets() {
+        let receiver = receiver();
+        let audio = [tone(), tone()].concat();
+        receiver.on_speaking_update(7, UserId(70));
+        receiver.on_tick(&Tick {
+            speaking: vec![(7, Some(&audio))],
+            ..Default::default()
+        });
+
+        assert_eq!(
+            receiver
+                .tts
+                .per_user_sound_buffer
+                .buffered_duration(UserId(70)),
+            Some((PACKET_DURATION * 2, PACKET_DURATION * 2))
+        );
+        // the second half is mixed into the following tick
+        receiver.on_tick(&Tick::default());
+        assert_eq!(frames_with(&receiver, UserId(70)), 1);
+        assert_eq!(receiver.lookback.buffered_duration(), PACKET_DURATION * 2);
+    }
+
+    /// Both maps describe the same pairs.
+    fn assert_mappings_consistent(receiver: &Receiver) {
+        let mut inverse: Vec<_> = receiver
+            .user_to_ssrc()
+            .into_iter()
+            .map(|(user, ssrc)| (ssrc, user))
+            .collect();
+        inverse.sort_by_key(|(ssrc, _)| *ssrc);
+        assert_eq!(receiver.ssrc_to_user(), inverse);
+    }
+
+    #[tokio::test]
+    async fn conflicting_mapping_leaves_both_maps_consistent() {
+        let receiver = receiver();
+        receiver.on_speaking_update(1, UserId(10));
+        receiver.on_speaking_update(2, UserId(20));
+        assert_mappings_consistent(&receiver);
+
+        // ssrc 1 goes to the second user, who leaves ssrc 2 behind
+        receiver.on_speaking_update(1, UserId(20));
+        assert_mappings_consistent(&receiver);
+        assert_eq!(receiver.ssrc_to_user(), [(1, UserId(20))]);
+        assert_eq!(receiver.mapping_conflicts(), 1);
+
+        // a user moving to a new ssrc isn't a conflict
+        receiver.on_speaking_update(3, UserId(20));
+        assert_mappings_consistent(&receiver);
+        assert_eq!(receiver.ssrc_to_user(), [(3, UserId(20))]);
+        assert_eq!(receiver.mapping_conflicts(), 1);
+    }
+
+    /// A user heard once and then speaking in a tick the driver couldn't decode, concealed with
+    /// `missing_audio`. Returns how much the user's buffer holds afterwards.
+    fn missing_tick(receiver: &Receiver) -> Duration {
+        let audio = tone();
+        receiver.on_speaking_update(7, UserId(70));
+        receiver.on_tick(&Tick {
+            speaking: vec![(7, Some(&audio))],
+            ..Default::default()
+        });
+        receiver.on_tick(&Tick {
+            speaking: vec![(7, None)],
+            ..Default::default()
+        });
+        let (buffered, _) = receiver
+            .tts
+            .per_user_sound_buffer
+            .buffered_duration(UserId(70))
+            .unwrap();
+        buffered
+    }
+
+    fn receiver_missing(missing_audio: MissingAudio) -> Receiver {
+        let mut config = Config::for_tests();
+        config.missing_audio = missing_audio;
+        Receiver::new(GuildId::new(1), config)
+    }
+
+    #[tokio::test]
+    async fn missing_audio_is_concealed_with_silence() {
+        let receiver = receiver_missing(MissingAudio::Silence);
+        assert_eq!(missing_tick(&receiver), PACKET_DURATION * 2);
+        assert_eq!(receiver.conceal_missing_audio(7), [None]);
+    }
+
+    #[tokio::test]
+    async fn missing_audio_repeats_the_last_packet_once() {
+        let receiver = receiver_missing(MissingAudio::Repeat);
+        receiver.remember_audio(7, &tone());
+        assert_eq!(receiver.conceal_missing_audio(7), [Some(tone())]);
+        assert_eq!(receiver.conceal_missing_audio(7), [None]);
+
+        assert_eq!(missing_tick(&receiver), PACKET_DURATION * 2);
+        // the tick's own gap used the repeat up
+        assert_eq!(receiver.conceal_missing_audio(7), [None]);
+    }
+
+    #[tokio::test]
+    async fn missing_audio_is_skipped() {
+        let receiver = receiver_missing(MissingAudio::Skip);
+        assert_eq!(missing_tick(&receiver), PACKET_DURATION);
+        assert!(receiver.conceal_missing_audio(7).is_empty());
+    }
+
+    #[tokio::test]
+    async fn empty_channel_pauses_the_lookback_until_someone_joins() {
+        let mut config = Config::for_tests();
+        config.empty_channel = EmptyChannelMode::Pause;
+        let receiver = Receiver::new(GuildId::new(1), config);
+        let audio = tone();
+        let tick = Tick {
+            speaking: vec![(7, Some(&audio))],
+            ..Default::default()
+        };
+        receiver.on_tick(&tick);
+
+        receiver.set_occupied(false);
+        assert!(!receiver.is_recording());
+        receiver.on_tick(&tick);
+        receiver.on_tick(&tick);
+        assert_eq!(receiver.lookback.buffered_duration(), PACKET_DURATION);
+
+        receiver.set_occupied(true);
+        assert!(receiver.is_recording());
+        receiver.on_tick(&tick);
+        assert_eq!(receiver.lookback.buffered_duration(), PACKET_DURATION * 2);
+    }
+
+    #[tokio::test]
+    async fn empty_channel_clear_drops_the_lookback() {
+        let mut config = Config::for_tests();
+        config.empty_channel = EmptyChannelMode::Clear;
+        let receiver = Receiver::new(GuildId::new(1), config);
+        receiver.on_tick(&Tick::default());
+        receiver.on_tick(&Tick::default());
+
+        receiver.set_occupied(false);
+        receiver.on_tick(&Tick::default());
+        assert_eq!(receiver.lookback.buffered_duration(), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn short_disconnect_is_padded_with_silence() {
+        let mut config = Config::for_tests();
+        config.reconnect_grace = Duration::from_secs(5);
+        let receiver = Receiver::new(GuildId::new(1), config);
+        let audio = tone();
+        for _ in 0..3 {
+            receiver.on_tick(&Tick {
+                speaking: vec![(7, Some(&audio))],
+                ..Default::default()
+            });
+        }
+
+        receiver.on_driver_disconnect();
+        std::thread::sleep(Duration::from_millis(65));
+        receiver.on_connect(None);
+        let buffered = receiver.lookback.buffered_duration();
+        assert!(buffered >= PACKET_DURATION * 6, "{:?}", buffered);
+        assert!(buffered < Duration::from_secs(1), "{:?}", buffered);
+
+        receiver.lookback.clear();
+        receiver.bridge_outage(Duration::from_millis(100));
+        assert_eq!(receiver.lookback.buffered_duration(), PACKET_DURATION * 5);
+    }
+
+    #[tokio::test]
+    async fn disconnect_past_the_grace_clears_the_lookback() {
+        let mut config = Config::for_tests();
+        config.reconnect_grace = Duration::from_secs(5);
+        let receiver = Receiver::new(GuildId::new(1), config);
+        receiver.on_tick(&Tick::default());
+
+        receiver.bridge_outage(Duration::from_secs(6));
+        assert_eq!(receiver.lookback.buffered_duration(), Duration::ZERO);
+    }
+
+    #[test]
+    fn user_labels_of_shared_display_names_differ() {
+        let first = user_label("../Ünïcode Name/", UserId(1234));
+        let second = user_label("../Ünïcode Name/", UserId(5678));
+        assert_ne!(first, second);
+        for label in [&first, &second] {
+            assert!(label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+            assert!(label.starts_with("n_code_Name_"), "{}", label);
+        }
+    }
+
+    #[test]
+    fn user_label_falls_back_for_unusable_names() {
+        assert_eq!(user_label("/// ", UserId(35)), "user_z");
+    }
+}
@@ -0,0 +1,141 @@
+use std::f64::consts::PI;
+
+/// Rates exports can be written at. Each is a small ratio away from 48kHz, which keeps the filter
+/// table of [`resample`] small.
+pub const SAMPLE_RATES: [u32; 10] = [
+    8000, 11025, 16000, 22050, 24000, 32000, 44100, 48000, 88200, 96000,
+];
+
+/// Zero crossings of the sinc on each side of a sample, at the lower of the two rates.
+const HALF_TAPS: usize = 16;
+
+/// Band limited conversion of interleaved pcm from one rate to another with a Blackman windowed
+/// sinc, which also low-passes below the new Nyquist when going down. The filter table grows with
+/// how little the two rates have in common, it stays small for [`SAMPLE_RATES`] and the usual
+/// rates of decoded files.
+pub fn resample(pcm: &[i16], channels: usize, from: u32, to: u32) -> Vec<i16> {
+    if from == to {
+        return pcm.to_vec();
+    }
+    let divisor = gcd(from, to);
+    let (step, phases) = ((from / divisor) as usize, (to / divisor) as usize);
+    // going down the cutoff follows the new rate, and the sinc widens in input samples with it
+    let cutoff = (to as f64 / from as f64).min(1.0);
+    let half_width = (HALF_TAPS as f64 / cutoff).ceil() as usize;
+    let taps = 2 * half_width;
+    // one row of weights per fractional position an output sample can fall on
+    let table: Vec<f64> = (0..phases)
+        .flat_map(|phase| {
+            let fraction = phase as f64 / phases as f64;
+            let row: Vec<f64> = (0..taps)
+                .map(|tap| {
+                    let offset = tap as f64 - (half_width as f64 - 1.0) - fraction;
+                    cutoff * sinc(cutoff * offset) * blackman(offset / half_width as f64)
+                })
+                .collect();
+            // unity gain at dc, whatever the phase
+            let sum: f64 = row.iter().sum();
+            row.into_iter().map(move |weight| weight / sum)
+        })
+        .collect();
+
+    let frames = pcm.len() / channels;
+    let out_frames = (frames * phases).div_ceil(step);
+    let mut out = Vec::with_capacity(out_frames * channels);
+    for n in 0..out_frames {
+        let position = n * step;
+        let (center, phase) = (position / phases, position % phases);
+        let weights = &table[phase * taps..(phase + 1) * taps];
+        let first = center as isize - (half_width as isize - 1);
+        for channel in 0..channels {
+            let mut sample = 0.0;
+            for (tap, weight) in weights.iter().enumerate() {
+                let index = first + tap as isize;
+                if index >= 0 && (index as usize) < frames {
+                    sample += weight * pcm[index as usize * channels + channel] as f64;
+                }
+            }
+            out.push(sample.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16);
+        }
+    }
+    out
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Blackman window over -1..1, zero outside it.
+fn blackman(x: f64) -> f64 {
+    if x.abs() >= 1.0 {
+        return 0.0;
+    }
+    let t = (x + 1.0) / 2.0;
+    0.42 - 0.5 * (2.0 * PI * t).cos() + 0.08 * (4.0 * PI * t).cos()
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `frames` of a `frequency` sine at `rate` in the left channel of interleaved stereo, the right
+    /// one silent.
+    fn sine(frequency: f64, rate: u32, frames: usize) -> Vec<i16> {
+        (0..frames)
+            .flat_map(|frame| {
+                let t = frame as f64 / rate as f64;
+                [
+                    (10000.0 * (2.0 * PI * frequency * t).sin()).round() as i16,
+                    0,
+                ]
+            })
+            .collect()
+    }
+
+    /// rms of the left channel, leaving out the edges where the filter runs off the input.
+    fn left_rms(pcm: &[i16]) -> f64 {
+        const EDGE: usize = 200;
+        let left: Vec<f64> = pcm.iter().step_by(2).map(|sample| *sample as f64).collect();
+        let inner = &left[EDGE..left.len() - EDGE];
+        (inner.iter().map(|sample| sample * sample).sum::<f64>() / inner.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn sine_stays_within_rounding_at_every_rate() {
+        // a second
+        let input = sine(1000.0, 48000, 48000);
+        for rate in SAMPLE_RATES {
+            let output = resample(&input, 2, 48000, rate);
+            assert_eq!(output.len(), 2 * rate as usize);
+            let expected = sine(1000.0, rate, output.len() / 2);
+            let error: Vec<i16> = output
+                .iter()
+                .zip(&expected)
+                .map(|(sample, expected)| sample - expected)
+                .collect();
+            let error = left_rms(&error);
+            assert!(error < 1.0, "{} LSB rms off at {} Hz", error, rate);
+            assert!(output.iter().skip(1).step_by(2).all(|sample| *sample == 0));
+        }
+    }
+
+    #[test]
+    fn tones_above_the_new_nyquist_are_removed() {
+        let input = sine(20000.0, 48000, 24000);
+        assert!(left_rms(&input) > 7000.0);
+        let output = resample(&input, 2, 48000, 16000);
+        assert!(left_rms(&output) < 1.0, "{}", left_rms(&output));
+    }
+}
@@ -0,0 +1,80 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serenity::all::Http;
+use serenity::model::id::ChannelId;
+
+use crate::receiver::{recovered_poisonings, Receiver};
+
+/// How much of the lookback each check drains, enough for a few frames of any frame duration.
+const CHECK_DURATION: Duration = Duration::from_secs(1);
+
+/// Runs [`crate::lookback::Lookback::self_check`] every `interval` for as long as the bot runs,
+/// logging the buffer's health. Locks poisoned by a panic since the previous check fail it too, the
+/// recording path recovers them so nothing else would notice. With `SELF_CHECK_ALERT` a failure is also posted to the log
+/// channel, only when the check starts failing and when it recovers so a broken buffer doesn't
+/// post every interval.
+pub fn spawn(receiver: Arc<Receiver>, http: Arc<Http>, log_channel: ChannelId, interval: Duration) {
+    tracing::info!(
+        "self checking the lookback every {}",
+        humantime::format_duration(interval)
+    );
+    tokio::spawn(async move {
+        let mut healthy = true;
+        let mut poisonings = recovered_poisonings();
+        loop {
+            tokio::time::sleep(interval).await;
+            let failure = check(&receiver, &mut poisonings).await.err();
+            let was_healthy = std::mem::replace(&mut healthy, failure.is_none());
+            if !receiver.config.self_check_alert || was_healthy == healthy {
+                continue;
+            }
+            let message = match failure {
+                Some(e) => format!("lookback self check failed, dumps may fail too: {}", e),
+                None => "lookback self check passes again".to_string(),
+            };
+            if let Err(e) = log_channel.say(&http, message).await {
+                tracing::error!("failed to post self check result {:?}", e);
+            }
+        }
+    });
+}
+
+/// `poisonings` is the [`recovered_poisonings`] count the previous check saw.
+async fn check(receiver: &Arc<Receiver>, poisonings: &mut u64) -> anyhow::Result<()> {
+    let started = Instant::now();
+    let lookback_receiver = receiver.clone();
+    // any other panic is on a blocking thread that only fails the check
+    let result =
+        tokio::task::spawn_blocking(move || lookback_receiver.lookback.self_check(CHECK_DURATION))
+            .await
+            .map_err(|e| anyhow::anyhow!("self check panicked: {}", e))
+            .and_then(|result| result);
+    let current = recovered_poisonings();
+    let recovered = current - std::mem::replace(poisonings, current);
+    let check = match result {
+        Ok(check) if check.undecodable > 0 => Err(anyhow::anyhow!(
+            "{} of the last {} packets don't decode",
+            check.undecodable,
+            check.packets
+        )),
+        Ok(_) if recovered > 0 => Err(anyhow::anyhow!(
+            "{} locks were poisoned by a panic since the last check, recording went on with \
+             whatever state it left behind",
+            recovered
+        )),
+        result => result,
+    }
+    .inspect_err(|e| tracing::error!("lookback self check failed {:?}", e))?;
+    tracing::info!(
+        buffered = %humantime::format_duration(Duration::from_secs(check.buffered.as_secs())),
+        checked_packets = check.packets,
+        checked_bytes = check.bytes,
+        tracked_users = receiver.tts.per_user_sound_buffer.tracked_users().len(),
+        unmapped_dropped = receiver.unmapped_dropped(),
+        mapping_conflicts = receiver.mapping_conflicts(),
+        elapsed_ms = started.elapsed().as_millis(),
+        "lookback self check passed"
+    );
+    Ok(())
+}
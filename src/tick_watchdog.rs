@@ -0,0 +1,95 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serenity::all::Http;
+use serenity::model::id::ChannelId;
+use songbird::Songbird;
+
+use crate::receiver::Receiver;
+
+/// Watches for the driver going quiet without disconnecting, which leaves the lookback frozen
+/// while everything looks fine. Once no tick has reached the lookback for `timeout` while the bot
+/// is connected and recording, the stall is logged and posted to the log channel and the call is
+/// left and joined again. Songbird's own reconnects and paused recording don't count, the time
+/// only runs while both hold.
+pub fn spawn(
+    receiver: Arc<Receiver>,
+    http: Arc<Http>,
+    manager: Arc<Songbird>,
+    log_channel: ChannelId,
+    timeout: Duration,
+) {
+    tracing::info!(
+        "reconnecting when voice ticks stall for {}",
+        humantime::format_duration(timeout)
+    );
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(timeout / 4);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut watching_since = Instant::now();
+        let mut stalled = false;
+        loop {
+            ticks.tick().await;
+            if !receiver.is_connected() || !receiver.is_recording() {
+                watching_since = Instant::now();
+                continue;
+            }
+            let quiet = receiver
+                .lookback
+                .since_last_tick()
+                .min(watching_since.elapsed());
+            if quiet < timeout {
+                if std::mem::take(&mut stalled) {
+                    tracing::info!("voice ticks resumed");
+                    say(&http, log_channel, "voice ticks resumed, recording again").await;
+                }
+                continue;
+            }
+            tracing::error!(
+                "no voice ticks for {:?} while connected, reconnecting",
+                quiet
+            );
+            let message = match reconnect(&receiver, &manager).await {
+                Ok(()) => format!(
+                    "no voice audio for {} while connected, the lookback stopped. reconnected",
+                    humantime::format_duration(Duration::from_secs(quiet.as_secs()))
+                ),
+                Err(e) => {
+                    tracing::error!("reconnect after stalled ticks failed {:?}", e);
+                    format!(
+                        "no voice audio for {} while connected, the lookback stopped. \
+                         reconnecting failed: {}",
+                        humantime::format_duration(Duration::from_secs(quiet.as_secs())),
+                        e
+                    )
+                }
+            };
+            say(&http, log_channel, &message).await;
+            stalled = true;
+            // the next attempt waits out a whole timeout again
+            watching_since = Instant::now();
+        }
+    });
+}
+
+/// Leaves and rejoins the current channel on the same call, so its event handlers stay attached
+/// and the outage is bridged like any other reconnect.
+async fn reconnect(receiver: &Receiver, manager: &Songbird) -> anyhow::Result<()> {
+    let channel = receiver
+        .channel()
+        .ok_or_else(|| anyhow::anyhow!("not in a voice channel"))?;
+    let handler_lock = manager
+        .get(receiver.guild_id)
+        .ok_or_else(|| anyhow::anyhow!("not connected to voice"))?;
+    handler_lock.lock().await.leave().await?;
+    manager
+        .join(receiver.guild_id, ChannelId::new(channel.0.get()))
+        .await?;
+    Ok(())
+}
+
+async fn say(http: &Http, log_channel: ChannelId, message: &str) {
+    if let Err(e) = log_channel.say(http, message).await {
+        tracing::error!("failed to post tick watchdog message {:?}", e);
+    }
+}
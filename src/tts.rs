@@ -1,112 +1,1109 @@
 use std::collections::HashMap;
-use std::env;
-use std::hash::BuildHasherDefault;
+use std::fmt;
 use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
 
-use audiopus::coder::Encoder;
 use circular_queue::CircularQueue;
-use nohash_hasher::NoHashHasher;
+use dashmap::DashMap;
+use dashmap::mapref::one::Ref;
+use rayon::prelude::*;
+use songbird::input::core::probe::Hint;
 use songbird::model::id::UserId;
-use tokio::sync::RwLock;
 
-use crate::encode;
+use crate::config::{Config, EncoderSettings, SilentReference};
+use crate::{crypt, decode, encode};
+use crate::encode::{SilencePacker, StreamOptions};
+use crate::filter::VoiceFilter;
+use crate::lookback::PACKET_DURATION;
 use crate::receiver::{
-    AUDIO_CHANNELS, AUDIO_FREQUENCY, empty_raw_audio, make_opus_encoder, MAX_OPUS_PACKET,
-    RawAudioPacket, read_ogg_file, user_to_ogg_file,
+    AUDIO_CHANNELS, AUDIO_FREQUENCY, FrameEncoder, lock_recovering, RawAudioPacket, read_ogg_file,
+    user_to_ogg_file,
 };
 
-/// 1000 / 20 samples per second. 60 seconds in a minute. 2 minutes.
-const BUFFER_SIZE: usize = (1000 / 20) * 60 * 2;
-
-#[derive(Default)]
 pub struct Tts {
-    pub per_user_sound_buffer: RwLock<PerUserSoundBuffer>,
+    pub per_user_sound_buffer: PerUserSoundBuffer,
     client: reqwest::Client,
+    tts_host: Option<String>,
+    stt_host: Option<String>,
+    voices: Vec<String>,
+    recording_key: Option<crypt::Key>,
+    min_reference_speech: Duration,
+    chunk_chars: Option<usize>,
+}
+
+/// What [`Tts::tts_chunked`] managed to speak.
+pub struct ChunkedSpeech {
+    /// wav of every chunk spoken, in order.
+    pub audio: bytes::Bytes,
+    pub chunks: usize,
+    /// why the chunks after the spoken ones are missing.
+    pub failed: Option<(usize, anyhow::Error)>,
 }
 
 impl Tts {
-    pub async fn tts(&self, user: UserId, text: String) -> anyhow::Result<bytes::Bytes> {
-        let tts_host = env::var("TTS_HOST")?;
-        let ogg_file = read_ogg_file(user_to_ogg_file(user)).await?;
+    pub fn new(config: &Config) -> Self {
+        Self {
+            per_user_sound_buffer: PerUserSoundBuffer::new(config),
+            client: Default::default(),
+            tts_host: config.tts_host.clone(),
+            stt_host: config.stt_host.clone(),
+            voices: config.tts_voices.clone(),
+            recording_key: config.recording_key,
+            min_reference_speech: config.min_reference_speech,
+            chunk_chars: config.tts_chunk_chars,
+        }
+    }
+
+    /// [`Self::tts`] of text longer than `TTS_CHUNK_CHARS` a few sentences at a time, see
+    /// [`split_sentences`], joined into one wav in order. Chunks are requested one after another
+    /// so a long paragraph doesn't become one request long enough to time out. When a chunk fails
+    /// the ones before it are kept, only the first failing fails the whole thing.
+    pub async fn tts_chunked(
+        &self,
+        user: UserId,
+        text: String,
+        voice: Option<String>,
+    ) -> anyhow::Result<ChunkedSpeech> {
+        let chunks = match self.chunk_chars {
+            Some(max_chars) if text.chars().count() > max_chars => {
+                split_sentences(&text, max_chars)
+            }
+            _ => {
+                return Ok(ChunkedSpeech {
+                    audio: self.tts(user, text, voice).await?,
+                    chunks: 1,
+                    failed: None,
+                });
+            }
+        };
+        let mut hint = Hint::default();
+        hint.mime_type("audio/wav").with_extension("wav");
+        let mut packets = Vec::new();
+        let mut failed = None;
+        for (index, chunk) in chunks.iter().enumerate() {
+            let spoken = self
+                .tts(user, chunk.clone(), voice.clone())
+                .await
+                .and_then(|audio| decode::decode_to_raw_audio(audio, &hint));
+            match spoken {
+                Ok(spoken) => packets.extend(spoken),
+                Err(e) if index == 0 => return Err(e),
+                Err(e) => {
+                    tracing::warn!("tts chunk {} of {} failed {:?}", index + 1, chunks.len(), e);
+                    failed = Some((index, e));
+                    break;
+                }
+            }
+        }
+        Ok(ChunkedSpeech {
+            audio: encode::encode_wav::<AUDIO_CHANNELS>(&packets.concat(), AUDIO_FREQUENCY).into(),
+            chunks: chunks.len(),
+            failed,
+        })
+    }
+
+    /// Speaks `text` with a backend `voice` if given, otherwise clones the user's reference.
+    #[tracing::instrument(
+        skip_all,
+        fields(
+            user = %user,
+            voice = voice.as_deref().unwrap_or("cloned"),
+            text_len = text.len()
+        )
+    )]
+    pub async fn tts(
+        &self,
+        user: UserId,
+        text: String,
+        voice: Option<String>,
+    ) -> anyhow::Result<bytes::Bytes> {
+        let tts_host = self
+            .tts_host
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("TTS_HOST is not configured"))?;
+        let form = reqwest::multipart::Form::new().text("text", text);
+        let form = match voice {
+            Some(voice) => {
+                // only forward known voices so arbitrary values can't reach the backend
+                if !self.voices.contains(&voice) {
+                    return Err(anyhow::anyhow!(
+                        "unknown voice '{}', available voices: {}",
+                        voice,
+                        if self.voices.is_empty() {
+                            "none".to_string()
+                        } else {
+                            self.voices.join(", ")
+                        }
+                    ));
+                }
+                form.text("voice", voice)
+            }
+            None => {
+                let ogg_file =
+                    read_ogg_file(user_to_ogg_file(user), self.recording_key.as_ref()).await?;
+                self.check_reference(&ogg_file)?;
+                tracing::info!(
+                    reference_bytes = ogg_file.len(),
+                    "sending cloning reference"
+                );
+                let file_part = reqwest::multipart::Part::bytes(ogg_file)
+                    .file_name("speaker.ogg")
+                    .mime_str("audio/ogg")?;
+                form.part("speaker", file_part)
+            }
+        };
+        let started = Instant::now();
+        let response = self
+            .client
+            .post(format!("{}/tts", tts_host))
+            .multipart(form)
+            .send()
+            .await
+            .inspect_err(|e| {
+                tracing::warn!(
+                    elapsed_ms = started.elapsed().as_millis(),
+                    "tts request failed {:?}",
+                    e
+                )
+            })?;
+        let status = response.status();
+        if !status.is_success() {
+            const MAX_LOGGED_BODY: usize = 500;
+            let body = response.text().await.unwrap_or_default();
+            let body: String = body.chars().take(MAX_LOGGED_BODY).collect();
+            tracing::warn!(
+                %status,
+                elapsed_ms = started.elapsed().as_millis(),
+                body,
+                "tts backend returned an error"
+            );
+            return Err(anyhow::anyhow!("tts backend returned {}", status));
+        }
+        let bytes = response.bytes().await?;
+        tracing::info!(
+            response_bytes = bytes.len(),
+            elapsed_ms = started.elapsed().as_millis(),
+            "tts done"
+        );
+        Ok(bytes)
+    }
+
+    /// Transcribes an ogg opus recording with the `STT_HOST` backend, which answers with the text.
+    pub async fn stt(&self, ogg_file: Vec<u8>) -> anyhow::Result<String> {
+        let stt_host = self
+            .stt_host
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("STT_HOST is not configured"))?;
         let file_part = reqwest::multipart::Part::bytes(ogg_file)
-            .file_name("speaker.ogg")
+            .file_name("audio.ogg")
             .mime_str("audio/ogg")?;
-        let form = reqwest::multipart::Form::new()
-            .part("speaker", file_part)
-            .text("text", text);
+        let form = reqwest::multipart::Form::new().part("audio", file_part);
+        let started = Instant::now();
         let response = self
             .client
-            .post(format!("{}/tts", tts_host))
+            .post(format!("{}/stt", stt_host))
             .multipart(form)
             .send()
-            .await?
-            .error_for_status()?;
-        Ok(response.bytes().await?)
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(anyhow::anyhow!("stt backend returned {}", status));
+        }
+        let text = response.text().await?;
+        tracing::debug!(
+            text_len = text.len(),
+            elapsed_ms = started.elapsed().as_millis(),
+            "stt done"
+        );
+        Ok(text)
+    }
+
+    /// Fails unless the reference has at least `MIN_REFERENCE_SPEECH` of speech, an empty or
+    /// mostly silent one only gets garbage back from the backend.
+    fn check_reference(&self, ogg_file: &[u8]) -> anyhow::Result<()> {
+        let speech = self.reference_speech(ogg_file);
+        if speech < self.min_reference_speech {
+            return Err(anyhow::anyhow!(
+                "the cloning reference only has {} of speech, at least {} is needed. \
+                 have them talk some more and /clone again",
+                humantime::format_duration(Duration::from_millis(speech.as_millis() as u64)),
+                humantime::format_duration(self.min_reference_speech)
+            ));
+        }
+        Ok(())
+    }
+
+    /// How much of a reference isn't silence, zero if it isn't ogg opus at all.
+    fn reference_speech(&self, ogg_file: &[u8]) -> Duration {
+        let silence = &self.per_user_sound_buffer.empty_encoded;
+        decode::demux(ogg_file)
+            .unwrap_or_default()
+            .iter()
+            .filter(|packet| !SilencePacker::is_silence(silence, packet))
+            .map(|packet| encode::packet_duration(packet))
+            .sum()
+    }
+}
+
+/// `text` cut into chunks of at most `max_chars` characters, each as many whole sentences as fit.
+/// A sentence longer than that is cut between words, a word longer than that wherever it has to
+/// be. Sentences end after `.`, `!` or `?` followed by whitespace.
+pub fn split_sentences(text: &str, max_chars: usize) -> Vec<String> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        let ends = matches!(c, '.' | '!' | '?')
+            && chars.peek().map_or(true, |(_, next)| next.is_whitespace());
+        if ends {
+            let end = index + c.len_utf8();
+            sentences.push(&text[start..end]);
+            start = end;
+        }
+    }
+    sentences.push(&text[start..]);
+
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    let mut push = |chunk: &mut String, piece: &str, separator: &str| {
+        let separator = if chunk.is_empty() { "" } else { separator };
+        if chunk.chars().count() + separator.chars().count() + piece.chars().count() > max_chars
+            && !chunk.is_empty()
+        {
+            chunks.push(std::mem::take(chunk));
+            chunk.push_str(piece);
+        } else {
+            chunk.push_str(separator);
+            chunk.push_str(piece);
+        }
+    };
+    for sentence in sentences.iter().map(|sentence| sentence.trim()) {
+        if sentence.is_empty() {
+            continue;
+        }
+        if sentence.chars().count() <= max_chars {
+            push(&mut chunk, sentence, " ");
+            continue;
+        }
+        for word in sentence.split_whitespace() {
+            let word: Vec<char> = word.chars().collect();
+            for piece in word.chunks(max_chars) {
+                push(&mut chunk, &piece.iter().collect::<String>(), " ");
+            }
+        }
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
     }
+    chunks
+}
+
+/// `new` appended to an `existing` reference as one stream tagged with `comments`, with the oldest
+/// audio dropped until it's no longer than `max`. Returns the stream and how long it is.
+pub fn append_reference(
+    existing: &[u8],
+    new: &[u8],
+    max: Duration,
+    comments: &[String],
+) -> anyhow::Result<(Vec<u8>, Duration)> {
+    let mut packets = decode::demux(existing)?;
+    packets.extend(decode::demux(new)?);
+    let mut duration: Duration = packets
+        .iter()
+        .map(|packet| encode::packet_duration(packet))
+        .sum();
+    let mut dropped = 0;
+    while duration > max && dropped < packets.len() {
+        duration -= encode::packet_duration(&packets[dropped]);
+        dropped += 1;
+    }
+    let ogg_file = encode::encode_with_comments::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(
+        &packets[dropped..],
+        &StreamOptions::default(),
+        comments,
+    )?;
+    Ok((ogg_file, duration))
+}
+
+/// The user's buffer has too little speech to clone from, cloning it would only produce noise.
+#[derive(Debug)]
+pub struct NoUsableAudio {
+    pub speech_ratio: f32,
+}
+
+impl fmt::Display for NoUsableAudio {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "no usable audio captured for this user, only {:.0}% of the buffer is speech",
+            self.speech_ratio * 100.0
+        )
+    }
+}
+
+impl std::error::Error for NoUsableAudio {}
+
+struct UserSoundPackets {
+    packets: CircularQueue<bytes::Bytes>,
+    /// whether each of `packets` counts as the user talking, speech or silence within the hangover
+    /// after it.
+    active: CircularQueue<bool>,
+    /// audio pushed since the user last spoke, `None` until they do.
+    since_spoke: Option<Duration>,
+    /// wall clock time the newest packet finished capturing, places the buffer on a timeline.
+    last_packet_at: SystemTime,
+    /// each user has their own encoder so users can be encoded in parallel and don't share
+    /// predictor state.
+    frame_encoder: FrameEncoder,
+    filter: VoiceFilter,
 }
 
 pub struct PerUserSoundBuffer {
-    user_to_sound_packets:
-        HashMap<UserId, CircularQueue<bytes::Bytes>, BuildHasherDefault<NoHashHasher<u64>>>,
-    opus_encoder: Mutex<Encoder>, // will never actually be contested
+    user_to_sound_packets: DashMap<UserId, Mutex<UserSoundPackets>>,
+    /// last time each user pushed actual audio, kept apart from their buffers so picking an
+    /// eviction victim doesn't lock every buffer.
+    last_active: DashMap<UserId, Instant>,
+    max_tracked_users: usize,
+    /// held while adding a user, so concurrent new users can't evict for the same free slot.
+    inserting: Mutex<()>,
+    buffer_size: usize,
+    /// for encoders of users seen from now on.
+    encoder_settings: Mutex<EncoderSettings>,
     empty_encoded: bytes::Bytes,
-    output_scratch_space: [u8; MAX_OPUS_PACKET],
+    /// max silent packets inside a single utterance.
+    utterance_gap_packets: usize,
+    speech_hangover: Duration,
+    /// cloned for each new user.
+    filter: VoiceFilter,
+    min_reference_speech_ratio: f32,
+    silent_reference: SilentReference,
 }
 
-impl Default for PerUserSoundBuffer {
-    fn default() -> Self {
-        let opus_encoder = make_opus_encoder();
-        let mut output_scratch_space = [0; MAX_OPUS_PACKET];
-        let empty_encoded = {
-            let empty = empty_raw_audio();
-            let result = opus_encoder
-                .encode(&empty, &mut output_scratch_space)
-                .unwrap();
-            bytes::Bytes::copy_from_slice(&output_scratch_space[..result])
-        };
-
+impl PerUserSoundBuffer {
+    pub fn new(config: &Config) -> Self {
         Self {
             user_to_sound_packets: Default::default(),
-            opus_encoder: opus_encoder.into(),
-            empty_encoded,
-            output_scratch_space,
+            last_active: Default::default(),
+            max_tracked_users: config.max_tracked_users,
+            inserting: Mutex::new(()),
+            buffer_size: config.encoder.packets_for_duration(config.clone_duration),
+            encoder_settings: Mutex::new(config.encoder),
+            empty_encoded: FrameEncoder::new(&config.encoder).empty_encoded().clone(),
+            utterance_gap_packets: config.encoder.packets_for_duration(config.utterance_gap),
+            speech_hangover: config.speech_hangover,
+            filter: VoiceFilter::new(config),
+            min_reference_speech_ratio: config.clone_min_speech_ratio,
+            silent_reference: config.silent_reference,
         }
     }
-}
 
-impl PerUserSoundBuffer {
-    pub fn push(&mut self, user: UserId, data: Option<RawAudioPacket>) {
-        let encoded_packet = self.encode_opus_packet(data);
-        let buf = self
-            .user_to_sound_packets
+    /// Safe to call concurrently for different users, only the pushed user's buffer is locked.
+    /// Returns the frame encoded by this push, if one was completed.
+    pub fn push(&self, user: UserId, data: Option<RawAudioPacket>) -> Option<bytes::Bytes> {
+        let buf = match self.user_to_sound_packets.get(&user) {
+            Some(buf) => buf,
+            None => self.insert_user(user),
+        };
+        let mut buf = lock_recovering(buf.value(), "user sound buffer");
+        let data = data.and_then(|packet| buf.filter.process(packet));
+        if data.is_some() {
+            self.last_active.insert(user, Instant::now());
+            buf.since_spoke = Some(Duration::ZERO);
+        } else {
+            buf.since_spoke = buf.since_spoke.map(|since| since + PACKET_DURATION);
+        }
+        let encoded_packet = buf.frame_encoder.push(data.as_ref())?;
+        let active = encoded_packet != self.empty_encoded
+            || buf
+                .since_spoke
+                .is_some_and(|since| since <= self.speech_hangover);
+        buf.active.push(active);
+        buf.packets.push(encoded_packet.clone());
+        buf.last_packet_at = SystemTime::now();
+        Some(encoded_packet)
+    }
+
+    /// A fresh buffer for the user unless a concurrent push beat us to it. The least recently
+    /// active users are evicted first to keep within `max_tracked_users`.
+    fn insert_user(&self, user: UserId) -> Ref<'_, UserId, Mutex<UserSoundPackets>> {
+        let _inserting = lock_recovering(&self.inserting, "inserting user");
+        if let Some(buf) = self.user_to_sound_packets.get(&user) {
+            return buf;
+        }
+        // counting and evicting take every shard, so no entry may be held yet
+        self.evict_down_to(self.max_tracked_users - 1);
+        self.last_active.insert(user, Instant::now());
+        self.user_to_sound_packets
             .entry(user)
-            .or_insert_with(|| CircularQueue::with_capacity(BUFFER_SIZE));
-        buf.push(encoded_packet);
-    }
-
-    fn encode_opus_packet(&mut self, data: Option<RawAudioPacket>) -> bytes::Bytes {
-        if let Some(data) = data {
-            let encoded_size = self
-                .opus_encoder
-                .lock()
-                .expect("encoded opus buf lock panicked")
-                .encode(&data, &mut self.output_scratch_space);
-            if let Ok(encoded_size) = encoded_size {
-                return bytes::Bytes::copy_from_slice(&self.output_scratch_space[..encoded_size]);
+            .or_insert_with(|| {
+                Mutex::new(UserSoundPackets {
+                    packets: CircularQueue::with_capacity(self.buffer_size),
+                    active: CircularQueue::with_capacity(self.buffer_size),
+                    since_spoke: None,
+                    last_packet_at: SystemTime::now(),
+                    // the startup silence packet, which everything else compares against
+                    frame_encoder: FrameEncoder::with_silence(
+                        &lock_recovering(&self.encoder_settings, "encoder settings"),
+                        self.empty_encoded.clone(),
+                    ),
+                    filter: self.filter.clone(),
+                })
+            })
+            .downgrade()
+    }
+
+    /// Rebuilds every user's encoder with new settings, see [`FrameEncoder::reconfigure`].
+    pub fn reconfigure_encoders(&self, settings: &EncoderSettings) {
+        *lock_recovering(&self.encoder_settings, "encoder settings") = *settings;
+        for buf in self.user_to_sound_packets.iter() {
+            lock_recovering(buf.value(), "user sound buffer")
+                .frame_encoder
+                .reconfigure(settings);
+        }
+    }
+
+    /// users that currently have a buffer.
+    pub fn tracked_users(&self) -> Vec<UserId> {
+        self.user_to_sound_packets
+            .iter()
+            .map(|entry| *entry.key())
+            .collect()
+    }
+
+    /// whether the user's buffer holds anything besides silence.
+    pub fn has_speech(&self, user: UserId) -> bool {
+        self.user_to_sound_packets.get(&user).is_some_and(|buf| {
+            self.speech_ratio(&lock_recovering(buf.value(), "user sound buffer").packets) > 0.0
+        })
+    }
+
+    /// The user's most recent speech packets up to `duration` of them, oldest first and without
+    /// the silence between them. Empty if the user has no buffer or hasn't spoken.
+    pub fn recent_speech(&self, user: UserId, duration: Duration) -> Vec<bytes::Bytes> {
+        let Some(buf) = self.user_to_sound_packets.get(&user) else {
+            return Vec::new();
+        };
+        let buf = lock_recovering(buf.value(), "user sound buffer");
+        let mut collected = Duration::ZERO;
+        let mut speech: Vec<_> = buf
+            .packets
+            .iter()
+            .filter(|packet| **packet != self.empty_encoded)
+            .take_while(|packet| {
+                let within = collected < duration;
+                collected += encode::packet_duration(packet);
+                within
+            })
+            .cloned()
+            .collect();
+        speech.reverse();
+        speech
+    }
+
+    /// How much the user's buffer holds and how much of that is speech, pauses within the speech
+    /// hangover included, `None` if it has no buffer. Only counts packets, nothing is decoded.
+    pub fn buffered_duration(&self, user: UserId) -> Option<(Duration, Duration)> {
+        let frame_duration =
+            lock_recovering(&self.encoder_settings, "encoder settings").frame_duration;
+        let buf = self.user_to_sound_packets.get(&user)?;
+        let buf = lock_recovering(buf.value(), "user sound buffer");
+        let speech = buf.active.iter().filter(|active| **active).count();
+        Some((
+            frame_duration * buf.packets.len() as u32,
+            frame_duration * speech as u32,
+        ))
+    }
+
+    /// Drops the least recently active users until at most `max` remain.
+    fn evict_down_to(&self, max: usize) {
+        while self.user_to_sound_packets.len() > max {
+            let lru_user = self
+                .last_active
+                .iter()
+                .min_by_key(|entry| *entry.value())
+                .map(|entry| *entry.key());
+            let Some(lru_user) = lru_user else {
+                break;
+            };
+            self.last_active.remove(&lru_user);
+            self.user_to_sound_packets.remove(&lru_user);
+            tracing::info!(
+                "evicted sound buffer for user {}, tracking limit of {} reached",
+                lru_user,
+                self.max_tracked_users
+            );
+        }
+    }
+
+    /// The user's buffer as one stream tagged with `comments`, see [`encode::user_comments`].
+    pub fn get_ogg_buffer(&self, user: UserId, comments: &[String]) -> anyhow::Result<Vec<u8>> {
+        let mut packets = Vec::new();
+        {
+            // closure to limit lock scope
+            let buf = self
+                .user_to_sound_packets
+                .get(&user)
+                .ok_or_else(|| anyhow::anyhow!("missing user registration"))?;
+            let circular_queue = &lock_recovering(buf.value(), "user sound buffer").packets;
+            packets.reserve(circular_queue.len());
+            for sample in circular_queue.asc_iter() {
+                packets.push(sample.clone());
             }
         }
-        self.empty_encoded.clone()
+        encode::encode_with_comments::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(
+            &packets,
+            &StreamOptions::default(),
+            comments,
+        )
     }
 
-    pub fn get_ogg_buffer(&self, user: UserId) -> anyhow::Result<Vec<u8>> {
-        let circular_queue = self
+    /// [`Self::get_ogg_buffer`] of every tracked user, each tagged with `comments` of its user. The
+    /// buffers are snapshotted in one pass over the map, each user's lock taken once, then muxed in
+    /// parallel without holding any of them.
+    pub fn get_all_ogg_buffers(
+        &self,
+        comments: impl Fn(UserId) -> Vec<String> + Sync,
+    ) -> anyhow::Result<HashMap<UserId, Vec<u8>>> {
+        let snapshots: Vec<(UserId, Vec<bytes::Bytes>)> = self
+            .user_to_sound_packets
+            .iter()
+            .map(|entry| {
+                let buf = lock_recovering(entry.value(), "user sound buffer");
+                (*entry.key(), buf.packets.asc_iter().cloned().collect())
+            })
+            .collect();
+        snapshots
+            .into_par_iter()
+            .map(|(user, packets)| {
+                Ok((
+                    user,
+                    encode::encode_with_comments::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(
+                        &packets,
+                        &StreamOptions::default(),
+                        &comments(user),
+                    )?,
+                ))
+            })
+            .collect()
+    }
+
+    /// The users' buffers padded with silence at both ends so they all cover the same stretch of
+    /// time, from the earliest buffered packet of any of them to the latest, and line up when
+    /// layered in a DAW. Users evicted in the meantime are left out. Each is tagged with `comments`
+    /// of its user.
+    pub fn get_aligned_ogg_buffers(
+        &self,
+        users: &[UserId],
+        comments: impl Fn(UserId) -> Vec<String>,
+    ) -> anyhow::Result<Vec<(UserId, Vec<u8>)>> {
+        self.get_aligned_packets(users)
+            .into_iter()
+            .map(|(user, packets)| {
+                Ok((
+                    user,
+                    encode::encode_with_comments::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(
+                        &packets,
+                        &StreamOptions::default(),
+                        &comments(user),
+                    )?,
+                ))
+            })
+            .collect()
+    }
+
+    /// Packets of [`Self::get_aligned_ogg_buffers`], every track the same length.
+    pub fn get_aligned_packets(&self, users: &[UserId]) -> Vec<(UserId, Vec<bytes::Bytes>)> {
+        let frame_duration =
+            lock_recovering(&self.encoder_settings, "encoder settings").frame_duration;
+        let tracks: Vec<(UserId, Vec<bytes::Bytes>, SystemTime)> = users
+            .iter()
+            .filter_map(|user| {
+                let buf = self.user_to_sound_packets.get(user)?;
+                let buf = lock_recovering(buf.value(), "user sound buffer");
+                let packets: Vec<_> = buf.packets.asc_iter().cloned().collect();
+                let start = buf.last_packet_at - frame_duration * packets.len() as u32;
+                Some((*user, packets, start))
+            })
+            .collect();
+        let Some(timeline_start) = tracks.iter().map(|(_, _, start)| *start).min() else {
+            return Vec::new();
+        };
+        // rounded to whole frames, the longest padded track sets the length for all of them
+        let leads: Vec<usize> = tracks
+            .iter()
+            .map(|(_, _, start)| {
+                let offset = start.duration_since(timeline_start).unwrap_or_default();
+                ((offset + frame_duration / 2).as_nanos() / frame_duration.as_nanos()) as usize
+            })
+            .collect();
+        let total = tracks
+            .iter()
+            .zip(&leads)
+            .map(|((_, packets, _), lead)| lead + packets.len())
+            .max()
+            .unwrap_or(0);
+        tracks
+            .into_iter()
+            .zip(leads)
+            .map(|((user, packets, _), lead)| {
+                let trail = total - lead - packets.len();
+                let mut padded = Vec::with_capacity(total);
+                padded.extend(std::iter::repeat(self.empty_encoded.clone()).take(lead));
+                padded.extend(packets);
+                padded.extend(std::iter::repeat(self.empty_encoded.clone()).take(trail));
+                (user, padded)
+            })
+            .collect()
+    }
+
+    /// The user's buffer as a `clone` reference, checked for enough speech first. Fails with
+    /// `NoUsableAudio` when `SILENT_REFERENCE` is `error` and the buffer falls short.
+    pub fn get_clone_reference(
+        &self,
+        user: UserId,
+        comments: &[String],
+    ) -> anyhow::Result<Vec<u8>> {
+        let speech_ratio = {
+            let buf = self
+                .user_to_sound_packets
+                .get(&user)
+                .ok_or_else(|| anyhow::anyhow!("missing user registration"))?;
+            let packets = &lock_recovering(buf.value(), "user sound buffer").packets;
+            self.speech_ratio(packets)
+        };
+        if speech_ratio <= 0.0 || speech_ratio < self.min_reference_speech_ratio {
+            match self.silent_reference {
+                SilentReference::Allow => {}
+                SilentReference::Warn => tracing::warn!(
+                    "cloning user {} with a speech ratio of only {:.3}",
+                    user,
+                    speech_ratio
+                ),
+                SilentReference::Error => return Err(NoUsableAudio { speech_ratio }.into()),
+            }
+        }
+        self.get_ogg_buffer(user, comments)
+    }
+
+    /// The most recent continuous speech from the user, trailing silence skipped and ending at
+    /// the first silence gap longer than the configured utterance gap, not counting silence within
+    /// the speech hangover. Tagged with `comments`.
+    pub fn get_last_utterance(&self, user: UserId, comments: &[String]) -> anyhow::Result<Vec<u8>> {
+        let mut utterance = Vec::new();
+        {
+            // closure to limit lock scope
+            let buf = self
+                .user_to_sound_packets
+                .get(&user)
+                .ok_or_else(|| anyhow::anyhow!("missing user registration"))?;
+            let buf = lock_recovering(buf.value(), "user sound buffer");
+            let mut gap = 0;
+            // newest to oldest
+            for (packet, active) in buf.packets.iter().zip(buf.active.iter()) {
+                if *packet == self.empty_encoded {
+                    if utterance.is_empty() {
+                        continue;
+                    }
+                    if !active {
+                        gap += 1;
+                    }
+                    if gap > self.utterance_gap_packets {
+                        break;
+                    }
+                } else {
+                    gap = 0;
+                }
+                utterance.push(packet.clone());
+            }
+        }
+        // silence collected before the gap was known to be too long
+        while utterance.last() == Some(&self.empty_encoded) {
+            utterance.pop();
+        }
+        if utterance.is_empty() {
+            return Err(anyhow::anyhow!("no speech buffered for user"));
+        }
+        utterance.reverse();
+        encode::encode_with_comments::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(
+            &utterance,
+            &StreamOptions::default(),
+            comments,
+        )
+    }
+
+    /// fraction of the buffered packets that aren't the silence placeholder.
+    fn speech_ratio(&self, packets: &CircularQueue<bytes::Bytes>) -> f32 {
+        if packets.is_empty() {
+            return 0.0;
+        }
+        let speech = packets
+            .iter()
+            .filter(|packet| **packet != self.empty_encoded)
+            .count();
+        speech as f32 / packets.len() as f32
+    }
+
+    /// Drops every user's buffer and encoder, returning how many there were. Users get a fresh
+    /// buffer on their next push.
+    pub fn clear(&self) -> usize {
+        let cleared = self.user_to_sound_packets.len();
+        self.user_to_sound_packets.clear();
+        self.user_to_sound_packets.shrink_to_fit();
+        self.last_active.clear();
+        self.last_active.shrink_to_fit();
+        cleared
+    }
+
+    /// Drops every user whose buffer is all silence or has a speech ratio below
+    /// `min_speech_ratio`. Returns how many buffers were dropped.
+    pub fn compact(&self, min_speech_ratio: f32) -> usize {
+        let mut reclaimed = Vec::new();
+        self.user_to_sound_packets.retain(|user, buf| {
+            let buf = lock_recovering(buf, "user sound buffer");
+            let speech_ratio = self.speech_ratio(&buf.packets);
+            let keep = speech_ratio > 0.0 && speech_ratio >= min_speech_ratio;
+            if !keep {
+                tracing::info!(
+                    "compacting sound buffer for user {}, speech ratio {:.3}",
+                    user,
+                    speech_ratio
+                );
+                reclaimed.push(*user);
+            }
+            keep
+        });
+        for user in &reclaimed {
+            self.last_active.remove(user);
+        }
+        reclaimed.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use audiopus::Application;
+
+    use super::*;
+    use crate::receiver::tests::tone;
+
+    fn buffer(configure: impl FnOnce(&mut Config)) -> PerUserSoundBuffer {
+        let mut config = Config::for_tests();
+        configure(&mut config);
+        PerUserSoundBuffer::new(&config)
+    }
+
+    #[test]
+    fn concurrent_pushes_keep_every_packet() {
+        let buffer = buffer(|_| {});
+        thread::scope(|scope| {
+            for thread in 0..8 {
+                let buffer = &buffer;
+                scope.spawn(move || {
+                    for _ in 0..25 {
+                        // every thread races to create the shared user's buffer
+                        buffer.push(UserId(1), Some(tone()));
+                        buffer.push(UserId(100 + thread), None);
+                    }
+                });
+            }
+        });
+        assert_eq!(buffer.tracked_users().len(), 9);
+        let (shared, speech) = buffer.buffered_duration(UserId(1)).unwrap();
+        assert_eq!(shared, PACKET_DURATION * 200);
+        assert_eq!(speech, shared);
+        for thread in 0..8 {
+            let (own, _) = buffer.buffered_duration(UserId(100 + thread)).unwrap();
+            assert_eq!(own, PACKET_DURATION * 25);
+        }
+    }
+
+    #[test]
+    fn sentences_are_packed_into_chunks_in_order() {
+        assert_eq!(
+            split_sentences("One. Two! Three?  Four.", 10),
+            ["One. Two!", "Three?", "Four."]
+        );
+        // only punctuation followed by whitespace ends a sentence
+        assert_eq!(
+            split_sentences("v1.2 is out. Yes.", 12),
+            ["v1.2 is out.", "Yes."]
+        );
+        assert!(split_sentences("  ", 10).is_empty());
+    }
+
+    #[test]
+    fn overlong_sentences_are_cut_between_words() {
+        assert_eq!(
+            split_sentences("aaaa bbbb cccc.", 9),
+            ["aaaa bbbb", "cccc."]
+        );
+        assert_eq!(split_sentences("abcdefghij", 4), ["abcd", "efgh", "ij"]);
+        // characters, not bytes
+        assert_eq!(split_sentences("ééé", 2), ["éé", "é"]);
+    }
+
+    /// Panics while holding the user's buffer lock, as a panicking encoder would.
+    fn poison(buffer: &PerUserSoundBuffer, user: UserId) {
+        thread::scope(|scope| {
+            let poisoner = scope.spawn(|| {
+                let buf = buffer.user_to_sound_packets.get(&user).unwrap();
+                let _guard = buf.lock().unwrap();
+                panic!("poisoning the user's buffer");
+            });
+            assert!(poisoner.join().is_err());
+        });
+        assert!(is_poisoned(buffer, user));
+    }
+
+    fn is_poisoned(buffer: &PerUserSoundBuffer, user: UserId) -> bool {
+        buffer
             .user_to_sound_packets
             .get(&user)
-            .ok_or_else(|| anyhow::anyhow!("missing user registration"))?;
-        let mut packets = Vec::with_capacity(circular_queue.len());
-        for sample in circular_queue.asc_iter() {
-            packets.push(sample.clone());
+            .unwrap()
+            .is_poisoned()
+    }
+
+    #[test]
+    fn pushes_recover_a_poisoned_buffer() {
+        let buffer = buffer(|_| {});
+        buffer.push(UserId(1), Some(tone()));
+        poison(&buffer, UserId(1));
+
+        assert!(buffer.push(UserId(1), Some(tone())).is_some());
+        assert!(buffer.push(UserId(1), None).is_some());
+        assert!(!is_poisoned(&buffer, UserId(1)));
+        let (buffered, _) = buffer.buffered_duration(UserId(1)).unwrap();
+        assert_eq!(buffered, PACKET_DURATION * 3);
+    }
+
+    #[test]
+    fn exports_recover_a_poisoned_buffer() {
+        let buffer = buffer(|_| {});
+        buffer.push(UserId(1), Some(tone()));
+        poison(&buffer, UserId(1));
+
+        assert!(buffer.has_speech(UserId(1)));
+        assert!(!is_poisoned(&buffer, UserId(1)));
+        poison(&buffer, UserId(1));
+        buffer.reconfigure_encoders(&Config::for_tests().encoder);
+        poison(&buffer, UserId(1));
+        assert!(buffer
+            .get_all_ogg_buffers(|_| Vec::new())
+            .unwrap()
+            .contains_key(&UserId(1)));
+        assert!(!is_poisoned(&buffer, UserId(1)));
+    }
+
+    #[test]
+    fn all_silence_reference_is_no_usable_audio() {
+        let buffer = buffer(|config| config.silent_reference = SilentReference::Error);
+        for _ in 0..50 {
+            buffer.push(UserId(1), None);
+        }
+        let error = buffer.get_clone_reference(UserId(1), &[]).unwrap_err();
+        let error = error.downcast_ref::<NoUsableAudio>().unwrap();
+        assert_eq!(error.speech_ratio, 0.0);
+    }
+
+    #[test]
+    fn reference_needs_the_minimum_speech_ratio() {
+        let buffer = buffer(|config| {
+            config.silent_reference = SilentReference::Error;
+            config.clone_min_speech_ratio = 0.5;
+        });
+        for i in 0..10 {
+            buffer.push(UserId(1), (i < 3).then(tone));
+            buffer.push(UserId(2), (i < 6).then(tone));
+        }
+        let error = buffer.get_clone_reference(UserId(1), &[]).unwrap_err();
+        assert!(error.downcast_ref::<NoUsableAudio>().is_some(), "{}", error);
+        assert!(buffer.get_clone_reference(UserId(2), &[]).is_ok());
+    }
+
+    #[test]
+    fn compact_drops_silent_and_mostly_silent_buffers() {
+        let buffer = buffer(|_| {});
+        for i in 0..10 {
+            buffer.push(UserId(1), None);
+            buffer.push(UserId(2), (i < 3).then(tone));
+            buffer.push(UserId(3), (i < 8).then(tone));
+        }
+        // without a ratio only the all silence buffer goes
+        assert_eq!(buffer.compact(0.0), 1);
+        let mut users = buffer.tracked_users();
+        users.sort();
+        assert_eq!(users, [UserId(2), UserId(3)]);
+
+        assert_eq!(buffer.compact(0.5), 1);
+        assert_eq!(buffer.tracked_users(), [UserId(3)]);
+    }
+
+    #[test]
+    fn users_first_heard_after_an_encoder_change_keep_the_silence_packet() {
+        let buffer = buffer(|_| {});
+        let mut settings = Config::for_tests().encoder;
+        settings.vbr = false;
+        settings.application = Application::Voip;
+        buffer.reconfigure_encoders(&settings);
+        for _ in 0..10 {
+            buffer.push(UserId(1), None);
         }
-        encode::encode::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(&packets)
+
+        assert_eq!(
+            buffer.buffered_duration(UserId(1)),
+            Some((PACKET_DURATION * 10, Duration::ZERO))
+        );
+        assert_eq!(buffer.compact(0.0), 1);
+        assert!(buffer.tracked_users().is_empty());
+    }
+
+    #[test]
+    fn pause_within_the_hangover_does_not_split_an_utterance() {
+        for (hangover, packets) in [(Duration::from_millis(300), 30), (Duration::ZERO, 10)] {
+            let buffer = buffer(|config| {
+                config.utterance_gap = Duration::from_millis(100);
+                config.speech_hangover = hangover;
+            });
+            for speaking in [true, false, true, false] {
+                for _ in 0..10 {
+                    buffer.push(UserId(1), speaking.then(tone));
+                }
+            }
+            let utterance = buffer.get_last_utterance(UserId(1), &[]).unwrap();
+            assert_eq!(
+                decode::demux(&utterance).unwrap().len(),
+                packets,
+                "with a {:?} hangover",
+                hangover
+            );
+        }
+    }
+
+    /// A clone reference of `speech` ticks of talking followed by `silence` ticks of silence.
+    fn reference(speech: usize, silence: usize) -> Vec<u8> {
+        let mut encoder = FrameEncoder::new(&Config::for_tests().encoder);
+        let audio = tone();
+        let mut packets: Vec<_> = (0..speech)
+            .filter_map(|_| encoder.push(Some(&audio)))
+            .collect();
+        packets.extend((0..silence).filter_map(|_| encoder.push(None)));
+        encode::encode::<AUDIO_FREQUENCY, AUDIO_CHANNELS>(&packets).unwrap()
+    }
+
+    #[test]
+    fn appending_twice_adds_both_captures() {
+        let max = Duration::from_secs(60);
+        let (once, duration) =
+            append_reference(&reference(10, 0), &reference(5, 5), max, &[]).unwrap();
+        assert_eq!(duration, PACKET_DURATION * 20);
+        let (twice, duration) = append_reference(&once, &reference(3, 0), max, &[]).unwrap();
+        assert_eq!(duration, PACKET_DURATION * 23);
+        assert_eq!(decode::demux(&twice).unwrap().len(), 23);
+    }
+
+    #[test]
+    fn appending_past_the_cap_drops_the_oldest_audio() {
+        let (capped, duration) = append_reference(
+            &reference(10, 0),
+            &reference(0, 10),
+            Duration::from_millis(250),
+            &[],
+        )
+        .unwrap();
+        assert_eq!(duration, PACKET_DURATION * 12);
+        let packets = decode::demux(&capped).unwrap();
+        assert_eq!(packets.len(), 12);
+        // the newest capture is kept whole, the first only in part
+        let silence = FrameEncoder::new(&Config::for_tests().encoder)
+            .empty_encoded()
+            .clone();
+        assert_eq!(
+            packets.iter().filter(|packet| **packet == silence).count(),
+            10
+        );
+    }
+
+    #[test]
+    fn reference_without_enough_speech_is_refused() {
+        let mut config = Config::for_tests();
+        config.min_reference_speech = Duration::from_secs(1);
+        let tts = Tts::new(&config);
+
+        assert!(tts.check_reference(&[]).is_err());
+        assert!(tts.check_reference(&reference(0, 0)).is_err());
+        assert!(tts.check_reference(&reference(10, 100)).is_err());
+        assert!(tts.check_reference(&reference(60, 10)).is_ok());
+    }
+
+    #[test]
+    fn aligned_tracks_are_padded_to_equal_length() {
+        let buffer = buffer(|_| {});
+        for _ in 0..10 {
+            buffer.push(UserId(1), Some(tone()));
+        }
+        thread::sleep(Duration::from_millis(100));
+        for _ in 0..3 {
+            buffer.push(UserId(2), Some(tone()));
+        }
+
+        let tracks = buffer.get_aligned_packets(&[UserId(1), UserId(2)]);
+        let [(first, early), (second, late)] = &tracks[..] else {
+            panic!("expected two tracks");
+        };
+        assert_eq!((*first, *second), (UserId(1), UserId(2)));
+        assert_eq!(early.len(), late.len());
+        let is_silence = |packet: &bytes::Bytes| *packet == buffer.empty_encoded;
+        // the later user starts after the earlier one's buffer ends
+        assert!(late.len() > 13, "{}", late.len());
+        assert!(early[..10].iter().all(|packet| !is_silence(packet)));
+        assert!(early[10..].iter().all(is_silence));
+        let (lead, speech) = late.split_at(late.len() - 3);
+        assert!(lead.iter().all(is_silence));
+        assert!(speech.iter().all(|packet| !is_silence(packet)));
+    }
+
+    #[test]
+    fn every_tracked_user_gets_their_own_stream() {
+        let buffer = buffer(|_| {});
+        for user in 1..=3 {
+            for _ in 0..user * 5 {
+                buffer.push(UserId(user), Some(tone()));
+            }
+        }
+        let buffers = buffer
+            .get_all_ogg_buffers(|user| vec![format!("USER={}", user)])
+            .unwrap();
+
+        let mut users: Vec<_> = buffers.keys().copied().collect();
+        users.sort_by_key(|user| user.0);
+        assert_eq!(users, [UserId(1), UserId(2), UserId(3)]);
+        for (user, ogg_data) in &buffers {
+            assert_eq!(decode::demux(ogg_data).unwrap().len(), user.0 as usize * 5);
+            let tag = format!("USER={}", user);
+            assert!(ogg_data
+                .windows(tag.len())
+                .any(|window| window == tag.as_bytes()));
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_active_user() {
+        let buffer = buffer(|config| config.max_tracked_users = 2);
+        let push = |user, data| {
+            thread::sleep(Duration::from_millis(2));
+            buffer.push(UserId(user), data);
+            assert!(buffer.tracked_users().len() <= 2);
+        };
+        push(1, None);
+        push(2, None);
+        // speaking makes the first user the most recently active
+        push(1, Some(tone()));
+        push(3, None);
+
+        let mut users = buffer.tracked_users();
+        users.sort_by_key(|user| user.0);
+        assert_eq!(users, [UserId(1), UserId(3)]);
     }
 }
@@ -0,0 +1,164 @@
+use std::f64::consts::TAU;
+use std::time::Duration;
+
+use crate::receiver::{AUDIO_CHANNELS, AUDIO_FREQUENCY};
+use crate::resample;
+
+/// Rate speech is analysed at, the highest band is well below its Nyquist.
+const ANALYSIS_RATE: u32 = 16000;
+
+/// 25ms blocks, short enough that pitch barely moves within one.
+const BLOCK_SAMPLES: usize = 400;
+
+/// Blocks quieter than this are pauses or background and don't count toward a profile.
+const FLOOR_DBFS: f64 = -45.0;
+
+/// Bands are log spaced between these, covering where voices differ most. Each is wide enough to
+/// hold a bin of the block's spectrum.
+const LOWEST_BAND_HZ: f64 = 150.0;
+const HIGHEST_BAND_HZ: f64 = 7000.0;
+const BANDS: usize = 18;
+
+/// Average spectral shape of someone's speech, loudness taken out. A rough fingerprint of mic and
+/// voice together, nowhere near what speaker verification uses.
+pub struct VoiceProfile {
+    /// dB per band relative to the profile's mean.
+    bands: [f64; BANDS],
+    /// how much audio was loud enough to count.
+    pub speech: Duration,
+}
+
+impl VoiceProfile {
+    /// Profile of 48kHz interleaved stereo pcm, `None` if none of it is loud enough to be speech.
+    pub fn of(pcm: &[i16]) -> Option<Self> {
+        let mono: Vec<i16> = pcm
+            .chunks_exact(AUDIO_CHANNELS as usize)
+            .map(|frame| {
+                (frame.iter().map(|sample| *sample as i32).sum::<i32>() / frame.len() as i32) as i16
+            })
+            .collect();
+        let mono = resample::resample(&mono, 1, AUDIO_FREQUENCY, ANALYSIS_RATE);
+
+        let hann: Vec<f64> = (0..BLOCK_SAMPLES)
+            .map(|n| 0.5 - 0.5 * (TAU * n as f64 / BLOCK_SAMPLES as f64).cos())
+            .collect();
+        // the band each bin of the block's spectrum falls in, bins outside all bands left out
+        let bin_hz = ANALYSIS_RATE as f64 / BLOCK_SAMPLES as f64;
+        let bins: Vec<(usize, usize)> = (1..BLOCK_SAMPLES / 2)
+            .filter_map(|bin| {
+                let hz = bin as f64 * bin_hz;
+                if !(LOWEST_BAND_HZ..HIGHEST_BAND_HZ).contains(&hz) {
+                    return None;
+                }
+                let band = (hz / LOWEST_BAND_HZ).ln() / (HIGHEST_BAND_HZ / LOWEST_BAND_HZ).ln()
+                    * BANDS as f64;
+                Some((bin, band as usize))
+            })
+            .collect();
+        let twiddles: Vec<(f64, f64)> = (0..BLOCK_SAMPLES)
+            .map(|n| {
+                let angle = TAU * n as f64 / BLOCK_SAMPLES as f64;
+                (angle.cos(), angle.sin())
+            })
+            .collect();
+        let floor = 10f64.powf(FLOOR_DBFS / 10.0);
+        let mut power = [0f64; BANDS];
+        let mut blocks = 0;
+        for block in mono.chunks_exact(BLOCK_SAMPLES) {
+            let block: Vec<f64> = block
+                .iter()
+                .zip(&hann)
+                .map(|(sample, window)| *sample as f64 / i16::MAX as f64 * window)
+                .collect();
+            let mean_square =
+                block.iter().map(|sample| sample * sample).sum::<f64>() / BLOCK_SAMPLES as f64;
+            if mean_square < floor {
+                continue;
+            }
+            for (bin, band) in &bins {
+                let (re, im) = block
+                    .iter()
+                    .enumerate()
+                    .fold((0.0, 0.0), |(re, im), (n, x)| {
+                        let (cos, sin) = twiddles[bin * n % BLOCK_SAMPLES];
+                        (re + x * cos, im - x * sin)
+                    });
+                power[*band] += (re * re + im * im) / mean_square;
+            }
+            blocks += 1;
+        }
+        if blocks == 0 {
+            return None;
+        }
+        let mut bands =
+            power.map(|power| 10.0 * (power / blocks as f64).max(f64::MIN_POSITIVE).log10());
+        let mean = bands.iter().sum::<f64>() / BANDS as f64;
+        bands.iter_mut().for_each(|band| *band -= mean);
+        Some(Self {
+            bands,
+            speech: Duration::from_secs_f64((blocks * BLOCK_SAMPLES) as f64 / ANALYSIS_RATE as f64),
+        })
+    }
+
+    /// How alike two profiles are from 0 to 1, the correlation of their band levels with anything
+    /// negative as 0. Different people on similar mics can score high and the same person on
+    /// another mic low.
+    pub fn similarity(&self, other: &Self) -> f32 {
+        let dot: f64 = self
+            .bands
+            .iter()
+            .zip(&other.bands)
+            .map(|(a, b)| a * b)
+            .sum();
+        let norm = |bands: &[f64; BANDS]| bands.iter().map(|band| band * band).sum::<f64>().sqrt();
+        let norms = norm(&self.bands) * norm(&other.bands);
+        if norms == 0.0 {
+            return 0.0;
+        }
+        (dot / norms).max(0.0) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DEFAULT_VERIFY_THRESHOLD;
+
+    /// Two seconds of 48kHz stereo, a tone at `frequency` with its first few harmonics so it fills
+    /// more than one band.
+    fn voice(frequency: f64, amplitude: f64) -> Vec<i16> {
+        (0..2 * AUDIO_FREQUENCY as usize)
+            .flat_map(|i| {
+                let t = i as f64 / AUDIO_FREQUENCY as f64;
+                let sample = (1..=3)
+                    .map(|harmonic| (TAU * frequency * harmonic as f64 * t).sin() / harmonic as f64)
+                    .sum::<f64>()
+                    * amplitude
+                    / 2.0;
+                [sample as i16; AUDIO_CHANNELS as usize]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn profile_is_similar_to_itself() {
+        let profile = VoiceProfile::of(&voice(220.0, 8000.0)).unwrap();
+        assert!((profile.similarity(&profile) - 1.0).abs() < 1e-4);
+        assert_eq!(profile.speech, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn no_profile_below_the_floor() {
+        assert!(VoiceProfile::of(&vec![0; 2 * AUDIO_FREQUENCY as usize * 2]).is_none());
+        // about -60 dBFS, well under FLOOR_DBFS
+        assert!(VoiceProfile::of(&voice(220.0, 30.0)).is_none());
+    }
+
+    #[test]
+    fn low_and_high_tones_are_told_apart() {
+        let low = VoiceProfile::of(&voice(180.0, 8000.0)).unwrap();
+        let high = VoiceProfile::of(&voice(1800.0, 8000.0)).unwrap();
+        let similarity = low.similarity(&high);
+        assert!(similarity < DEFAULT_VERIFY_THRESHOLD, "{}", similarity);
+    }
+}
@@ -0,0 +1,94 @@
+use crate::receiver::AUDIO_CHANNELS;
+
+const BACKGROUND: [u8; 3] = [0x2b, 0x2d, 0x31];
+const FOREGROUND: [u8; 3] = [0x58, 0x65, 0xf2];
+const CENTER_LINE: [u8; 3] = [0x4e, 0x50, 0x58];
+
+/// Renders interleaved pcm as a png of its peak envelope, one column per slice of time with a bar
+/// mirrored around the center line as tall as the loudest sample in that slice.
+pub fn render_png(pcm: &[i16], width: u32, height: u32) -> anyhow::Result<Vec<u8>> {
+    let width = width.max(1);
+    let height = height.max(1);
+    let peaks = peak_envelope(pcm, width as usize);
+    let center = height / 2;
+
+    let mut pixels = Vec::with_capacity(width as usize * height as usize * 3);
+    for y in 0..height {
+        // distance from the center line as a fraction of half the height
+        let level = y.abs_diff(center) as f32 / center.max(1) as f32;
+        for peak in &peaks {
+            let color = if y == center {
+                CENTER_LINE
+            } else if level <= *peak {
+                FOREGROUND
+            } else {
+                BACKGROUND
+            };
+            pixels.extend_from_slice(&color);
+        }
+    }
+
+    let mut png_data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png_data, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+    writer.finish()?;
+    Ok(png_data)
+}
+
+/// Peak of each of `columns` equal slices of the audio, 0 to 1.
+fn peak_envelope(pcm: &[i16], columns: usize) -> Vec<f32> {
+    let frames = pcm.len() / AUDIO_CHANNELS as usize;
+    (0..columns)
+        .map(|column| {
+            let start = frames * column / columns * AUDIO_CHANNELS as usize;
+            let end = frames * (column + 1) / columns * AUDIO_CHANNELS as usize;
+            let peak = pcm[start..end]
+                .iter()
+                .map(|sample| sample.unsigned_abs())
+                .max()
+                .unwrap_or(0);
+            // i16::MIN is a step further out than i16::MAX
+            (peak as f32 / i16::MAX as f32).min(1.0)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn peak_envelope_splits_at_column_boundaries() {
+        // 4 columns of 100 frames, a peak on the first and last frame of the middle two
+        let mut pcm = vec![0i16; 400 * AUDIO_CHANNELS as usize];
+        let frame = |frame: usize| frame * AUDIO_CHANNELS as usize;
+        pcm[frame(100)] = i16::MAX;
+        pcm[frame(299) + 1] = i16::MIN;
+        pcm[frame(350)] = i16::MAX / 2;
+
+        let peaks = peak_envelope(&pcm, 4);
+        assert_eq!(peaks.len(), 4);
+        assert_eq!(peaks[0], 0.0);
+        assert_eq!(peaks[1], 1.0);
+        assert_eq!(peaks[2], 1.0);
+        assert!((peaks[3] - 0.5).abs() < 0.001, "{}", peaks[3]);
+    }
+
+    #[test]
+    fn rendered_png_has_the_requested_size() {
+        let pcm: Vec<i16> = (0..48000).map(|i| (i % 2000) as i16 * 10).collect();
+        let png_data = render_png(&pcm, 300, 80).unwrap();
+
+        let reader = png::Decoder::new(Cursor::new(png_data))
+            .read_info()
+            .unwrap();
+        let info = reader.info();
+        assert_eq!((info.width, info.height), (300, 80));
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+    }
+}